@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use futures_util::Stream;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::schema::{ClientUsageReport, ModelUsageReport, UsageReport, SCHEMA_VERSION};
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest response line looked at for token counts, the final objects of Ollama are far
+/// shorter. Longer lines, e.g. of responses that are not NDJSON, are relayed unmetered.
+const MAX_LINE: usize = 1024 * 1024;
+
+/// Limits applied to every API key. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_day: Option<u64>,
+}
+
+/// Usage of one model by a client.
+#[derive(Debug, Default, Clone, Copy)]
+struct ModelUsage {
+    requests: u64,
+    tokens: u64,
+}
+
+/// Usage of a single client, an API key or another identity, or an address without
+/// authentication, counted in fixed windows.
+#[derive(Debug)]
+struct KeyUsage {
+    minute_start: Instant,
+    minute_requests: u32,
+    day_start: Instant,
+    day_tokens: u64,
+    total_requests: u64,
+    total_tokens: u64,
+    models: HashMap<String, ModelUsage>, // counted from the final chunks
+    secret: bool, // the client is an API key, only shown masked
+}
+
+impl KeyUsage {
+    fn new(now: Instant) -> Self {
+        KeyUsage {
+            minute_start: now,
+            minute_requests: 0,
+            day_start: now,
+            day_tokens: 0,
+            total_requests: 0,
+            total_tokens: 0,
+            models: HashMap::new(),
+            secret: true,
+        }
+    }
+
+    /// How the client is shown in logs and reports.
+    fn label(&self, key: &str) -> String {
+        if self.secret { mask_key(key) } else { key.to_string() }
+    }
+
+    fn roll_windows(&mut self, now: Instant) {
+        if now.duration_since(self.minute_start) >= MINUTE {
+            self.minute_start = now;
+            self.minute_requests = 0;
+        }
+        if now.duration_since(self.day_start) >= DAY {
+            self.day_start = now;
+            self.day_tokens = 0;
+        }
+    }
+}
+
+/// Per-client request and token accounting, limited for authenticated clients.
+#[derive(Debug)]
+pub struct Accounting {
+    limits: Limits,
+    usage: Mutex<HashMap<String, KeyUsage>>,
+}
+
+impl Accounting {
+    pub fn new(limits: Limits) -> Self {
+        Accounting { limits, usage: Mutex::new(HashMap::new()) }
+    }
+
+    /// Counts a new request for `key`, `secret` if it is an API key.
+    /// Returns `Err(retry_after)` without counting it if a limit is exceeded.
+    pub fn admit(&self, key: &str, secret: bool) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(key.to_string()).or_insert_with(|| KeyUsage::new(now));
+        entry.roll_windows(now);
+        entry.secret = secret;
+        if let Some(max) = self.limits.tokens_per_day {
+            if entry.day_tokens >= max {
+                return Err(DAY.saturating_sub(now.duration_since(entry.day_start)));
+            }
+        }
+        if let Some(max) = self.limits.requests_per_minute {
+            if entry.minute_requests >= max {
+                return Err(MINUTE.saturating_sub(now.duration_since(entry.minute_start)));
+            }
+        }
+        entry.minute_requests += 1;
+        entry.total_requests += 1;
+        Ok(())
+    }
+
+    /// Counts a request of an unauthenticated client, never limited.
+    pub fn count(&self, client: &str) {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(client.to_string()).or_insert_with(|| KeyUsage::new(now));
+        entry.secret = false;
+        entry.total_requests += 1;
+    }
+
+    /// Usage of every key, as persisted by the storage layer.
+    pub fn export(&self) -> Vec<(String, Value)> {
+        let now = Instant::now();
+        let usage = self.usage.lock().unwrap();
+        usage.iter().map(|(key, u)| (key.clone(), json!({
+            "total_requests": u.total_requests,
+            "total_tokens": u.total_tokens,
+            "day_tokens": u.day_tokens,
+            "day_age_secs": now.duration_since(u.day_start).as_secs(),
+            "models": u.models.iter().map(|(model, m)| (model.clone(), json!({ "requests": m.requests, "tokens": m.tokens }))).collect::<serde_json::Map<_, _>>(),
+            "secret": u.secret,
+        }))).collect()
+    }
+
+    /// Usage of every client, the most tokens first, served on `/admin/usage`.
+    pub fn report(&self) -> UsageReport {
+        let usage = self.usage.lock().unwrap();
+        let mut clients = usage.iter().map(|(key, u)| {
+            let mut models = u.models.iter().map(|(model, m)| ModelUsageReport {
+                model: model.clone(),
+                requests: m.requests,
+                tokens: m.tokens,
+            }).collect::<Vec<_>>();
+            models.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.model.cmp(&b.model)));
+            ClientUsageReport {
+                client: u.label(key),
+                requests: u.total_requests,
+                tokens: u.total_tokens,
+                day_tokens: u.day_tokens,
+                models,
+            }
+        }).collect::<Vec<_>>();
+        clients.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.client.cmp(&b.client)));
+        UsageReport { schema_version: SCHEMA_VERSION, generated_at: chrono::Utc::now().to_rfc3339(), clients }
+    }
+
+    /// Restores usage exported by `export`, possibly by another balancer instance.
+    pub fn restore(&self, key: &str, value: &Value) {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(key.to_string()).or_insert_with(|| KeyUsage::new(now));
+        entry.total_requests = entry.total_requests.max(value["total_requests"].as_u64().unwrap_or(0));
+        entry.total_tokens = entry.total_tokens.max(value["total_tokens"].as_u64().unwrap_or(0));
+        let day_age = Duration::from_secs(value["day_age_secs"].as_u64().unwrap_or(u64::MAX));
+        if day_age < DAY {
+            entry.day_start = now.checked_sub(day_age).unwrap_or(now);
+            entry.day_tokens = entry.day_tokens.max(value["day_tokens"].as_u64().unwrap_or(0));
+        }
+        for (model, m) in value["models"].as_object().into_iter().flatten() {
+            let restored = entry.models.entry(model.clone()).or_default();
+            restored.requests = restored.requests.max(m["requests"].as_u64().unwrap_or(0));
+            restored.tokens = restored.tokens.max(m["tokens"].as_u64().unwrap_or(0));
+        }
+        // usage persisted before clients were told apart was only ever of API keys
+        entry.secret = value["secret"].as_bool().unwrap_or(true);
+    }
+
+    pub fn record_tokens(&self, key: &str, model: &str, tokens: u64) {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(key.to_string()).or_insert_with(|| KeyUsage::new(now));
+        entry.roll_windows(now);
+        entry.day_tokens += tokens;
+        entry.total_tokens += tokens;
+        let model_usage = entry.models.entry(model.to_string()).or_default();
+        model_usage.requests += 1;
+        model_usage.tokens += tokens;
+        info!(
+            "Client {} used {} tokens of {}, today: {}, total: {} tokens in {} requests",
+            entry.label(key), tokens, model, entry.day_tokens, entry.total_tokens, entry.total_requests
+        );
+        if self.limits.tokens_per_day.is_some_and(|max| entry.day_tokens >= max) {
+            warn!("Client {} exhausted its daily token quota", entry.label(key));
+        }
+    }
+}
+
+/// Only the first characters of a key are ever logged.
+pub fn mask_key(key: &str) -> String {
+    let prefix: String = key.chars().take(4).collect();
+    format!("{}***", prefix)
+}
+
+/// Extracts the model and the token usage reported by Ollama in a final response object.
+/// Embeddings only report `prompt_eval_count`, and have no `done`.
+fn tokens_of(line: &[u8]) -> Option<(String, u64)> {
+    let value: Value = serde_json::from_slice(line).ok()?;
+    if value["done"] == json!(false) {
+        return None;
+    }
+    let (eval, prompt_eval) = (value["eval_count"].as_u64(), value["prompt_eval_count"].as_u64());
+    if eval.is_none() && prompt_eval.is_none() {
+        return None;
+    }
+    let model = value["model"].as_str().unwrap_or_default().to_string();
+    Some((model, eval.unwrap_or(0) + prompt_eval.unwrap_or(0)))
+}
+
+/// Relays a response body while picking up the token counts of NDJSON responses,
+/// which are charged to `key` once seen.
+pub struct MeteredBody<S> {
+    stream: S,
+    accounting: Arc<Accounting>,
+    key: String,
+    line: Vec<u8>,
+    /// The current line outgrew `MAX_LINE`, it is skipped up to its end.
+    skipping: bool,
+}
+
+impl<S> MeteredBody<S> {
+    pub fn new(stream: S, accounting: Arc<Accounting>, key: String) -> Self {
+        MeteredBody { stream, accounting, key, line: Vec::new(), skipping: false }
+    }
+
+    fn inspect(&mut self, line: &[u8]) {
+        if let Some((model, tokens)) = tokens_of(line) {
+            self.accounting.record_tokens(&self.key, &model, tokens);
+        }
+    }
+}
+
+impl<S, E> Stream for MeteredBody<S>
+where
+    S: Stream<Item = Result<bytes::Bytes, E>> + Unpin,
+{
+    type Item = Result<bytes::Bytes, E>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let res = Pin::new(&mut self.stream).poll_next(cx);
+        match &res {
+            Poll::Ready(Some(Ok(bytes))) => {
+                let mut bytes = &bytes[..];
+                if self.skipping {
+                    match bytes.iter().position(|b| *b == b'\n') {
+                        Some(pos) => {
+                            self.skipping = false;
+                            bytes = &bytes[pos + 1..];
+                        },
+                        None => bytes = &[],
+                    }
+                }
+                self.line.extend_from_slice(bytes);
+                while let Some(pos) = self.line.iter().position(|b| *b == b'\n') {
+                    let line = self.line.drain(..=pos).collect::<Vec<u8>>();
+                    self.inspect(&line);
+                }
+                // bodies with a Content-Length, e.g. embeddings, are not polled past their end
+                if self.line.ends_with(b"}") && serde_json::from_slice::<serde::de::IgnoredAny>(&self.line).is_ok() {
+                    let line = std::mem::take(&mut self.line);
+                    self.inspect(&line);
+                }
+                if self.line.len() > MAX_LINE {
+                    self.line = Vec::new();
+                    self.skipping = true;
+                }
+            },
+            Poll::Ready(None) => {
+                // non-streaming responses are a single object without a trailing newline
+                let line = std::mem::take(&mut self.line);
+                self.inspect(&line);
+            },
+            _ => {},
+        }
+        res
+    }
+}
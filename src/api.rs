@@ -1,7 +1,7 @@
 use crate::state::ModelConfig;
 use reqwest::Method;
 
-use crate::backend::send_request;
+use crate::backend::{check_ollama_response, send_request, TimeoutProfile};
 
 pub async fn api_tags(
     backend_url: &str, timeout_secs: u32
@@ -9,14 +9,15 @@ pub async fn api_tags(
     let uri = "/api/tags";
     let res = send_request(
         (uri.to_string(), Method::GET, uri.to_string(), None, None),
-        backend_url, timeout_secs
+        backend_url, TimeoutProfile::read(timeout_secs)
     ).await?;
+    check_ollama_response(res.status(), res.headers())?;
 
     let data = res.json::<serde_json::Value>().await?;
     let models = data["models"].as_array().unwrap().iter().map(|m| {
         let name = m["name"].as_str().unwrap().to_string();
         let detail = m.clone();
-        ModelConfig { name, detail }
+        ModelConfig::new(name, detail)
     }).collect();
     Ok(models)
 }
@@ -27,14 +28,30 @@ pub async fn api_ps(
     let uri = "/api/ps";
     let res = send_request(
         (uri.to_string(), Method::GET, uri.to_string(), None, None),
-        backend_url, timeout_secs
+        backend_url, TimeoutProfile::read(timeout_secs)
     ).await?;
+    check_ollama_response(res.status(), res.headers())?;
 
     let data = res.json::<serde_json::Value>().await?;
     let models = data["models"].as_array().unwrap().iter().map(|m| {
         let name = m["name"].as_str().unwrap().to_string();
         let detail = m.clone();
-        ModelConfig { name, detail }
+        ModelConfig::new(name, detail)
     }).collect();
     Ok(models)
-}
\ No newline at end of file
+}
+
+pub async fn api_version(
+    backend_url: &str, timeout_secs: u32
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let uri = "/api/version";
+    let res = send_request(
+        (uri.to_string(), Method::GET, uri.to_string(), None, None),
+        backend_url, TimeoutProfile::read(timeout_secs)
+    ).await?;
+    check_ollama_response(res.status(), res.headers())?;
+
+    let data = res.json::<serde_json::Value>().await?;
+    let version = data["version"].as_str().ok_or("Missing 'version' field")?;
+    Ok(version.to_string())
+}
@@ -0,0 +1,66 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+
+/// Which backend served one turn of a session.
+#[derive(Debug, Clone)]
+pub struct RoutingRecord {
+    pub at: DateTime<Utc>,
+    pub model: String,
+    pub server: String,
+    pub server_name: String,
+    pub candidates: usize, // servers the request was raced on, 0 if it shared an identical request's response
+    pub ttft: Duration,
+}
+
+/// Bounded routing history per session, to see whether placement changed between turns.
+/// Both the turns kept per session and the sessions themselves are bounded,
+/// the least recently active session is dropped first.
+#[derive(Debug)]
+pub struct AuditTrail {
+    turns: usize,
+    sessions: usize,
+    tick: u64,
+    trails: HashMap<String, (VecDeque<RoutingRecord>, u64)>,
+}
+
+pub type SharedAuditTrail = Arc<Mutex<AuditTrail>>;
+
+impl AuditTrail {
+    pub fn new(turns: usize, sessions: usize) -> Self {
+        AuditTrail { turns, sessions, tick: 0, trails: HashMap::new() }
+    }
+
+    /// Records a turn and returns the trail of the session, oldest first.
+    pub fn record(&mut self, session: &str, record: RoutingRecord) -> Vec<RoutingRecord> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (trail, last_used) = self.trails.entry(session.to_string()).or_default();
+        trail.push_back(record);
+        while trail.len() > self.turns {
+            trail.pop_front();
+        }
+        *last_used = tick;
+        let trail = trail.iter().cloned().collect();
+        if self.trails.len() > self.sessions {
+            let oldest = self.trails.iter().min_by_key(|(_, (_, last_used))| *last_used).map(|(s, _)| s.clone());
+            if let Some(oldest) = oldest {
+                self.trails.remove(&oldest);
+            }
+        }
+        trail
+    }
+
+    /// Every session with its trail, most recently active first.
+    pub fn export(&self) -> Vec<(String, Vec<RoutingRecord>)> {
+        let mut sessions = self.trails.iter().collect::<Vec<_>>();
+        sessions.sort_by_key(|(_, (_, last_used))| std::cmp::Reverse(*last_used));
+        sessions.into_iter().map(|(s, (trail, _))| (s.clone(), trail.iter().cloned().collect())).collect()
+    }
+}
+
+/// Value of the `X-Routing-Trail` header: the servers of the last turns, oldest first.
+pub fn trail_header(trail: &[RoutingRecord]) -> String {
+    trail.iter().map(|r| r.server_name.as_str()).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,327 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
+use hyper::header::{HeaderMap, HeaderName, AUTHORIZATION, COOKIE};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::config::AuthScope;
+use crate::tls;
+
+/// Who a request comes from, as established by a provider of the chain.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// Requests are accounted and limited by it, the key itself for API keys.
+    pub id: String,
+    /// Whether `id` is a credential, never shown in full.
+    pub secret: bool,
+}
+
+impl Identity {
+    /// What the identity can be shown as outside the load balancer: a stable id derived from
+    /// the key for credentials, the identity itself otherwise.
+    pub fn public_id(&self) -> String {
+        if !self.secret {
+            return self.id.clone();
+        }
+        let digest = Sha256::digest(self.id.as_bytes());
+        format!("key:{}", digest[..8].iter().map(|b| format!("{:02x}", b)).collect::<String>())
+    }
+}
+
+/// What a provider makes of a request.
+pub enum AuthOutcome {
+    Allowed(Identity),
+    /// Credentials of its kind, but wrong: the chain stops there.
+    Denied,
+    /// No credentials of its kind, the next provider is asked.
+    Abstained,
+}
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = AuthOutcome> + Send + 'a>>;
+
+/// Tells who a request comes from, one scheme per implementation.
+pub trait AuthProvider: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap, remote_addr: SocketAddr) -> AuthFuture<'a>;
+}
+
+/// Set of API keys accepted from clients as `Authorization: Bearer <key>`.
+#[derive(Debug)]
+pub struct ApiKeys {
+    keys: HashSet<String>,
+}
+
+impl ApiKeys {
+    /// Loads keys from a file, one key per line. Empty lines and lines starting with `#` are skipped.
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let keys = contents.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        Ok(ApiKeys { keys })
+    }
+
+    pub fn extend(&mut self, keys: impl IntoIterator<Item = String>) {
+        self.keys.extend(keys);
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+impl AuthProvider for ApiKeys {
+    fn name(&self) -> &'static str {
+        "keys"
+    }
+
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap, _remote_addr: SocketAddr) -> AuthFuture<'a> {
+        let presented = headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+        let outcome = match presented {
+            None => AuthOutcome::Abstained,
+            Some(key) => match self.keys.get(key) {
+                Some(key) => AuthOutcome::Allowed(Identity { id: key.clone(), secret: true }),
+                None => AuthOutcome::Denied,
+            },
+        };
+        Box::pin(std::future::ready(outcome))
+    }
+}
+
+/// The identity in a header set by a proxy in front of the balancer, e.g. the subject of a
+/// client certificate after mTLS. The header is ignored from any other address.
+#[derive(Debug)]
+pub struct TrustedHeader {
+    header: HeaderName,
+    proxies: Vec<IpAddr>,
+}
+
+impl TrustedHeader {
+    pub fn new(header: &str, proxies: Vec<IpAddr>) -> Result<Self, String> {
+        let header = HeaderName::try_from(header).map_err(|e| format!("Invalid trusted header {}: {}", header, e))?;
+        Ok(TrustedHeader { header, proxies })
+    }
+}
+
+impl AuthProvider for TrustedHeader {
+    fn name(&self) -> &'static str {
+        "trusted-header"
+    }
+
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap, remote_addr: SocketAddr) -> AuthFuture<'a> {
+        let identity = headers.get(&self.header)
+            .filter(|_| self.proxies.contains(&remote_addr.ip()))
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty());
+        let outcome = match identity {
+            Some(identity) => AuthOutcome::Allowed(Identity { id: identity.to_string(), secret: false }),
+            None => AuthOutcome::Abstained,
+        };
+        Box::pin(std::future::ready(outcome))
+    }
+}
+
+/// Asks an external service whether the credentials of the client are valid, failing closed.
+#[derive(Debug)]
+pub struct HttpCallout {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl HttpCallout {
+    pub fn new(url: String) -> Self {
+        let http = tls::client_builder().timeout(Duration::from_secs(5)).build().unwrap();
+        HttpCallout { url, http }
+    }
+}
+
+impl AuthProvider for HttpCallout {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap, remote_addr: SocketAddr) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let credentials = [AUTHORIZATION, COOKIE].into_iter()
+                .filter_map(|name| headers.get(&name).map(|value| (name, value.clone())))
+                .collect::<HeaderMap>();
+            if credentials.is_empty() {
+                return AuthOutcome::Abstained;
+            }
+            let resp = self.http.get(&self.url)
+                .headers(credentials)
+                .header("X-Forwarded-For", remote_addr.ip().to_string())
+                .send().await;
+            match resp {
+                Ok(resp) if resp.status().is_success() => {
+                    let id = resp.headers().get("x-auth-identity")
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or("anonymous");
+                    AuthOutcome::Allowed(Identity { id: id.to_string(), secret: false })
+                },
+                Ok(resp) => {
+                    if !matches!(resp.status().as_u16(), 401 | 403) {
+                        warn!("Authentication service {} answered {}, denying", self.url, resp.status());
+                    }
+                    AuthOutcome::Denied
+                },
+                Err(e) => {
+                    warn!("Authentication service {} failed, denying: {}", self.url, e);
+                    AuthOutcome::Denied
+                },
+            }
+        })
+    }
+}
+
+/// Signed tokens sent as `Authorization: Bearer <jwt>`, identified by their `sub`. They must
+/// not be expired, and name the configured audience if any. Bearer values that are not JWTs are
+/// left to the other providers, so that API keys and tokens can be chained.
+pub struct Jwt {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl std::fmt::Debug for Jwt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Jwt").field("algorithms", &self.validation.algorithms).finish_non_exhaustive()
+    }
+}
+
+impl Jwt {
+    /// Loads a PEM public key, or else a shared secret, the algorithms accepted following its kind.
+    pub fn load(path: &str, audience: Option<&str>) -> Result<Self, String> {
+        let contents = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let (key, algorithms) = if contents.starts_with(b"-----BEGIN") {
+            if let Ok(key) = DecodingKey::from_rsa_pem(&contents) {
+                (key, vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512, Algorithm::PS256, Algorithm::PS384, Algorithm::PS512])
+            } else if let Ok(key) = DecodingKey::from_ec_pem(&contents) {
+                (key, vec![Algorithm::ES256, Algorithm::ES384])
+            } else if let Ok(key) = DecodingKey::from_ed_pem(&contents) {
+                (key, vec![Algorithm::EdDSA])
+            } else {
+                return Err(format!("{} is not an RSA, EC or Ed25519 public key", path));
+            }
+        } else {
+            let secret = contents.trim_ascii_end();
+            if secret.is_empty() {
+                return Err(format!("The JWT secret in {} is empty", path));
+            }
+            (DecodingKey::from_secret(secret), vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512])
+        };
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        match audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(Jwt { key, validation })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+impl AuthProvider for Jwt {
+    fn name(&self) -> &'static str {
+        "jwt"
+    }
+
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap, _remote_addr: SocketAddr) -> AuthFuture<'a> {
+        let token = headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .filter(|token| token.split('.').count() == 3);
+        let outcome = match token.map(|token| jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)) {
+            None => AuthOutcome::Abstained,
+            Some(Ok(data)) => AuthOutcome::Allowed(Identity { id: data.claims.sub, secret: false }),
+            Some(Err(e)) => {
+                debug!("Rejected JWT: {}", e);
+                AuthOutcome::Denied
+            },
+        };
+        Box::pin(std::future::ready(outcome))
+    }
+}
+
+/// The providers tried in order on the requests of their scope. The first to allow or deny a
+/// request decides, a request of a scope having providers is refused if they all abstain.
+#[derive(Debug, Default)]
+pub struct AuthChain {
+    providers: Vec<(AuthScope, Box<dyn AuthProvider>)>,
+}
+
+impl AuthChain {
+    pub fn push(&mut self, scope: AuthScope, provider: Box<dyn AuthProvider>) {
+        self.providers.push((scope, provider));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Whether some provider authenticates the requests of `scope`.
+    pub fn covers(&self, scope: AuthScope) -> bool {
+        self.providers.iter().any(|(s, _)| *s == AuthScope::All || *s == scope)
+    }
+
+    pub fn describe(&self) -> String {
+        self.providers.iter().map(|(scope, provider)| format!("{}@{:?}", provider.name(), scope).to_lowercase()).collect::<Vec<_>>().join(", ")
+    }
+
+    /// `Ok(None)` for the requests no provider applies to, `Err(())` for those refused.
+    pub async fn authenticate(&self, path: &str, headers: &HeaderMap, remote_addr: SocketAddr) -> Result<Option<Identity>, ()> {
+        match scope_of(path) {
+            Some(scope) => self.authenticate_scope(scope, headers, remote_addr).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Same as `authenticate`, for the requests of `scope` whatever their path.
+    pub async fn authenticate_scope(&self, scope: AuthScope, headers: &HeaderMap, remote_addr: SocketAddr) -> Result<Option<Identity>, ()> {
+        let applicable = self.providers.iter()
+            .filter(|(s, _)| *s == AuthScope::All || *s == scope)
+            .map(|(_, provider)| provider)
+            .collect::<Vec<_>>();
+        if applicable.is_empty() {
+            return Ok(None);
+        }
+        for provider in applicable {
+            match provider.authenticate(headers, remote_addr).await {
+                AuthOutcome::Allowed(identity) => return Ok(Some(identity)),
+                AuthOutcome::Denied => return Err(()),
+                AuthOutcome::Abstained => continue,
+            }
+        }
+        Err(())
+    }
+}
+
+/// The scope of a path, none for those never authenticated by the chain: `/`, `/healthz` and
+/// `/readyz` must stay reachable for health checks, `/admin/register` checks its own
+/// registration token, and the backends pulling through the `/v2` registry mirror send no
+/// credentials, the mirror only serving the addresses of the backends instead. Every other admin endpoint is authenticated by the chain like the API.
+pub fn scope_of(path: &str) -> Option<AuthScope> {
+    match path {
+        "/" | "/healthz" | "/readyz" | "/admin/register" => None,
+        _ if path.starts_with("/v2") => None,
+        // draining a backend is an operation on the fleet, not an inference call
+        _ if path.starts_with("/admin/") || path.starts_with("/backend/") || path == "/metrics" => Some(AuthScope::Admin),
+        _ => Some(AuthScope::Api),
+    }
+}
@@ -0,0 +1,76 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::tls;
+
+/// What the authorization service made of a request.
+pub enum AuthzDecision {
+    /// With the headers to add to the request forwarded to the backends.
+    Allowed(HeaderMap),
+    Denied(String),
+}
+
+/// Delegates the authorization of each request to an external policy engine, e.g. OPA behind
+/// a small adapter, so that no policy lives in the load balancer.
+#[derive(Debug)]
+pub struct Authorizer {
+    url: String,
+    fail_open: bool,
+    http: reqwest::Client,
+}
+
+impl Authorizer {
+    pub fn new(url: String, fail_open: bool) -> Self {
+        let http = tls::client_builder().timeout(Duration::from_secs(5)).build().unwrap();
+        Authorizer { url, fail_open, http }
+    }
+
+    pub async fn authorize(
+        &self,
+        method: &str,
+        path: &str,
+        model: Option<&str>,
+        identity: Option<&str>,
+        remote_addr: SocketAddr,
+    ) -> AuthzDecision {
+        let check = json!({
+            "method": method,
+            "path": path,
+            "model": model,
+            "identity": identity,
+            "remote_addr": remote_addr.ip().to_string(),
+        });
+        let resp = match self.http.post(&self.url).json(&check).send().await {
+            Ok(resp) => resp,
+            Err(e) if self.fail_open => {
+                warn!("Authorization service {} failed, allowing: {}", self.url, e);
+                return AuthzDecision::Allowed(HeaderMap::new());
+            },
+            Err(e) => {
+                warn!("Authorization service {} failed, denying: {}", self.url, e);
+                return AuthzDecision::Denied("Authorization service unavailable".to_string());
+            },
+        };
+        let status = resp.status();
+        // an empty body is a plain allow or deny by status
+        let verdict = resp.json::<Value>().await.unwrap_or(Value::Null);
+        let reason = verdict["reason"].as_str().unwrap_or("Denied by policy").to_string();
+        if !status.is_success() || verdict["allow"] == json!(false) {
+            return AuthzDecision::Denied(reason);
+        }
+        let headers = verdict["headers"].as_object().into_iter().flatten()
+            .filter_map(|(name, value)| {
+                let header = HeaderName::try_from(name.as_str()).ok()
+                    .zip(value.as_str().and_then(|value| HeaderValue::from_str(value).ok()));
+                if header.is_none() {
+                    warn!("Authorization service {} injected an invalid header {}, ignored", self.url, name);
+                }
+                header
+            })
+            .collect();
+        AuthzDecision::Allowed(headers)
+    }
+}
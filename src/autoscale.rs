@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::schema::{AutoscaleReport, SCHEMA_VERSION};
+use crate::state::{requests_queued, snapshot_servers, Health, ServerSnapshot, SharedServerList};
+use crate::tls;
+
+/// Below this share of servers meeting the SLO, one more server is asked for.
+const SLO_TARGET: f64 = 0.9;
+
+/// Alive, not misconfigured, isolated, draining or a duplicate.
+fn takes_requests(snap: &ServerSnapshot) -> bool {
+    snap.state.health != Health::Dead && snap.state.misconfigured.is_none()
+        && !snap.state.isolated && snap.state.leaving.is_none() && snap.state.duplicate_of.is_none()
+}
+
+/// Demand seen at one instant.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    healthy: usize,
+    busy: usize,
+    queued: usize,
+}
+
+/// Turns the demand the balancer sees into the backend count it calls for, so that external
+/// automation can start or stop GPU workers. The demand is sampled every second, or on each
+/// report without a webhook, and averaged over a window, not to flap on a burst.
+#[derive(Debug)]
+pub struct Autoscaler {
+    window: Duration,
+    target_utilization: f64,
+    slo_ttft_ms: u32,
+    webhook: Option<String>,
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl Autoscaler {
+    pub fn new(window: Duration, target_utilization: f64, slo_ttft_ms: u32, webhook: Option<String>) -> Self {
+        Autoscaler { window, target_utilization, slo_ttft_ms, webhook, samples: Mutex::new(VecDeque::new()) }
+    }
+
+    fn sample(&self, servers: &SharedServerList) {
+        let snaps = snapshot_servers(servers.clone(), false);
+        let taking = snaps.values().filter(|snap| takes_requests(snap));
+        let (healthy, busy) = taking.fold((0, 0), |(healthy, busy), snap| (healthy + 1, busy + snap.state.busy as usize));
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        while samples.front().is_some_and(|sample| now.duration_since(sample.at) >= self.window) {
+            samples.pop_front();
+        }
+        samples.push_back(Sample { at: now, healthy, busy, queued: requests_queued() });
+    }
+
+    /// The signal over the last window.
+    pub fn report(&self, servers: &SharedServerList) -> AutoscaleReport {
+        // no loop samples the demand without a webhook to post to
+        if self.webhook.is_none() {
+            self.sample(servers);
+        }
+        let snaps = snapshot_servers(servers.clone(), false);
+        let healthy = snaps.values().filter(|snap| takes_requests(snap)).collect::<Vec<_>>();
+        let (busy, queued, utilization) = {
+            let samples = self.samples.lock().unwrap();
+            let n = samples.len().max(1) as f64;
+            (
+                samples.iter().map(|s| s.busy).sum::<usize>() as f64 / n,
+                samples.iter().map(|s| s.queued).sum::<usize>() as f64 / n,
+                samples.iter().map(|s| s.busy as f64 / s.healthy.max(1) as f64).sum::<f64>() / n,
+            )
+        };
+        // servers not measured yet are given the benefit of the doubt
+        let slo_compliance = match self.slo_ttft_ms {
+            0 => 1.0,
+            slo => healthy.iter()
+                .filter(|snap| snap.state.latency_ms.is_none_or(|ms| ms <= slo as f32))
+                .count() as f64 / healthy.len().max(1) as f64,
+        };
+        let demand = ((busy + queued) / self.target_utilization).ceil() as usize;
+        let (mut desired, mut reason) = match (queued > 0.0, busy > 0.0) {
+            (true, _) => (demand, "queue"),
+            (false, true) => (demand, "utilization"),
+            (false, false) => (1, "idle"),
+        };
+        if slo_compliance < SLO_TARGET && desired <= healthy.len() {
+            (desired, reason) = (healthy.len() + 1, "slo");
+        }
+        AutoscaleReport {
+            schema_version: SCHEMA_VERSION,
+            desired_servers: desired.max(1),
+            current_servers: snaps.len(),
+            healthy_servers: healthy.len(),
+            queue_depth: queued,
+            utilization,
+            slo_compliance,
+            reason: reason.to_string(),
+            window_secs: self.window.as_secs(),
+        }
+    }
+}
+
+/// Samples the demand every second and, every window, posts the signal to the webhook if the
+/// desired count changed.
+pub async fn autoscale_loop(autoscaler: Arc<Autoscaler>, servers: SharedServerList) {
+    let Some(webhook) = autoscaler.webhook.clone() else {
+        return;
+    };
+    let http = tls::client_builder().timeout(Duration::from_secs(10)).build().unwrap();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut last_desired = None;
+    for tick in 1u64.. {
+        ticker.tick().await;
+        autoscaler.sample(&servers);
+        if tick % autoscaler.window.as_secs().max(1) != 0 {
+            continue;
+        }
+        let report = autoscaler.report(&servers);
+        if last_desired == Some(report.desired_servers) {
+            continue;
+        }
+        info!(
+            "Autoscaling signal: {} servers desired, {} healthy ({}, utilization {:.2}, queue {:.1}, SLO compliance {:.2})",
+            report.desired_servers, report.healthy_servers, report.reason, report.utilization, report.queue_depth, report.slo_compliance
+        );
+        // a failed delivery is retried on the next window
+        match http.post(&webhook).json(&report).send().await.and_then(|resp| resp.error_for_status()) {
+            Ok(_) => last_desired = Some(report.desired_servers),
+            Err(e) => warn!("Failed to post the autoscaling signal to {}: {}", webhook, e),
+        }
+    }
+}
@@ -1,23 +1,436 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::time::{Duration, Instant};
 use reqwest::{StatusCode, Method, Client, header::HeaderMap};
-use hyper;
-use futures_util::stream::StreamExt;
+use rand::Rng;
+use futures_util::stream::{BoxStream, StreamExt};
 use futures_util::Stream;
 use std::pin::Pin;
-use tracing::{info, error};
+use tracing::{info, warn, error};
+
+use crate::body::Body;
+use crate::dns::NegativeCacheResolver;
+use crate::redact::{redact_headers, redact_text};
+use crate::state::{SharedServerList, RACE_STATS};
+use crate::tls;
 
 /// Runtime options for the backend request.
 #[derive(Clone, Copy, Debug)]
 pub struct ReqOpt {
-    pub timeout: u32,
-    pub timeout_ft: u32,
+    pub timeouts: TimeoutProfile,
+    /// Longest measurement window, in seconds from the first token.
     pub time_measure: u32,
+    /// Stream lines after which the measurement stops early, 0 to always wait for the window.
+    pub measure_tokens: u32,
+}
+/// Limits of a request to a backend, in seconds, 0 for none.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeoutProfile {
+    /// Establishing the connection.
+    pub connect: u32,
+    /// From sending the request to the first chunk of the response.
+    pub first_token: u32,
+    /// Between two chunks of the response.
+    pub idle: u32,
+    /// The whole request, until the end of the response.
+    pub total: u32,
+}
+
+impl TimeoutProfile {
+    /// Only a limit on every read, what the requests of a sync use.
+    pub fn read(secs: u32) -> Self {
+        TimeoutProfile { connect: 1, first_token: secs, idle: secs, total: 0 }
+    }
+
+    /// The read timeout of the client, which also bounds the wait for the response headers:
+    /// none unless it is the idle timeout as well, the relays enforce the idle timeout per chunk.
+    fn read_secs(&self) -> u32 {
+        match (self.first_token, self.idle) {
+            (0, _) | (_, 0) => 0,
+            (first_token, idle) if idle >= first_token => idle,
+            _ => 0,
+        }
+    }
+
+    fn deadline(secs: u32, from: Instant) -> Option<Instant> {
+        (secs > 0).then(|| from + Duration::from_secs(secs.into()))
+    }
+}
+
+impl std::str::FromStr for TimeoutProfile {
+    type Err = String;
+
+    /// We expect something like "1:10:30:0"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let limits = s.split(':').map(|n| n.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid timeout: {}", e))?;
+        let [connect, first_token, idle, total] = limits[..] else {
+            return Err("Invalid timeout profile format. Use CONNECT:FIRST_TOKEN:IDLE:TOTAL".to_string());
+        };
+        Ok(TimeoutProfile { connect, first_token, idle, total })
+    }
+}
+
+/// Ends `stream` with a timeout error once no chunk came for `idle` seconds, 0 for never.
+/// The response headers are waited for with the first token timeout instead.
+pub fn idle_limited<S, E>(stream: S, idle: u32) -> BoxStream<'static, Result<bytes::Bytes, std::io::Error>>
+where
+    S: Stream<Item = Result<bytes::Bytes, E>> + Send + Unpin + 'static,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let limit = (idle > 0).then(|| Duration::from_secs(idle.into()));
+    futures_util::stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+        match until(limit.map(|limit| Instant::now() + limit), stream.next()).await {
+            Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(stream))),
+            Ok(Some(Err(e))) => Some((Err(std::io::Error::other(e)), Some(stream))),
+            Ok(None) => None,
+            Err(_) => Some((Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("No chunk for {}s", idle))), None)),
+        }
+    }).boxed()
+}
+
+/// Runs `fut` until `deadline`, if any.
+async fn until<T>(deadline: Option<Instant>, fut: impl std::future::Future<Output = T>) -> Result<T, tokio::time::error::Elapsed> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), fut).await,
+        None => Ok(fut.await),
+    }
+}
+
+/// How sequential requests retry the next backend after a failure.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub base_delay: Duration,
+    pub jitter: f32, // fraction of the delay randomly added or removed
+    pub retry_on: Vec<u16>, // statuses worth another backend, besides network errors
+    pub budget: Arc<RetryBudget>,
+}
+
+/// Seconds of traffic the retry budget is computed over.
+const BUDGET_WINDOW: usize = 60;
+
+/// Caps retries at a fraction of the recent requests, shared by every request,
+/// so that failing over cannot multiply the traffic on the backends left during an incident.
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f32,
+    min_retries: u64, // allowed over the window however low the traffic
+    buckets: Mutex<VecDeque<(Instant, u64, u64)>>, // second, requests, retries
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f32, min_retries: u64) -> Self {
+        RetryBudget { ratio, min_retries, buckets: Mutex::new(VecDeque::new()) }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut (Instant, u64, u64), u64, u64) -> bool) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let window = Duration::from_secs(BUDGET_WINDOW as u64);
+        while buckets.front().is_some_and(|(at, _, _)| now.duration_since(*at) >= window) {
+            buckets.pop_front();
+        }
+        if buckets.back().is_none_or(|(at, _, _)| now.duration_since(*at) >= Duration::from_secs(1)) {
+            buckets.push_back((now, 0, 0));
+        }
+        let (requests, retries) = buckets.iter().fold((0, 0), |(q, r), (_, bq, br)| (q + bq, r + br));
+        f(buckets.back_mut().unwrap(), requests, retries)
+    }
+
+    /// Counts a request in the traffic the budget is a fraction of.
+    pub fn record_request(&self) {
+        self.update(|bucket, _, _| {
+            bucket.1 += 1;
+            true
+        });
+    }
+
+    /// Takes a retry from the budget, false if it is exhausted.
+    pub fn withdraw(&self) -> bool {
+        self.update(|bucket, requests, retries| {
+            let allowed = (requests as f32 * self.ratio) as u64 + self.min_retries;
+            if retries >= allowed {
+                return false;
+            }
+            bucket.2 += 1;
+            true
+        })
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the `attempt`-th retry (1-based), doubled on each retry.
+    pub fn delay(&self, attempt: usize) -> Duration {
+        if self.base_delay.is_zero() {
+            return Duration::ZERO;
+        }
+        let exp = attempt.saturating_sub(1).min(16) as u32;
+        let delay = self.base_delay.saturating_mul(1 << exp);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + rand::rng().random_range(-jitter..=jitter);
+        delay.mul_f32(factor)
+    }
+
+    pub fn should_retry(&self, status: StatusCode) -> bool {
+        self.retry_on.contains(&status.as_u16())
+    }
+}
+
+/// The backend answered with something else than JSON, typically the HTML
+/// error page of a reverse proxy standing in front of a missing Ollama.
+#[derive(Debug)]
+pub struct NotJsonError {
+    pub status: StatusCode,
+    pub content_type: String,
+}
+
+impl std::fmt::Display for NotJsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "responded {} with `{}` instead of JSON, check the proxy in front of it", self.status, self.content_type)
+    }
 }
+
+impl std::error::Error for NotJsonError {}
+
+/// The backend redirected the request, which is not followed: Ollama never redirects, the
+/// address is probably wrong, e.g. http for https, or a proxy sends the requests elsewhere.
+#[derive(Debug)]
+pub struct RedirectError {
+    pub status: StatusCode,
+    pub location: String,
+}
+
+impl std::fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "redirected with {} to `{}`, fix its address or allow the host with --backend-redirect-allow",
+            self.status, self.location
+        )
+    }
+}
+
+impl std::error::Error for RedirectError {}
+
+/// Every Ollama API answers in JSON or NDJSON without redirecting, anything else comes from
+/// somewhere else. But for 429, which a rate limiting proxy in front of a backend answers in its own format.
+pub fn check_ollama_response(
+    status: StatusCode, headers: &HeaderMap
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Ok(());
+    }
+    if status.is_redirection() && status != StatusCode::NOT_MODIFIED {
+        let location = headers.get(reqwest::header::LOCATION).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        return Err(Box::new(RedirectError { status, location: location.to_string() }));
+    }
+    let content_type = match headers.get(reqwest::header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return Ok(()),
+    };
+    if content_type.contains("json") {
+        Ok(())
+    } else {
+        Err(Box::new(NotJsonError { status, content_type: content_type.to_string() }))
+    }
+}
+
+/// Longest backoff a `Retry-After` header may ask for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Delay asked by a `Retry-After` header, in seconds or as an HTTP date, at most `MAX_RETRY_AFTER`.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            (at.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().unwrap_or_default()
+        },
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
 #[derive(Debug)]
 pub struct PerformanceInfo {
     pub first_token_time: Instant,
-    // TODO: we can't use token/s because float is not supported by max_by_key
-    pub duration_tokens: usize,
+    pub ttft: Duration,
+    /// Bytes received within the measurement.
+    pub bytes: usize,
+    /// Tokens generated within the measurement, from the stream chunks or the final `eval_count`.
+    pub tokens: usize,
+    /// Tokens per second reported by the backend, when the generation ended within the measurement.
+    pub eval_rate: Option<f32>,
+    /// Time from the first token to the end of the measurement.
+    pub measured: Duration,
+}
+
+impl PerformanceInfo {
+    /// Tokens per second over the measurement, comparable between windows cut short
+    /// by `measure_tokens` at different times, so that a verbose answer is not mistaken
+    /// for a fast one.
+    pub fn rate(&self) -> f32 {
+        self.eval_rate.unwrap_or_else(|| self.tokens as f32 / self.measured.as_secs_f32().max(0.001))
+    }
+}
+
+/// What a line of a generation stream says about its tokens.
+enum TokenLine {
+    /// A chunk carrying one token, or none.
+    Chunk(usize),
+    /// The final chunk, with the token count and generation time in nanoseconds of the whole answer.
+    Done { eval_count: usize, eval_duration: Option<u64> },
+}
+
+fn tokens_per_sec(eval_count: usize, eval_duration: Option<u64>) -> Option<f32> {
+    eval_duration.filter(|ns| *ns > 0).map(|ns| eval_count as f32 / (ns as f32 / 1e9))
+}
+
+fn token_line(line: &[u8]) -> TokenLine {
+    let Ok(chunk) = serde_json::from_slice::<serde_json::Value>(line) else {
+        return TokenLine::Chunk(0);
+    };
+    if chunk["done"] == serde_json::Value::Bool(true) {
+        if let Some(eval_count) = chunk["eval_count"].as_u64() {
+            return TokenLine::Done { eval_count: eval_count as usize, eval_duration: chunk["eval_duration"].as_u64() };
+        }
+    }
+    // Ollama streams a token per chunk, as the content, the thinking or the response of /api/generate
+    let message = &chunk["message"];
+    let generated = [&message["content"], &message["thinking"], &chunk["response"]].iter()
+        .any(|text| text.as_str().is_some_and(|text| !text.is_empty()))
+        || message["tool_calls"].as_array().is_some_and(|calls| !calls.is_empty());
+    TokenLine::Chunk(generated as usize)
+}
+
+/// What a line of a response held back by a tentative relay says about the answer.
+enum Verdict {
+    /// Nothing generated yet.
+    Pending,
+    /// A token, or the final chunk.
+    Answered,
+    /// An error reported in the stream, as Ollama does for models failing to load.
+    Failed,
+}
+
+fn line_verdict(line: &[u8]) -> Verdict {
+    if serde_json::from_slice::<serde_json::Value>(line).is_ok_and(|chunk| chunk.get("error").is_some()) {
+        return Verdict::Failed;
+    }
+    match token_line(line) {
+        TokenLine::Chunk(0) => Verdict::Pending,
+        _ => Verdict::Answered,
+    }
+}
+
+/// Bytes of a backend response.
+pub type ResponseStream = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Send>>;
+
+/// Racers still running behind the leader of a race, in the order they answer. Their requests
+/// are aborted once dropped.
+pub struct Standby {
+    pub racers: BoxStream<'static, (String, ResponseStream)>,
+    pub aborts: Vec<tokio::task::AbortHandle>,
+}
+
+impl Drop for Standby {
+    fn drop(&mut self) {
+        let running = self.aborts.iter().filter(|abort| !abort.is_finished()).count();
+        for abort in &self.aborts {
+            abort.abort();
+        }
+        if running > 0 {
+            RACE_STATS.aborted.fetch_add(running as u64, Ordering::Relaxed);
+            info!("Aborted {} servers of the race on standby", running);
+        }
+    }
+}
+
+struct Tentative {
+    server: String,
+    stream: ResponseStream,
+    held: Vec<u8>,
+    scanned: usize,
+    standby: Option<Standby>,
+    tail: Option<reqwest::Error>,
+    ended: bool,
+}
+
+impl Tentative {
+    /// The verdict of the complete lines held back that were not scanned yet.
+    fn scan(&mut self) -> Verdict {
+        while let Some(pos) = self.held[self.scanned..].iter().position(|b| *b == b'\n') {
+            let verdict = line_verdict(&self.held[self.scanned..self.scanned + pos]);
+            self.scanned += pos + 1;
+            if !matches!(verdict, Verdict::Pending) {
+                return verdict;
+            }
+        }
+        Verdict::Pending
+    }
+}
+
+/// Relays the leader of a race tentatively: its bytes are held back until it sends a token or
+/// its final chunk, the commit point, and should it break off or report an error before, the
+/// next racer to answer takes over. The racers on standby are aborted at the commit point.
+pub fn tentative(leader: String, stream: ResponseStream, standby: Standby) -> ResponseStream {
+    let relay = Tentative { server: leader, stream, held: Vec::new(), scanned: 0, standby: Some(standby), tail: None, ended: false };
+    futures_util::stream::unfold(relay, |mut relay| async move {
+        while relay.standby.is_some() {
+            let failure = match relay.stream.next().await {
+                Some(Ok(chunk)) => {
+                    relay.held.extend_from_slice(&chunk);
+                    match relay.scan() {
+                        Verdict::Pending => continue,
+                        Verdict::Answered => None,
+                        Verdict::Failed => Some(None),
+                    }
+                },
+                Some(Err(e)) => Some(Some(e)),
+                None => {
+                    relay.ended = true;
+                    // non-streaming answers are a single object without a trailing newline
+                    match line_verdict(&relay.held[relay.scanned..]) {
+                        Verdict::Failed => Some(None),
+                        _ if relay.held.is_empty() => Some(None),
+                        _ => None,
+                    }
+                },
+            };
+            let Some(error) = failure else {
+                info!("Server {} answered, committed to it", relay.server);
+                relay.standby = None;
+                break;
+            };
+            let next = relay.standby.as_mut().unwrap().racers.next().await;
+            match next {
+                Some((server, stream)) => {
+                    warn!("Server {} failed before its first token, switching to server {}", relay.server, server);
+                    relay = Tentative { server, stream, held: Vec::new(), scanned: 0, standby: relay.standby.take(), tail: None, ended: false };
+                },
+                None => {
+                    warn!("Server {} failed before its first token, and no other server of the race answered", relay.server);
+                    relay.standby = None;
+                    relay.tail = error;
+                },
+            }
+        }
+        if !relay.held.is_empty() {
+            let held = std::mem::take(&mut relay.held);
+            return Some((Ok(bytes::Bytes::from(held)), relay));
+        }
+        if let Some(e) = relay.tail.take() {
+            relay.ended = true;
+            return Some((Err(e), relay));
+        }
+        if relay.ended {
+            return None;
+        }
+        let item = relay.stream.next().await?;
+        Some((item, relay))
+    }).boxed()
 }
 
 pub struct RepackedResponse {
@@ -27,8 +440,7 @@ pub struct RepackedResponse {
 }
 
 impl RepackedResponse {
-    pub async fn into_string(self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let max_preview = 100;
+    pub async fn into_string(self, max_preview: usize) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
         let mut body = String::new();
         let mut stream = self.stream;
         while let Some(chunk) = stream.next().await {
@@ -39,11 +451,154 @@ impl RepackedResponse {
                 break;
             }
         }
-        Ok(format!("RepackedResponse {{ status: {:?}, headers: {:?}, body: \"{}\" }}", self.status, self.headers, body))
+        Ok(format!(
+            "RepackedResponse {{ status: {:?}, headers: {}, body: \"{}\" }}",
+            self.status, redact_headers(&self.headers), redact_text(&body)
+        ))
+    }
+}
+
+/// Clients keyed by backend and timeouts in seconds, so that requests reuse
+/// the connections of the previous ones instead of opening new ones.
+type ClientKey = (String, u32, u32);
+
+static CLIENTS: LazyLock<Mutex<HashMap<ClientKey, Client>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// HTTP version spoken to the backends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendHttp {
+    /// HTTP/1.1, or HTTP/2 when the backend offers it through TLS ALPN.
+    Auto,
+    Http1,
+    /// HTTP/2 with prior knowledge, concurrent requests to a backend share one connection.
+    Http2,
+}
+
+static BACKEND_HTTP: Mutex<BackendHttp> = Mutex::new(BackendHttp::Auto);
+
+/// Sets the HTTP version of every backend client, when building the load balancer.
+pub fn set_backend_http(http: BackendHttp) {
+    *BACKEND_HTTP.lock().unwrap() = http;
+}
+
+static REDIRECT_ALLOWLIST: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Hosts, or HOST:PORT, the backends may redirect to, set when building the load balancer.
+/// Other redirects are not followed.
+pub fn set_redirect_allowlist(hosts: Vec<String>) {
+    *REDIRECT_ALLOWLIST.lock().unwrap() = hosts;
+}
+
+/// Follows the redirects to the allowed hosts only, a handful of them at most.
+fn redirect_policy() -> reqwest::redirect::Policy {
+    let allowlist = REDIRECT_ALLOWLIST.lock().unwrap().clone();
+    if allowlist.is_empty() {
+        return reqwest::redirect::Policy::none();
+    }
+    reqwest::redirect::Policy::custom(move |attempt| {
+        let url = attempt.url();
+        let host = url.host_str().unwrap_or_default();
+        let host_port = format!("{}:{}", host, url.port_or_known_default().unwrap_or_default());
+        let allowed = allowlist.iter().any(|a| a.eq_ignore_ascii_case(host) || a.eq_ignore_ascii_case(&host_port));
+        if !allowed || attempt.previous().len() >= 5 {
+            attempt.stop()
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+static DNS_RESOLVER: Mutex<Option<Arc<NegativeCacheResolver>>> = Mutex::new(None);
+
+/// Remembers backend hostnames that fail to resolve for `ttl`, marking their servers dead,
+/// set when building the load balancer.
+pub fn set_dns_negative_ttl(ttl: Duration, servers: SharedServerList) {
+    *DNS_RESOLVER.lock().unwrap() = Some(Arc::new(NegativeCacheResolver::new(ttl, servers)));
+}
+
+/// Drops the clients and the resolver of a previous load balancer, which point at its servers.
+pub fn reset() {
+    CLIENTS.lock().unwrap().clear();
+    *DNS_RESOLVER.lock().unwrap() = None;
+}
+
+/// Drops the clients of a removed backend, closing their idle connections.
+pub fn forget_clients(backend_url: &str) {
+    CLIENTS.lock().unwrap().retain(|(url, _, _), _| url != backend_url);
+}
+
+/// The client of a backend for a connect timeout and a read timeout, 0 meaning none.
+fn pooled_client(backend_url: &str, timeouts: &TimeoutProfile) -> Result<Client, reqwest::Error> {
+    let (connect_secs, read_secs) = (timeouts.connect, timeouts.read_secs());
+    let key = (backend_url.to_string(), connect_secs, read_secs);
+    if let Some(client) = CLIENTS.lock().unwrap().get(&key) {
+        return Ok(client.clone());
+    }
+    let mut builder = tls::client_builder()
+        .connect_timeout(Duration::from_secs(connect_secs.into()))
+        .redirect(redirect_policy());
+    if read_secs == 0 {
+        builder = builder.pool_idle_timeout(None);
+    } else {
+        let timeout = Duration::from_secs(read_secs.into());
+        builder = builder.read_timeout(timeout).pool_idle_timeout(timeout);
+    }
+    builder = match *BACKEND_HTTP.lock().unwrap() {
+        BackendHttp::Auto => builder,
+        BackendHttp::Http1 => builder.http1_only(),
+        // keep idle multiplexed connections alive, a dead one would fail every stream on it
+        BackendHttp::Http2 => builder
+            .http2_prior_knowledge()
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_while_idle(true),
+    };
+    if let Some(resolver) = DNS_RESOLVER.lock().unwrap().clone() {
+        builder = builder.dns_resolver(resolver);
+    }
+    let client = builder.build()?;
+    // clients share their connection pool with their clones
+    Ok(CLIENTS.lock().unwrap().entry(key).or_insert(client).clone())
+}
+
+pub type UnpackedRequest = (String, Method, String, Option<HeaderMap>, Option<RequestBody>);
+
+/// Body of a request to the backends: buffered, so that it can be replayed on another
+/// backend, or passed through as it arrives when only one backend will ever see it.
+#[derive(Clone, Debug)]
+pub enum RequestBody {
+    Whole(bytes::Bytes),
+    /// Taken by the first backend it is sent to, a replay fails.
+    Stream(Arc<Mutex<Option<Body>>>),
+}
+
+impl RequestBody {
+    pub fn stream(body: Body) -> Self {
+        RequestBody::Stream(Arc::new(Mutex::new(Some(body))))
+    }
+
+    pub fn as_bytes(&self) -> Option<&bytes::Bytes> {
+        match self {
+            RequestBody::Whole(bytes) => Some(bytes),
+            RequestBody::Stream(_) => None,
+        }
+    }
+
+    fn into_reqwest(self) -> Result<reqwest::Body, Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            RequestBody::Whole(bytes) => Ok(bytes.into()),
+            RequestBody::Stream(body) => match body.lock().unwrap().take() {
+                Some(body) => Ok(reqwest::Body::wrap(body)),
+                None => Err("the streamed request body was already sent to another backend".into()),
+            },
+        }
     }
 }
 
-pub type UnpackedRequest = (String, Method, String, Option<hyper::HeaderMap>, Option<bytes::Bytes>);
+impl From<bytes::Bytes> for RequestBody {
+    fn from(bytes: bytes::Bytes) -> Self {
+        RequestBody::Whole(bytes)
+    }
+}
 
 pub async fn send_request_monitored(
     req: UnpackedRequest,
@@ -53,46 +608,80 @@ pub async fn send_request_monitored(
     let (uri, req_method, _path, headers, whole_body) = req;
     let uri = format!("{}{}", backend_url, uri);
 
-    let mut builder = Client::builder()
-        .connect_timeout(Duration::from_secs(opts.timeout.into()));
-    if opts.timeout_ft == 0 {
-        builder = builder.pool_idle_timeout(None);
-    } else {
-        let timeout = Duration::from_secs(opts.timeout_ft.into());
-        builder = builder.read_timeout(timeout).pool_idle_timeout(timeout);
-    }
-    let client = builder.build().unwrap();
+    let timeouts = opts.timeouts;
+    let client = pooled_client(backend_url, &timeouts)?;
     let mut request_builder = client.request(req_method, &uri);
+    if timeouts.total > 0 {
+        request_builder = request_builder.timeout(Duration::from_secs(timeouts.total.into()));
+    }
     if let Some(headers) = headers {
-        for (k, v) in headers.iter() {
-            request_builder = request_builder.header(k.as_str(), v.to_str().unwrap());
-        }
+        request_builder = request_builder.headers(headers);
     }
     if let Some(whole_body) = whole_body {
-        request_builder = request_builder.body(whole_body);
+        request_builder = request_builder.body(whole_body.into_reqwest()?);
     }
 
-    let response = match request_builder.send().await {
-        Ok(resp) => resp,
-        Err(e) => {
+    let start = Instant::now();
+    let first_token_by = TimeoutProfile::deadline(timeouts.first_token, start);
+    let response = match until(first_token_by, request_builder.send()).await {
+        Ok(Ok(resp)) => resp,
+        Ok(Err(e)) => {
             error!("Error sending request to {}: {}", backend_url, e);
             return Err(e.into());
-        }
+        },
+        Err(_) => {
+            error!("No response from {} within {}s", backend_url, timeouts.first_token);
+            return Err(format!("No response within the first token timeout of {}s", timeouts.first_token).into());
+        },
     };
     let status = response.status();
     let resp_headers = response.headers().clone();
+    check_ollama_response(status, &resp_headers)?;
     let mut stream = response.bytes_stream().boxed();
     let mut buffer = Vec::new();
     let mut bytes_count = 0;
+    let mut tokens = 0;
+    let mut eval_rate = None;
+    let mut line_start = 0;
     let mut ftt: Option<Instant> = None;
+    let mut last = Instant::now();
     let t_measure = Duration::from_secs(opts.time_measure.into());
     loop {
-        let res = stream.next().await;
+        let res = match ftt {
+            None => match until(first_token_by, stream.next()).await {
+                Ok(res) => res,
+                Err(_) => return Err(format!("No first token within {}s", timeouts.first_token).into()),
+            },
+            Some(_) => match until(TimeoutProfile::deadline(timeouts.idle, last), stream.next()).await {
+                Ok(res) => res,
+                Err(_) => {
+                    error!("No chunk from {} for {}s", backend_url, timeouts.idle);
+                    break;
+                },
+            },
+        };
         let now = Instant::now();
         match res {
             Some(Ok(chunk)) => {
                 buffer.extend_from_slice(&chunk);
                 bytes_count += chunk.len();
+                while let Some(pos) = buffer[line_start..].iter().position(|b| *b == b'\n') {
+                    let line = &buffer[line_start..line_start + pos];
+                    line_start += pos + 1;
+                    match token_line(line) {
+                        TokenLine::Chunk(n) => tokens += n,
+                        TokenLine::Done { eval_count, eval_duration } => {
+                            tokens = tokens.max(eval_count);
+                            eval_rate = tokens_per_sec(eval_count, eval_duration);
+                        },
+                    }
+                }
+                last = now;
+                // small fast models reach the token count long before the window is over
+                if opts.measure_tokens > 0 && tokens >= opts.measure_tokens as usize {
+                    ftt.get_or_insert(now);
+                    break;
+                }
                 match ftt {
                     None => {
                         ftt = Some(now);
@@ -108,7 +697,14 @@ pub async fn send_request_monitored(
                 error!("Error reading chunk from {}: {}", backend_url, e);
                 break;
             },
-            None => break,
+            None => {
+                // non-streaming responses are a single object without a trailing newline
+                if let TokenLine::Done { eval_count, eval_duration } = token_line(&buffer[line_start..]) {
+                    tokens = tokens.max(eval_count);
+                    eval_rate = tokens_per_sec(eval_count, eval_duration);
+                }
+                break;
+            },
         }
     }
     let ftt = match ftt {
@@ -118,13 +714,17 @@ pub async fn send_request_monitored(
         Some(ftt) => ftt,
     };
 
-    info!("Backend {} received {} bytes in {} seconds", backend_url, bytes_count, ftt.elapsed().as_secs_f32());
+    info!("Backend {} received {} tokens ({} bytes) in {} seconds", backend_url, tokens, bytes_count, ftt.elapsed().as_secs_f32());
     let buf_stream = futures_util::stream::iter(vec![Ok(bytes::Bytes::from(buffer))]);
     stream = buf_stream.chain(stream).boxed();
     
     let perf = PerformanceInfo {
         first_token_time: ftt,
-        duration_tokens: bytes_count,
+        ttft: ftt.duration_since(start),
+        bytes: bytes_count,
+        tokens,
+        eval_rate,
+        measured: last.saturating_duration_since(ftt),
     };
     let repacked = RepackedResponse {
         status,
@@ -137,30 +737,27 @@ pub async fn send_request_monitored(
 pub async fn send_request(
     req: UnpackedRequest,
     backend_url: &str,
-    timeout_secs: u32,
+    timeouts: TimeoutProfile,
 ) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
     let (uri, req_method, _path, headers, whole_body) = req;
     let uri = format!("{}{}", backend_url, uri);
 
-    let mut builder = reqwest::Client::builder().connect_timeout(Duration::from_secs(1));
-    if timeout_secs == 0 {
-        builder = builder.pool_idle_timeout(None);
-    } else {
-        let timeout = Duration::from_secs(timeout_secs.into());
-        builder = builder.read_timeout(timeout).pool_idle_timeout(timeout);
-    }
-    let client = builder.build()?;
+    let client = pooled_client(backend_url, &timeouts)?;
     let mut request_builder = client.request(req_method, &uri);
+    if timeouts.total > 0 {
+        request_builder = request_builder.timeout(Duration::from_secs(timeouts.total.into()));
+    }
 
     if let Some(headers) = headers {
-        for (k, v) in headers.iter() {
-            request_builder = request_builder.header(k.as_str(), v.to_str().unwrap());
-        }
+        request_builder = request_builder.headers(headers);
     }
     if let Some(whole_body) = whole_body {
-        request_builder = request_builder.body(whole_body);
+        request_builder = request_builder.body(whole_body.into_reqwest()?);
     }
 
-    let response = request_builder.send().await?;
-    Ok(response)
+    let first_token_by = TimeoutProfile::deadline(timeouts.first_token, Instant::now());
+    match until(first_token_by, request_builder.send()).await {
+        Ok(response) => Ok(response?),
+        Err(_) => Err(format!("No response within {}s", timeouts.first_token).into()),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,693 @@
+//! The load balancer as a whole, for the binary and for the services embedding it.
+
+use futures_util::future;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use clap::Parser;
+use ordermap::OrderMap;
+use tracing::{info, warn};
+
+#[cfg(feature = "discovery")]
+use crate::discovery;
+use crate::{backend, handler, heatmap, membership, prober, redact, registry, state, stats, storage, strategy, tls, traces, autoscale};
+use crate::relay::RELAY_STATS;
+#[cfg(unix)]
+use crate::systemd;
+use crate::body::Body;
+use crate::config::{self, Args, AuthProviderKind, AuthProviderSpec, AuthScope, ServerConfig};
+use crate::state::{add_server, assign_domains, begin_shutdown, refresh_loop, status_reporter, streams_in_flight, sync_server, wait_for_idle_fleet, ConversationMap, SharedServerList};
+use crate::handler::{dispatch, DispatchMode, DispatchOpt, RaceRelay};
+use crate::backend::{set_backend_http, set_dns_negative_ttl, set_redirect_allowlist, BackendHttp, ReqOpt, RetryBudget, RetryPolicy};
+use crate::auth::{ApiKeys, AuthChain, AuthProvider, HttpCallout, Jwt, TrustedHeader};
+use crate::authz::Authorizer;
+use crate::accounting::{Accounting, Limits};
+use crate::profiles::{BackendProfiles, ModelProfiles, ProfileStore};
+use crate::audit::AuditTrail;
+use crate::traces::{TraceSampling, Tracer};
+use crate::health::HealthPolicy;
+use crate::runtime::{RuntimeConfig, TimeoutConfig};
+use crate::coalesce::Coalescer;
+use crate::events::EventBus;
+use crate::cache::ResponseCache;
+use crate::mirror::RegistryMirror;
+use crate::pull::AutoPull;
+use crate::listener::Listener;
+use crate::concurrency::{InflightLimit, ModelConcurrency};
+use crate::registry::Registry;
+use crate::autoscale::Autoscaler;
+use crate::storage::{load_warm_cache, open_storage, persist_loop, restore_state, save_warm_cache, SharedStorage};
+
+/// With --reuse-port, a draining process stops listening once no connection came for this long.
+const REUSE_PORT_QUIET: Duration = Duration::from_millis(200);
+/// And at the latest after this long, even if connections keep coming.
+const REUSE_PORT_CLOSE_MAX: Duration = Duration::from_secs(2);
+
+/// Set while a load balancer is alive. The server statistics, the membership changefeed and the
+/// shutdown state are kept process-wide, two load balancers at once would mix them up.
+static ALIVE: AtomicBool = AtomicBool::new(false);
+
+/// The claim of a load balancer on the process-wide state, and its background tasks,
+/// stopped when it is dropped.
+struct Instance {
+    tasks: Vec<tokio::task::AbortHandle>,
+}
+
+impl Instance {
+    /// Fails while another load balancer is alive, or else starts over from a clean state.
+    fn claim() -> Result<Self, Box<dyn std::error::Error>> {
+        if ALIVE.swap(true, Ordering::SeqCst) {
+            return Err("Another load balancer is alive in this process, drop it before building a new one".into());
+        }
+        state::reset();
+        stats::reset();
+        heatmap::reset();
+        membership::reset();
+        backend::reset();
+        handler::reset_blob_pins();
+        RELAY_STATS.reset();
+        Ok(Instance { tasks: Vec::new() })
+    }
+
+    fn spawn<F>(&mut self, task: F)
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.push(tokio::spawn(task).abort_handle());
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        ALIVE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Configures a load balancer, with every option of the command line.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let balancer = ollama_load_balancer::LoadBalancer::builder()
+///     .server("http://192.168.1.100:11434", "s0")
+///     .server("http://192.168.1.101:11434", "s1")
+///     .build()
+///     .await?;
+/// # Ok(()) }
+/// ```
+#[derive(Debug)]
+pub struct LoadBalancerBuilder {
+    args: Args,
+    health_policy: Option<Arc<dyn HealthPolicy>>,
+}
+
+impl LoadBalancerBuilder {
+    /// Replaces every option, e.g. with the ones parsed from a command line.
+    pub fn args(mut self, args: Args) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Adds a backend, or a pool to discover like `dns+srv://NAME` as on the command line.
+    pub fn server(mut self, address: &str, name: &str) -> Self {
+        self.args.servers.push(ServerConfig { address: address.to_string(), name: name.to_string() });
+        self
+    }
+
+    /// Address `serve` listens on, or `unix:PATH`.
+    pub fn listen(mut self, address: &str) -> Self {
+        self.args.listen = address.to_string();
+        self
+    }
+
+    /// How the health scores of the servers move, instead of the `--health-*` options.
+    pub fn health_policy(mut self, policy: Arc<dyn HealthPolicy>) -> Self {
+        self.health_policy = Some(policy);
+        self
+    }
+
+    /// Sets the load balancer up and starts its background tasks: the sync of the servers,
+    /// discovery, probes, persistence. Must run within a Tokio runtime.
+    ///
+    /// Only one load balancer may be alive in a process at a time: building another one fails
+    /// until it is dropped, which stops its background tasks. The TLS implementation, backend
+    /// HTTP version and redaction are set for the whole process by the one built last.
+    pub async fn build(self) -> Result<LoadBalancer, Box<dyn std::error::Error>> {
+        let LoadBalancerBuilder { args, health_policy } = self;
+        if health_policy.is_none() {
+            // a server failing would never die, or would never be selected to begin with
+            if args.health_decay <= 1.0 {
+                return Err(format!("--health-decay must be greater than 1, got {}", args.health_decay).into());
+            }
+            if args.health_initial <= 0.0 {
+                return Err(format!("--health-initial must be positive, got {}", args.health_initial).into());
+            }
+        }
+        let mut instance = Instance::claim()?;
+        redact::init(&args.redact_keys);
+        // every client is built after this
+        match tls::set_tls_backend(args.tls_backend)? {
+            Some(backend) => info!("TLS backend: {}", backend.name()),
+            None => warn!("Built without TLS, only plain HTTP backends and services can be reached"),
+        }
+        let global_opts = ReqOpt {
+            timeouts: TimeoutConfig::from_args(&args).chat,
+            time_measure: args.time_measure,
+            measure_tokens: args.measure_tokens,
+        };
+
+        info!("Timeout settings: {:?}", global_opts);
+        let storage = open_storage(&args.storage).map_err(|e| e as Box<dyn std::error::Error>)?;
+        info!("Using {} storage", storage.name());
+        let providers = match (args.auth_provider.is_empty(), &args.api_keys_file) {
+            (true, Some(_)) => vec![AuthProviderSpec { kind: AuthProviderKind::Keys, scope: AuthScope::All }],
+            _ => args.auth_provider.clone(),
+        };
+        let mut auth = AuthChain::default();
+        for spec in providers {
+            let provider: Box<dyn AuthProvider> = match spec.kind {
+                AuthProviderKind::Keys => {
+                    let file = args.api_keys_file.as_ref().ok_or("The keys authentication provider requires --api-keys-file")?;
+                    let mut keys = ApiKeys::load(file)?;
+                    info!("Loaded {} API keys from {}", keys.len(), file);
+                    // keys provisioned in a shared storage are accepted as well
+                    let stored_keys = storage.list(storage::NS_KEYS).map_err(|e| e as Box<dyn std::error::Error>)?;
+                    keys.extend(stored_keys.into_iter().map(|(key, _)| key));
+                    Box::new(keys)
+                },
+                AuthProviderKind::TrustedHeader => {
+                    if args.trusted_proxies.is_empty() {
+                        warn!("No --trusted-proxies, the trusted-header authentication provider accepts nobody");
+                    }
+                    Box::new(TrustedHeader::new(&args.trusted_header, args.trusted_proxies.clone())?)
+                },
+                AuthProviderKind::Http => {
+                    let url = args.auth_url.clone().ok_or("The http authentication provider requires --auth-url")?;
+                    Box::new(HttpCallout::new(url))
+                },
+                AuthProviderKind::Jwt => {
+                    let file = args.jwt_key_file.as_ref().ok_or("The jwt authentication provider requires --jwt-key-file")?;
+                    let jwt = Jwt::load(file, args.jwt_audience.as_deref())?;
+                    info!("Checking JWTs with the key of {}", file);
+                    Box::new(jwt)
+                },
+            };
+            auth.push(spec.scope, provider);
+        }
+        if !auth.is_empty() {
+            info!("Authentication providers: {}", auth.describe());
+            if !auth.covers(AuthScope::Admin) {
+                if !args.open_admin {
+                    return Err("No authentication provider covers the admin endpoints, add one scoped to admin or all, or pass --open-admin".into());
+                }
+                warn!("The admin endpoints are reachable without credentials (--open-admin)");
+            }
+        }
+        let auth = (!auth.is_empty()).then(|| Arc::new(auth));
+        let authz = args.authz_url.clone().map(|url| {
+            info!("Authorizing requests with {}{}", url, if args.authz_fail_open { ", failing open" } else { "" });
+            Arc::new(Authorizer::new(url, args.authz_fail_open))
+        });
+        let limits = Limits {
+            requests_per_minute: args.rate_limit_rpm,
+            tokens_per_day: args.quota_tokens_per_day,
+        };
+        let accounting = if auth.is_some() {
+            info!("Per-key limits: {:?}", limits);
+            Some(Arc::new(Accounting::new(limits)))
+        } else {
+            if limits.requests_per_minute.is_some() || limits.tokens_per_day.is_some() {
+                warn!("Rate limits and quotas are per API key, ignored without --api-keys-file");
+            }
+            // clients are then told apart by their address, and never limited
+            args.track_usage.then(|| Arc::new(Accounting::new(Limits::default())))
+        };
+        let mut runtime = RuntimeConfig::from_args(&args);
+        if let Some(policy) = health_policy {
+            runtime.health = policy;
+        }
+        let dispatch_opts = DispatchOpt {
+            req: global_opts,
+            annotate_availability: args.annotate_availability,
+            annotate_servers: args.annotate_servers,
+            open_admin: args.open_admin,
+            compress: args.compress,
+            auth,
+            authz,
+            accounting,
+            affinity: args.affinity,
+            mode: match args.mode.as_str() {
+                "single" => DispatchMode::Single,
+                "hybrid" => DispatchMode::Hybrid,
+                _ => DispatchMode::Parallel,
+            },
+            race_relay: match args.race_relay.as_str() {
+                "immediate" => RaceRelay::Immediate,
+                _ => RaceRelay::Buffered,
+            },
+            conversations: args.conversation_routing.then(||
+                Arc::new(Mutex::new(ConversationMap::new(
+                    args.conversation_cache_size.max(1),
+                    (args.conversation_ttl > 0).then(|| Duration::from_secs(args.conversation_ttl)),
+                )))
+            ),
+            strategy: Arc::from(strategy::make_strategy(&args.strategy)?),
+            runtime: Arc::new(runtime),
+            profiles: Arc::new(ProfileStore::new(args.model_profiles.clone(), match &args.model_profiles {
+                Some(file) => {
+                    let profiles = ModelProfiles::load(file)?;
+                    info!("Loaded {} model profiles from {}", profiles.len(), file);
+                    profiles
+                },
+                None => ModelProfiles::default(),
+            }, args.backend_options.clone(), match &args.backend_options {
+                Some(file) => {
+                    let backends = BackendProfiles::load(file)?;
+                    info!("Loaded options of {} backends from {}", backends.len(), file);
+                    backends
+                },
+                None => BackendProfiles::default(),
+            })),
+            audit: (args.audit_turns > 0).then(||
+                Arc::new(Mutex::new(AuditTrail::new(args.audit_turns, args.audit_sessions.max(1))))
+            ),
+            audit_header: args.audit_header,
+            tracer: (args.trace_buffer > 0).then(|| Arc::new(Tracer::new(TraceSampling {
+                slow: Duration::from_millis(args.trace_slow_ms),
+                success_rate: args.trace_sample,
+                per_endpoint: args.trace_sample_for.iter().map(|e| (e.endpoint.clone(), e.rate)).collect(),
+            }, args.trace_buffer, args.trace_export.clone()))),
+            upstream_in_body: args.upstream_in_body,
+            events: EventBus::default(),
+            cache: (args.cache_size_mb > 0).then(|| Arc::new(ResponseCache::new(
+                args.cache_size_mb * 1024 * 1024, Duration::from_secs(args.cache_ttl)
+            ))),
+            fallbacks: Arc::new(args.model_fallback.iter().map(|f| (f.model.clone(), f.fallbacks.clone())).collect()),
+            coalescer: args.coalesce.then(|| Arc::new(Coalescer::default())),
+            retry: RetryPolicy {
+                max_attempts: args.retry_max_attempts,
+                base_delay: Duration::from_millis(args.retry_base_delay_ms),
+                jitter: args.retry_jitter,
+                retry_on: args.retry_on_status.clone(),
+                budget: Arc::new(RetryBudget::new(args.retry_budget, args.retry_budget_min)),
+            },
+            default_model: args.default_model.clone(),
+            auto_pull: args.auto_pull.then(|| Arc::new(AutoPull::default())),
+            concurrency: Arc::new(ModelConcurrency::new(&args.model_concurrency)),
+            inflight: Arc::new(InflightLimit::new(args.max_inflight)),
+            started: std::time::Instant::now(),
+            drain_redirect: args.drain_redirect.clone(),
+            registry: match &args.register_token_file {
+                Some(file) => {
+                    let token = std::fs::read_to_string(file)?.trim().to_string();
+                    if token.is_empty() {
+                        return Err(format!("Registration token file {} is empty", file).into());
+                    }
+                    info!("Servers may register themselves, expiring after {}s without a heartbeat", args.register_ttl);
+                    Some(Arc::new(Registry::new(token, Duration::from_secs(args.register_ttl.max(1)))))
+                },
+                None => None,
+            },
+            autoscaler: Arc::new(Autoscaler::new(
+                Duration::from_secs(args.autoscale_window.max(1)),
+                args.autoscale_target_utilization.clamp(0.05, 1.0),
+                args.autoscale_slo_ttft_ms,
+                args.autoscale_webhook.clone(),
+            )),
+            mirror: match &args.registry_mirror_dir {
+                Some(dir) => {
+                    info!("Serving a registry mirror of {} from {}", args.registry_upstream, dir);
+                    Some(Arc::new(RegistryMirror::new(dir, &args.registry_upstream, args.registry_mirror_host.clone())?))
+                },
+                None => {
+                    if args.registry_mirror_host.is_some() {
+                        warn!("--registry-mirror-host is ignored without --registry-mirror-dir");
+                    }
+                    None
+                },
+            },
+        };
+        set_backend_http(match args.backend_http.as_str() {
+            "http1" => BackendHttp::Http1,
+            "http2" => BackendHttp::Http2,
+            _ => BackendHttp::Auto,
+        });
+        set_redirect_allowlist(args.backend_redirect_allow.clone());
+        info!("Dispatch mode: {}, selection strategy: {}, backend HTTP: {}", args.mode, dispatch_opts.strategy.name(), args.backend_http);
+        info!("Retry policy: {:?}", dispatch_opts.retry);
+        info!("Runtime configuration: {:?}", dispatch_opts.runtime);
+
+        let servers: SharedServerList = Arc::new(Mutex::new(OrderMap::new()));
+        if args.dns_negative_ttl > 0 {
+            set_dns_negative_ttl(Duration::from_secs(args.dns_negative_ttl), servers.clone());
+        }
+        let mut discoveries = args.discover.clone();
+        let mut add_or_discover = |s: &config::ServerConfig| match config::DiscoverySource::from_server(s) {
+            Some(Ok(source)) => discoveries.push(source),
+            Some(Err(e)) => warn!("Fail to parse server `{}`: {}", s.address, e),
+            None => add_server(servers.clone(), s),
+        };
+        args.servers.iter().for_each(&mut add_or_discover);
+
+        if let Some(file) = &args.server_file {
+            let contents = std::fs::read_to_string(file)?;
+            contents.lines().for_each(|line| {
+                if line.is_empty() {
+                    return;
+                }
+                let server: Result<config::ServerConfig, _> = line.parse();
+                match server {
+                    Ok(s) => add_or_discover(&s),
+                    Err(e) => warn!("Fail to parse server `{}`: {}", line, e),
+                }
+            });
+        }
+
+        assign_domains(servers.clone(), &args.gpu_domain);
+
+        let server_addrs = servers.lock().unwrap().keys().cloned().collect::<Vec<String>>();
+        if server_addrs.is_empty() && discoveries.is_empty() && dispatch_opts.registry.is_none() {
+            return Err("No servers provided".into());
+        }
+        #[cfg(feature = "discovery")]
+        {
+            let dns_refresh = Duration::from_secs(args.dns_refresh_secs.max(1));
+            for source in &discoveries {
+                let task = discovery::spawn(source, args.k8s_api_url.as_deref(), dns_refresh, servers.clone(), dispatch_opts.runtime.clone())?;
+                instance.tasks.push(task.abort_handle());
+            }
+        }
+        #[cfg(not(feature = "discovery"))]
+        if let Some(source) = discoveries.first() {
+            return Err(format!("Discovering servers from {:?} requires building with the `discovery` feature", source).into());
+        }
+        if let Some(registry) = &dispatch_opts.registry {
+            instance.spawn(registry::expire_loop(registry.clone(), servers.clone()));
+        }
+        match (&dispatch_opts.tracer, &args.trace_export) {
+            (Some(tracer), Some(url)) => {
+                info!("Exporting the kept traces to {}", url);
+                instance.spawn(traces::export_loop(tracer.clone()));
+            },
+            (None, Some(_)) => warn!("--trace-export is ignored without --trace-buffer"),
+            _ => {},
+        }
+        if let Some(webhook) = &args.autoscale_webhook {
+            info!("Posting the autoscaling signal to {} every {}s when it changes", webhook, args.autoscale_window.max(1));
+            instance.spawn(autoscale::autoscale_loop(dispatch_opts.autoscaler.clone(), servers.clone()));
+        }
+
+        let warmed = match load_warm_cache(storage.as_ref(), &servers) {
+            Ok(warmed) => warmed,
+            Err(e) => {
+                warn!("Failed to load the warm cache from {} storage: {}", storage.name(), e);
+                0
+            }
+        };
+
+        // restored before any sync, which would otherwise race it for the health of the servers
+        if let Err(e) = restore_state(
+            storage.as_ref(), &servers, dispatch_opts.accounting.as_deref(), dispatch_opts.conversations.as_ref()
+        ) {
+            warn!("Failed to restore state from {} storage: {}", storage.name(), e);
+        }
+
+        // initialize all servers
+        let total = server_addrs.len();
+        let initial_sync = {
+            let servers = servers.clone();
+            let runtime = dispatch_opts.runtime.clone();
+            async move {
+                let sync_tasks = server_addrs.into_iter().map(
+                    |s| tokio::spawn(sync_server(servers.clone(), s, runtime.sync_timeout, runtime.health.clone()))
+                ).collect::<Vec<_>>();
+                let healths = future::join_all(sync_tasks).await;
+
+                let (healthy, dead): (Vec<_>, Vec<_>) = healths
+                    .into_iter().partition(|h|
+                        *h.as_ref().unwrap_or(&state::Health::Dead) != state::Health::Dead);
+                info!("Initial health summary: {} healthy, {} dead", healthy.len(), dead.len());
+            }
+        };
+        let preload = (!args.preload.is_empty()).then(|| prober::preload_models(
+            servers.clone(), args.preload.clone(), args.preload_keep_alive.clone(), (*dispatch_opts.runtime).clone()
+        ));
+        // serve right away: servers join the selection as they sync, unreachable ones only delay
+        // themselves, and /readyz tells orchestrators when enough of them answered
+        info!("Warm cache covers {} of {} servers, syncing in the background", warmed, total);
+        let (synced_tx, synced) = tokio::sync::watch::channel(false);
+        instance.spawn(async move {
+            initial_sync.await;
+            let _ = synced_tx.send(true);
+            if let Some(preload) = preload {
+                preload.await;
+            }
+        });
+
+        instance.spawn(persist_loop(
+            storage.clone(),
+            servers.clone(),
+            dispatch_opts.accounting.clone(),
+            dispatch_opts.conversations.clone(),
+            Duration::from_secs(args.storage_flush_interval.max(1)),
+        ));
+
+        if let Some(model) = &args.probe_model {
+            info!("Probing servers with model {} every {}s", model, args.probe_interval);
+            instance.spawn(prober::shadow_prober(servers.clone(), prober::ProbeOpt {
+                model: model.clone(),
+                interval: Duration::from_secs(args.probe_interval.max(1)),
+                req: global_opts,
+                latency_alpha: dispatch_opts.runtime.latency_alpha,
+                restore_trust: args.strict,
+            }, dispatch_opts.runtime.health.clone()));
+        } else if args.strict {
+            warn!("Strict mode without --probe-model: unreliable servers stay excluded until restarted");
+        }
+
+        #[cfg(unix)]
+        if dispatch_opts.profiles.is_reloadable() {
+            instance.spawn(reload_on_sighup(dispatch_opts.profiles.clone()));
+        }
+
+        instance.spawn(status_reporter(servers.clone(), Duration::from_secs(args.status_interval.max(1))));
+        if args.sync_interval > 0 {
+            let runtime = &dispatch_opts.runtime;
+            instance.spawn(refresh_loop(
+                servers.clone(), Duration::from_secs(args.sync_interval), runtime.sync_timeout, runtime.health.clone()
+            ));
+        }
+
+        Ok(LoadBalancer { args, servers, dispatch_opts, storage, synced, _instance: instance })
+    }
+}
+
+/// A load balancer in front of Ollama servers. `handle` answers a request in-process,
+/// `serve` listens for clients like the binary does.
+pub struct LoadBalancer {
+    args: Args,
+    servers: SharedServerList,
+    dispatch_opts: DispatchOpt,
+    storage: SharedStorage,
+    /// Set once every server answered its initial sync, or failed to.
+    synced: tokio::sync::watch::Receiver<bool>,
+    _instance: Instance,
+}
+
+impl LoadBalancer {
+    /// The options of the command line without any argument, no server yet.
+    pub fn builder() -> LoadBalancerBuilder {
+        LoadBalancerBuilder { args: Args::parse_from(["ollama_load_balancer"]), health_policy: None }
+    }
+
+    /// Answers a request like the listener would, as if it came from `remote_addr`.
+    pub async fn handle(&self, req: Request<Body>, remote_addr: SocketAddr) -> Response<Body> {
+        match dispatch(req, self.servers.clone(), remote_addr, self.dispatch_opts.clone()).await {
+            Ok(resp) => resp,
+            Err(infallible) => match infallible {},
+        }
+    }
+
+    /// Accepts clients on the listening address until CTRL+C or SIGTERM, draining on the latter.
+    pub async fn serve(self) -> Result<(), Box<dyn std::error::Error>> {
+        let LoadBalancer { args, servers, dispatch_opts, storage, synced, _instance } = self;
+        let mut listener = Some(Listener::bind(&args.listen, args.reuse_port).await?);
+        // HTTP/1.1, and HTTP/2 for the clients that speak it from the start
+        let builder = auto::Builder::new(TokioExecutor::new());
+        let graceful = GracefulShutdown::new();
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        info!(
+            "Ollama Load Balancer listening on {} (HTTP/1.1 and h2c){}",
+            listener.as_ref().unwrap(), if args.reuse_port { " with SO_REUSEPORT" } else { "" }
+        );
+        // accepting connections, but only ready once the servers synced, as /readyz tells
+        #[cfg(unix)]
+        {
+            let mut synced = synced;
+            tokio::spawn(async move {
+                if synced.wait_for(|synced| *synced).await.is_ok() {
+                    systemd::notify("READY=1");
+                }
+            });
+            if let Some(interval) = systemd::watchdog_interval() {
+                tokio::spawn(systemd::watchdog(interval));
+            }
+        }
+
+        let grace = Duration::from_secs(args.shutdown_grace);
+        let mut deadline = None;
+        // on SIGTERM, keep answering with 503 or a redirect until the streams in flight are done,
+        // so that clients are not refused connections before the orchestrator stops routing to us
+        let mut draining = None;
+        // with SO_REUSEPORT, when to stop accepting: once no connection came for a moment, or at the latest
+        let mut closing: Option<(tokio::time::Instant, tokio::time::Instant)> = None;
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                accepted = async { listener.as_ref().unwrap().accept().await }, if listener.is_some() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept a connection: {}", e);
+                        continue;
+                    }
+                },
+                signal = &mut shutdown, if draining.is_none() => {
+                    begin_shutdown();
+                    #[cfg(unix)]
+                    systemd::notify("STOPPING=1");
+                    deadline = Some(tokio::time::Instant::now() + grace);
+                    match signal {
+                        ShutdownSignal::Interrupt => break,
+                        ShutdownSignal::Terminate if args.reuse_port => {
+                            // the process sharing the port takes the new connections, but closing the socket
+                            // would reset those already queued on it: accept them until the queue stays empty
+                            let now = tokio::time::Instant::now();
+                            closing = Some((now + REUSE_PORT_QUIET, now + REUSE_PORT_CLOSE_MAX));
+                            draining = Some(Box::pin(wait_for_idle_fleet(servers.clone(), grace)));
+                            continue;
+                        },
+                        ShutdownSignal::Terminate => {
+                            info!("Draining: waiting up to {}s for the requests in flight, {} new requests",
+                                args.shutdown_grace, if args.drain_redirect.is_some() { "redirecting" } else { "refusing" });
+                            draining = Some(Box::pin(wait_for_idle_fleet(servers.clone(), grace)));
+                            continue;
+                        },
+                    }
+                },
+                _ = async { tokio::time::sleep_until(closing.unwrap().0).await }, if closing.is_some() => {
+                    listener = None;
+                    closing = None;
+                    info!("Draining: stopped listening, waiting up to {}s for the requests in flight", args.shutdown_grace);
+                    continue;
+                },
+                // the accept queue is emptied before anything else
+                _ = future::OptionFuture::from(draining.as_mut()), if draining.is_some() && closing.is_none() => break,
+                _ = tokio::signal::ctrl_c(), if draining.is_some() => {
+                    info!("Received CTRL+C while draining, shutting down now");
+                    break;
+                },
+            };
+            if let Some((quiet, close_by)) = closing.as_mut() {
+                *quiet = (tokio::time::Instant::now() + REUSE_PORT_QUIET).min(*close_by);
+            }
+            let servers = servers.clone();
+            let opts = dispatch_opts.clone();
+            let service = service_fn(move |req: Request<Incoming>| {
+                dispatch(req.map(Body::new), servers.clone(), remote_addr, opts.clone())
+            });
+            let conn = builder.serve_connection(TokioIo::new(stream), service);
+            let conn = graceful.watch(conn.into_owned());
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    warn!("Connection from {} failed: {}", remote_addr, e);
+                }
+            });
+        }
+
+        // Stop accepting new connections, and let the streams in flight finish
+        let in_flight = streams_in_flight(&servers);
+        if in_flight > 0 {
+            info!("Waiting up to {}s for {} streams in flight", args.shutdown_grace, in_flight);
+        }
+        let deadline = deadline.unwrap_or_else(|| tokio::time::Instant::now() + grace);
+        if tokio::time::timeout_at(deadline, graceful.shutdown()).await.is_err() {
+            warn!("Shutdown grace period is over, cutting {} streams in flight", streams_in_flight(&servers));
+        }
+        drop(listener);
+
+        match save_warm_cache(storage.as_ref(), &servers) {
+            Ok(saved) => info!("Saved {} servers to the warm cache", saved),
+            Err(e) => warn!("Failed to save the warm cache to {} storage: {}", storage.name(), e),
+        }
+
+        Ok(())
+    }
+}
+
+/// Reloads the model profiles whenever the process receives SIGHUP.
+#[cfg(unix)]
+async fn reload_on_sighup(profiles: Arc<ProfileStore>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP, profiles won't be reloaded: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match profiles.reload() {
+            Ok((generation, models, backends)) => info!(
+                "Reloaded {} model profiles and the options of {} backends, now generation {}", models, backends, generation
+            ),
+            Err(e) => warn!("Failed to reload profiles, keeping the current ones: {}", e),
+        }
+    }
+}
+
+/// How the process was asked to stop.
+enum ShutdownSignal {
+    /// CTRL+C: stop accepting connections right away.
+    Interrupt,
+    /// SIGTERM from a service manager: drain first.
+    Terminate,
+}
+
+async fn shutdown_signal() -> ShutdownSignal {
+    // Wait for the CTRL+C signal, or SIGTERM from a service manager
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terms) => { terms.recv().await; },
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => {
+            res.expect("Failed to listen for ctrl_c");
+            info!("Received CTRL+C, shutting down gracefully...");
+            ShutdownSignal::Interrupt
+        },
+        _ = terminate => {
+            info!("Received SIGTERM, draining before shutting down...");
+            ShutdownSignal::Terminate
+        },
+    }
+}
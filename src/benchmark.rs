@@ -0,0 +1,131 @@
+use std::time::Duration;
+use chrono::Utc;
+use serde_json::Value;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::backend::ReqOpt;
+use crate::prober::{probe_server, ProbeOpt};
+use crate::schema::{BenchmarkReport, SCHEMA_VERSION};
+use crate::state::{reset_latency, set_isolated, snapshot_servers, wait_for_drain, SharedServerList};
+
+/// Runs of a benchmark unless asked otherwise.
+const DEFAULT_RUNS: usize = 5;
+
+/// Longest a benchmark keeps a server out of the rotation unless asked otherwise, in seconds.
+const DEFAULT_MAX_SECS: u64 = 120;
+
+/// What `/admin/benchmark` was asked to do.
+#[derive(Debug)]
+pub struct BenchmarkRequest {
+    pub server: String,
+    pub model: String,
+    pub runs: usize,
+    pub max_duration: Duration,
+}
+
+impl BenchmarkRequest {
+    /// Reads `{ "server": ..., "model": ..., "runs": ..., "max_secs": ... }`, the server
+    /// by address or name, the model defaulting to the probe model.
+    pub fn parse(body: &Value, servers: &SharedServerList, probe_model: Option<&str>) -> Result<Self, String> {
+        let wanted = body["server"].as_str().ok_or("Request body must contain a 'server' field")?;
+        let snaps = snapshot_servers(servers.clone(), false);
+        let (server, snap) = snaps.iter()
+            .find(|(addr, snap)| addr.as_str() == wanted || snap.name == wanted)
+            .ok_or_else(|| format!("Unknown server {}", wanted))?;
+        let model = body["model"].as_str().or(probe_model)
+            .ok_or("Request body must contain a 'model' field without --probe-model")?;
+        if !snap.models.contains_key(model) {
+            return Err(format!("Server {} does not host model {}", server, model));
+        }
+        Ok(BenchmarkRequest {
+            server: server.clone(),
+            model: model.to_string(),
+            runs: body["runs"].as_u64().map_or(DEFAULT_RUNS, |n| n as usize).max(1),
+            max_duration: Duration::from_secs(body["max_secs"].as_u64().unwrap_or(DEFAULT_MAX_SECS)),
+        })
+    }
+}
+
+/// Puts the server back into the rotation however the benchmark ends.
+struct Isolation {
+    servers: SharedServerList,
+    server: String,
+}
+
+impl Drop for Isolation {
+    fn drop(&mut self) {
+        set_isolated(self.servers.clone(), &self.server, false);
+        info!("Server {} is back in the rotation", self.server);
+    }
+}
+
+/// Takes the server out of the rotation, waits for its requests in flight, measures its time
+/// to first token alone, and puts it back with the median as its new latency prior.
+/// The whole window, draining included, never exceeds `max_duration`.
+/// Returns `None` if the server is already being benchmarked.
+pub async fn run_benchmark(
+    servers: SharedServerList,
+    bench: BenchmarkRequest,
+    req: ReqOpt,
+) -> Option<BenchmarkReport> {
+    if !set_isolated(servers.clone(), &bench.server, true) {
+        return None;
+    }
+    let _isolation = Isolation { servers: servers.clone(), server: bench.server.clone() };
+    let deadline = Instant::now() + bench.max_duration;
+    info!("Benchmarking {} with model {}, {} runs within {}s", bench.server, bench.model, bench.runs, bench.max_duration.as_secs());
+    let previous_latency_ms = snapshot_servers(servers.clone(), false).get(&bench.server).and_then(|s| s.state.latency_ms);
+
+    let drained = wait_for_drain(servers.clone(), &bench.server, bench.max_duration).await;
+    if !drained {
+        warn!("Server {} still had requests in flight when its benchmark window closed", bench.server);
+    }
+    let probe = ProbeOpt {
+        model: bench.model.clone(),
+        interval: Duration::ZERO,
+        req,
+        latency_alpha: 0.0,
+        restore_trust: false,
+    };
+    let mut ttft_ms = Vec::new();
+    let mut failures = 0;
+    for run in 1..=bench.runs {
+        if !drained {
+            break;
+        }
+        match tokio::time::timeout_at(deadline, probe_server(&bench.server, &probe)).await {
+            Ok(Ok(ttft)) => {
+                info!("Benchmark run {}/{} on {} took {}ms to the first token", run, bench.runs, bench.server, ttft.as_millis());
+                ttft_ms.push(ttft.as_secs_f32() * 1000.0);
+            },
+            Ok(Err(e)) => {
+                warn!("Benchmark run {}/{} on {} failed: {}", run, bench.runs, bench.server, e);
+                failures += 1;
+            },
+            Err(_) => {
+                warn!("Benchmark of {} ran out of time after {} runs", bench.server, run - 1);
+                break;
+            },
+        }
+    }
+
+    let mut sorted = ttft_ms.clone();
+    sorted.sort_by(f32::total_cmp);
+    let latency_ms = sorted.get(sorted.len() / 2).copied();
+    match latency_ms {
+        Some(latency) => reset_latency(servers.clone(), &bench.server, latency),
+        None => warn!("Benchmark of {} measured nothing, keeping its latency prior", bench.server),
+    }
+    Some(BenchmarkReport {
+        schema_version: SCHEMA_VERSION,
+        generated_at: Utc::now().to_rfc3339(),
+        server: bench.server,
+        model: bench.model,
+        drained,
+        ttft_ms,
+        failures,
+        previous_latency_ms,
+        latency_ms: latency_ms.or(previous_latency_ms),
+    })
+}
@@ -0,0 +1,220 @@
+//! Command line client of the load balancer's admin API.
+//!
+//! `ollama-lb-ctl servers list`, `ollama-lb-ctl model pull llama3.2 --server gpu-1`, ...
+
+use clap::{Parser, Subcommand};
+use futures_util::StreamExt;
+use reqwest::{Method, StatusCode};
+use ollama_load_balancer::print_servers;
+use ollama_load_balancer::schema::{HealthReport, StateReport, SCHEMA_VERSION};
+use serde_json::{json, Value};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Manage an Ollama load balancer through its admin API", long_about = None)]
+struct Cli {
+    /// Address of the load balancer.
+    #[arg(long, env = "OLLAMA_LB_URL", default_value = "http://127.0.0.1:11434")]
+    url: String,
+
+    /// API key, for the endpoints that require one with --api-keys-file.
+    #[arg(long, env = "OLLAMA_LB_API_KEY")]
+    api_key: Option<String>,
+
+    /// Print compact JSON, and the raw state instead of the server table.
+    #[arg(long)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the backends.
+    #[command(subcommand)]
+    Servers(ServersCommand),
+    /// Manage the models of the backends.
+    #[command(subcommand)]
+    Model(ModelCommand),
+    /// Print racing and strict mode statistics.
+    Stats,
+}
+
+#[derive(Subcommand, Debug)]
+enum ServersCommand {
+    /// List the backends with their health and load.
+    List,
+    /// Add a backend, as ADDR=NAME, e.g. http://10.0.0.5:11434=gpu-5.
+    Add { server: String },
+    /// Remove a backend, by address or name.
+    Remove { server: String },
+    /// Route requests around a backend, e.g. before its maintenance.
+    Drain {
+        server: String,
+        /// Seconds until the backend goes down.
+        #[arg(long, default_value_t = 0)]
+        in_secs: u64,
+    },
+    /// Put a drained backend back into the rotation.
+    Rejoin { server: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ModelCommand {
+    /// Pull a model on every backend, or on one.
+    Pull {
+        model: String,
+        #[arg(long)]
+        server: Option<String>,
+    },
+    /// Unload a model from every backend having it loaded, or from one.
+    Unload {
+        model: String,
+        #[arg(long)]
+        server: Option<String>,
+    },
+}
+
+/// Typed client of the admin API.
+struct AdminClient {
+    url: String,
+    api_key: Option<String>,
+    http: reqwest::Client,
+}
+
+impl AdminClient {
+    fn new(url: &str, api_key: Option<String>) -> Self {
+        AdminClient { url: url.trim_end_matches('/').to_string(), api_key, http: reqwest::Client::new() }
+    }
+
+    fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        let builder = self.http.request(method, format!("{}{}", self.url, path));
+        match &self.api_key {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        }
+    }
+
+    /// Sends a JSON request, an error status becomes an error with the message of the answer.
+    async fn call(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut builder = self.request(method, path);
+        if let Some(body) = body {
+            builder = builder.json(&body);
+        }
+        let resp = builder.send().await?;
+        let status = resp.status();
+        let answer = resp.json::<Value>().await.unwrap_or_default();
+        if !status.is_success() {
+            let msg = answer["error"].as_str().map(str::to_string).unwrap_or_else(|| answer.to_string());
+            return Err(format!("{}: {}", status, msg).into());
+        }
+        Ok(answer)
+    }
+
+    async fn state(&self) -> Result<StateReport, Box<dyn std::error::Error>> {
+        let state: StateReport = serde_json::from_value(self.call(Method::GET, "/admin/state", None).await?)?;
+        if state.schema_version != SCHEMA_VERSION {
+            return Err(format!("Unsupported schema version {}, expected {}", state.schema_version, SCHEMA_VERSION).into());
+        }
+        Ok(state)
+    }
+
+    async fn add_server(&self, server: &str) -> Result<Value, Box<dyn std::error::Error>> {
+        self.call(Method::POST, "/admin/servers", Some(json!({ "server": server }))).await
+    }
+
+    async fn remove_server(&self, server: &str) -> Result<Value, Box<dyn std::error::Error>> {
+        self.call(Method::DELETE, "/admin/servers", Some(json!({ "server": server }))).await
+    }
+
+    async fn drain(&self, server: &str, in_secs: u64) -> Result<Value, Box<dyn std::error::Error>> {
+        self.call(Method::POST, "/backend/leave", Some(json!({ "server": server, "in_secs": in_secs }))).await
+    }
+
+    async fn rejoin(&self, server: &str) -> Result<Value, Box<dyn std::error::Error>> {
+        self.call(Method::POST, "/backend/rejoin", Some(json!({ "server": server }))).await
+    }
+
+    async fn unload(&self, model: &str, server: Option<&str>) -> Result<Value, Box<dyn std::error::Error>> {
+        self.call(Method::POST, "/admin/unload", Some(json!({ "model": model, "server": server }))).await
+    }
+
+    /// Pulls a model, calling `progress` with every progress line of the backends.
+    async fn pull(&self, model: &str, server: Option<&str>, mut progress: impl FnMut(&Value)) -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = self.request(Method::POST, "/api/pull").json(&json!({ "model": model, "stream": true }));
+        if let Some(server) = server {
+            builder = builder.header("X-Ollama-Server", server);
+        }
+        let resp = builder.send().await?;
+        if resp.status() != StatusCode::OK {
+            let status = resp.status();
+            let answer = resp.json::<Value>().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, answer["error"].as_str().unwrap_or_default()).into());
+        }
+        let mut stream = resp.bytes_stream();
+        let mut line = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            line.extend_from_slice(&chunk?);
+            while let Some(pos) = line.iter().position(|b| *b == b'\n') {
+                let status = serde_json::from_slice::<Value>(&line.drain(..=pos).collect::<Vec<u8>>()).unwrap_or_default();
+                if let Some(e) = status["error"].as_str() {
+                    return Err(e.to_string().into());
+                }
+                progress(&status);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let client = AdminClient::new(&cli.url, cli.api_key.clone());
+    let answer = match cli.command {
+        Command::Servers(ServersCommand::List) if cli.json => client.call(Method::GET, "/admin/state", None).await?,
+        Command::Servers(ServersCommand::List) => {
+            let state = client.state().await?;
+            print_servers(&state);
+            return Ok(());
+        },
+        Command::Stats => {
+            let state = client.state().await?;
+            let alive = state.servers.iter().filter(|s| s.health != HealthReport::Dead).count();
+            json!({ "servers": state.servers.len(), "alive": alive, "racing": state.racing, "strict": state.strict })
+        },
+        Command::Servers(ServersCommand::Add { server }) => client.add_server(&server).await?,
+        Command::Servers(ServersCommand::Remove { server }) => client.remove_server(&server).await?,
+        Command::Servers(ServersCommand::Drain { server, in_secs }) => client.drain(&server, in_secs).await?,
+        Command::Servers(ServersCommand::Rejoin { server }) => client.rejoin(&server).await?,
+        Command::Model(ModelCommand::Unload { model, server }) => client.unload(&model, server.as_deref()).await?,
+        Command::Model(ModelCommand::Pull { model, server }) => {
+            let json_output = cli.json;
+            client.pull(&model, server.as_deref(), |status| {
+                if json_output {
+                    println!("{}", status);
+                } else if let Some(text) = status["status"].as_str() {
+                    let server = status["server"].as_str().unwrap_or("all");
+                    match (status["completed"].as_u64(), status["total"].as_u64()) {
+                        (Some(done), Some(total)) if total > 0 => println!("[{}] {} {}%", server, text, done * 100 / total),
+                        _ => println!("[{}] {}", server, text),
+                    }
+                }
+            }).await?;
+            json!({ "status": "success" })
+        },
+    };
+    if cli.json {
+        println!("{}", answer);
+    } else {
+        println!("{}", serde_json::to_string_pretty(&answer)?);
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
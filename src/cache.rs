@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use futures_util::Stream;
+use hyper::{HeaderMap, Response, StatusCode};
+
+use crate::body::Body;
+use serde_json::Value;
+
+/// Whether the answer to a request only depends on the request itself:
+/// embeddings always do, generations do with a zero temperature or a fixed seed.
+pub fn is_deterministic(path: &str, body: &Value) -> bool {
+    match path {
+        "/api/embed" => true,
+        "/api/chat" => {
+            let options = &body["options"];
+            options["temperature"].as_f64() == Some(0.0) || !options["seed"].is_null()
+        },
+        _ => false,
+    }
+}
+
+/// The key of a request in the cache, apart for every tenant.
+pub fn cache_key(path: &str, body: &[u8], tenant: Option<&str>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (path, body, tenant).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct Entries {
+    tick: u64,
+    bytes: usize,
+    map: HashMap<u64, CachedResponse>,
+    /// Keys by the tick they were last used at, least recently used first.
+    recency: BTreeMap<u64, u64>,
+}
+
+impl Entries {
+    fn remove(&mut self, key: u64) -> Option<CachedResponse> {
+        let entry = self.map.remove(&key)?;
+        self.recency.remove(&entry.last_used);
+        self.bytes -= entry.body.len();
+        Some(entry)
+    }
+}
+
+/// LRU cache of complete responses, bounded by the total size of their bodies.
+/// Entries older than the TTL are never served.
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        ResponseCache {
+            capacity,
+            ttl,
+            entries: Mutex::new(Entries { tick: 0, bytes: 0, map: HashMap::new(), recency: BTreeMap::new() }),
+        }
+    }
+
+    pub fn get(&self, key: u64) -> Option<Response<Body>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        let expired = entries.map.get(&key)?.stored_at.elapsed() > self.ttl;
+        if expired {
+            entries.remove(key);
+            return None;
+        }
+        let entry = entries.map.get_mut(&key).unwrap();
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        let mut resp = Response::new(Body::from(entry.body.clone()));
+        *resp.status_mut() = entry.status;
+        *resp.headers_mut() = entry.headers.clone();
+        entries.recency.remove(&previous);
+        entries.recency.insert(tick, key);
+        Some(resp)
+    }
+
+    fn insert(&self, key: u64, status: StatusCode, headers: HeaderMap, body: Bytes) {
+        if body.len() > self.capacity {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let last_used = entries.tick;
+        entries.remove(key);
+        entries.bytes += body.len();
+        entries.recency.insert(last_used, key);
+        entries.map.insert(key, CachedResponse { status, headers, body, stored_at: Instant::now(), last_used });
+        while entries.bytes > self.capacity {
+            let Some((_, oldest)) = entries.recency.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = entries.map.remove(&oldest) {
+                entries.bytes -= evicted.body.len();
+            }
+        }
+    }
+
+    /// Passes a successful response through, keeping a copy once it is complete.
+    pub fn store(self: &Arc<Self>, key: u64, resp: Response<Body>) -> Response<Body> {
+        if !resp.status().is_success() {
+            return resp;
+        }
+        let (parts, body) = resp.into_parts();
+        let recording = RecordingBody {
+            stream: body,
+            cache: self.clone(),
+            key,
+            head: Some((parts.status, parts.headers.clone())),
+            body: Vec::new(),
+        };
+        Response::from_parts(parts, Body::wrap_stream(recording))
+    }
+}
+
+/// Records the body it relays, and caches it if the stream ends without error.
+struct RecordingBody<S> {
+    stream: S,
+    cache: Arc<ResponseCache>,
+    key: u64,
+    head: Option<(StatusCode, HeaderMap)>,
+    body: Vec<u8>,
+}
+
+impl<S, E> Stream for RecordingBody<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let res = Pin::new(&mut self.stream).poll_next(cx);
+        match &res {
+            Poll::Ready(Some(Ok(bytes))) => {
+                if self.head.is_some() && self.body.len() + bytes.len() <= self.cache.capacity {
+                    self.body.extend_from_slice(bytes);
+                } else {
+                    // too large to ever fit, stop recording
+                    self.head = None;
+                    self.body = Vec::new();
+                }
+            },
+            Poll::Ready(Some(Err(_))) => self.head = None,
+            Poll::Ready(None) => {
+                if let Some((status, headers)) = self.head.take() {
+                    let body = Bytes::from(std::mem::take(&mut self.body));
+                    self.cache.insert(self.key, status, headers, body);
+                }
+            },
+            Poll::Pending => {},
+        }
+        res
+    }
+}
@@ -0,0 +1,183 @@
+//! `ollama_load_balancer check`: validates the configuration and prints the effective one,
+//! without serving, and `ollama_load_balancer ping`: probes every configured server.
+
+use std::time::{Duration, Instant};
+use futures_util::future;
+
+use crate::api::api_version;
+use crate::auth::ApiKeys;
+use crate::backend::set_redirect_allowlist;
+use crate::config::{Args, DiscoverySource, ServerConfig};
+use crate::profiles::{BackendProfiles, ModelProfiles};
+use crate::runtime::RuntimeConfig;
+use crate::{strategy, tls};
+
+/// The servers and discoveries of `--servers` and `--server-file`, every malformed entry
+/// reported rather than skipped.
+fn collect_servers(args: &Args, problems: &mut Vec<String>) -> (Vec<ServerConfig>, Vec<DiscoverySource>) {
+    let mut servers = Vec::new();
+    let mut discoveries = args.discover.clone();
+    let mut classify = |s: ServerConfig, problems: &mut Vec<String>| match DiscoverySource::from_server(&s) {
+        Some(Ok(source)) => discoveries.push(source),
+        Some(Err(e)) => problems.push(format!("Server {}: {}", s.address, e)),
+        None => servers.push(s),
+    };
+    for s in &args.servers {
+        classify(s.clone(), problems);
+    }
+    if let Some(file) = &args.server_file {
+        match std::fs::read_to_string(file) {
+            Ok(contents) => for (n, line) in contents.lines().enumerate() {
+                if line.is_empty() {
+                    continue;
+                }
+                match line.parse::<ServerConfig>() {
+                    Ok(s) => classify(s, problems),
+                    Err(e) => problems.push(format!("{}:{}: `{}`: {}", file, n + 1, line, e)),
+                }
+            },
+            Err(e) => problems.push(format!("Cannot read server file {}: {}", file, e)),
+        }
+    }
+    (servers, discoveries)
+}
+
+/// Resolves the host of a server address, as the backend clients will.
+async fn resolve(address: &str, timeout: Duration) -> Result<Vec<String>, String> {
+    let url = reqwest::Url::parse(address).map_err(|e| format!("not a URL, e.g. http://HOST:PORT: {}", e))?;
+    let (host, port) = url.host_str().zip(url.port_or_known_default()).ok_or("no host or port")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    match tokio::time::timeout(timeout, tokio::net::lookup_host((host.clone(), port))).await {
+        Ok(Ok(addrs)) => Ok(addrs.map(|addr| addr.to_string()).collect()),
+        Ok(Err(e)) => Err(format!("cannot resolve {}: {}", host, e)),
+        Err(_) => Err(format!("resolving {} timed out", host)),
+    }
+}
+
+/// Asks every server for its version and prints a table, returning the servers that failed.
+pub async fn ping_servers(servers: &[ServerConfig], timeout_secs: u32) -> Vec<String> {
+    let pings = servers.iter().map(|s| async move {
+        let start = Instant::now();
+        let version = api_version(&s.address, timeout_secs).await.map_err(|e| e.to_string());
+        (s, version, start.elapsed())
+    });
+    let results = future::join_all(pings).await;
+    println!("{:<16} {:<32} {:<10} {:>8}  ERROR", "NAME", "ADDRESS", "VERSION", "LATENCY");
+    let mut failed = Vec::new();
+    for (s, version, elapsed) in results {
+        match version {
+            Ok(version) => println!("{:<16} {:<32} {:<10} {:>6}ms", s.name, s.address, version, elapsed.as_millis()),
+            Err(e) => {
+                println!("{:<16} {:<32} {:<10} {:>8}  {}", s.name, s.address, "-", "-", e);
+                failed.push(format!("Server {} does not answer: {}", s.address, e));
+            },
+        }
+    }
+    failed
+}
+
+/// Validates everything the configuration refers to: servers and their addresses, files,
+/// strategy and TLS implementation, and with `ping` that every server answers.
+/// Prints the effective configuration, fails if anything is wrong.
+pub async fn check(args: &Args, ping: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut problems = Vec::new();
+    let (servers, discoveries) = collect_servers(args, &mut problems);
+    if servers.is_empty() && discoveries.is_empty() && args.register_token_file.is_none() {
+        problems.push("No servers provided".to_string());
+    }
+    if !discoveries.is_empty() && !cfg!(feature = "discovery") {
+        problems.push("Discovering servers requires building with the `discovery` feature".to_string());
+    }
+    if let Err(e) = strategy::make_strategy(&args.strategy) {
+        problems.push(e);
+    }
+    if let Err(e) = tls::set_tls_backend(args.tls_backend) {
+        problems.push(e);
+    }
+    if !(0.0..=1.0).contains(&args.latency_alpha) {
+        problems.push(format!("--latency-alpha must be between 0 and 1, got {}", args.latency_alpha));
+    }
+    if let Some(file) = &args.api_keys_file {
+        if let Err(e) = ApiKeys::load(file) {
+            problems.push(format!("Cannot load API keys from {}: {}", file, e));
+        }
+    }
+    if let Some(file) = &args.model_profiles {
+        if let Err(e) = ModelProfiles::load(file) {
+            problems.push(format!("Cannot load model profiles from {}: {}", file, e));
+        }
+    }
+    if let Some(file) = &args.backend_options {
+        if let Err(e) = BackendProfiles::load(file) {
+            problems.push(format!("Cannot load backend options from {}: {}", file, e));
+        }
+    }
+    if let Some(file) = &args.register_token_file {
+        match std::fs::read_to_string(file) {
+            Ok(token) if token.trim().is_empty() => problems.push(format!("Registration token file {} is empty", file)),
+            Ok(_) => {},
+            Err(e) => problems.push(format!("Cannot read registration token file {}: {}", file, e)),
+        }
+    }
+
+    println!("Listen: {}", args.listen);
+    println!("Servers:");
+    let timeout = Duration::from_secs(args.timeout.max(1) as u64);
+    let mut resolved = Vec::new();
+    for s in &servers {
+        match resolve(&s.address, timeout).await {
+            Ok(addrs) => {
+                println!("  {} = {} -> {}", s.address, s.name, addrs.join(", "));
+                resolved.push(s.clone());
+            },
+            Err(e) => {
+                println!("  {} = {} -> ?", s.address, s.name);
+                problems.push(format!("Server {}: {}", s.address, e));
+            },
+        }
+    }
+    for source in &discoveries {
+        println!("  discovered by {:?}", source);
+    }
+    println!("Dispatch mode: {}, selection strategy: {}, backend HTTP: {}", args.mode, args.strategy, args.backend_http);
+    println!("Runtime configuration: {:#?}", RuntimeConfig::from_args(args));
+    // the servers that do not resolve are already reported
+    if ping && !resolved.is_empty() {
+        set_redirect_allowlist(args.backend_redirect_allow.clone());
+        println!();
+        problems.extend(ping_servers(&resolved, args.timeout).await);
+    }
+
+    if problems.is_empty() {
+        println!("Configuration OK");
+        return Ok(());
+    }
+    println!();
+    for problem in &problems {
+        println!("error: {}", problem);
+    }
+    Err(format!("{} problem(s) found in the configuration", problems.len()).into())
+}
+
+/// Probes every configured server and prints a table, failing if any does not answer.
+pub async fn ping(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let mut problems = Vec::new();
+    let (servers, discoveries) = collect_servers(args, &mut problems);
+    if servers.is_empty() {
+        problems.push(match discoveries.is_empty() {
+            true => "No servers provided".to_string(),
+            false => "Only discovered servers, which the running load balancer lists with `status`".to_string(),
+        });
+    }
+    set_redirect_allowlist(args.backend_redirect_allow.clone());
+    if !servers.is_empty() {
+        problems.extend(ping_servers(&servers, args.timeout.max(1)).await);
+    }
+    for problem in &problems {
+        eprintln!("error: {}", problem);
+    }
+    match problems.len() {
+        0 => Ok(()),
+        n => Err(format!("{} problem(s) found", n).into()),
+    }
+}
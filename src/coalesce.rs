@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use futures_util::stream::{self, Stream, StreamExt};
+use hyper::{HeaderMap, Response, StatusCode};
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::body::Body;
+
+/// Identical requests in flight, keyed by the fingerprint of their path and body.
+/// The first one goes to the backends, the others replay its response as it streams.
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    flights: Mutex<HashMap<u64, Arc<Flight>>>,
+}
+
+/// A response shared by every identical request, buffered from its first byte
+/// so that requests joining late still get all of it.
+#[derive(Debug, Default)]
+pub struct Flight {
+    state: Mutex<FlightState>,
+    updated: Notify,
+    /// Every client went away, the backend call is cancelled.
+    abandoned: Notify,
+}
+
+#[derive(Debug, Default)]
+struct FlightState {
+    head: Option<(StatusCode, HeaderMap)>,
+    chunks: Vec<Bytes>,
+    error: Option<String>,
+    done: bool,
+    followers: usize,
+    subscribers: usize,
+}
+
+pub enum Role {
+    Leader(Subscription),
+    Follower(Subscription),
+}
+
+/// A client of a flight, until it got the whole response or went away.
+#[derive(Debug)]
+pub struct Subscription {
+    coalescer: Arc<Coalescer>,
+    key: u64,
+    flight: Arc<Flight>,
+}
+
+pub fn fingerprint(path: &str, body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Coalescer {
+    /// Joins the flight of an identical request, or starts a new one.
+    pub fn join(self: &Arc<Self>, key: u64) -> Role {
+        let mut flights = self.flights.lock().unwrap();
+        let subscription = |flight: &Arc<Flight>| Subscription { coalescer: self.clone(), key, flight: flight.clone() };
+        match flights.get(&key) {
+            Some(flight) => {
+                let mut state = flight.state.lock().unwrap();
+                state.followers += 1;
+                state.subscribers += 1;
+                Role::Follower(subscription(flight))
+            },
+            None => {
+                let flight = Arc::new(Flight::default());
+                flight.state.lock().unwrap().subscribers = 1;
+                flights.insert(key, flight.clone());
+                Role::Leader(subscription(&flight))
+            },
+        }
+    }
+
+    /// Closes the flight to newcomers, unless another one took its key already.
+    fn close(&self, key: u64, flight: &Arc<Flight>) {
+        let mut flights = self.flights.lock().unwrap();
+        if flights.get(&key).is_some_and(|current| Arc::ptr_eq(current, flight)) {
+            flights.remove(&key);
+        }
+    }
+
+    /// Runs the request of the leader and relays its response into its flight, in the
+    /// background, until the response is complete or every client of the flight went away.
+    pub fn lead<F>(self: &Arc<Self>, leader: &Subscription, resp: F)
+    where
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        let (coalescer, key, flight) = (self.clone(), leader.key, leader.flight.clone());
+        tokio::spawn(async move {
+            let relay = async {
+                let (parts, mut body) = resp.await.into_parts();
+                flight.update(|state| state.head = Some((parts.status, parts.headers)));
+                while let Some(chunk) = body.next().await {
+                    match chunk {
+                        Ok(chunk) => flight.update(|state| state.chunks.push(chunk)),
+                        Err(e) => {
+                            flight.update(|state| state.error = Some(e.to_string()));
+                            break;
+                        },
+                    }
+                }
+            };
+            // dropping the response closes the connection to the backend
+            tokio::select! {
+                _ = relay => {},
+                _ = flight.abandoned.notified() => {
+                    info!("Every client of a coalesced request went away, cancelled it");
+                    return;
+                },
+            }
+            coalescer.close(key, &flight);
+            let followers = flight.state.lock().unwrap().followers;
+            if followers > 0 {
+                info!("Served {} coalesced requests with a single backend call", followers);
+            }
+            flight.update(|state| state.done = true);
+        });
+    }
+}
+
+impl Flight {
+    fn update(&self, f: impl FnOnce(&mut FlightState)) {
+        f(&mut self.state.lock().unwrap());
+        self.updated.notify_waiters();
+    }
+}
+
+impl Subscription {
+    /// A response replaying the flight from its first byte.
+    pub async fn response(self) -> Response<Body> {
+        let (status, headers) = loop {
+            let updated = self.flight.updated.notified();
+            if let Some(head) = self.flight.state.lock().unwrap().head.clone() {
+                break head;
+            }
+            updated.await;
+        };
+        let mut resp = Response::new(Body::wrap_stream(self.replay()));
+        *resp.status_mut() = status;
+        *resp.headers_mut() = headers;
+        resp
+    }
+
+    fn replay(self) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+        // `None` once the stream failed, the error is only reported once
+        stream::unfold((self, Some(0)), |(subscription, next)| async move {
+            let next = next?;
+            loop {
+                let flight = &subscription.flight;
+                let updated = flight.updated.notified();
+                let item = {
+                    let state = flight.state.lock().unwrap();
+                    if let Some(chunk) = state.chunks.get(next) {
+                        Some(Some(Ok(chunk.clone())))
+                    } else if let Some(e) = &state.error {
+                        Some(Some(Err(std::io::Error::other(e.clone()))))
+                    } else if state.done {
+                        Some(None)
+                    } else {
+                        None
+                    }
+                };
+                match item {
+                    Some(item) => {
+                        drop(updated);
+                        return item.map(|res| {
+                            let next = res.is_ok().then_some(next + 1);
+                            (res, (subscription, next))
+                        });
+                    },
+                    None => updated.await,
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // locked in the order of `join`, so that nobody joins a flight being abandoned
+        let mut flights = self.coalescer.flights.lock().unwrap();
+        let mut state = self.flight.state.lock().unwrap();
+        state.subscribers -= 1;
+        if state.subscribers == 0 && !state.done {
+            if flights.get(&self.key).is_some_and(|current| Arc::ptr_eq(current, &self.flight)) {
+                flights.remove(&self.key);
+            }
+            self.flight.abandoned.notify_one();
+        }
+    }
+}
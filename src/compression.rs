@@ -0,0 +1,124 @@
+use std::io::Write;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use futures_util::stream::{self, StreamExt};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Response, StatusCode};
+
+use crate::body::{Body, BoxError};
+
+/// Brotli quality of the responses, fast enough to compress a token stream as it goes.
+const BROTLI_QUALITY: u32 = 4;
+
+/// Encodings the responses to the clients can be compressed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+/// The encoding the client prefers in its `Accept-Encoding`, brotli on a tie.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let accepted = headers.get_all(header::ACCEPT_ENCODING).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','));
+    let mut best: Option<(Encoding, f32)> = None;
+    for coding in accepted {
+        let mut params = coding.split(';');
+        let encoding = match params.next().unwrap_or_default().trim() {
+            "br" => Encoding::Brotli,
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            _ => continue,
+        };
+        let q = params
+            .find_map(|p| p.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()))
+            .unwrap_or(1.0);
+        let better = best.is_none_or(|(_, best_q)| q > best_q || (q == best_q && encoding == Encoding::Brotli));
+        if q > 0.0 && better {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether a response is worth compressing: text that is not already encoded.
+fn is_compressible(resp: &Response<Body>) -> bool {
+    let headers = resp.headers();
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    resp.status() != StatusCode::NO_CONTENT && resp.status() != StatusCode::NOT_MODIFIED
+        && !headers.contains_key(header::CONTENT_ENCODING)
+        && (content_type.starts_with("application/json") || content_type.starts_with("application/x-ndjson")
+            || content_type.starts_with("text/"))
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::fast())),
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, 22))),
+        }
+    }
+
+    /// Compresses a chunk and flushes it, so that a streamed answer reaches the client
+    /// token by token instead of once the compressor's window is full.
+    fn push(&mut self, chunk: &[u8]) -> std::io::Result<Bytes> {
+        let out = match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            },
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            },
+        };
+        Ok(Bytes::from(std::mem::take(out)))
+    }
+
+    fn finish(self) -> std::io::Result<Bytes> {
+        let out = match self {
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Brotli(encoder) => encoder.into_inner(),
+        };
+        Ok(Bytes::from(out))
+    }
+}
+
+/// Compresses the body of a response as it is relayed, if it is worth it.
+pub fn compress(resp: Response<Body>, encoding: Encoding) -> Response<Body> {
+    if !is_compressible(&resp) {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    parts.headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let encoded = stream::unfold((body, Some(Encoder::new(encoding))), |(mut body, encoder)| async move {
+        let mut encoder = encoder?;
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                let out = encoder.push(&chunk).map_err(BoxError::from);
+                Some((out, (body, Some(encoder))))
+            },
+            Some(Err(e)) => Some((Err(e), (body, None))),
+            None => Some((encoder.finish().map_err(BoxError::from), (body, None))),
+        }
+    }).filter(|chunk| std::future::ready(chunk.as_ref().map_or(true, |chunk| !chunk.is_empty())));
+    Response::from_parts(parts, Body::wrap_stream(encoded))
+}
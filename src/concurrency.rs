@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures_util::Stream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+use crate::config::ModelLimit;
+
+/// Fleet-wide caps on the generations of a model in flight, whatever the number of servers,
+/// e.g. to protect the shared storage or the power budget from too many 70B jobs.
+#[derive(Debug, Default)]
+pub struct ModelConcurrency {
+    limits: HashMap<String, (usize, Arc<Semaphore>)>,
+}
+
+impl ModelConcurrency {
+    pub fn new(limits: &[ModelLimit]) -> Self {
+        ModelConcurrency {
+            limits: limits.iter().map(|l| (l.model.clone(), (l.max, Arc::new(Semaphore::new(l.max))))).collect(),
+        }
+    }
+
+    /// Waits for a generation of `model` to be allowed, at most `timeout` if given.
+    /// The permit is held until the response is fully relayed, `None` for models without a cap.
+    /// Returns the cap if the wait timed out.
+    pub async fn acquire(&self, model: &str, timeout: Option<Duration>) -> Result<Option<OwnedSemaphorePermit>, usize> {
+        let Some((max, semaphore)) = self.limits.get(model) else {
+            return Ok(None);
+        };
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        info!("{} generations of {} in flight, waiting for one to finish", max, model);
+        let acquire = semaphore.clone().acquire_owned();
+        let permit = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire).await.map_err(|_| *max)?,
+            None => acquire.await,
+        };
+        // the semaphores are never closed
+        Ok(Some(permit.unwrap()))
+    }
+}
+
+/// Global cap on the inference requests in flight, beyond which new ones are turned away
+/// right away instead of fanning out to the backends, to protect them during traffic spikes.
+#[derive(Debug, Default)]
+pub struct InflightLimit {
+    max: Option<usize>,
+    current: Arc<AtomicUsize>,
+    rejected: AtomicU64,
+}
+
+impl InflightLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        InflightLimit { max, ..Default::default() }
+    }
+
+    /// Counts a request in until the guard is dropped, unless the cap is reached.
+    pub fn try_acquire(&self) -> Option<InflightGuard> {
+        let admitted = self.current.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            self.max.is_none_or(|max| current < max).then_some(current + 1)
+        });
+        match admitted {
+            Ok(_) => Some(InflightGuard(self.current.clone())),
+            Err(_) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn max(&self) -> Option<usize> {
+        self.max
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// A request counted by an `InflightLimit`.
+#[derive(Debug)]
+pub struct InflightGuard(Arc<AtomicUsize>);
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Keeps a request counted in flight until its response is fully relayed.
+pub struct InflightBody<S> {
+    stream: S,
+    _guard: InflightGuard,
+}
+
+impl<S> InflightBody<S> {
+    pub fn new(stream: S, guard: InflightGuard) -> Self {
+        InflightBody { stream, _guard: guard }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for InflightBody<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
@@ -1,4 +1,8 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::backend::TimeoutProfile;
+use crate::runtime::EndpointClass;
+
 /// Struct to hold the user-supplied server address and its human-readable name.
 /// Format on the command line should be:  ip:port=Name
 #[derive(Debug, Clone)]
@@ -23,6 +27,335 @@ impl std::str::FromStr for ServerConfig {
     }
 }
 
+/// How many servers a request is fanned out to, written as MIN:MAX.
+#[derive(Debug, Clone, Copy)]
+pub struct SelectCount {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl std::str::FromStr for SelectCount {
+    type Err = String;
+
+    /// We expect something like "3:6", or "2" for exactly two servers
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| n.trim().parse::<usize>().map_err(|e| format!("Invalid server count `{}`: {}", n, e));
+        let (min, max) = match s.split_once(':') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if min == 0 || max < min {
+            return Err("Invalid server count. Use MIN:MAX with 1 <= MIN <= MAX".to_string());
+        }
+        Ok(SelectCount { min, max })
+    }
+}
+
+/// Server count for a single endpoint, written as ENDPOINT=MIN:MAX.
+#[derive(Debug, Clone)]
+pub struct EndpointSelectCount {
+    pub endpoint: String,
+    pub count: SelectCount,
+}
+
+impl std::str::FromStr for EndpointSelectCount {
+    type Err = String;
+
+    /// We expect something like "/api/chat=1:2"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (endpoint, count) = s.split_once('=')
+            .ok_or("Invalid endpoint server count format. Use ENDPOINT=MIN:MAX")?;
+        Ok(EndpointSelectCount {
+            endpoint: endpoint.trim().to_string(),
+            count: count.parse()?,
+        })
+    }
+}
+
+/// Timeouts of a class of endpoints, written as CLASS=CONNECT:FIRST_TOKEN:IDLE:TOTAL.
+#[derive(Debug, Clone)]
+pub struct ClassTimeouts {
+    pub class: EndpointClass,
+    pub profile: TimeoutProfile,
+}
+
+impl std::str::FromStr for ClassTimeouts {
+    type Err = String;
+
+    /// We expect something like "chat=1:120:30:0"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, profile) = s.split_once('=')
+            .ok_or("Invalid endpoint timeouts format. Use CLASS=CONNECT:FIRST_TOKEN:IDLE:TOTAL")?;
+        Ok(ClassTimeouts {
+            class: class.trim().parse()?,
+            profile: profile.parse()?,
+        })
+    }
+}
+
+/// Trace sampling rate for a single endpoint, written as ENDPOINT=RATE.
+#[derive(Debug, Clone)]
+pub struct EndpointSampleRate {
+    pub endpoint: String,
+    pub rate: f64,
+}
+
+impl std::str::FromStr for EndpointSampleRate {
+    type Err = String;
+
+    /// We expect something like "/api/embed=0.001"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (endpoint, rate) = s.split_once('=')
+            .ok_or("Invalid endpoint sampling rate format. Use ENDPOINT=RATE")?;
+        let rate: f64 = rate.trim().parse().map_err(|_| format!("Invalid sampling rate: {}", rate))?;
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(format!("Sampling rate {} is not between 0 and 1", rate));
+        }
+        Ok(EndpointSampleRate { endpoint: endpoint.trim().to_string(), rate })
+    }
+}
+
+/// Servers sharing physical hardware, written as NAME=ADDR,ADDR,...
+#[derive(Debug, Clone)]
+pub struct GpuDomain {
+    pub name: String,
+    pub addresses: Vec<String>,
+}
+
+impl std::str::FromStr for GpuDomain {
+    type Err = String;
+
+    /// We expect something like "box1=127.0.0.1:11433,127.0.0.1:11434"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, addresses) = s.split_once('=')
+            .ok_or("Invalid GPU domain format. Use NAME=ADDR,ADDR,...")?;
+        let addresses = addresses.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect::<Vec<_>>();
+        if addresses.is_empty() {
+            return Err("A GPU domain needs at least one server address".to_string());
+        }
+        Ok(GpuDomain { name: name.trim().to_string(), addresses })
+    }
+}
+
+/// Models to use instead of one no healthy server hosts, written as MODEL=FALLBACK,FALLBACK,...
+#[derive(Debug, Clone)]
+pub struct ModelFallback {
+    pub model: String,
+    pub fallbacks: Vec<String>,
+}
+
+impl std::str::FromStr for ModelFallback {
+    type Err = String;
+
+    /// We expect something like "llama3.3:70b=llama3.1:8b,mistral"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (model, fallbacks) = s.split_once('=')
+            .ok_or("Invalid model fallback format. Use MODEL=FALLBACK,FALLBACK,...")?;
+        let fallbacks = fallbacks.split(',').map(|m| m.trim().to_string()).filter(|m| !m.is_empty()).collect::<Vec<_>>();
+        if fallbacks.is_empty() {
+            return Err("A model fallback needs at least one fallback model".to_string());
+        }
+        Ok(ModelFallback { model: model.trim().to_string(), fallbacks })
+    }
+}
+
+/// Most generations of a model in flight across the fleet, written as MODEL=N.
+#[derive(Debug, Clone)]
+pub struct ModelLimit {
+    pub model: String,
+    pub max: usize,
+}
+
+impl std::str::FromStr for ModelLimit {
+    type Err = String;
+
+    /// We expect something like "llama3.3:70b=2"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (model, max) = s.rsplit_once('=')
+            .ok_or("Invalid model concurrency format. Use MODEL=N")?;
+        let max = max.trim().parse::<usize>().map_err(|e| format!("Invalid concurrency {}: {}", max, e))?;
+        if max == 0 {
+            return Err("A model concurrency must be at least 1".to_string());
+        }
+        Ok(ModelLimit { model: model.trim().to_string(), max })
+    }
+}
+
+/// Where backends are discovered, e.g. `k8s:NAMESPACE/SERVICE` for the ready endpoints
+/// of a Kubernetes Service.
+#[derive(Debug, Clone)]
+pub enum DiscoverySource {
+    Kubernetes { namespace: String, service: String },
+    /// The hosts of the SRV records of `name`, or the addresses of `name` on `port`,
+    /// resolved periodically and named after `pool`.
+    Dns { name: String, port: Option<u16>, pool: String },
+    /// The instances of an mDNS service on the LAN, `_ollama._tcp.local` by default.
+    Mdns { service: String },
+}
+
+impl DiscoverySource {
+    /// The DNS discovery a server given as `dns+srv://NAME=POOL` or `dns://HOST[:PORT]=POOL` stands for.
+    pub fn from_server(server: &ServerConfig) -> Option<Result<Self, String>> {
+        let pool = server.name.clone();
+        if let Some(name) = server.address.strip_prefix("dns+srv://") {
+            return Some(Ok(DiscoverySource::Dns { name: name.trim_end_matches('/').to_string(), port: None, pool }));
+        }
+        let target = server.address.strip_prefix("dns://")?.trim_end_matches('/');
+        let (host, port) = match target.rsplit_once(':') {
+            Some((host, port)) => match port.parse::<u16>() {
+                Ok(port) => (host, port),
+                Err(e) => return Some(Err(format!("Invalid port in {}: {}", server.address, e))),
+            },
+            None => (target, 11434),
+        };
+        Some(Ok(DiscoverySource::Dns { name: host.to_string(), port: Some(port), pool }))
+    }
+}
+
+impl std::str::FromStr for DiscoverySource {
+    type Err = String;
+
+    /// We expect something like "k8s:ai/ollama", "mdns" or "mdns:_ollama._tcp"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "mdns" {
+            return Ok(DiscoverySource::Mdns { service: "_ollama._tcp.local".to_string() });
+        }
+        match s.split_once(':') {
+            Some(("k8s", target)) => {
+                let (namespace, service) = target.split_once('/')
+                    .filter(|(namespace, service)| !namespace.is_empty() && !service.is_empty())
+                    .ok_or("Invalid Kubernetes discovery. Use k8s:NAMESPACE/SERVICE")?;
+                Ok(DiscoverySource::Kubernetes { namespace: namespace.to_string(), service: service.to_string() })
+            },
+            Some(("mdns", service)) if service.starts_with('_') => {
+                let service = service.trim_end_matches('.');
+                let service = if service.ends_with(".local") { service.to_string() } else { format!("{}.local", service) };
+                Ok(DiscoverySource::Mdns { service })
+            },
+            _ => Err(format!("Unknown discovery {}. Use k8s:NAMESPACE/SERVICE or mdns[:_SERVICE._tcp]", s)),
+        }
+    }
+}
+
+/// Requests an authentication provider applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthScope {
+    /// The Ollama API.
+    Api,
+    /// The /admin/ and /backend/ endpoints and /metrics, but /admin/register, which checks
+    /// its own registration token.
+    Admin,
+    All,
+}
+
+/// A link of the authentication chain, written as NAME[@SCOPE].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthProviderKind {
+    Keys,
+    TrustedHeader,
+    Http,
+    Jwt,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthProviderSpec {
+    pub kind: AuthProviderKind,
+    pub scope: AuthScope,
+}
+
+impl std::str::FromStr for AuthProviderSpec {
+    type Err = String;
+
+    /// We expect something like "keys", "trusted-header@admin" or "http@all"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, scope) = s.split_once('@').unwrap_or((s, "api"));
+        let kind = match name {
+            "keys" => AuthProviderKind::Keys,
+            "trusted-header" => AuthProviderKind::TrustedHeader,
+            "http" => AuthProviderKind::Http,
+            "jwt" => AuthProviderKind::Jwt,
+            _ => return Err(format!("Unknown authentication provider {}, expected keys, trusted-header, http or jwt", name)),
+        };
+        let scope = match scope {
+            "api" => AuthScope::Api,
+            "admin" => AuthScope::Admin,
+            "all" => AuthScope::All,
+            _ => return Err(format!("Unknown authentication scope {}, expected api, admin or all", scope)),
+        };
+        Ok(AuthProviderSpec { kind, scope })
+    }
+}
+
+/// A model to load on a server at startup, written as MODEL@SERVER.
+#[derive(Debug, Clone)]
+pub struct Preload {
+    pub model: String,
+    pub server: String,
+}
+
+impl std::str::FromStr for Preload {
+    type Err = String;
+
+    /// We expect something like "llama3.3:70b@gpu-1", the server by address or name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (model, server) = s.rsplit_once('@')
+            .ok_or("Invalid preload format. Use MODEL@SERVER")?;
+        let (model, server) = (model.trim(), server.trim());
+        if model.is_empty() || server.is_empty() {
+            return Err("A preload needs both a model and a server".to_string());
+        }
+        Ok(Preload { model: model.to_string(), server: server.to_string() })
+    }
+}
+
+// The command line: the load balancer itself, or one of the subcommands
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(flatten)]
+    pub args: Args,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the load balancer, the default without a subcommand.
+    Run {
+        #[command(flatten)]
+        args: Args,
+    },
+
+    /// Validate the configuration and print the effective one, without serving.
+    /// Exits non-zero if anything is wrong.
+    Check {
+        #[command(flatten)]
+        args: Args,
+
+        /// Also check that every server answers.
+        #[arg(long)]
+        ping: bool,
+    },
+
+    /// Probe every configured server and print a table, exits non-zero if any does not answer.
+    Ping {
+        #[command(flatten)]
+        args: Args,
+    },
+
+    /// Print the readiness and servers of a running load balancer.
+    Status {
+        /// Address of the load balancer.
+        #[arg(long, env = "OLLAMA_LB_URL", default_value = "http://127.0.0.1:11434")]
+        url: String,
+
+        /// API key, for the admin endpoints when they require one.
+        #[arg(long, env = "OLLAMA_LB_API_KEY")]
+        api_key: Option<String>,
+    },
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
@@ -30,6 +363,7 @@ pub struct Args {
     ///
     /// This is a required argument. It specifies the addresses of the Ollama servers
     /// that the load balancer will distribute requests to, plus a friendly name.
+    /// dns+srv://NAME=POOL and dns://HOST[:PORT]=POOL stand for the servers found in DNS.
     #[arg(short, long)]
     pub servers: Vec<ServerConfig>,
 
@@ -37,6 +371,37 @@ pub struct Args {
     #[arg(long)]
     pub server_file: Option<String>,
 
+    /// Keeps the servers in sync with a dynamic source, e.g. k8s:NAMESPACE/SERVICE watches the
+    /// endpoints of a Kubernetes Service, so that pods join and leave the pool as they scale,
+    /// and mdns browses the _ollama._tcp services announced on the LAN.
+    #[arg(long)]
+    pub discover: Vec<DiscoverySource>,
+
+    /// Seconds between two resolutions of the servers given as dns+srv://NAME=POOL, the hosts of
+    /// the SRV records of NAME, or as dns://HOST[:PORT]=POOL, every address of HOST,
+    /// and between two mDNS browses.
+    #[arg(long, default_value_t = 30)]
+    pub dns_refresh_secs: u64,
+
+    /// Path to a file containing the token servers register themselves with on /admin/register,
+    /// as `Authorization: Bearer <token>`. Enables self-registration.
+    #[arg(long)]
+    pub register_token_file: Option<String>,
+
+    /// Seconds after its announced shutdown a server that left with /backend/leave may take to
+    /// rejoin. Past them it is dead until a sync finds it again, and its failures count.
+    #[arg(long, default_value_t = 600)]
+    pub leave_timeout: u64,
+
+    /// Seconds a self-registered server stays without renewing its registration before it is removed.
+    #[arg(long, default_value_t = 60)]
+    pub register_ttl: u64,
+
+    /// Kubernetes API used by --discover k8s:..., e.g. http://127.0.0.1:8001 behind `kubectl proxy`.
+    /// Defaults to the cluster the load balancer runs in, with its service account.
+    #[arg(long)]
+    pub k8s_api_url: Option<String>,
+
     /// Timeout for common requests in seconds. (except for /api/chat)
     #[arg(long, default_value_t = 1)]
     pub timeout: u32,
@@ -45,11 +410,524 @@ pub struct Args {
     #[arg(long, default_value_t = 10)]
     pub timeout_ft: u32,
 
+    /// Syntax is --timeout-for CLASS=CONNECT:FIRST_TOKEN:IDLE:TOTAL, in seconds, 0 for none,
+    /// overriding --timeout and --timeout-ft for a class of endpoints:
+    /// `chat` (/api/chat, /api/generate, /api/embed), `tags` (/api/tags, /api/ps, /api/show)
+    /// or `pull` (/api/pull, /api/blobs).
+    ///
+    /// For example `--timeout-for chat=1:120:30:0` waits long for large models to load,
+    /// but not for a stream that stopped.
+    #[arg(long)]
+    pub timeout_for: Vec<ClassTimeouts>,
+
+    /// Longest first token timeout in seconds a client may ask for with the `X-LB-Timeout-FT`
+    /// header, e.g. for the cold start of a large model. 0 ignores the header.
+    #[arg(long, default_value_t = 300)]
+    pub max_timeout_ft: u32,
+
+    /// Timeout in seconds of the /api/tags, /api/ps and /api/version requests syncing a server.
+    #[arg(long, default_value_t = 5)]
+    pub sync_timeout: u32,
+
+    /// Bytes of a failed response kept in the logs.
+    #[arg(long, default_value_t = 100)]
+    pub preview_len: usize,
+
+    /// Weight of the newest sample in the latency averages, between 0 and 1: higher follows
+    /// changes faster, lower smooths out outliers.
+    #[arg(long, default_value_t = 0.2)]
+    pub latency_alpha: f32,
+
+    /// Chat requests queued for a server longer than this many milliseconds are logged.
+    #[arg(long, default_value_t = 100)]
+    pub queue_log_threshold_ms: u128,
+
     /// Time to measure the server's performance.
     #[arg(long, default_value_t = 2)]
     pub time_measure: u32,
 
-    /// Listening address. Defaults to "0.0.0.0:11434"
+    /// Tokens after which the measurement stops before --time-measure is over,
+    /// so that fast models start relaying early. 0 always waits.
+    #[arg(long, default_value_t = 0)]
+    pub measure_tokens: u32,
+
+    /// Annotate merged model listings of /api/tags and /api/ps with `available_now`,
+    /// telling whether an idle server already has the model loaded.
+    #[arg(long)]
+    pub annotate_availability: bool,
+
+    /// Annotate merged model listings of /api/tags and /api/ps with `servers`, the alive servers
+    /// hosting each model and whether they have it loaded. This tells API clients the backend addresses.
+    #[arg(long)]
+    pub annotate_servers: bool,
+
+    /// Path to a file containing accepted API keys, one per line.
+    ///
+    /// When set, clients must send `Authorization: Bearer <key>`, otherwise they get 401.
+    #[arg(long)]
+    pub api_keys_file: Option<String>,
+
+    /// Syntax is --auth-provider NAME[@SCOPE], tried in the given order until one identifies the client:
+    /// keys checks the bearer key against --api-keys-file, trusted-header takes the identity
+    /// from --trusted-header set by a proxy of --trusted-proxies, e.g. after mTLS, http asks
+    /// --auth-url, and jwt checks a bearer JWT against --jwt-key-file.
+    /// SCOPE is api (default), admin or all. Defaults to keys@all with --api-keys-file.
+    #[arg(long)]
+    pub auth_provider: Vec<AuthProviderSpec>,
+
+    /// Leave the admin endpoints open when no --auth-provider covers them.
+    ///
+    /// Without it, an authentication chain that only covers the API scope is refused at startup,
+    /// and the admin endpoints (/admin/, /backend/ and /metrics) and `X-LB-Backend` are refused
+    /// with 403 to clients without admin credentials, also when no authentication is configured.
+    #[arg(long)]
+    pub open_admin: bool,
+
+    /// Header carrying the client identity for the trusted-header provider.
+    #[arg(long, default_value = "X-Forwarded-User")]
+    pub trusted_header: String,
+
+    /// Addresses of the proxies --trusted-header is accepted from, e.g. 10.0.0.1,10.0.0.2.
+    #[arg(long, value_delimiter = ',')]
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+
+    /// Endpoint the http provider checks the credentials with: it gets the Authorization and
+    /// Cookie headers of the client, and answers 2xx with the identity in `X-Auth-Identity`, or 401/403.
+    #[arg(long)]
+    pub auth_url: Option<String>,
+
+    /// Key the jwt provider checks the signatures of the tokens with: a PEM public key for
+    /// RS*, PS*, ES* and EdDSA tokens, or the shared secret of HS* tokens, trailing newline trimmed.
+    #[arg(long)]
+    pub jwt_key_file: Option<String>,
+
+    /// Audience the tokens of the jwt provider must name in `aud`. Not checked when unset.
+    #[arg(long)]
+    pub jwt_audience: Option<String>,
+
+    /// Endpoint authorizing each authenticated request, like Envoy's ext_authz: it gets a JSON
+    /// POST with the method, path, model and identity, and answers 2xx with an optional
+    /// `{"allow": bool, "headers": {...}, "reason": "..."}`. The headers are added to the
+    /// request forwarded to the backends. Anything else denies the request.
+    #[arg(long)]
+    pub authz_url: Option<String>,
+
+    /// Let the requests through when the --authz-url service cannot be reached, instead of denying them.
+    #[arg(long, default_value_t = false)]
+    pub authz_fail_open: bool,
+
+    /// Count the requests and tokens of every client by address when no authentication is
+    /// configured, see /admin/usage. Authenticated clients are always counted by identity.
+    #[arg(long, default_value_t = false)]
+    pub track_usage: bool,
+
+    /// Maximum number of requests per minute for each API key. Requires --api-keys-file.
+    #[arg(long)]
+    pub rate_limit_rpm: Option<u32>,
+
+    /// Maximum number of tokens (prompt + generated) per day for each API key. Requires --api-keys-file.
+    #[arg(long)]
+    pub quota_tokens_per_day: Option<u64>,
+
+    /// Interval in seconds between two syncs of every server, so that models pulled directly
+    /// on a backend are listed without waiting for traffic. 0 only syncs on startup and requests.
+    #[arg(long, default_value_t = 60)]
+    pub sync_interval: u64,
+
+    /// Minimum interval in seconds between two server status reports. Reports are only printed on change.
+    #[arg(long, default_value_t = 5)]
+    pub status_interval: u64,
+
+    /// Comma-separated header names and JSON keys whose values are redacted from the logs.
+    /// A trailing `*` matches by prefix. Pass an empty value to disable redaction.
+    #[arg(long, value_delimiter = ',', default_values_t = crate::redact::DEFAULT_KEYS.map(String::from))]
+    pub redact_keys: Vec<String>,
+
+    /// Route each client to the same backend instead of racing, keeping Ollama's KV cache warm.
+    ///
+    /// Clients are told apart by their `X-Session-Id` header, or by their IP address without it.
+    #[arg(long)]
+    pub affinity: bool,
+
+    /// Route follow-up turns of a chat to the backend that served the previous turn,
+    /// recognized by hashing the message history.
+    #[arg(long)]
+    pub conversation_routing: bool,
+
+    /// Number of conversations remembered by --conversation-routing.
+    #[arg(long, default_value_t = 1024)]
+    pub conversation_cache_size: usize,
+
+    /// Seconds a conversation stays pinned to its server without a new turn. 0 keeps the pins
+    /// until they are evicted by newer ones.
+    #[arg(long, default_value_t = 3600)]
+    pub conversation_ttl: u64,
+
+    /// Where to keep usage, health history and sessions: `memory`, `sqlite:<path>` or `redis://host:port`.
+    ///
+    /// SQLite and Redis require building with the `sqlite` and `redis` cargo features.
+    /// Several balancer instances can share their state through Redis.
+    #[arg(long, default_value = "memory")]
+    pub storage: String,
+
+    /// Interval in seconds between two writes of the state to the storage.
+    #[arg(long, default_value_t = 30)]
+    pub storage_flush_interval: u64,
+
+    /// Syntax is --gpu-domain NAME=ADDR,ADDR,... to declare servers sharing physical hardware,
+    /// e.g. two Ollama instances on one GPU box.
+    ///
+    /// Their connections and health count jointly, and a request is never raced on two of them.
+    #[arg(long)]
+    pub gpu_domain: Vec<GpuDomain>,
+
+    /// Send only one of several byte-identical /api/chat or /api/embed requests in flight to the backends,
+    /// and stream its response to every client that sent it.
+    #[arg(long)]
+    pub coalesce: bool,
+
+    /// Size in MiB of the cache of deterministic responses: /api/embed, and /api/chat with
+    /// a zero temperature or a fixed seed, kept apart per API key, or per client address with
+    /// --track-usage. 0 disables the cache.
+    #[arg(long, default_value_t = 0)]
+    pub cache_size_mb: usize,
+
+    /// Time in seconds a cached response is served for.
+    #[arg(long, default_value_t = 3600)]
+    pub cache_ttl: u64,
+
+    /// Syntax is --model-fallback MODEL=FALLBACK,FALLBACK,... Chat requests for a model no healthy
+    /// server hosts use the first fallback one hosts, noted in an `X-Model-Substituted` header.
+    #[arg(long)]
+    pub model_fallback: Vec<ModelFallback>,
+
+    /// Dispatch mode of /api/chat: parallel races the selected servers, single tries them
+    /// one after another, which spares the GPUs of small clusters, and hybrid only races them
+    /// when no selected server has the model loaded.
+    #[arg(long, default_value = "parallel", value_parser = clap::builder::PossibleValuesParser::new(["parallel", "single", "hybrid"]))]
+    pub mode: String,
+
+    /// When a race answers the client: buffered relays the fastest server once the measurement
+    /// window is over, immediate relays the first server to answer right away, tentatively: the
+    /// others stand by and take over should it fail before its first token, and are aborted once
+    /// it is relayed, which cuts the time to first token but can't switch servers afterwards.
+    #[arg(long, default_value = "buffered", value_parser = clap::builder::PossibleValuesParser::new(["buffered", "immediate"]))]
+    pub race_relay: String,
+
+    /// Server selection strategy: health, round-robin, least-connections or lowest-latency.
+    #[arg(long, default_value = "health", value_parser = clap::builder::PossibleValuesParser::new(crate::strategy::STRATEGIES))]
+    pub strategy: String,
+
+    /// Path to a JSON file with per-model settings, the first matching pattern wins:
+    /// `[{ "model": "llama3.3:70b", "timeout_ft": 120, "queue_timeout": 600 }, { "model": "*", "queue_timeout": 5 }]`
+    ///
+    /// `queue_timeout` is the longest time a chat request waits for an idle server before failing.
+    #[arg(long)]
+    pub model_profiles: Option<String>,
+
+    /// Path to a JSON file with per-backend settings, by server address or name:
+    /// `[{ "server": "gpu-1", "timeout_ft": 60, "timeout_idle": 30, "weight": 2.0, "max_concurrent": 4 }]`
+    ///
+    /// Reloaded on SIGHUP along with --model-profiles.
+    #[arg(long)]
+    pub backend_options: Option<String>,
+
+    /// Small model used to periodically probe every server hosting it with a 2-token generation.
+    /// The measured time to first token is kept apart from the one of production traffic,
+    /// a failed probe costs the server health like a failed request.
+    #[arg(long)]
+    pub probe_model: Option<String>,
+
+    /// Interval in seconds between two rounds of synthetic probes.
+    #[arg(long, default_value_t = 60)]
+    pub probe_interval: u64,
+
+    /// Turns of routing history kept per session, see /admin/sessions. 0 disables the audit trail.
+    ///
+    /// Sessions are told apart by the `X-Session-Id` header, or by the client IP without it.
+    #[arg(long, default_value_t = 0)]
+    pub audit_turns: usize,
+
+    /// Most sessions kept in the audit trail, the least recently active one is dropped first.
+    #[arg(long, default_value_t = 1024)]
+    pub audit_sessions: usize,
+
+    /// Echo the routing history of the session in an `X-Routing-Trail` response header.
+    #[arg(long)]
+    pub audit_header: bool,
+
+    /// Traces of requests kept in memory, see /admin/traces. 0 disables tracing.
+    ///
+    /// Whether a trace is kept is decided once the response body is over: failed, truncated
+    /// and slow requests always are, fast successes are sampled.
+    #[arg(long, default_value_t = 0)]
+    pub trace_buffer: usize,
+
+    /// Requests answered slower than this, in milliseconds, are always traced.
+    #[arg(long, default_value_t = 5000)]
+    pub trace_slow_ms: u64,
+
+    /// Share of the fast successful requests traced, from 0 to 1.
+    #[arg(long, default_value_t = 0.01)]
+    pub trace_sample: f64,
+
+    /// Syntax is --trace-sample-for ENDPOINT=RATE, overrides --trace-sample for one endpoint.
+    #[arg(long)]
+    pub trace_sample_for: Vec<EndpointSampleRate>,
+
+    /// URL the kept traces are posted to as JSON every few seconds, for a trace collector.
+    /// Requires --trace-buffer.
+    #[arg(long)]
+    pub trace_export: Option<String>,
+
+    /// Also report the backend that served a chat response as `lb_upstream` in its final chunk,
+    /// the one with `"done": true`, besides the `X-LB-Upstream` response header.
+    #[arg(long)]
+    pub upstream_in_body: bool,
+
+    /// Number of servers a request is sent to, as MIN:MAX. Servers with the model loaded
+    /// are preferred up to MAX, others are added until MIN is reached.
+    #[arg(long, default_value = "3:6")]
+    pub select_count: SelectCount,
+
+    /// Syntax is --select-count-for ENDPOINT=MIN:MAX, overrides --select-count for one endpoint.
+    ///
+    /// For example `--select-count-for /api/chat=1:2` keeps small clusters from racing every server.
+    #[arg(long)]
+    pub select_count_for: Vec<EndpointSelectCount>,
+
+    /// Probability that a request also tries to resurrect dead servers.
+    #[arg(long, default_value_t = 0.1)]
+    pub resurrect_p: f32,
+
+    /// Number of dead servers tried when resurrecting, taken out of the server count.
+    #[arg(long, default_value_t = 1)]
+    pub resurrect_n: usize,
+
+    /// Never send requests to servers that failed during streaming (Unreliable or SecondChanceGiven),
+    /// even if no other server is left. They are trusted again after a successful synthetic probe.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Most backends tried by a sequentially forwarded request, such as /api/show.
+    #[arg(long, default_value_t = 6)]
+    pub retry_max_attempts: usize,
+
+    /// Delay in milliseconds before the first retry, doubled on each further retry.
+    #[arg(long, default_value_t = 100)]
+    pub retry_base_delay_ms: u64,
+
+    /// Fraction of the retry delay randomly added or removed, between 0 and 1.
+    #[arg(long, default_value_t = 0.2)]
+    pub retry_jitter: f32,
+
+    /// Comma-separated backend statuses retried on another backend.
+    #[arg(long, value_delimiter = ',', default_value = "502,503,504")]
+    pub retry_on_status: Vec<u16>,
+
+    /// How a backend answering 429 Too Many Requests is treated: backoff selects it less often
+    /// for the Retry-After of the response without touching its health, failure like any failure.
+    #[arg(long = "backend-429", default_value = "backoff", value_parser = clap::builder::PossibleValuesParser::new(["backoff", "failure"]))]
+    pub backend_429: String,
+
+    /// Seconds a backend answering 429 without a Retry-After is backed off.
+    #[arg(long = "backend-429-backoff-secs", default_value_t = 5)]
+    pub backend_429_backoff_secs: u64,
+
+    /// Retries of the whole fleet may not exceed this fraction of the requests of the last minute,
+    /// so that failing over does not multiply the traffic on the backends left during an incident.
+    #[arg(long, default_value_t = 0.2)]
+    pub retry_budget: f32,
+
+    /// Retries always allowed per minute on top of --retry-budget, for quiet periods.
+    #[arg(long, default_value_t = 10)]
+    pub retry_budget_min: u64,
+
+    /// When servers that have the model but not loaded get selected: top-up only fills the selection
+    /// up to its minimum, wait never makes them load it while another server has it loaded, even a
+    /// busy one, and eager has idle ones load it as soon as every server having it loaded is busy.
+    #[arg(long, default_value = "top-up", value_parser = clap::builder::PossibleValuesParser::new(["top-up", "wait", "eager"]))]
+    pub cold_load: String,
+
+    /// Servers whose time to first token for a model is more than this many times the fastest
+    /// server's for the same model are selected last, see /admin/stats. 0 disables.
+    #[arg(long, default_value_t = 3.0)]
+    pub model_slow_factor: f32,
+
+    /// Health score of a freshly synced or resurrected server. Servers are selected in
+    /// proportion to their score. Must be positive.
+    #[arg(long, default_value_t = 1.0)]
+    pub health_initial: f32,
+
+    /// Added to the health of the server that won a race.
+    #[arg(long, default_value_t = 4.0)]
+    pub health_best_increment: f32,
+
+    /// Added to the health of the other servers that answered successfully.
+    #[arg(long, default_value_t = 2.0)]
+    pub health_increment: f32,
+
+    /// The health of a failed server is divided by this, it dies below --health-initial. Must
+    /// be greater than 1.
+    #[arg(long, default_value_t = 2.0)]
+    pub health_decay: f32,
+
+    /// Whether chat requests sync their candidates first: always, concurrent sends right away and
+    /// syncs meanwhile, and off never does, for low-latency deployments.
+    #[arg(long, default_value = "always", value_parser = clap::builder::PossibleValuesParser::new(["always", "concurrent", "off"]))]
+    pub presync: String,
+
+    /// Chat requests do not sync again the candidates synced less than this many seconds ago,
+    /// saving the round-trips to the backends before sending. 0 always syncs.
+    #[arg(long, default_value_t = 0)]
+    pub presync_ttl: u64,
+
+    /// Answer streamed chat requests right away and send an empty chunk every this many seconds
+    /// until the backend answers, for clients that time out while backends are selected.
+    /// Failures are then reported in the stream. 0 disables it.
+    #[arg(long, default_value_t = 0)]
+    pub heartbeat_secs: u64,
+
+    /// Compress responses with gzip or brotli for the clients that accept it.
+    /// Leave it off behind a proxy that compresses by itself.
+    #[arg(long)]
+    pub compress: bool,
+
+    /// Healthy servers required before /readyz reports ready.
+    #[arg(long, default_value_t = 1)]
+    pub ready_min_servers: usize,
+
+    /// Comma-separated models that must each be hosted by a healthy server before /readyz
+    /// reports ready, e.g. the main model of the deployment.
+    #[arg(long, value_delimiter = ',')]
+    pub ready_models: Vec<String>,
+
+    /// HTTP version spoken to the backends: auto uses HTTP/1.1, or HTTP/2 when negotiated over TLS,
+    /// http2 uses HTTP/2 with prior knowledge, multiplexing the parallel fan-out and concurrent clients
+    /// over one connection per backend, and http1 never uses HTTP/2.
+    #[arg(long, default_value = "auto", value_parser = clap::builder::PossibleValuesParser::new(["auto", "http1", "http2"]))]
+    pub backend_http: String,
+
+    /// Hosts, or HOST:PORT, the backends may redirect to, e.g. an intentional http to https
+    /// redirect. Other redirects are not followed: the backend is reported misconfigured
+    /// rather than the request going to an unexpected host.
+    #[arg(long, value_delimiter = ',')]
+    pub backend_redirect_allow: Vec<String>,
+
+    /// TLS implementation of the connections to the backends and other services: native uses the
+    /// platform library, e.g. a FIPS validated OpenSSL, rustls a pure Rust one. Either must be
+    /// compiled in with the `native-tls` or `rustls` feature. Defaults to the native one if built.
+    #[arg(long)]
+    pub tls_backend: Option<crate::tls::TlsBackend>,
+
+    /// Seconds a backend hostname that failed to resolve is remembered as such: its servers are
+    /// marked dead, and their requests fail right away meanwhile instead of each waiting for the
+    /// resolver. 0 disables the cache.
+    #[arg(long, default_value_t = 10)]
+    pub dns_negative_ttl: u64,
+
+    /// Replaces the keep_alive of every chat request: a duration like "10m", seconds, or "-1" to
+    /// keep models loaded. Model profiles can override it per model.
+    #[arg(long, allow_hyphen_values = true)]
+    pub keep_alive: Option<String>,
+
+    /// The keep_alive given to the model on the servers that lost a parallel race, e.g. "30s", so
+    /// that a model nobody asks for there again frees their memory early. By default their models
+    /// stay loaded as long as the race request asked.
+    #[arg(long, allow_hyphen_values = true)]
+    pub loser_keep_alive: Option<String>,
+
+    /// Chat requests with more messages than this lose their oldest ones, system messages and
+    /// the last message excepted. 0 disables the limit.
+    #[arg(long, default_value_t = 0)]
+    pub max_history_messages: usize,
+
+    /// Same as --max-history-messages, with the tokens of the history estimated from its length.
+    #[arg(long, default_value_t = 0)]
+    pub max_history_tokens: usize,
+
+    /// Syntax is --preload MODEL@SERVER, the server by address or name. Loads the model on the
+    /// server at startup, so that the first requests do not wait for it. Can be repeated.
+    #[arg(long)]
+    pub preload: Vec<Preload>,
+
+    /// How long preloaded models stay loaded without requests, as Ollama's keep_alive: a duration
+    /// like "2h", or "-1" for as long as the server runs.
+    #[arg(long, default_value = "-1", allow_hyphen_values = true)]
+    pub preload_keep_alive: String,
+
+    /// When fewer servers than the minimum selection host a requested model, pull it in the
+    /// background on idle healthy servers. The request goes to the servers that already have it.
+    /// Models no server hosts are never pulled.
+    #[arg(long)]
+    pub auto_pull: bool,
+
+    /// Model used by /api/chat, /api/embed and /api/show requests without a `model` field,
+    /// which are rejected otherwise.
+    #[arg(long)]
+    pub default_model: Option<String>,
+
+    /// Directory the load balancer caches model layers in, serving them to the backends as a
+    /// registry mirror under /v2/. Enables the mirror.
+    #[arg(long)]
+    pub registry_mirror_dir: Option<String>,
+
+    /// Registry the mirror pulls manifests and layers from.
+    #[arg(long, default_value = "https://registry.ollama.ai")]
+    pub registry_upstream: String,
+
+    /// HOST:PORT the backends reach the load balancer at. With it, /api/pull makes the backends
+    /// pull through the mirror instead of from the internet.
+    #[arg(long)]
+    pub registry_mirror_host: Option<String>,
+
+    /// Syntax is --model-concurrency MODEL=N. At most N chat requests for the model are relayed
+    /// at once across all servers, the others wait up to the queue_timeout of the model profile.
+    #[arg(long)]
+    pub model_concurrency: Vec<ModelLimit>,
+
+    /// Maximum inference requests (chat, embed, generate) in flight across all servers, until
+    /// their response is relayed. Beyond it, requests are answered 429 with Retry-After.
+    #[arg(long)]
+    pub max_inflight: Option<usize>,
+
+    /// Seconds to wait on shutdown for the streams in flight to finish, new requests being
+    /// refused meanwhile. The streams still running afterwards are cut.
+    #[arg(long, default_value_t = 30)]
+    pub shutdown_grace: u64,
+
+    /// Load balancer to redirect new requests to with `307` while draining on SIGTERM,
+    /// e.g. `http://10.0.0.2:11434`, instead of refusing them with `503`.
+    #[arg(long)]
+    pub drain_redirect: Option<String>,
+
+    /// URL the autoscaling signal is posted to as JSON whenever the desired server count changes,
+    /// for the automation starting and stopping GPU workers.
+    #[arg(long)]
+    pub autoscale_webhook: Option<String>,
+
+    /// Seconds the demand is averaged over by the autoscaling signal.
+    #[arg(long, default_value_t = 30)]
+    pub autoscale_window: u64,
+
+    /// Share of the healthy servers relaying a stream the autoscaling signal aims at.
+    #[arg(long, default_value_t = 0.7)]
+    pub autoscale_target_utilization: f64,
+
+    /// Time to first token in milliseconds servers should meet. When more than 10% of them miss it,
+    /// the autoscaling signal asks for one more server. 0 disables the SLO.
+    #[arg(long, default_value_t = 0)]
+    pub autoscale_slo_ttft_ms: u32,
+
+    /// Listening address, or unix:PATH to listen on a unix socket. Defaults to "0.0.0.0:11434"
     #[arg(short = 'l', long, default_value = "0.0.0.0:11434")]
     pub listen: String,
+
+    /// Bind the listening address with SO_REUSEPORT, so that the new process of an upgrade can
+    /// listen alongside this one. On SIGTERM this one keeps accepting the connections queued on
+    /// its socket until none came for 200ms, 2s at most, then stops listening and drains the
+    /// requests in flight, while the new one takes every new connection. The kernel still
+    /// resets a connection arriving in the instant the socket closes, which clients must retry.
+    #[arg(long)]
+    pub reuse_port: bool,
 }
@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::StreamExt;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::config::{DiscoverySource, ServerConfig};
+use crate::dns_sd::{browse_mdns, lookup_srv};
+use crate::runtime::RuntimeConfig;
+use crate::state::{add_server, remove_server, sync_server, SharedServerList};
+use crate::tls;
+
+/// Pause before listing again after a failed list or watch.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest wait for a DNS answer.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the answers to an mDNS browse are collected.
+const MDNS_WINDOW: Duration = Duration::from_secs(2);
+
+/// Browses an instance may be missing from before it is removed, multicast datagrams get lost.
+const MDNS_MISSES: u32 = 3;
+
+/// Seconds a watch request lasts before the API server ends it, and we list again.
+const WATCH_TIMEOUT_SECS: u64 = 300;
+
+/// Where the service account of a pod is mounted.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Applies the server lists found by a discovery source to the shared server list.
+/// A source only removes the servers it added, never those given on the command line.
+struct Reconciler {
+    source: String,
+    servers: SharedServerList,
+    runtime: Arc<RuntimeConfig>,
+    owned: HashSet<String>,
+}
+
+impl Reconciler {
+    fn apply(&mut self, found: Vec<ServerConfig>) {
+        let found_addrs = found.iter().map(|s| s.address.clone()).collect::<HashSet<_>>();
+        let gone = self.owned.difference(&found_addrs).cloned().collect::<Vec<_>>();
+        for addr in gone {
+            info!("Server {} left {}", addr, self.source);
+            remove_server(self.servers.clone(), &addr);
+            self.owned.remove(&addr);
+        }
+        for server in found {
+            if self.owned.contains(&server.address) || self.servers.lock().unwrap().contains_key(&server.address) {
+                continue;
+            }
+            info!("Server {} ({}) joined {}", server.address, server.name, self.source);
+            add_server(self.servers.clone(), &server);
+            self.owned.insert(server.address.clone());
+            tokio::spawn(sync_server(self.servers.clone(), server.address, self.runtime.sync_timeout, self.runtime.health.clone()));
+        }
+    }
+}
+
+/// Starts keeping the servers in sync with `source` in the background, in the returned task.
+pub fn spawn(
+    source: &DiscoverySource,
+    k8s_api_url: Option<&str>,
+    dns_refresh: Duration,
+    servers: SharedServerList,
+    runtime: Arc<RuntimeConfig>,
+) -> Result<tokio::task::JoinHandle<()>, Box<dyn std::error::Error>> {
+    let task = match source {
+        DiscoverySource::Kubernetes { namespace, service } => {
+            let api = KubeApi::new(k8s_api_url)?;
+            let reconciler = Reconciler {
+                source: format!("k8s:{}/{}", namespace, service),
+                servers,
+                runtime,
+                owned: HashSet::new(),
+            };
+            info!("Discovering servers from the endpoints of service {}/{} on {}", namespace, service, api.url);
+            tokio::spawn(watch_kubernetes(api, namespace.clone(), service.clone(), reconciler))
+        },
+        DiscoverySource::Dns { name, port, pool } => {
+            let reconciler = Reconciler {
+                source: match port {
+                    Some(port) => format!("dns://{}:{}", name, port),
+                    None => format!("dns+srv://{}", name),
+                },
+                servers,
+                runtime,
+                owned: HashSet::new(),
+            };
+            info!("Discovering servers of pool {} from {} every {}s", pool, reconciler.source, dns_refresh.as_secs());
+            tokio::spawn(refresh_dns(name.clone(), *port, pool.clone(), dns_refresh, reconciler))
+        },
+        DiscoverySource::Mdns { service } => {
+            let reconciler = Reconciler {
+                source: format!("mdns:{}", service),
+                servers,
+                runtime,
+                owned: HashSet::new(),
+            };
+            info!("Discovering servers announced as {} on the LAN every {}s", service, dns_refresh.as_secs());
+            tokio::spawn(browse_lan(service.clone(), dns_refresh, reconciler))
+        },
+    };
+    Ok(task)
+}
+
+/// The servers `name` resolves to: the hosts of its SRV records without a port,
+/// or else all its addresses on `port`.
+async fn resolve_dns(name: &str, port: Option<u16>, pool: &str, timeout: Duration) -> Result<Vec<ServerConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(port) = port else {
+        let records = lookup_srv(name, timeout).await?;
+        return Ok(records.into_iter().map(|r| {
+            let host = r.target.trim_end_matches('.').to_string();
+            let label = host.split('.').next().unwrap_or_default().to_string();
+            ServerConfig { address: format!("http://{}:{}", host, r.port), name: format!("{}-{}", pool, label) }
+        }).collect());
+    };
+    let addrs = tokio::time::timeout(timeout, tokio::net::lookup_host((name, port))).await
+        .map_err(|_| format!("Resolution of {} timed out", name))??;
+    let mut servers = addrs.map(|addr| ServerConfig {
+        address: format!("http://{}", addr),
+        name: format!("{}-{}", pool, addr.ip()),
+    }).collect::<Vec<_>>();
+    servers.sort_by(|a, b| a.address.cmp(&b.address));
+    servers.dedup_by(|a, b| a.address == b.address);
+    Ok(servers)
+}
+
+/// Resolves the servers every `interval`. A failed resolution keeps the known servers,
+/// a DNS outage should not empty the pool.
+async fn refresh_dns(name: String, port: Option<u16>, pool: String, interval: Duration, mut reconciler: Reconciler) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match resolve_dns(&name, port, &pool, DNS_TIMEOUT).await {
+            Ok(found) => reconciler.apply(found),
+            Err(e) => warn!("Failed to resolve the servers of {}, keeping {} known ones: {}", reconciler.source, reconciler.owned.len(), e),
+        }
+    }
+}
+
+/// Browses the instances of `service` every `interval`. An instance is only removed once it
+/// missed several browses in a row, a failed browse keeps them all.
+async fn browse_lan(service: String, interval: Duration, mut reconciler: Reconciler) {
+    let mut seen: HashMap<String, (ServerConfig, u32)> = HashMap::new();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let instances = match browse_mdns(&service, MDNS_WINDOW).await {
+            Ok(instances) => instances,
+            Err(e) => {
+                warn!("Failed to browse {}, keeping {} known servers: {}", service, reconciler.owned.len(), e);
+                continue;
+            },
+        };
+        seen.values_mut().for_each(|(_, misses)| *misses += 1);
+        for instance in instances {
+            let host = match instance.addrs.first() {
+                Some(std::net::IpAddr::V6(ip)) => format!("[{}]", ip),
+                Some(ip) => ip.to_string(),
+                // resolvable where nss-mdns is installed
+                None => instance.host.trim_end_matches('.').to_string(),
+            };
+            let label = instance.name.split('.').next().unwrap_or_default().to_string();
+            let server = ServerConfig { address: format!("http://{}:{}", host, instance.port), name: format!("mdns-{}", label) };
+            seen.insert(instance.name, (server, 0));
+        }
+        seen.retain(|_, (_, misses)| *misses < MDNS_MISSES);
+        reconciler.apply(seen.values().map(|(server, _)| server.clone()).collect());
+    }
+}
+
+/// Client of the Kubernetes API, authenticated with the service account of the pod in a cluster.
+struct KubeApi {
+    url: String,
+    http: reqwest::Client,
+    in_cluster: bool,
+}
+
+impl KubeApi {
+    fn new(url: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(url) = url {
+            return Ok(KubeApi { url: url.trim_end_matches('/').to_string(), http: tls::client(), in_cluster: false });
+        }
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| "Kubernetes discovery outside of a cluster requires --k8s-api-url")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let host = if host.contains(':') { format!("[{}]", host) } else { host };
+        #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+        return Err(format!("The Kubernetes API at {}:{} requires building with a TLS feature", host, port).into());
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        {
+            let ca = std::fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT_DIR))?;
+            let http = tls::client_builder()
+                .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+                .build()?;
+            Ok(KubeApi { url: format!("https://{}:{}", host, port), http, in_cluster: true })
+        }
+    }
+
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        let builder = self.http.get(format!("{}{}", self.url, path));
+        if !self.in_cluster {
+            return builder;
+        }
+        // projected tokens are rotated, read it every time
+        match std::fs::read_to_string(format!("{}/token", SERVICE_ACCOUNT_DIR)) {
+            Ok(token) => builder.bearer_auth(token.trim()),
+            Err(e) => {
+                warn!("Failed to read the service account token: {}", e);
+                builder
+            },
+        }
+    }
+}
+
+/// The ready endpoints of a Service, on its port named `http`, or else its first port.
+fn endpoint_servers(endpoints: &Value, service: &str) -> Vec<ServerConfig> {
+    let mut servers = Vec::new();
+    for subset in endpoints["subsets"].as_array().into_iter().flatten() {
+        let ports = subset["ports"].as_array().cloned().unwrap_or_default();
+        let port = ports.iter().find(|p| p["name"] == "http").or(ports.first()).and_then(|p| p["port"].as_u64());
+        let Some(port) = port else { continue };
+        for address in subset["addresses"].as_array().into_iter().flatten() {
+            let Some(ip) = address["ip"].as_str() else { continue };
+            let host = if ip.contains(':') { format!("[{}]", ip) } else { ip.to_string() };
+            let name = address["targetRef"]["name"].as_str().map_or_else(|| format!("{}-{}", service, ip), str::to_string);
+            servers.push(ServerConfig { address: format!("http://{}:{}", host, port), name });
+        }
+    }
+    servers
+}
+
+/// Lists the endpoints of the Service, then watches their changes, listing again whenever
+/// the watch ends or fails.
+async fn watch_kubernetes(api: KubeApi, namespace: String, service: String, mut reconciler: Reconciler) {
+    loop {
+        let listed = async {
+            let resp = api.get(&format!("/api/v1/namespaces/{}/endpoints/{}", namespace, service)).send().await?;
+            match resp.status() {
+                // a Service without any endpoint yet
+                reqwest::StatusCode::NOT_FOUND => Ok(Value::Null),
+                _ => resp.error_for_status()?.json::<Value>().await,
+            }
+        }.await;
+        let endpoints = match listed {
+            Ok(endpoints) => endpoints,
+            Err(e) => {
+                warn!("Failed to list the endpoints of {}/{}: {}", namespace, service, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            },
+        };
+        reconciler.apply(endpoint_servers(&endpoints, &service));
+        let version = endpoints["metadata"]["resourceVersion"].as_str().unwrap_or_default().to_string();
+        if let Err(e) = watch_endpoints(&api, &namespace, &service, &version, &mut reconciler).await {
+            warn!("Watch of the endpoints of {}/{} failed: {}", namespace, service, e);
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+}
+
+/// Applies the changes of the endpoints from `version` on, until the API server ends the watch.
+async fn watch_endpoints(
+    api: &KubeApi,
+    namespace: &str,
+    service: &str,
+    version: &str,
+    reconciler: &mut Reconciler,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = format!(
+        "/api/v1/namespaces/{}/endpoints?watch=true&fieldSelector=metadata.name%3D{}&resourceVersion={}&timeoutSeconds={}",
+        namespace, service, version, WATCH_TIMEOUT_SECS,
+    );
+    let resp = api.get(&path).send().await?.error_for_status()?;
+    let mut stream = resp.bytes_stream();
+    let mut line = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        line.extend_from_slice(&chunk?);
+        while let Some(pos) = line.iter().position(|b| *b == b'\n') {
+            let event = serde_json::from_slice::<Value>(&line.drain(..=pos).collect::<Vec<u8>>())?;
+            match event["type"].as_str() {
+                Some("ADDED") | Some("MODIFIED") => reconciler.apply(endpoint_servers(&event["object"], service)),
+                Some("DELETED") => reconciler.apply(Vec::new()),
+                // e.g. 410 Gone once the version is too old, list again
+                Some("ERROR") => return Err(event["object"]["message"].as_str().unwrap_or("watch error").to_string().into()),
+                _ => {},
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use tracing::{info, warn};
+
+use crate::state::{mark_unresolvable, SharedServerList};
+
+/// Resolves backend hostnames with the system resolver, remembering the failures for a while:
+/// a backend whose name stopped resolving is marked dead and fails right away instead of
+/// costing every request and every sync a fresh lookup timeout.
+#[derive(Clone)]
+pub struct NegativeCacheResolver {
+    ttl: Duration,
+    failures: Arc<Mutex<HashMap<String, (Instant, String)>>>,
+    servers: SharedServerList,
+}
+
+impl NegativeCacheResolver {
+    pub fn new(ttl: Duration, servers: SharedServerList) -> Self {
+        NegativeCacheResolver { ttl, failures: Arc::new(Mutex::new(HashMap::new())), servers }
+    }
+
+    /// The failure of `host` if it is still remembered.
+    fn cached_failure(&self, host: &str) -> Option<String> {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(host) {
+            Some((at, error)) if at.elapsed() < self.ttl => Some(format!(
+                "{} did not resolve {}s ago: {}", host, at.elapsed().as_secs(), error
+            )),
+            Some(_) => {
+                failures.remove(host);
+                None
+            },
+            None => None,
+        }
+    }
+}
+
+impl Resolve for NegativeCacheResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            if let Some(error) = resolver.cached_failure(&host) {
+                return Err(error.into());
+            }
+            let resolved = tokio::net::lookup_host((host.as_str(), 0)).await
+                .map(|addrs| addrs.collect::<Vec<SocketAddr>>());
+            match resolved {
+                Ok(addrs) => {
+                    if resolver.failures.lock().unwrap().remove(&host).is_some() {
+                        info!("Backend host {} resolves again", host);
+                    }
+                    Ok(Box::new(addrs.into_iter()) as Addrs)
+                },
+                Err(e) => {
+                    warn!("Failed to resolve backend host {}: {}, failing its requests for {}s", host, e, resolver.ttl.as_secs());
+                    mark_unresolvable(&resolver.servers, &host);
+                    resolver.failures.lock().unwrap().insert(host, (Instant::now(), e.to_string()));
+                    Err(e.into())
+                },
+            }
+        })
+    }
+}
@@ -0,0 +1,367 @@
+//! SRV lookups and mDNS browsing for the discovery of servers, the wire format left
+//! to `simple-dns`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use simple_dns::rdata::RData;
+use simple_dns::{Name, Packet, PacketFlag, Question, CLASS, TYPE};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A service record: a host and port serving the looked up service.
+#[derive(Debug, Clone)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Where mDNS responders listen, they answer queries from other ports by unicast.
+const MDNS_GROUP: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353));
+
+/// What a lookup needs from /etc/resolv.conf.
+#[derive(Debug, PartialEq)]
+struct ResolvConf {
+    nameservers: Vec<SocketAddr>,
+    /// Suffixes tried on names with fewer dots than `ndots`.
+    search: Vec<String>,
+    ndots: usize,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        ResolvConf { nameservers: vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53)], search: Vec::new(), ndots: 1 }
+    }
+}
+
+impl ResolvConf {
+    fn parse(conf: &str) -> Self {
+        let mut parsed = ResolvConf { nameservers: Vec::new(), ..Default::default() };
+        for line in conf.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => if let Some(ip) = words.next().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+                    parsed.nameservers.push(SocketAddr::new(ip, 53));
+                },
+                // the last of `domain` and `search` wins
+                Some("domain") | Some("search") => {
+                    parsed.search = words.map(|domain| domain.trim_end_matches('.').to_string()).collect();
+                },
+                Some("options") => for option in words {
+                    if let Some(ndots) = option.strip_prefix("ndots:").and_then(|n| n.parse().ok()) {
+                        parsed.ndots = ndots;
+                    }
+                },
+                _ => {},
+            }
+        }
+        if parsed.nameservers.is_empty() {
+            parsed.nameservers = ResolvConf::default().nameservers;
+        }
+        parsed
+    }
+
+    fn system() -> Self {
+        std::fs::read_to_string("/etc/resolv.conf").map(|conf| ResolvConf::parse(&conf)).unwrap_or_default()
+    }
+
+    /// The names to look up in turn for `name`, like the system resolver does.
+    fn candidates(&self, name: &str) -> Vec<String> {
+        if name.ends_with('.') {
+            return vec![name.trim_end_matches('.').to_string()];
+        }
+        let searched = self.search.iter().map(|domain| format!("{}.{}", name, domain));
+        if name.matches('.').count() >= self.ndots {
+            std::iter::once(name.to_string()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(name.to_string())).collect()
+        }
+    }
+}
+
+/// Reads a big-endian 16-bit header field, the id and flags being all a lookup needs
+/// before parsing the whole answer.
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+/// The SRV records of an answer, nothing if it is malformed.
+fn parse_srv_answer(msg: &[u8]) -> Vec<SrvRecord> {
+    let Ok(packet) = Packet::parse(msg) else {
+        return Vec::new();
+    };
+    packet.answers.iter().filter_map(|record| match &record.rdata {
+        RData::SRV(srv) => Some(SrvRecord {
+            priority: srv.priority,
+            weight: srv.weight,
+            port: srv.port,
+            target: srv.target.to_string(),
+        }),
+        _ => None,
+    }).collect()
+}
+
+/// A query with a single question of type `rtype` for `name`.
+fn encode_query(id: u16, recursive: bool, name: &str, rtype: TYPE) -> Result<Vec<u8>, String> {
+    let name = name.trim_end_matches('.');
+    if name.split('.').any(|label| label.is_empty() || label.len() > 63) {
+        return Err(format!("Invalid domain name {}", name));
+    }
+    let mut query = Packet::new_query(id);
+    if recursive {
+        query.set_flags(PacketFlag::RECURSION_DESIRED);
+    }
+    let qname = Name::new(name).map_err(|e| format!("Invalid domain name {}: {}", name, e))?;
+    query.questions.push(Question::new(qname, rtype.into(), CLASS::IN.into(), false));
+    query.build_bytes_vec().map_err(|e| format!("Cannot encode the query for {}: {}", name, e))
+}
+
+type LookupError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Sends `query` over UDP, and again over TCP when the answer did not fit in a datagram.
+async fn exchange(server: SocketAddr, query: &[u8], timeout: Duration) -> Result<Vec<u8>, LookupError> {
+    let id = read_u16(query, 0).unwrap_or_default();
+    let local: SocketAddr = if server.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+    let socket = tokio::net::UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut answer = vec![0u8; 4096];
+    let answer = tokio::time::timeout(timeout, async {
+        loop {
+            let len = socket.recv(&mut answer).await?;
+            // ignore stray datagrams
+            if read_u16(&answer[..len], 0) == Some(id) {
+                return Ok::<_, std::io::Error>(answer[..len].to_vec());
+            }
+        }
+    }).await.map_err(|_| format!("Query to {} timed out", server))??;
+    if answer.get(2).is_some_and(|flags| flags & 0x02 != 0) {
+        return exchange_tcp(server, query, timeout).await;
+    }
+    Ok(answer)
+}
+
+/// Sends `query` over TCP, each message prefixed with its length.
+async fn exchange_tcp(server: SocketAddr, query: &[u8], timeout: Duration) -> Result<Vec<u8>, LookupError> {
+    let exchange = async {
+        let mut stream = tokio::net::TcpStream::connect(server).await?;
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(query);
+        stream.write_all(&framed).await?;
+        let len = stream.read_u16().await? as usize;
+        let mut answer = vec![0u8; len];
+        stream.read_exact(&mut answer).await?;
+        Ok::<_, std::io::Error>(answer)
+    };
+    let answer = tokio::time::timeout(timeout, exchange).await
+        .map_err(|_| format!("Query to {} over TCP timed out", server))??;
+    if read_u16(&answer, 0) != read_u16(query, 0) {
+        return Err(format!("Answer from {} over TCP does not match the query", server).into());
+    }
+    Ok(answer)
+}
+
+/// Asks the nameservers in turn until one answers, `None` if the name does not exist.
+/// A nameserver that fails, times out or refuses leaves the question to the next one.
+async fn query_nameservers(nameservers: &[SocketAddr], name: &str, rtype: TYPE, timeout: Duration) -> Result<Option<Vec<u8>>, LookupError> {
+    let query = encode_query(rand::random::<u16>(), true, name, rtype)?;
+    let mut last_error = None;
+    for server in nameservers {
+        match exchange(*server, &query, timeout).await {
+            Ok(answer) => match answer.get(3).map(|flags| flags & 0x0F) {
+                Some(0) => return Ok(Some(answer)),
+                Some(3) => return Ok(None),
+                Some(rcode) => last_error = Some(format!("Lookup of {} on {} failed with code {}", name, server, rcode)),
+                None => last_error = Some(format!("Truncated answer for {} from {}", name, server)),
+            },
+            Err(e) => last_error = Some(format!("Lookup of {} on {} failed: {}", name, server, e)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| "No nameserver to ask".to_string()).into())
+}
+
+/// Looks up the SRV records of `name` with the nameservers and search domains of
+/// /etc/resolv.conf, the resolver of the standard library only knowing addresses.
+pub async fn lookup_srv(name: &str, timeout: Duration) -> Result<Vec<SrvRecord>, LookupError> {
+    let conf = ResolvConf::system();
+    let mut exists = false;
+    for candidate in conf.candidates(name) {
+        let Some(answer) = query_nameservers(&conf.nameservers, &candidate, TYPE::SRV, timeout).await? else {
+            continue;
+        };
+        let mut records = parse_srv_answer(&answer);
+        if records.is_empty() {
+            exists = true;
+            continue;
+        }
+        // the preferred records first
+        records.sort_by_key(|r| (r.priority, std::cmp::Reverse(r.weight)));
+        return Ok(records);
+    }
+    if exists {
+        Ok(Vec::new())
+    } else {
+        Err(format!("{} does not exist", name).into())
+    }
+}
+
+/// An instance of a service announced over mDNS.
+#[derive(Debug, Clone)]
+pub struct MdnsInstance {
+    /// e.g. `gpu-box._ollama._tcp.local`
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<std::net::IpAddr>,
+}
+
+/// Browses the instances of `service`, e.g. `_ollama._tcp.local`, collecting the answers of every
+/// responder on the LAN for `window`. Instances without an SRV record in the answers are skipped.
+pub async fn browse_mdns(service: &str, window: Duration) -> Result<Vec<MdnsInstance>, Box<dyn std::error::Error + Send + Sync>> {
+    let query = encode_query(0, false, service, TYPE::PTR)?;
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+    socket.send_to(&query, MDNS_GROUP).await?;
+
+    let service = service.trim_end_matches('.').to_lowercase();
+    let mut names = Vec::new();
+    let mut targets = HashMap::new();
+    let mut hosts: HashMap<String, Vec<std::net::IpAddr>> = HashMap::new();
+    let deadline = tokio::time::Instant::now() + window;
+    let mut buf = vec![0u8; 9000];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, _) = received?;
+        // malformed messages and queries are skipped
+        let Ok(packet) = Packet::parse(&buf[..len]) else {
+            continue;
+        };
+        if !packet.has_flags(PacketFlag::RESPONSE) {
+            continue;
+        }
+        // answers and additional records alike
+        for record in packet.answers.iter().chain(&packet.name_servers).chain(&packet.additional_records) {
+            let name = record.name.to_string().to_lowercase();
+            match &record.rdata {
+                RData::PTR(instance) if name == service => names.push(instance.0.to_string().to_lowercase()),
+                RData::SRV(srv) => {
+                    targets.insert(name, (srv.target.to_string().to_lowercase(), srv.port));
+                },
+                RData::A(a) => hosts.entry(name).or_default().push(Ipv4Addr::from(a.address).into()),
+                RData::AAAA(aaaa) => hosts.entry(name).or_default().push(Ipv6Addr::from(aaaa.address).into()),
+                _ => {},
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names.into_iter().filter_map(|name| {
+        let (host, port) = targets.get(&name)?.clone();
+        let mut addrs = hosts.get(&host).cloned().unwrap_or_default();
+        // IPv4 first, link-local IPv6 addresses would need a scope
+        addrs.sort_by_key(|addr| addr.is_ipv6());
+        addrs.dedup();
+        Some(MdnsInstance { name, host, port, addrs })
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use simple_dns::rdata::SRV;
+    use simple_dns::ResourceRecord;
+    use super::*;
+
+    /// An answer to `query` carrying an SRV record per (port, target).
+    fn srv_answer(query: &[u8], records: &[(u16, &str)], truncated: bool) -> Vec<u8> {
+        let mut answer = Packet::parse(query).unwrap().into_reply();
+        answer.set_flags(PacketFlag::RECURSION_AVAILABLE);
+        if truncated {
+            answer.set_flags(PacketFlag::TRUNCATION);
+        }
+        let qname = answer.questions[0].qname.clone();
+        for (port, target) in records {
+            let srv = SRV { priority: 10, weight: 5, port: *port, target: Name::new_unchecked(target) };
+            answer.answers.push(ResourceRecord::new(qname.clone(), CLASS::IN, 60, RData::SRV(srv)));
+        }
+        answer.build_bytes_vec().unwrap()
+    }
+
+    #[test]
+    fn resolv_conf_keeps_every_nameserver_and_the_last_search_list() {
+        let conf = ResolvConf::parse("# comment\nnameserver 10.0.0.2\ndomain corp\nsearch svc.cluster.local cluster.local.\nnameserver ::1\noptions ndots:5 timeout:2\nnameserver bogus\n");
+        assert_eq!(conf.nameservers, vec!["10.0.0.2:53".parse().unwrap(), "[::1]:53".parse().unwrap()]);
+        assert_eq!(conf.search, vec!["svc.cluster.local", "cluster.local"]);
+        assert_eq!(conf.ndots, 5);
+        assert_eq!(ResolvConf::parse("search lan\n").nameservers, ResolvConf::default().nameservers);
+    }
+
+    #[test]
+    fn search_domains_come_first_for_short_names() {
+        let conf = ResolvConf { search: vec!["lan".to_string()], ndots: 2, ..Default::default() };
+        assert_eq!(conf.candidates("_ollama._tcp"), vec!["_ollama._tcp.lan", "_ollama._tcp"]);
+        assert_eq!(conf.candidates("_ollama._tcp.example.com"), vec!["_ollama._tcp.example.com", "_ollama._tcp.example.com.lan"]);
+        assert_eq!(conf.candidates("_ollama._tcp.example.com."), vec!["_ollama._tcp.example.com"]);
+    }
+
+    #[test]
+    fn srv_records_are_parsed_from_an_answer() {
+        let query = encode_query(7, true, "_ollama._tcp.example.com", TYPE::SRV).unwrap();
+        let records = parse_srv_answer(&srv_answer(&query, &[(11434, "gpu-1.example.com"), (11435, "gpu-2.example.com")], false));
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].priority, records[0].weight, records[0].port), (10, 5, 11434));
+        assert_eq!(records[1].target, "gpu-2.example.com");
+        assert!(encode_query(7, true, "bad..name", TYPE::SRV).is_err());
+    }
+
+    #[test]
+    fn looping_name_pointers_are_refused() {
+        // one answer whose name points at itself
+        let msg = [0u8, 0, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0, 0xC0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 0];
+        assert!(parse_srv_answer(&msg).is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_asked_again_over_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let udp = tokio::net::UdpSocket::bind(server).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+            udp.send_to(&srv_answer(&buf[..len], &[], true), from).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap() as usize;
+            let mut query = vec![0u8; len];
+            stream.read_exact(&mut query).await.unwrap();
+            let answer = srv_answer(&query, &[(11434, "gpu-1.example.com")], false);
+            stream.write_all(&(answer.len() as u16).to_be_bytes()).await.unwrap();
+            stream.write_all(&answer).await.unwrap();
+        });
+        let answer = query_nameservers(&[server], "_ollama._tcp.example.com", TYPE::SRV, Duration::from_secs(2)).await.unwrap();
+        assert_eq!(parse_srv_answer(&answer.unwrap())[0].target, "gpu-1.example.com");
+    }
+
+    #[tokio::test]
+    async fn failing_nameservers_leave_the_question_to_the_next_one() {
+        let refusing = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let answering = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let servers = [refusing.local_addr().unwrap(), answering.local_addr().unwrap()];
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, from) = refusing.recv_from(&mut buf).await.unwrap();
+            let mut answer = srv_answer(&buf[..len], &[], false);
+            answer[3] |= 5; // REFUSED
+            refusing.send_to(&answer, from).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, from) = answering.recv_from(&mut buf).await.unwrap();
+            let mut answer = srv_answer(&buf[..len], &[], false);
+            answer[3] |= 3; // NXDOMAIN
+            answering.send_to(&answer, from).await.unwrap();
+        });
+        let answer = query_nameservers(&servers, "_ollama._tcp.example.com", TYPE::SRV, Duration::from_secs(2)).await.unwrap();
+        assert!(answer.is_none());
+    }
+}
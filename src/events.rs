@@ -0,0 +1,54 @@
+use bytes::Bytes;
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// Events buffered for each subscriber, slow ones skip what they missed.
+const EVENT_BUFFER: usize = 256;
+
+/// Fans out events to the clients of `/admin/events`, as NDJSON.
+/// Publishing without any subscriber costs next to nothing.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<Bytes>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus { sender: broadcast::channel(EVENT_BUFFER).0 }
+    }
+}
+
+impl EventBus {
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, event: &impl Serialize) {
+        if !self.has_subscribers() {
+            return;
+        }
+        match serde_json::to_vec(event) {
+            Ok(mut line) => {
+                line.push(b'\n');
+                // a subscriber leaving in between is not an error
+                let _ = self.sender.send(Bytes::from(line));
+            },
+            Err(e) => warn!("Failed to serialize event: {}", e),
+        }
+    }
+
+    /// Every event published from now on, one JSON document per line.
+    pub fn subscribe(&self) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+        stream::unfold(self.sender.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(line) => return Some((Ok(line), receiver)),
+                    Err(RecvError::Lagged(n)) => warn!("Events subscriber lagging, skipped {} events", n),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,62 @@
+use serde_json::Value;
+
+/// Request features that older Ollama versions do not understand.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    /// `tools` definitions for function calling, since Ollama 0.3.0
+    Tools,
+    /// `format` given as a JSON schema object, since Ollama 0.5.0
+    StructuredOutputs,
+}
+
+impl Feature {
+    pub fn min_version(&self) -> (u32, u32, u32) {
+        match self {
+            Feature::Tools => (0, 3, 0),
+            Feature::StructuredOutputs => (0, 5, 0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Tools => "tools",
+            Feature::StructuredOutputs => "structured outputs",
+        }
+    }
+}
+
+/// Parses versions like `0.5.7` or `0.6.0-rc1` into a comparable triple.
+pub fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let core = version.trim().trim_start_matches('v').split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|p| p.parse::<u32>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let patch = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Servers with an unknown version are given the benefit of the doubt.
+pub fn supports(version: Option<&str>, feature: Feature) -> bool {
+    match version.and_then(parse_version) {
+        Some(v) => v >= feature.min_version(),
+        None => true,
+    }
+}
+
+/// Rewrites a request body for a server running `version`.
+///
+/// Returns `Ok(None)` if the body can be sent as is, `Ok(Some(body))` if it was translated
+/// to something the server understands, and `Err(feature)` if the server can't serve it at all.
+pub fn adapt_body(body: &Value, version: Option<&str>) -> Result<Option<Value>, Feature> {
+    let mut adapted = None;
+    if body.get("tools").is_some_and(|tools| !tools.is_null()) && !supports(version, Feature::Tools) {
+        return Err(Feature::Tools);
+    }
+    if body.get("format").is_some_and(Value::is_object) && !supports(version, Feature::StructuredOutputs) {
+        // older servers only know about plain JSON mode, the schema is dropped
+        let mut body = body.clone();
+        body["format"] = Value::from("json");
+        adapted = Some(body);
+    }
+    Ok(adapted)
+}
@@ -1,10 +1,42 @@
 use crate::state::{
-    mark_server_more_healthy, mark_server_less_healthy,
-    print_server_statuses, select_servers, snapshot_servers, sync_server,
-    FailureRecord, SelOpt, SharedServerList
+    mark_server_more_healthy, mark_server_less_healthy, mark_server_busy, mark_server_misconfigured, mark_if_misconfigured,
+    can_serve, conversation_hashes, has_healthy_server, notify_server_released, rank_by_affinity, wait_for_idle_server, record_latency, request_status_report, select_servers, select_servers_excluding, server_versions, snapshot_servers, sync_all, sync_server,
+    add_server, begin_dispatch, begin_queue, find_server, find_server_at, has_model_loaded, is_server_ip, healthiest_server, is_freshly_synced, is_shutting_down, readiness_gaps, remove_server, set_leaving, expire_leaving, explain_selection, FailureRecord, Health, ModelConfig, ServerSnapshot, SharedConversationMap, SharedServerList, RACE_STATS
 };
-use crate::backend::{UnpackedRequest, ReqOpt, send_request_monitored, send_request};
-use hyper::{Body, Request, Response, StatusCode};
+use crate::backend::{UnpackedRequest, PerformanceInfo, RepackedResponse, ReqOpt, RequestBody, RetryPolicy, Standby, TimeoutProfile, check_ollama_response, idle_limited, retry_after, send_request_monitored, send_request, tentative};
+use crate::auth::{scope_of, AuthChain};
+use crate::authz::{AuthzDecision, Authorizer};
+use crate::features::adapt_body;
+use crate::strategy::SelectionStrategy;
+use crate::profiles::{GenerationGuard, ProfileStore};
+use crate::config::{AuthScope, ServerConfig};
+use crate::runtime::{Overload, Presync, RuntimeConfig};
+use crate::redact::redact_json;
+use crate::schema::{admin_schema, conversations_report, exclusion_reports, sessions_report, state_report, CandidateReport, Event, ExplainReport, Outcome, RaceEvent, SelectionEvent, TraceReport, SCHEMA_VERSION};
+use crate::events::EventBus;
+use crate::registry::{Registered, Registry};
+use crate::membership;
+use crate::autoscale::Autoscaler;
+use crate::heatmap;
+#[cfg(feature = "metrics")]
+use crate::{metrics, state::streams_in_flight};
+use crate::stats;
+use crate::upstream::{AnnotatedBody, Upstream};
+use crate::relay::{Delivered, Received, RelayTally};
+use crate::cache::{cache_key, is_deterministic, ResponseCache};
+use crate::pull::{handle_pull, AutoPull};
+use crate::benchmark::{run_benchmark, BenchmarkRequest};
+use crate::mirror::{handle_registry, RegistryMirror};
+use crate::audit::{trail_header, RoutingRecord, SharedAuditTrail};
+use crate::traces::{trace_id, TracedBody, Tracer};
+use crate::coalesce::{fingerprint, Coalescer, Role};
+use crate::accounting::{Accounting, MeteredBody, mask_key};
+use crate::compression::{compress, negotiate};
+use crate::heartbeat::with_heartbeat;
+use crate::concurrency::{InflightBody, InflightLimit, ModelConcurrency};
+use tokio::sync::OwnedSemaphorePermit;
+use crate::body::{self, Body};
+use hyper::{header, Request, Response, StatusCode};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::convert::Infallible;
@@ -12,33 +44,34 @@ use reqwest;
 use futures_util::stream::StreamExt;
 use futures_util::Stream;
 use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::sync::atomic::Ordering;
 use std::task::{Context, Poll};
 use futures_util::future;
-use hyper::body;
 use tokio;
 use serde_json::json;
 use tracing::{info, warn, error};
 
-/// Required because two different versions of crate `http` are being used
-/// reqwest is a new version, hyper is an old version and the new API is completely
-/// different so for now I chose to stay with the old version of hyper.
-pub fn hyper_method_to_reqwest_method(method: hyper::Method) -> Result<reqwest::Method, Box<dyn std::error::Error>> {
-    return Ok(method.as_str().parse::<reqwest::Method>()?);
+/// Like `unpack_req`, but leaves the body as it arrives, for requests that only ever go
+/// to one backend: uploads of several gigabytes are never held in memory.
+fn unpack_req_streamed(req: Request<Body>) -> UnpackedRequest {
+    let uri = req.uri().to_string();
+    let req_method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let headers = req.headers().clone();
+    (uri, req_method, path, Some(headers), Some(RequestBody::stream(req.into_body())))
 }
 
-async fn unpack_req(mut req: Request<Body>) -> Result<UnpackedRequest, Box<dyn std::error::Error>> {
+async fn unpack_req(req: Request<Body>) -> Result<UnpackedRequest, Box<dyn std::error::Error>> {
     let uri = req.uri().to_string();
-    let whole_body = body::to_bytes(req.body_mut()).await.unwrap_or_default();
-    let req_method = match hyper_method_to_reqwest_method(req.method().clone()) {
-        Ok(m) => m,
-        Err(e) => {
-            return Err(e.into());
-        }
-    };
+    let req_method = req.method().clone();
     let path = req.uri().path().to_string();
-    let headers = req.headers().clone();
+    let mut headers = req.headers().clone();
+    // the balancer reads the responses, reqwest negotiates the encodings it can decode
+    headers.remove(header::ACCEPT_ENCODING);
+    let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
 
-    Ok((uri, req_method, path, Some(headers), Some(whole_body)))
+    Ok((uri, req_method, path, Some(headers), Some(whole_body.into())))
 }
 
 fn parse_body(body: &bytes::Bytes) -> Result<Value, Box<dyn std::error::Error>> {
@@ -60,30 +93,849 @@ fn make_json_resp(
         .unwrap()
 }
 
+/// How /api/chat uses the selected servers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DispatchMode {
+    /// Race them all, the fastest one answers.
+    Parallel,
+    /// Try them one after another, the next one only if the previous one failed.
+    Single,
+    /// Like single when the first one has the model loaded, so warm models are never computed
+    /// twice, and race them when the model is cold everywhere.
+    Hybrid,
+}
+
+/// When a race answers the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RaceRelay {
+    /// Once the measurement window is over, with the fastest server.
+    Buffered,
+    /// As soon as a server sends its first token, tentatively: the others stand by until it
+    /// commits, to take over should it fail before.
+    Immediate,
+}
+
+/// Options that shape how the load balancer answers its clients.
+#[derive(Clone, Debug)]
+pub struct DispatchOpt {
+    pub req: ReqOpt,
+    pub annotate_availability: bool,
+    pub annotate_servers: bool,
+    pub open_admin: bool,
+    pub compress: bool,
+    pub auth: Option<Arc<AuthChain>>,
+    pub authz: Option<Arc<Authorizer>>,
+    pub accounting: Option<Arc<Accounting>>,
+    pub affinity: bool,
+    pub mode: DispatchMode,
+    pub race_relay: RaceRelay,
+    pub conversations: Option<SharedConversationMap>,
+    pub strategy: Arc<dyn SelectionStrategy>,
+    pub runtime: Arc<RuntimeConfig>,
+    pub profiles: Arc<ProfileStore>,
+    pub audit: Option<SharedAuditTrail>,
+    pub audit_header: bool,
+    pub upstream_in_body: bool, // echo the upstream in the final chunk too
+    pub coalescer: Option<Arc<Coalescer>>,
+    pub fallbacks: Arc<HashMap<String, Vec<String>>>, // model -> models to use when no healthy server hosts it
+    pub cache: Option<Arc<ResponseCache>>,
+    pub events: EventBus,
+    pub retry: RetryPolicy,
+    pub mirror: Option<Arc<RegistryMirror>>,
+    pub default_model: Option<String>, // used by requests without a `model` field
+    pub auto_pull: Option<Arc<AutoPull>>,
+    pub concurrency: Arc<ModelConcurrency>,
+    pub inflight: Arc<InflightLimit>,
+    pub started: std::time::Instant,
+    pub drain_redirect: Option<String>, // peer the new requests go to while draining
+    pub registry: Option<Arc<Registry>>,
+    pub autoscaler: Arc<Autoscaler>,
+    pub tracer: Option<Arc<Tracer>>,
+}
+
+/// Retry-After of the requests turned away by `--max-inflight`, streams last seconds.
+const INFLIGHT_RETRY_AFTER_SECS: u64 = 1;
+
+fn make_unauthorized_resp() -> Response<Body> {
+    let mut resp = make_json_resp(StatusCode::UNAUTHORIZED, json!({ "error": "Missing or invalid credentials" }));
+    resp.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    resp
+}
+
+fn make_rate_limited_resp(retry_after: std::time::Duration) -> Response<Body> {
+    let mut resp = make_json_resp(StatusCode::TOO_MANY_REQUESTS, json!({ "error": "Rate limit or quota exceeded" }));
+    // round up so clients never retry too early
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    resp.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+    resp
+}
+
+/// The model a request is about, from its `model` or `name` field. Uploads are not read.
+async fn request_model(req: Request<Body>) -> Result<(Request<Body>, Option<String>), Box<dyn std::error::Error + Send + Sync>> {
+    if req.method() != hyper::Method::POST || req.uri().path().starts_with("/api/blobs/") {
+        return Ok((req, None));
+    }
+    let (parts, body) = req.into_parts();
+    let whole_body = body::to_bytes(body).await?;
+    let model = parse_body(&whole_body).ok().and_then(|body| {
+        ["model", "name"].iter().find_map(|field| body[field].as_str().filter(|m| !m.is_empty()).map(str::to_string))
+    });
+    Ok((Request::from_parts(parts, Body::from(whole_body)), model))
+}
+
+/// Fills in `model` for requests that name none, leaving anything else untouched.
+async fn with_default_model(req: Request<Body>, default_model: &str) -> Request<Body> {
+    let (mut parts, body) = req.into_parts();
+    let whole_body = body::to_bytes(body).await.unwrap_or_default();
+    let mut body = match parse_body(&whole_body) {
+        Ok(body) if body.is_object() => body,
+        _ => return Request::from_parts(parts, Body::from(whole_body)),
+    };
+    let named = ["model", "name"].iter().any(|field| body[field].as_str().is_some_and(|m| !m.is_empty()));
+    if named {
+        return Request::from_parts(parts, Body::from(whole_body));
+    }
+    info!("Request for {} names no model, using {}", parts.uri.path(), default_model);
+    body["model"] = json!(default_model);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Request::from_parts(parts, Body::from(body.to_string()))
+}
+
 pub async fn dispatch(
     req: Request<Body>,
     servers: SharedServerList,
     remote_addr: std::net::SocketAddr,
-    opts: ReqOpt,
+    dopts: DispatchOpt,
 ) -> Result<Response<Body>, Infallible> {
+    let Some(tracer) = dopts.tracer.clone() else {
+        return route(req, servers, remote_addr, dopts).await;
+    };
+    let started = std::time::Instant::now();
+    let mut trace = TraceReport {
+        trace_id: trace_id(req.headers()),
+        at: chrono::Utc::now().to_rfc3339(),
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        status: 0,
+        duration_ms: 0,
+        total_ms: 0,
+        outcome: String::new(),
+        upstream: None,
+        reason: String::new(),
+    };
+    let resp = route(req, servers, remote_addr, dopts).await?;
+    trace.status = resp.status().as_u16();
+    trace.duration_ms = started.elapsed().as_millis() as u64;
+    trace.upstream = resp.headers().get("X-LB-Upstream")
+        .and_then(|v| v.to_str().ok())
+        .and_then(Upstream::parse)
+        .map(|upstream| upstream.server);
+    // kept or dropped once the body is over, a stream may still break off
+    let (parts, body) = resp.into_parts();
+    let tally = parts.extensions.get::<Arc<RelayTally>>().cloned();
+    Ok(Response::from_parts(parts, Body::new(TracedBody::new(body, tally, tracer, trace, started))))
+}
+
+async fn route(
+    mut req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    dopts: DispatchOpt,
+) -> Result<Response<Body>, Infallible> {
+    let _dispatching = begin_dispatch();
     let path = req.uri().path().to_string();
     let remote = remote_addr.to_string();
     let method = req.method().to_string();
+    if is_shutting_down() && path != "/" && path != "/healthz" {
+        if let Some(peer) = &dopts.drain_redirect {
+            let path_and_query = req.uri().path_and_query().map_or(path.as_str(), |pq| pq.as_str());
+            let location = format!("{}{}", peer.trim_end_matches('/'), path_and_query);
+            info!("{} - {} {} - redirected to {}: draining", remote, method, path, location);
+            // 307 keeps the method and the body
+            return Ok(Response::builder()
+                .status(StatusCode::TEMPORARY_REDIRECT)
+                .header(header::LOCATION, location)
+                .header(header::CONNECTION, "close")
+                .body(Body::empty())
+                .unwrap());
+        }
+        warn!("{} - {} {} - rejected: shutting down", remote, method, path);
+        let mut resp = make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": "The load balancer is shutting down" }));
+        resp.headers_mut().insert(header::CONNECTION, header::HeaderValue::from_static("close"));
+        return Ok(resp);
+    }
+    let encoding = negotiate(req.headers()).filter(|_| dopts.compress && req.method() != hyper::Method::HEAD);
+    // forcing a backend bypasses the selection and its health checks, an operator's privilege
+    if req.headers().contains_key("x-lb-backend") && !admin_privileged(&dopts, req.headers(), remote_addr).await {
+        warn!("{} - {} {} - rejected: X-LB-Backend without admin credentials", remote, method, path);
+        return Ok(make_json_resp(StatusCode::FORBIDDEN, json!({ "error": "X-LB-Backend requires admin credentials" })));
+    }
+    // adding, removing or draining backends must not be open to whoever reaches the port
+    if scope_of(&path) == Some(AuthScope::Admin) && admin_open(&dopts) && !dopts.open_admin {
+        warn!("{} - {} {} - rejected: no authentication provider covers the admin endpoints", remote, method, path);
+        return Ok(make_json_resp(StatusCode::FORBIDDEN, json!({
+            "error": "Admin endpoints require admin credentials, configure an authentication provider scoped to admin or pass --open-admin"
+        })));
+    }
+    let mut client_key = None;
+    // what the authorization service gets, never the API key itself
+    let mut authz_identity = None;
+    if let Some(auth) = &dopts.auth {
+        let identity = match auth.authenticate(&path, req.headers(), remote_addr).await {
+            Ok(identity) => identity,
+            Err(()) => {
+                warn!("{} - {} {} - rejected: missing or invalid credentials", remote, method, path);
+                return Ok(make_unauthorized_resp());
+            }
+        };
+        if let Some(identity) = identity {
+            if let Some(accounting) = &dopts.accounting {
+                if let Err(retry_after) = accounting.admit(&identity.id, identity.secret) {
+                    let client = if identity.secret { mask_key(&identity.id) } else { identity.id.clone() };
+                    warn!("{} - {} {} - rejected: client {} is over its limits", remote, method, path, client);
+                    return Ok(make_rate_limited_resp(retry_after));
+                }
+            }
+            // the credentials are meant for the load balancer, do not leak them to the backends
+            req.headers_mut().remove(header::AUTHORIZATION);
+            authz_identity = Some(identity.public_id());
+            client_key = Some(identity.id);
+        }
+    }
+    if let Some(accounting) = dopts.accounting.as_ref().filter(|_| client_key.is_none() && scope_of(&path) == Some(AuthScope::Api)) {
+        let client = format!("ip:{}", remote_addr.ip());
+        accounting.count(&client);
+        authz_identity = Some(client.clone());
+        client_key = Some(client);
+    }
+    // only the requests running a model weigh on the backends
+    let inflight = if matches!(path.as_str(), "/api/chat" | "/api/embed" | "/api/generate") {
+        match dopts.inflight.try_acquire() {
+            Some(guard) => Some(guard),
+            None => {
+                warn!("{} - {} {} - rejected: {} requests in flight", remote, method, path, dopts.inflight.current());
+                let mut resp = make_json_resp(StatusCode::TOO_MANY_REQUESTS, json!({ "error": "Too many requests in flight, retry later" }));
+                resp.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(INFLIGHT_RETRY_AFTER_SECS));
+                return Ok(resp);
+            },
+        }
+    } else {
+        None
+    };
+    if let Some(default_model) = &dopts.default_model {
+        if matches!(path.as_str(), "/api/chat" | "/api/embed" | "/api/show") {
+            req = with_default_model(req, default_model).await;
+        }
+    }
+    if let Some(authz) = dopts.authz.as_ref().filter(|_| scope_of(&path).is_some()) {
+        let (checked, model) = match request_model(req).await {
+            Ok(checked) => checked,
+            Err(e) => {
+                warn!("{} - {} {} - rejected: failed to read the body: {}", remote, method, path, e);
+                return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": format!("Error reading request body: {}", e) })));
+            },
+        };
+        req = checked;
+        match authz.authorize(&method, &path, model.as_deref(), authz_identity.as_deref(), remote_addr).await {
+            AuthzDecision::Allowed(headers) => {
+                for (name, value) in headers.iter() {
+                    req.headers_mut().insert(name, value.clone());
+                }
+            },
+            AuthzDecision::Denied(reason) => {
+                warn!("{} - {} {} - rejected by the authorization service: {}", remote, method, path, reason);
+                return Ok(make_json_resp(StatusCode::FORBIDDEN, json!({ "error": reason })));
+            },
+        }
+    }
     let response = match path.as_str() {
-        "/" => Ok(Response::builder()
+        "/" => Ok(handle_root(&req, servers, dopts.started)),
+        "/api/tags" => handle_tags(req, servers, remote_addr, dopts.annotate_availability, dopts.annotate_servers).await,
+        "/api/ps" => handle_ps(req, servers, remote_addr, dopts.annotate_availability, dopts.annotate_servers).await,
+        "/api/show" => handle_request_ha(req, servers, remote_addr, dopts.clone()).await,
+        "/api/pull" => handle_pull(req, servers, remote_addr, &dopts.runtime, dopts.mirror.as_deref()).await,
+        "/api/generate" => handle_generate(req, servers, remote_addr).await,
+        _ if path.starts_with("/api/blobs/") => handle_blobs(req, servers, remote_addr, dopts.runtime.timeouts.pull).await,
+        "/api/create" => handle_create(req, servers, remote_addr, dopts.runtime.timeouts.pull).await,
+        "/api/chat" if dopts.runtime.heartbeat.is_some() => {
+            let (interval, dopts, tenant) = (dopts.runtime.heartbeat.unwrap(), dopts.clone(), authz_identity.clone());
+            with_heartbeat(req, interval, move |req| handle_model_request(req, servers, remote_addr, dopts, tenant)).await
+        },
+        "/api/chat" | "/api/embed" => handle_model_request(req, servers, remote_addr, dopts.clone(), authz_identity.clone()).await,
+        // liveness of the balancer itself, whatever the state of the backends
+        "/healthz" => Ok(make_json_resp(StatusCode::OK, json!({ "status": "alive" }))),
+        "/readyz" => Ok(handle_readyz(servers, &dopts.runtime)),
+        "/admin/state" => Ok(make_json_resp(StatusCode::OK, json!(state_report(&servers, dopts.runtime.selection.default.strict, &dopts.profiles)))),
+        "/admin/sessions" => Ok(match &dopts.audit {
+            Some(audit) => make_json_resp(StatusCode::OK, json!(sessions_report(&audit.lock().unwrap()))),
+            None => make_json_resp(StatusCode::NOT_FOUND, json!({ "error": "Routing audit trail is disabled, see --audit-turns" })),
+        }),
+        "/admin/conversations" => Ok(match &dopts.conversations {
+            Some(conversations) => handle_admin_conversations(req, servers, conversations).await,
+            None => make_json_resp(StatusCode::NOT_FOUND, json!({ "error": "Conversation routing is disabled, see --conversation-routing" })),
+        }),
+        "/admin/events" => Ok(Response::builder()
             .status(StatusCode::OK)
-            .body(Body::from("Ollama is running"))
+            .header("Content-Type", "application/x-ndjson")
+            .body(Body::wrap_stream(dopts.events.subscribe()))
             .unwrap()
         ),
-        "/api/tags" => handle_tags(req, servers, remote_addr).await,
-        "/api/show" => handle_request_ha(req, servers, remote_addr, opts).await,
-        "/api/generate" => handle_generate(req, servers, remote_addr).await,
-        "/api/chat" => handle_chat_parallel(req, servers, remote_addr, opts).await,
+        "/admin/explain" => Ok(handle_explain(&req, servers, &dopts)),
+        "/admin/usage" => Ok(match &dopts.accounting {
+            Some(accounting) => make_json_resp(StatusCode::OK, json!(accounting.report())),
+            None => make_json_resp(StatusCode::NOT_FOUND, json!({ "error": "Usage accounting is disabled, see --track-usage" })),
+        }),
+        "/admin/heatmap" => Ok(handle_heatmap(&req)),
+        "/admin/traces" => Ok(match &dopts.tracer {
+            Some(tracer) => make_json_resp(StatusCode::OK, json!(tracer.report())),
+            None => make_json_resp(StatusCode::NOT_FOUND, json!({ "error": "Tracing is disabled, see --trace-buffer" })),
+        }),
+        "/admin/stats" => Ok(make_json_resp(StatusCode::OK, json!(stats::report()))),
+        "/admin/autoscale" => Ok(make_json_resp(StatusCode::OK, json!(dopts.autoscaler.report(&servers)))),
+        #[cfg(feature = "metrics")]
+        "/metrics" => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", metrics::CONTENT_TYPE)
+            .body(Body::from(metrics::render(&dopts.autoscaler.report(&servers), streams_in_flight(&servers), &dopts.inflight)))
+            .unwrap()
+        ),
+        #[cfg(not(feature = "metrics"))]
+        "/metrics" => Ok(make_json_resp(StatusCode::NOT_FOUND, json!({ "error": "Built without the `metrics` feature" }))),
+        "/admin/schema" => Ok(make_json_resp(StatusCode::OK, admin_schema())),
+        "/admin/benchmark" => handle_benchmark(req, servers, dopts.clone()).await,
+        "/admin/servers" => handle_admin_servers(req, servers, &dopts.runtime).await,
+        "/admin/unload" => handle_unload(req, servers, &dopts.runtime).await,
+        "/admin/resync" => handle_resync(req, servers, &dopts.runtime).await,
+        "/admin/register" => Ok(match &dopts.registry {
+            Some(registry) => handle_register(req, servers, remote_addr, registry, &dopts.runtime).await,
+            None => make_json_resp(StatusCode::NOT_FOUND, json!({ "error": "Self-registration is disabled, see --register-token-file" })),
+        }),
+        "/backend/leave" => handle_backend_leave(req, servers, remote_addr, true, &dopts.runtime).await,
+        "/backend/rejoin" => handle_backend_leave(req, servers, remote_addr, false, &dopts.runtime).await,
+        // without credentials, only the backends may make the mirror download layers to the disk
+        _ if path.starts_with("/v2") && dopts.mirror.is_some() && !is_server_ip(servers.clone(), remote_addr.ip()) => {
+            warn!("{} - {} {} - rejected: not a backend address", remote, method, path);
+            Ok(make_json_resp(StatusCode::FORBIDDEN, json!({ "error": "The registry mirror only serves the backends" })))
+        },
+        _ if path.starts_with("/v2") && dopts.mirror.is_some() => handle_registry(req, dopts.mirror.clone().unwrap()).await,
         _ => handle_return_501(req, servers, remote_addr, format!("Endpoint {} is not implemented", path).as_str()).await,
     };
     let status = response.as_ref().map(|r| r.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
     info!("{} - {} {} - {} {}", remote, method, path, status.as_u16(), status.canonical_reason().unwrap_or("Unknown"));
-    response
+    let response = match inflight {
+        Some(guard) => response.map(|resp| resp.map(|body| Body::wrap_stream(InflightBody::new(body, guard)))),
+        None => response,
+    };
+    let response = match (dopts.accounting, client_key) {
+        (Some(accounting), Some(key)) => response.map(|resp|
+            resp.map(|body| Body::wrap_stream(MeteredBody::new(body, accounting, key)))
+        ),
+        _ => response,
+    };
+    match encoding {
+        Some(encoding) => response.map(|resp| compress(resp, encoding)),
+        None => response,
+    }
+}
+
+/// Forwards the endpoints that run a model, through the response cache if enabled.
+/// `tenant` is the client identity the cached responses are kept apart by, if any.
+async fn handle_model_request(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    dopts: DispatchOpt,
+    tenant: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+    // a request forced to a server is meant to reach it
+    let forced = req.headers().contains_key("x-lb-backend");
+    match dopts.cache.clone().filter(|_| !forced) {
+        Some(cache) => handle_cached(req, servers, remote_addr, dopts, cache, tenant).await,
+        None => handle_inference(req, servers, remote_addr, dopts).await,
+    }
+}
+
+/// Forwards the endpoints that run a model, coalescing identical requests if enabled.
+async fn handle_inference(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    dopts: DispatchOpt,
+) -> Result<Response<Body>, Infallible> {
+    let forced = req.headers().contains_key("x-lb-backend");
+    match dopts.coalescer.clone().filter(|_| !forced) {
+        Some(coalescer) => handle_coalesced(req, servers, remote_addr, dopts, coalescer).await,
+        None => forward_inference(req, servers, remote_addr, dopts).await,
+    }
+}
+
+async fn forward_inference(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    dopts: DispatchOpt,
+) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() == "/api/embed" {
+        return handle_request_ha(req, servers, remote_addr, dopts).await;
+    }
+    handle_chat_parallel(req, servers, remote_addr, dopts).await
+}
+
+/// Requests per model, server and hour, `?hours=N` (24 by default) and `?model=NAME` narrow it.
+fn handle_heatmap(req: &Request<Body>) -> Response<Body> {
+    let param = |name: &str| req.uri().query().and_then(|q| q.split('&').find_map(|p| {
+        p.split_once('=').filter(|(k, _)| *k == name).map(|(_, v)| v.replace("%3A", ":").replace("%3a", ":"))
+    }));
+    let hours = match param("hours").map(|v| v.parse::<i64>()) {
+        None => 24,
+        Some(Ok(hours)) if hours > 0 => hours,
+        Some(_) => return make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "'hours' must be a positive integer" })),
+    };
+    make_json_resp(StatusCode::OK, json!(heatmap::report(hours, param("model").as_deref())))
+}
+
+/// Liveness probe, like Ollama. With `?status=1`, also a summary of the servers,
+/// so probes can tell a running balancer from one with no backend left.
+fn handle_root(req: &Request<Body>, servers: SharedServerList, started: std::time::Instant) -> Response<Body> {
+    let verbose = req.uri().query().is_some_and(|q| q.split('&').any(|p| p == "status=1" || p == "status=true"));
+    if !verbose {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("Ollama is running"))
+            .unwrap();
+    }
+    let snaps = snapshot_servers(servers, false);
+    let dead = snaps.values().filter(|snap| snap.state.health == Health::Dead).count();
+    make_json_resp(StatusCode::OK, json!({
+        "status": if dead < snaps.len() { "ok" } else { "no_backend" },
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": started.elapsed().as_secs(),
+        "servers": { "total": snaps.len(), "healthy": snaps.len() - dead, "dead": dead },
+    }))
+}
+
+/// Readiness probe: 503 until enough servers are healthy and every critical model
+/// is hosted by one of them, so orchestrators hold traffic back until then.
+fn handle_readyz(servers: SharedServerList, runtime: &RuntimeConfig) -> Response<Body> {
+    let (healthy, unknown, gaps) = readiness_gaps(servers, &runtime.readiness);
+    // ready may come before the startup sync is over, as soon as enough servers answered
+    let stage = if unknown > 0 { "syncing" } else { "synced" };
+    if gaps.is_empty() {
+        make_json_resp(StatusCode::OK, json!({ "status": "ready", "stage": stage, "healthy": healthy, "unknown": unknown }))
+    } else {
+        make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({
+            "status": "not_ready", "stage": stage, "healthy": healthy, "unknown": unknown, "reasons": gaps
+        }))
+    }
+}
+
+/// Lets a backend announce a planned shutdown, `{ "in_secs": 30 }`, and its return.
+/// Requests are routed around it right away, while those in flight finish, and its failures
+/// are not held against it until it rejoins, or until `leave_timeout` after the announced
+/// shutdown. The server is the one at the client IP, unless named by address or name in `server`.
+/// Shutdowns are announced at most `MAX_LEAVE_NOTICE` ahead.
+async fn handle_backend_leave(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    leaving: bool,
+    runtime: &RuntimeConfig,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != hyper::Method::POST {
+        return Ok(make_json_resp(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "Use POST" })));
+    }
+    let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let body = parse_body(&whole_body).unwrap_or_default();
+    let Some(server) = find_server(servers.clone(), body["server"].as_str(), Some(remote_addr.ip())) else {
+        return Ok(make_json_resp(StatusCode::NOT_FOUND, json!({ "error": "No such server" })));
+    };
+    if leaving {
+        let in_secs = match &body["in_secs"] {
+            Value::Null => 0,
+            value => match value.as_u64().filter(|secs| *secs <= MAX_LEAVE_NOTICE.as_secs()) {
+                Some(secs) => secs,
+                None => return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({
+                    "error": format!("'in_secs' must be a number of seconds from 0 to {}", MAX_LEAVE_NOTICE.as_secs())
+                }))),
+            },
+        };
+        let notice = std::time::Duration::from_secs(in_secs);
+        // bounded above, the conversion and the addition cannot fail
+        let at = chrono::Utc::now() + chrono::TimeDelta::from_std(notice).unwrap_or_default();
+        set_leaving(servers.clone(), &server, Some(at));
+        warn!("Server {} announced going down in {}s, draining it", server, in_secs);
+        let (target, expires_in) = (server.clone(), notice.saturating_add(runtime.leave_timeout));
+        tokio::spawn(async move {
+            tokio::time::sleep(expires_in).await;
+            expire_leaving(servers, &target, at);
+        });
+        return Ok(make_json_resp(StatusCode::OK, json!({ "server": server, "leaving_at": at.to_rfc3339() })));
+    }
+    set_leaving(servers.clone(), &server, None);
+    let health = sync_server(servers, server.clone(), runtime.sync_timeout, runtime.health.clone()).await;
+    info!("Server {} rejoined, now: {:?}", server, health);
+    Ok(make_json_resp(StatusCode::OK, json!({ "server": server, "alive": health != Health::Dead })))
+}
+
+/// Longest notice of a shutdown announced on `/backend/leave`.
+const MAX_LEAVE_NOTICE: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+
+/// Adds a server with `POST { "server": "ADDR=NAME" }`, syncing it right away,
+/// or removes one with `DELETE { "server": ... }`, by address or name.
+/// `GET` lists the servers at the current revision, `GET ?watch=true&since=N` streams the changes.
+async fn handle_admin_servers(
+    req: Request<Body>,
+    servers: SharedServerList,
+    runtime: &RuntimeConfig,
+) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    if method == hyper::Method::GET {
+        let param = |name: &str| req.uri().query().and_then(|q| q.split('&').find_map(|p| {
+            p.split_once('=').filter(|(k, _)| *k == name).map(|(_, v)| v.to_string())
+        }));
+        if !matches!(param("watch").as_deref(), Some("true" | "1")) {
+            return Ok(make_json_resp(StatusCode::OK, json!(membership::snapshot(&servers))));
+        }
+        let since = match param("since").map(|v| v.parse::<u64>()) {
+            Some(Ok(since)) => Some(since),
+            Some(Err(_)) => return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "'since' must be a revision number" }))),
+            None => None,
+        };
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/x-ndjson")
+            .body(Body::wrap_stream(membership::watch(&servers, since)))
+            .unwrap());
+    }
+    let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let body = parse_body(&whole_body).unwrap_or_default();
+    let Some(wanted) = body["server"].as_str() else {
+        return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "Request body must contain a 'server' field" })));
+    };
+    match method {
+        hyper::Method::POST => {
+            let server = match wanted.parse::<ServerConfig>() {
+                Ok(server) => server,
+                Err(e) => return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": e }))),
+            };
+            add_server(servers.clone(), &server);
+            let health = sync_server(servers, server.address.clone(), runtime.sync_timeout, runtime.health.clone()).await;
+            Ok(make_json_resp(StatusCode::OK, json!({ "server": server.address, "alive": health != Health::Dead })))
+        },
+        hyper::Method::DELETE => Ok(match find_server(servers.clone(), Some(wanted), None) {
+            Some(server) => {
+                remove_server(servers, &server);
+                make_json_resp(StatusCode::OK, json!({ "server": server, "removed": true }))
+            },
+            None => make_json_resp(StatusCode::NOT_FOUND, json!({ "error": format!("Unknown server {}", wanted) })),
+        }),
+        _ => Ok(make_json_resp(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "Use GET to list or watch the servers, POST to add one or DELETE to remove one" }))),
+    }
+}
+
+/// Registers a server with `POST { "address": ..., "name": ..., "tags": [...] }` and the shared
+/// token, the agent next to it posting again as a heartbeat. Without an address, the server is
+/// the client on `port`, 11434 by default. `DELETE { "address": ... }` deregisters it, `GET` lists them.
+async fn handle_register(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    registry: &Registry,
+    runtime: &RuntimeConfig,
+) -> Response<Body> {
+    if !registry.authenticate(req.headers()) {
+        warn!("{} - rejected registration: missing or invalid token", remote_addr);
+        let mut resp = make_json_resp(StatusCode::UNAUTHORIZED, json!({ "error": "Missing or invalid registration token" }));
+        resp.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        return resp;
+    }
+    let method = req.method().clone();
+    let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let body = parse_body(&whole_body).unwrap_or_default();
+    match method {
+        hyper::Method::GET => make_json_resp(StatusCode::OK, registry.report()),
+        hyper::Method::POST => {
+            let address = match body["address"].as_str() {
+                Some(address) if address.contains("://") => address.trim_end_matches('/').to_string(),
+                Some(address) => format!("http://{}", address.trim_end_matches('/')),
+                None => {
+                    let port = body["port"].as_u64().unwrap_or(11434);
+                    format!("http://{}", std::net::SocketAddr::new(remote_addr.ip(), port as u16))
+                },
+            };
+            let name = body["name"].as_str().map_or_else(|| address.clone(), str::to_string);
+            let tags = body["tags"].as_array().into_iter().flatten()
+                .filter_map(|tag| tag.as_str().map(str::to_string))
+                .collect::<Vec<_>>();
+            let server = ServerConfig { address, name };
+            let ttl = registry.ttl().as_secs();
+            match registry.register(servers, &server, tags, runtime) {
+                Registered::Conflict => make_json_resp(StatusCode::CONFLICT, json!({
+                    "error": format!("Server {} is configured or discovered, not registered", server.address)
+                })),
+                registered => make_json_resp(StatusCode::OK, json!({
+                    "server": server.address,
+                    "name": server.name,
+                    "new": matches!(registered, Registered::New),
+                    "ttl_secs": ttl,
+                })),
+            }
+        },
+        hyper::Method::DELETE => {
+            let Some(address) = body["address"].as_str() else {
+                return make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "Request body must contain an 'address' field" }));
+            };
+            match registry.deregister(servers, address) {
+                true => make_json_resp(StatusCode::OK, json!({ "server": address, "removed": true })),
+                false => make_json_resp(StatusCode::NOT_FOUND, json!({ "error": format!("Server {} is not registered", address) })),
+            }
+        },
+        _ => make_json_resp(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "Use POST to register, DELETE to deregister or GET to list" })),
+    }
+}
+
+/// Lists the pinned conversations, or unpins one with `DELETE { "key": ... }`, e.g. for a user
+/// stuck on a bad backend, or all those of a server with `DELETE { "server": ... }`.
+async fn handle_admin_conversations(
+    req: Request<Body>,
+    servers: SharedServerList,
+    conversations: &SharedConversationMap,
+) -> Response<Body> {
+    match *req.method() {
+        hyper::Method::GET => make_json_resp(StatusCode::OK, json!(conversations_report(&conversations.lock().unwrap()))),
+        hyper::Method::DELETE => {
+            let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+            let body = parse_body(&whole_body).unwrap_or_default();
+            if let Some(key) = body["key"].as_str() {
+                let Ok(hash) = u64::from_str_radix(key, 16) else {
+                    return make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": format!("Invalid key {}", key) }));
+                };
+                return match conversations.lock().unwrap().remove(hash) {
+                    Some(server) => {
+                        info!("Unpinned conversation {} from server {}", key, server);
+                        make_json_resp(StatusCode::OK, json!({ "key": key, "server": server, "removed": true }))
+                    },
+                    None => make_json_resp(StatusCode::NOT_FOUND, json!({ "error": format!("Unknown conversation {}", key) })),
+                };
+            }
+            let Some(wanted) = body["server"].as_str() else {
+                return make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "Request body must contain a 'key' or 'server' field" }));
+            };
+            // also accept the address of a server that was removed since
+            let server = find_server(servers, Some(wanted), None).unwrap_or_else(|| wanted.to_string());
+            let removed = conversations.lock().unwrap().remove_server(&server);
+            info!("Unpinned {} conversations from server {}", removed, server);
+            make_json_resp(StatusCode::OK, json!({ "server": server, "removed": removed }))
+        },
+        _ => make_json_resp(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "Use GET to list the pins or DELETE to remove some" })),
+    }
+}
+
+/// Explains which servers a request for `?model=` would be routed to, and why the others
+/// would not, without sending anything. `&endpoint=` picks the selection options of an endpoint.
+fn handle_explain(req: &Request<Body>, servers: SharedServerList, dopts: &DispatchOpt) -> Response<Body> {
+    let param = |name: &str| req.uri().query().and_then(|q| q.split('&').find_map(|p| {
+        p.split_once('=').filter(|(k, _)| *k == name).map(|(_, v)| v.replace("%3A", ":").replace("%3a", ":"))
+    }));
+    let Some(model) = param("model").filter(|m| !m.is_empty()) else {
+        return make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "Query must contain a 'model' parameter" }));
+    };
+    let endpoint = param("endpoint").unwrap_or_else(|| "/api/chat".to_string());
+    let strict = dopts.runtime.selection.for_endpoint(&endpoint).strict;
+    let pinned = dopts.profiles.checkout().0.get(&model).servers;
+    let (eligible, excluded) = explain_selection(servers, &model, strict, pinned.as_deref());
+    make_json_resp(StatusCode::OK, json!(ExplainReport {
+        schema_version: SCHEMA_VERSION,
+        model,
+        eligible,
+        excluded: exclusion_reports(&excluded),
+    }))
+}
+
+/// Syncs the server named by `POST { "server": ... }`, or every server, right away,
+/// e.g. after pulling a model directly on a backend.
+async fn handle_resync(
+    req: Request<Body>,
+    servers: SharedServerList,
+    runtime: &RuntimeConfig,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != hyper::Method::POST {
+        return Ok(make_json_resp(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "Use POST" })));
+    }
+    let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let body = parse_body(&whole_body).unwrap_or_default();
+    let synced = match body["server"].as_str() {
+        Some(wanted) => match find_server(servers.clone(), Some(wanted), None) {
+            Some(server) => vec![(server.clone(), sync_server(servers, server, runtime.sync_timeout, runtime.health.clone()).await)],
+            None => return Ok(make_json_resp(StatusCode::NOT_FOUND, json!({ "error": format!("Unknown server {}", wanted) }))),
+        },
+        None => sync_all(servers, runtime.sync_timeout, runtime.health.clone()).await,
+    };
+    info!("Resynced {} servers on request", synced.len());
+    let synced = synced.into_iter().map(|(server, health)| json!({ "server": server, "alive": health != Health::Dead })).collect::<Vec<_>>();
+    Ok(make_json_resp(StatusCode::OK, json!({ "servers": synced })))
+}
+
+/// Unloads a model with `POST { "model": ..., "server": ... }` from the named server,
+/// or from every server having it loaded.
+async fn handle_unload(
+    req: Request<Body>,
+    servers: SharedServerList,
+    runtime: &RuntimeConfig,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != hyper::Method::POST {
+        return Ok(make_json_resp(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "Use POST" })));
+    }
+    let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let body = parse_body(&whole_body).unwrap_or_default();
+    let Some(model) = body["model"].as_str() else {
+        return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "Request body must contain a 'model' field" })));
+    };
+    let targets = match body["server"].as_str() {
+        Some(wanted) => match find_server(servers.clone(), Some(wanted), None) {
+            Some(server) => vec![server],
+            None => return Ok(make_json_resp(StatusCode::NOT_FOUND, json!({ "error": format!("Unknown server {}", wanted) }))),
+        },
+        None => snapshot_servers(servers.clone(), false).into_keys()
+            .filter(|server| has_model_loaded(servers.clone(), server, model))
+            .collect(),
+    };
+    let mut unloaded = Vec::new();
+    let mut failed = Vec::new();
+    for server in targets {
+        let unload = json!({ "model": model, "keep_alive": 0 }).to_string();
+        let req = ("/api/generate".to_string(), reqwest::Method::POST, "/api/generate".to_string(), None, Some(bytes::Bytes::from(unload).into()));
+        match send_request(req, &server, TimeoutProfile::read(runtime.sync_timeout.max(10))).await.and_then(|r| Ok(r.error_for_status()?)) {
+            Ok(_) => {
+                info!("Unloaded {} from {}", model, server);
+                unloaded.push(server.clone());
+            },
+            Err(e) => {
+                warn!("Failed to unload {} from {}: {}", model, server, e);
+                failed.push(server.clone());
+            },
+        }
+        sync_server(servers.clone(), server, runtime.sync_timeout, runtime.health.clone()).await;
+    }
+    let status = if failed.is_empty() { StatusCode::OK } else { StatusCode::BAD_GATEWAY };
+    Ok(make_json_resp(status, json!({ "model": model, "unloaded": unloaded, "failed": failed })))
+}
+
+/// Benchmarks one server in isolation, answering once it is back in the rotation.
+async fn handle_benchmark(
+    req: Request<Body>,
+    servers: SharedServerList,
+    dopts: DispatchOpt,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != hyper::Method::POST {
+        return Ok(make_json_resp(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "Use POST to start a benchmark" })));
+    }
+    let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let body = parse_body(&whole_body).unwrap_or_default();
+    let bench = match BenchmarkRequest::parse(&body, &servers, dopts.runtime.probe_model.as_deref()) {
+        Ok(bench) => bench,
+        Err(e) => return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": e }))),
+    };
+    let server = bench.server.clone();
+    // detached, so that the latency priors get updated even if the client goes away
+    let report = tokio::spawn(run_benchmark(servers, bench, dopts.req)).await;
+    Ok(match report {
+        Ok(Some(report)) => make_json_resp(StatusCode::OK, json!(report)),
+        Ok(None) => make_json_resp(StatusCode::CONFLICT, json!({ "error": format!("Server {} is already being benchmarked", server) })),
+        Err(e) => make_json_resp(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": format!("Benchmark failed: {}", e) })),
+    })
+}
+
+/// Answers deterministic requests from the response cache when possible.
+async fn handle_cached(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    dopts: DispatchOpt,
+    cache: Arc<ResponseCache>,
+    tenant: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let whole_body = body::to_bytes(body).await.unwrap_or_default();
+    let path = parts.uri.path().to_string();
+    let deterministic = parse_body(&whole_body).is_ok_and(|body| is_deterministic(&path, &body));
+    let req = Request::from_parts(parts, Body::from(whole_body.clone()));
+    if !deterministic {
+        return handle_inference(req, servers, remote_addr, dopts).await;
+    }
+    // a client never gets the answer to another one's request, nor learns what it asked
+    let key = cache_key(&path, &whole_body, tenant.as_deref());
+    // hits went through the rate limits already, and are metered like any response
+    if let Some(resp) = cache.get(key) {
+        info!("Served {} for client {} from the response cache", path, remote_addr);
+        return Ok(resp);
+    }
+    let Ok(resp) = handle_inference(req, servers, remote_addr, dopts).await;
+    Ok(cache.store(key, resp))
+}
+
+/// Sends only one of several identical chat or embedding requests to the backends, the
+/// others share its response. The leader runs detached so that its followers are served
+/// even if its own client goes away, and is cancelled once every client went away.
+async fn handle_coalesced(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    dopts: DispatchOpt,
+    coalescer: Arc<Coalescer>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let whole_body = body::to_bytes(body).await.unwrap_or_default();
+    let key = fingerprint(parts.uri.path(), &whole_body);
+    let model = serde_json::from_slice::<Value>(&whole_body).ok()
+        .and_then(|body| body["model"].as_str().map(str::to_string))
+        .unwrap_or_default();
+    let headers = parts.headers.clone();
+    let embed = parts.uri.path() == "/api/embed";
+    let req = Request::from_parts(parts, Body::from(whole_body));
+    match coalescer.join(key) {
+        Role::Follower(subscription) => {
+            info!("Request from {} joined an identical one in flight", remote_addr);
+            let mut resp = subscription.response().await;
+            // only the chat routing is audited
+            if !embed {
+                record_follower(&dopts, &headers, remote_addr, &model, &mut resp);
+            }
+            Ok(resp)
+        },
+        Role::Leader(subscription) => {
+            coalescer.lead(&subscription, async move {
+                let Ok(resp) = forward_inference(req, servers, remote_addr, dopts).await;
+                resp
+            });
+            Ok(subscription.response().await)
+        },
+    }
+}
+
+/// Records the routing of a request served by an identical one in its own session, whose
+/// routing trail replaces the one of the leader's session.
+fn record_follower(dopts: &DispatchOpt, headers: &hyper::HeaderMap, remote_addr: std::net::SocketAddr, model: &str, resp: &mut Response<Body>) {
+    resp.headers_mut().remove("X-Routing-Trail");
+    let Some(audit) = &dopts.audit else {
+        return;
+    };
+    let Some(upstream) = resp.headers().get("X-LB-Upstream").and_then(|v| v.to_str().ok()).and_then(Upstream::parse) else {
+        return;
+    };
+    let trail = audit.lock().unwrap().record(&affinity_key(Some(headers), remote_addr), RoutingRecord {
+        at: chrono::Utc::now(),
+        model: model.to_string(),
+        server: upstream.server,
+        server_name: upstream.name,
+        candidates: 0,
+        ttft: upstream.ttft,
+    });
+    if dopts.audit_header {
+        if let Ok(value) = header::HeaderValue::from_str(&trail_header(&trail)) {
+            resp.headers_mut().insert("X-Routing-Trail", value);
+        }
+    }
 }
 
 // Handle request with high availability
@@ -91,7 +943,7 @@ pub async fn handle_request_ha(
     req: Request<Body>,
     servers: SharedServerList,
     remote_addr: std::net::SocketAddr,
-    opts: ReqOpt,
+    dopts: DispatchOpt,
 ) -> Result<Response<Body>, Infallible> {
     let unpacked_req = match unpack_req(req).await {
         Ok(req) => req,
@@ -100,7 +952,7 @@ pub async fn handle_request_ha(
         }
     };
 
-    let body = match parse_body(unpacked_req.4.as_ref().unwrap()) {
+    let body = match parse_body(unpacked_req.4.as_ref().and_then(RequestBody::as_bytes).unwrap()) {
         Ok(body) => body,
         Err(e) => {
             return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": format!("Error parsing request body: {}", e) })));
@@ -115,25 +967,88 @@ pub async fn handle_request_ha(
     if model.is_empty() {
         return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "Request body must contain a 'model' field" })));
     }
-    let selected_keys = select_servers(servers.clone(), model.to_string(), SelOpt {
-        count: (3, 6),
-        resurrect_p: 0.1,
-        resurrect_n: 1,
-    });
+    let pinned = dopts.profiles.checkout().0.get(model).servers;
+    // showing a model is no reason to spread it over the cluster
+    if let Some(auto_pull) = dopts.auto_pull.as_ref().filter(|_| unpacked_req.2 == "/api/embed") {
+        let wanted = dopts.runtime.selection.for_endpoint(&unpacked_req.2).count.0;
+        auto_pull.provision(servers.clone(), model, wanted, pinned.as_deref(), &dopts.runtime, dopts.mirror.clone());
+    }
+    let forced = match forced_backend(servers.clone(), unpacked_req.3.as_ref(), remote_addr) {
+        Ok(forced) => forced,
+        Err(e) => return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": e }))),
+    };
+    let selected_keys = match forced {
+        Some(forced) => vec![forced],
+        None => {
+            let selection = select_servers(
+                servers.clone(), model.to_string(), dopts.runtime.selection.for_endpoint(&unpacked_req.2), pinned.as_deref(),
+                dopts.strategy.as_ref()
+            );
+            if dopts.events.has_subscribers() {
+                dopts.events.publish(&Event::Selection(SelectionEvent::new(model, &selection.servers, &selection.excluded)));
+            }
+            selection.servers
+        },
+    };
     if selected_keys.is_empty() {
         return Ok(make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": "No available servers" })));
     }
 
-    for server_url in selected_keys {
-        match send_request(unpacked_req.clone(), &server_url, opts.timeout).await {
+    let retry = &dopts.retry;
+    let timeouts = dopts.runtime.timeouts.for_endpoint(&unpacked_req.2);
+    retry.budget.record_request();
+    let attempts = selected_keys.len().min(retry.max_attempts.max(1));
+    for (attempt, server_url) in selected_keys.into_iter().take(attempts).enumerate() {
+        if attempt > 0 {
+            if !retry.budget.withdraw() {
+                warn!("Retry budget exhausted, not retrying {} for client {}", unpacked_req.2, remote_addr);
+                break;
+            }
+            tokio::time::sleep(retry.delay(attempt)).await;
+        }
+        let last = attempt + 1 == attempts;
+        let sent_at = std::time::Instant::now();
+        match send_request(unpacked_req.clone(), &server_url, timeouts).await {
             Ok(response) => {
+                if let Some(backoff) = overload_backoff(response.status(), response.headers(), dopts.runtime.overload) {
+                    mark_server_busy(servers.clone(), &server_url, backoff);
+                    if !last {
+                        warn!("Sequential request to server {} returned {}, trying the next one", server_url, response.status());
+                        continue;
+                    }
+                }
+                if let Err(e) = check_ollama_response(response.status(), response.headers()) {
+                    mark_server_misconfigured(servers.clone(), &server_url, e.to_string());
+                    continue;
+                }
+                if !last && retry.should_retry(response.status()) {
+                    warn!("Sequential request to server {} returned {}, retrying", server_url, response.status());
+                    continue;
+                }
                 info!("Chosen server {} to serve client {}", server_url, remote_addr);
+                let upstream = Upstream {
+                    name: servers.lock().unwrap().get(&server_url).map(|s| s.name.clone()).unwrap_or_default(),
+                    server: server_url.clone(),
+                    ttft: sent_at.elapsed(),
+                    failover: attempt > 0,
+                };
                 let status = response.status();
-                let mut resp_builder = Response::builder().status(u16::from(status));
+                // showing a model says nothing about how fast it runs
+                if status.is_success() && unpacked_req.2 != "/api/show" {
+                    stats::record(&server_url, model, upstream.ttft, None, dopts.runtime.latency_alpha);
+                    heatmap::record(&server_url, model);
+                }
+                let mut resp_builder = Response::builder().status(status);
                 for (key_h, value) in response.headers() {
-                    resp_builder = resp_builder.header(key_h.to_string(), value.to_str().unwrap());
+                    resp_builder = resp_builder.header(key_h, value);
+                }
+                if let Ok(value) = header::HeaderValue::from_str(&upstream.header_value()) {
+                    resp_builder = resp_builder.header("X-LB-Upstream", value);
                 }
-                let stream = response.bytes_stream().boxed();
+                let tally = Arc::new(RelayTally::default());
+                resp_builder = resp_builder.extension(tally.clone());
+                let stream = Received::new(idle_limited(response.bytes_stream(), timeouts.idle), tally.clone());
+                let stream = Delivered::new(stream, tally, upstream.server, remote_addr.to_string());
                 return Ok(resp_builder.body(Body::wrap_stream(stream)).unwrap());
             },
             Err(e) => {
@@ -145,58 +1060,406 @@ pub async fn handle_request_ha(
     Ok(make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": "All chosen backends failed" })))
 }
 
+/// How long a backend answering `status` is backed off instead of penalized, if it is overloaded.
+fn overload_backoff(status: StatusCode, headers: &hyper::HeaderMap, overload: Overload) -> Option<std::time::Duration> {
+    match overload {
+        Overload::Backoff(default) if status == StatusCode::TOO_MANY_REQUESTS => Some(retry_after(headers).unwrap_or(default)),
+        _ => None,
+    }
+}
+
+/// First token timeout in seconds asked for by the client with `X-LB-Timeout-FT`.
+fn requested_timeout_ft(headers: Option<&hyper::HeaderMap>) -> Option<u32> {
+    headers?.get("x-lb-timeout-ft")?.to_str().ok()?.trim().parse().ok()
+}
+
+/// The server forced by the client with `X-LB-Backend`, bypassing the selection,
+/// or the error refusing it when it is not one of the servers or is routed around.
+fn forced_backend(servers: SharedServerList, headers: Option<&hyper::HeaderMap>, remote_addr: std::net::SocketAddr) -> Result<Option<String>, String> {
+    let Some(wanted) = headers.and_then(|h| h.get("x-lb-backend")) else {
+        return Ok(None);
+    };
+    let wanted = wanted.to_str().unwrap_or_default().trim();
+    let Some(server) = find_server_at(servers.clone(), wanted) else {
+        return Err(format!("Unknown backend {} in X-LB-Backend", wanted));
+    };
+    // the header picks among the servers, it does not resurrect the ones routed around
+    let unavailable = servers.lock().unwrap().get(&server).and_then(|srv| {
+        if srv.state.isolated {
+            Some("being benchmarked")
+        } else if srv.state.leaving.is_some() {
+            Some("leaving")
+        } else if srv.state.health == Health::Dead {
+            Some("dead")
+        } else {
+            None
+        }
+    });
+    if let Some(reason) = unavailable {
+        return Err(format!("Backend {} in X-LB-Backend is {}", server, reason));
+    }
+    info!("Client {} forced server {}", remote_addr, server);
+    Ok(Some(server))
+}
+
+/// Whether no authentication provider covers the admin endpoints.
+fn admin_open(dopts: &DispatchOpt) -> bool {
+    !dopts.auth.as_ref().is_some_and(|auth| auth.covers(AuthScope::Admin))
+}
+
+/// Whether a request may use an operator's privilege: its credentials are accepted for the admin
+/// endpoints, or these were deliberately left open with --open-admin.
+async fn admin_privileged(dopts: &DispatchOpt, headers: &hyper::HeaderMap, remote_addr: std::net::SocketAddr) -> bool {
+    match dopts.auth.as_ref().filter(|_| !admin_open(dopts)) {
+        Some(auth) => matches!(auth.authenticate_scope(AuthScope::Admin, headers, remote_addr).await, Ok(Some(_))),
+        None => dopts.open_admin,
+    }
+}
+
+/// Key used for sticky routing: the `X-Session-Id` header if any, the client IP otherwise.
+fn affinity_key(headers: Option<&hyper::HeaderMap>, remote_addr: std::net::SocketAddr) -> String {
+    headers
+        .and_then(|h| h.get("x-session-id"))
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(|v| format!("session:{}", v))
+        .unwrap_or_else(|| format!("client:{}", remote_addr.ip()))
+}
+
 pub async fn handle_chat_parallel(
     req: Request<Body>,
     servers: SharedServerList,
     remote_addr: std::net::SocketAddr,
-    opts: ReqOpt,
+    dopts: DispatchOpt,
 ) -> Result<Response<Body>, Infallible> {
-    let unpacked_req = match unpack_req(req).await {
+    let mut opts = dopts.req;
+    let mut unpacked_req = match unpack_req(req).await {
         Ok(req) => req,
         Err(e) => {
             return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": format!("Error handling request: {}", e) })));
         }
     };
 
-    let body = match parse_body(unpacked_req.4.as_ref().unwrap()) {
+    let mut body = match parse_body(unpacked_req.4.as_ref().and_then(RequestBody::as_bytes).unwrap()) {
         Ok(body) => body,
         Err(e) => {
             return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": format!("Error parsing request body: {}", e) })));
         }
     };
+    // a model no healthy server hosts is replaced by the first fallback that one hosts
+    let substitution = body["model"].as_str().and_then(|requested| {
+        let fallbacks = dopts.fallbacks.get(requested)?;
+        if has_healthy_server(servers.clone(), requested) {
+            return None;
+        }
+        let used = fallbacks.iter().find(|m| has_healthy_server(servers.clone(), m))?;
+        info!("No healthy server hosts {}, falling back to {}", requested, used);
+        Some((requested.to_string(), used.clone()))
+    });
+    if let Some((_, used)) = &substitution {
+        body["model"] = json!(used);
+    }
+    let (profiles, generation) = dopts.profiles.checkout();
+    let keep_alive = body["model"].as_str().and_then(|model| profiles.get(model).keep_alive)
+        .or(dopts.runtime.keep_alive.clone());
+    if let Some(keep_alive) = &keep_alive {
+        body["keep_alive"] = keep_alive.clone();
+    }
+    // small context backends handle overflowing histories differently, make them all see the same
+    let history_limit = dopts.runtime.history_limit;
+    let truncated = if history_limit.is_set() { history_limit.truncate(&mut body) } else { 0 };
+    if truncated > 0 {
+        info!("Dropped the {} oldest messages of the history from {}", truncated, remote_addr);
+    }
+    if substitution.is_some() || truncated > 0 || keep_alive.is_some() {
+        unpacked_req.4 = Some(bytes::Bytes::from(body.to_string()).into());
+        if let Some(headers) = unpacked_req.3.as_mut() {
+            headers.remove(header::CONTENT_LENGTH);
+        }
+    }
     let model = match body["model"].as_str() {
         Some(model) => model,
         None => {
             return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": "Request body must contain a 'model' field" })));
         }
     };
-    let selected_keys = select_servers(servers.clone(), model.to_string(), SelOpt {
-        count: (3, 6),
-        resurrect_p: 0.1,
-        resurrect_n: 1,
+    let RuntimeConfig {
+        sync_timeout, preview_len, latency_alpha, presync, presync_ttl, overload, max_timeout_ft, ..
+    } = *dopts.runtime;
+    let health_cfg = dopts.runtime.health.clone();
+    let profile = profiles.get(model);
+    let mut timeout_ft_chosen = false;
+    if let Some(timeout_ft) = profile.timeout_ft {
+        opts.timeouts.first_token = timeout_ft;
+        timeout_ft_chosen = true;
+    }
+    // the client knows best how heavy its request is, within bounds
+    if let Some(wanted) = requested_timeout_ft(unpacked_req.3.as_ref()).filter(|_| max_timeout_ft > 0) {
+        let timeout_ft = wanted.clamp(1, max_timeout_ft);
+        info!("Client {} asked for a first token timeout of {}s, using {}s", remote_addr, wanted, timeout_ft);
+        opts.timeouts.first_token = timeout_ft;
+        timeout_ft_chosen = true;
+    }
+    if let Some(time_measure) = profile.time_measure {
+        opts.time_measure = time_measure;
+    }
+    if let Some(measure_tokens) = profile.measure_tokens {
+        opts.measure_tokens = measure_tokens;
+    }
+    if dopts.race_relay == RaceRelay::Immediate {
+        // the first token is all there is to measure
+        opts.measure_tokens = 1;
+    }
+    let queue_timeout = profile.queue_timeout.map(|secs| std::time::Duration::from_secs(secs.into()));
+    let queued = begin_queue();
+    let permit = match dopts.concurrency.acquire(model, queue_timeout).await {
+        Ok(permit) => permit,
+        Err(max) => {
+            warn!("Request for model {} from {} timed out waiting for one of its {} generations", model, remote_addr, max);
+            return Ok(make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({
+                "error": format!("Model {} already has {} generations in flight", model, max)
+            })));
+        }
+    };
+    if let Some(queue_timeout) = profile.queue_timeout {
+        let queued_at = std::time::Instant::now();
+        if !wait_for_idle_server(servers.clone(), model, std::time::Duration::from_secs(queue_timeout.into())).await {
+            warn!("Request for model {} from {} timed out in the queue after {}s", model, remote_addr, queue_timeout);
+            return Ok(make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({
+                "error": format!("No server became available for model {} within {}s", model, queue_timeout)
+            })));
+        }
+        let waited = queued_at.elapsed();
+        if waited.as_millis() > dopts.runtime.queue_log_threshold_ms {
+            info!("Request for model {} waited {:.1}s in the queue", model, waited.as_secs_f32());
+        }
+    }
+    drop(queued);
+    let sel_opt = dopts.runtime.selection.for_endpoint(&unpacked_req.2);
+    if let Some(auto_pull) = &dopts.auto_pull {
+        auto_pull.provision(servers.clone(), model, sel_opt.count.0, profile.servers.as_deref(), &dopts.runtime, dopts.mirror.clone());
+    }
+    let forced = match forced_backend(servers.clone(), unpacked_req.3.as_ref(), remote_addr) {
+        Ok(forced) => forced,
+        Err(e) => return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": e }))),
+    };
+    // continuations of a known conversation go back to the server holding its KV cache
+    let history = body["messages"].as_array().map(|m| conversation_hashes(model, m)).unwrap_or_default();
+    let previous = dopts.conversations.as_ref().filter(|_| forced.is_none()).and_then(|c| c.lock().unwrap().lookup(&history));
+    let previous = previous.filter(|server| {
+        let ok = can_serve(servers.clone(), server, model, sel_opt.strict, profile.servers.as_deref());
+        if ok {
+            info!("Conversation continues on server {}", server);
+        } else {
+            info!("Server {} of the previous turn can't serve this conversation anymore", server);
+        }
+        ok
     });
+    let mut sticky = previous.into_iter().collect::<Vec<String>>();
+    let session = affinity_key(unpacked_req.3.as_ref(), remote_addr);
+    if dopts.affinity && forced.is_none() {
+        let key = &session;
+        let ranked = rank_by_affinity(servers.clone(), model, key, sel_opt.strict, profile.servers.as_deref());
+        info!("Affinity ranking for {}: [{}]", key, ranked.join(", "));
+        sticky.extend(ranked.into_iter().filter(|s| !sticky.contains(s)).collect::<Vec<_>>());
+    }
+    // without a sticky candidate, e.g. the model lives on dead servers only, fall back to racing
+    // a forced server is sticky, it gets the request alone
+    let is_sticky = forced.is_some() || !sticky.is_empty();
+    let is_forced = forced.is_some();
+    let selected_keys = if let Some(forced) = forced {
+        vec![forced]
+    } else if is_sticky {
+        sticky
+    } else {
+        let selection = select_servers(servers.clone(), model.to_string(), sel_opt, profile.servers.as_deref(), dopts.strategy.as_ref());
+        if dopts.events.has_subscribers() {
+            dopts.events.publish(&Event::Selection(SelectionEvent::new(model, &selection.servers, &selection.excluded)));
+        }
+        selection.servers
+    };
     if selected_keys.is_empty() {
         return Ok(make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": "No available servers" })));
     }
 
-    let tasks: Vec<_> = selected_keys.iter().map(|server_url| {
-        let req = unpacked_req.clone();
+    // make sure every server receives a request it understands
+    let versions = server_versions(servers.clone(), &selected_keys);
+    let mut adapted_bodies = HashMap::new();
+    let mut unsupported = None;
+    let selected_keys = selected_keys.into_iter().filter(|key| {
+        let version = versions.get(key).cloned().flatten();
+        match adapt_body(&body, version.as_deref()) {
+            Ok(adapted) => {
+                if let Some(adapted) = adapted {
+                    info!("Translated request for server {} (Ollama {})", key, version.as_deref().unwrap_or("unknown"));
+                    adapted_bodies.insert(key.clone(), bytes::Bytes::from(adapted.to_string()));
+                }
+                true
+            },
+            Err(feature) => {
+                warn!("Server {} (Ollama {}) does not support {}, skipped", key, version.as_deref().unwrap_or("unknown"), feature.name());
+                unsupported = Some(feature);
+                false
+            },
+        }
+    }).collect::<Vec<String>>();
+    if selected_keys.is_empty() {
+        let msg = match unsupported {
+            Some(feature) => format!("No available server supports {}", feature.name()),
+            None => "No available servers".to_string(),
+        };
+        return Ok(make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": msg })));
+    }
+    // sticky routing sends the request to the top ranked server only, the others are left to fail over to
+    let (mut selected_keys, sticky_fallbacks) = if is_sticky {
+        let mut selected_keys = selected_keys;
+        let rest = selected_keys.split_off(1);
+        (selected_keys, Some(rest))
+    } else {
+        (selected_keys, None)
+    };
+
+    let spawn_request = |server_url: &String, adapted: Option<&bytes::Bytes>| {
+        let mut req = unpacked_req.clone();
+        if let Some(adapted) = adapted {
+            req.4 = Some(adapted.clone().into());
+            if let Some(headers) = req.3.as_mut() {
+                headers.remove(header::CONTENT_LENGTH);
+            }
+        }
         let url = server_url.clone();
         let servers = servers.clone();
+        let health_cfg = health_cfg.clone();
+        // the backend settings of the generation the request started with, even after a reload
+        let name = servers.lock().unwrap().get(&url).map(|srv| srv.name.clone()).unwrap_or_default();
+        let mut opts = opts;
+        opts.timeouts = generation.backends.get(&url, &name).timeouts(opts.timeouts, timeout_ft_chosen);
+        let pending = PendingGuard::new(servers.clone(), url.clone());
         tokio::spawn(async move {
-            let health = sync_server(servers, url.to_owned(), opts.timeout).await;
-            if health == crate::state::Health::Dead {
-                warn!("Server {} is dead", url);
-                return Err(Box::<dyn std::error::Error + Send + Sync>::from(
-                    std::io::Error::new(std::io::ErrorKind::Other, format!("Server {} is dead", url))
-                ));
+            let _pending = pending;
+            let fresh = presync == Presync::Off || is_freshly_synced(servers.clone(), &url, presync_ttl);
+            if !fresh && presync == Presync::Concurrent {
+                tokio::spawn(sync_server(servers.clone(), url.clone(), sync_timeout, health_cfg.clone()));
+            } else if !fresh {
+                let health = sync_server(servers, url.to_owned(), sync_timeout, health_cfg).await;
+                if health == crate::state::Health::Dead {
+                    warn!("Server {} is dead", url);
+                    return Err(Box::<dyn std::error::Error + Send + Sync>::from(
+                        std::io::Error::other(format!("Server {} is dead", url))
+                    ));
+                }
+                info!("Server {} is healthy", url);
             }
-            info!("Server {} is healthy", url);
             send_request_monitored(req, url.as_str(), opts).await
         })
-    }).collect();
+    };
 
-    let results = future::join_all(tasks).await;
+    let warm = dopts.mode == DispatchMode::Hybrid
+        && selected_keys.first().is_some_and(|server| has_model_loaded(servers.clone(), server, model));
+    if warm {
+        info!("Model {} is loaded on server {}, not racing", model, selected_keys[0]);
+    }
+    dopts.retry.budget.record_request();
+    let mut standby = None;
+    let results = if dopts.mode == DispatchMode::Single || warm {
+        // sequential failover, the next server is only tried when the previous one failed
+        let mut results = Vec::new();
+        for (attempt, server_url) in selected_keys.iter().enumerate() {
+            if attempt > 0 && !dopts.retry.budget.withdraw() {
+                warn!("Retry budget exhausted, not failing over to {} for client {}", server_url, remote_addr);
+                break;
+            }
+            let res = spawn_request(server_url, adapted_bodies.get(server_url)).await;
+            let ok = matches!(&res, Ok(Ok((_, repacked))) if repacked.status.is_success());
+            results.push(res);
+            if ok {
+                break;
+            }
+        }
+        results
+    } else if dopts.race_relay == RaceRelay::Immediate {
+        // the first server to answer successfully leads, and is relayed tentatively
+        let handles = selected_keys.iter().map(|s| spawn_request(s, adapted_bodies.get(s))).collect::<Vec<_>>();
+        let aborts = handles.iter().map(|handle| handle.abort_handle()).collect::<Vec<_>>();
+        let mut pending = handles.into_iter().enumerate()
+            .map(|(i, handle)| async move { (i, handle.await) })
+            .collect::<futures_util::stream::FuturesUnordered<_>>();
+        let mut done = Vec::new();
+        while let Some((i, res)) = pending.next().await {
+            let ok = matches!(&res, Ok(Ok((_, repacked))) if repacked.status.is_success());
+            done.push((i, res));
+            if ok {
+                break;
+            }
+        }
+        // the others stand by until the first one commits, dropping the join handles would not cancel them
+        if !pending.is_empty() {
+            let keys = selected_keys.clone();
+            let aborts = aborts.into_iter().enumerate()
+                .filter(|(i, _)| !done.iter().any(|(j, _)| j == i))
+                .map(|(_, abort)| abort)
+                .collect();
+            let racers = pending.filter_map(move |(i, res)| future::ready(match res {
+                Ok(Ok((_, repacked))) if repacked.status.is_success() => Some((keys[i].clone(), repacked.stream)),
+                _ => None,
+            })).boxed();
+            standby = Some(Standby { racers, aborts });
+        }
+        done.sort_by_key(|(i, _)| *i);
+        selected_keys = done.iter().map(|(i, _)| selected_keys[*i].clone()).collect();
+        done.into_iter().map(|(_, res)| res).collect()
+    } else {
+        future::join_all(selected_keys.iter().map(|s| spawn_request(s, adapted_bodies.get(s)))).await
+    };
+    let mut results = results;
+    // nothing reached the client yet, so a request no candidate answered can go on to the next ranked servers,
+    // unless its body was streamed to the first one or the client asked for a server
+    let replayable = !matches!(unpacked_req.4, Some(RequestBody::Stream(_)));
+    let answered = results.iter().any(|res| matches!(res, Ok(Ok((_, repacked))) if repacked.status.is_success()));
+    // a request the backends refused, e.g. with 400 or 404, would be refused by the next ones as well
+    let retryable = |res: &Result<Result<(PerformanceInfo, RepackedResponse), _>, _>| match res {
+        Ok(Ok((_, repacked))) => dopts.retry.should_retry(repacked.status),
+        _ => true,
+    };
+    let selected_count = selected_keys.len();
+    if !answered && replayable && !is_forced && results.iter().all(retryable) {
+        let fallbacks = match sticky_fallbacks {
+            Some(rest) => rest,
+            None => select_servers_excluding(
+                servers.clone(), model.to_string(), sel_opt, profile.servers.as_deref(), dopts.strategy.as_ref(), &selected_keys
+            ).servers,
+        };
+        let versions = server_versions(servers.clone(), &fallbacks);
+        for server_url in fallbacks {
+            let adapted = match adapt_body(&body, versions.get(&server_url).cloned().flatten().as_deref()) {
+                Ok(adapted) => adapted.map(|adapted| bytes::Bytes::from(adapted.to_string())),
+                Err(_) => continue,
+            };
+            if !dopts.retry.budget.withdraw() {
+                warn!("Retry budget exhausted, not failing over to {} for client {}", server_url, remote_addr);
+                break;
+            }
+            info!("No candidate answered before its first token, failing over to server {} for client {}", server_url, remote_addr);
+            let res = spawn_request(&server_url, adapted.as_ref()).await;
+            let done = matches!(&res, Ok(Ok((_, repacked))) if repacked.status.is_success()) || !retryable(&res);
+            results.push(res);
+            selected_keys.push(server_url);
+            if done {
+                break;
+            }
+        }
+    }
+    // what every candidate did, only worth building for subscribers of the events stream
+    let race = dopts.events.has_subscribers().then(|| {
+        results.iter().zip(&selected_keys).map(|(res, server)| match res {
+            Ok(Ok((perf, repacked))) if repacked.status.is_success() => CandidateReport::answered(server, perf),
+            Ok(Ok((perf, repacked))) => CandidateReport::failed(server, Some(perf), format!("Status {}", repacked.status)),
+            Ok(Err(e)) => CandidateReport::failed(server, None, e.to_string()),
+            Err(e) => CandidateReport::failed(server, None, e.to_string()),
+        }).collect::<Vec<_>>()
+    });
+    // servers tried once no selected one answered, the response of one of them is a failover
+    let failed_over = selected_keys[selected_count..].to_vec();
     // firstly, partition the results into successful and failed
     let (ok_results, failed_results): (Vec<_>, Vec<_>) = 
         results.into_iter().zip(selected_keys).partition(|res_server|
@@ -207,56 +1470,160 @@ pub async fn handle_chat_parallel(
         }
     );
 
+    let failed_count = failed_results.len();
     if failed_results.len() > 0 {
         warn!("{} parallel requests failed", failed_results.len());
         // log failed requests & mark less healthy asynchrously
         let servers = servers.clone();
+        let health_cfg = health_cfg.clone();
         tokio::spawn(async move {
             for (res, server) in failed_results {
-                mark_server_less_healthy(servers.clone(), &server);
                 match res {
                     Err(e) => {
+                        mark_server_less_healthy(servers.clone(), &server, health_cfg.as_ref());
                         warn!("Parallel request failed: {:?}", e);
                     },
                     Ok(Err(e)) => {
+                        if !mark_if_misconfigured(servers.clone(), &server, e.as_ref()) {
+                            mark_server_less_healthy(servers.clone(), &server, health_cfg.as_ref());
+                        }
                         warn!("Parallel request failed: {:?}", e);
                     },
                     Ok(Ok((perf, repacked))) => {
-                        warn!("Parallel request failed: Performance: {:?}, Response: {:?}", perf, repacked.into_string().await);
+                        match overload_backoff(repacked.status, &repacked.headers, overload) {
+                            Some(backoff) => mark_server_busy(servers.clone(), &server, backoff),
+                            None => mark_server_less_healthy(servers.clone(), &server, health_cfg.as_ref()),
+                        }
+                        warn!("Parallel request failed: Performance: {:?}, Response: {:?}", perf, repacked.into_string(preview_len).await);
                     },
                 }
             }
         });
     }
 
+    let candidates = ok_results.len() + failed_count;
     let ok_servers = ok_results.iter().map(|res_server| res_server.1.clone()).collect::<Vec<String>>();
-    let best = ok_results.into_iter().filter_map(|res_server|
+    let latencies = ok_results.iter().filter_map(|res_server| match res_server {
+        (Ok(Ok((perf, _))), server) => Some((server.clone(), perf.ttft, perf.rate())),
+        _ => None,
+    }).collect::<Vec<_>>();
+    let mut finished = ok_results.into_iter().filter_map(|res_server|
         if let Ok(Ok((perf, repacked))) = res_server.0 {
             Some((perf, repacked, res_server.1))
         } else {
             None
         }
-    ).max_by_key(|(perf, _, _)| perf.duration_tokens);
+    ).collect::<Vec<_>>();
+    // fastest first, ties go to the earliest selected
+    finished.sort_by(|(a, _, _), (b, _, _)| b.rate().total_cmp(&a.rate()));
+    if finished.len() > 1 {
+        let ranking = finished.iter().map(|(perf, _, server)| format!("{} ({:.1} tokens/s)", server, perf.rate())).collect::<Vec<_>>();
+        info!("Ranked racers: {}", ranking.join(", "));
+    }
+    let best = (!finished.is_empty()).then(|| finished.remove(0));
+    abort_losers(finished, model, dopts.runtime.loser_keep_alive.clone());
+    if let Some(mut candidates) = race {
+        if let Some((_, _, best_server)) = &best {
+            candidates.iter_mut().filter(|c| &c.server == best_server).for_each(|c| c.outcome = Outcome::Winner);
+        }
+        dopts.events.publish(&Event::Race(RaceEvent::new(model, candidates)));
+    }
     
-    if let Some((_, resp, best_server)) = best {
+    if let Some((perf, resp, best_server)) = best {
+        if let (Some(conversations), Some(hash)) = (&dopts.conversations, history.last()) {
+            conversations.lock().unwrap().record(*hash, &best_server);
+        }
         // mark more healthy asynchronously
         let best_server_clone = best_server.clone();
-        let servers = servers.clone();
+        let servers_clone = servers.clone();
+        let model_owned = model.to_string();
         tokio::spawn(async move {
-            mark_server_more_healthy(servers.clone(), &best_server_clone, true);
+            let servers = servers_clone;
+            mark_server_more_healthy(servers.clone(), &best_server_clone, true, health_cfg.as_ref());
+            for (server, ttft, rate) in latencies {
+                record_latency(servers.clone(), &server, ttft, latency_alpha);
+                stats::record(&server, &model_owned, ttft, Some(rate), latency_alpha);
+            }
+            heatmap::record(&best_server_clone, &model_owned);
             for server in ok_servers {
                 if server != best_server_clone {
-                    mark_server_more_healthy(servers.clone(), &server, false);
+                    mark_server_more_healthy(servers.clone(), &server, false, health_cfg.as_ref());
                 }
             }
         });
 
         info!("Chosen server {} to serve client {}", best_server, remote_addr);
-        let mut resp_builder = Response::builder().status(u16::from(resp.status));
+        let trail = dopts.audit.as_ref().map(|audit| {
+            let server_name = servers.lock().unwrap().get(&best_server).map(|s| s.name.clone()).unwrap_or_default();
+            audit.lock().unwrap().record(&session, RoutingRecord {
+                at: chrono::Utc::now(),
+                model: model.to_string(),
+                server: best_server.clone(),
+                server_name,
+                candidates,
+                ttft: perf.ttft,
+            })
+        });
+        let upstream = Upstream {
+            name: servers.lock().unwrap().get(&best_server).map(|s| s.name.clone()).unwrap_or_default(),
+            server: best_server.clone(),
+            ttft: perf.ttft,
+            failover: failed_over.contains(&best_server),
+        };
+        let mut resp_builder = Response::builder().status(resp.status);
         for (k, v) in resp.headers.iter() {
-            resp_builder = resp_builder.header(k.to_string(), v.to_str().unwrap());
+            // the annotated final chunk is longer
+            if dopts.upstream_in_body && k == header::CONTENT_LENGTH {
+                continue;
+            }
+            resp_builder = resp_builder.header(k, v);
+        }
+        if let Ok(value) = header::HeaderValue::from_str(&upstream.header_value()) {
+            resp_builder = resp_builder.header("X-LB-Upstream", value);
+        }
+        if let Some((requested, used)) = &substitution {
+            if let Ok(value) = header::HeaderValue::from_str(&format!("{} -> {}", requested, used)) {
+                resp_builder = resp_builder.header("X-Model-Substituted", value);
+            }
+        }
+        if truncated > 0 {
+            resp_builder = resp_builder.header("X-History-Truncated", truncated);
+        }
+        if let (Some(trail), true) = (trail, dopts.audit_header) {
+            // names that are not valid header values are not worth failing the response
+            if let Ok(value) = header::HeaderValue::from_str(&trail_header(&trail)) {
+                resp_builder = resp_builder.header("X-Routing-Trail", value);
+            }
         }
-        let hyper_body = Body::wrap_stream(resp.stream);
+        // keep the server marked busy until the stream is fully relayed
+        let tally = Arc::new(RelayTally::default());
+        let idle = generation.backends.get(&best_server, &upstream.name).timeouts(opts.timeouts, timeout_ft_chosen).idle;
+        let idle_timeout = (idle > 0).then(|| std::time::Duration::from_secs(idle.into()));
+        let stream = match standby {
+            Some(standby) => tentative(best_server.clone(), resp.stream, standby),
+            None => resp.stream,
+        };
+        let guarded = ResponseBodyWithGuard {
+            stream: Received::new(stream, tally.clone()),
+            _guard: ServerGuard::new(servers.clone(), best_server.clone()),
+            _generation: generation,
+            _permit: permit,
+            servers: servers.clone(),
+            key: best_server,
+            had_error: false,
+            idle_timeout,
+            stall_timer: idle_timeout.map(|idle| Box::pin(tokio::time::sleep(idle))),
+            stalled: false,
+            tally: tally.clone(),
+        };
+        let client = remote_addr.to_string();
+        // for the trace of the request, decided once the relay is over
+        resp_builder = resp_builder.extension(tally.clone());
+        let hyper_body = if dopts.upstream_in_body {
+            Body::wrap_stream(Delivered::new(AnnotatedBody::new(guarded, &upstream), tally, upstream.server, client))
+        } else {
+            Body::wrap_stream(Delivered::new(guarded, tally, upstream.server, client))
+        };
         let response = resp_builder.body(hyper_body).unwrap();
         Ok(response)
     } else {
@@ -264,24 +1631,90 @@ pub async fn handle_chat_parallel(
     }
 }
 
+/// Closes the connections of the streams that lost the race, so that their backends
+/// stop generating instead of finishing an answer nobody reads. With a `keep_alive`,
+/// an empty generation then shortens how long their model stays loaded.
+fn abort_losers(losers: Vec<(PerformanceInfo, RepackedResponse, String)>, model: &str, keep_alive: Option<Value>) {
+    for (perf, repacked, server) in losers {
+        drop(repacked);
+        RACE_STATS.aborted.fetch_add(1, Ordering::Relaxed);
+        RACE_STATS.wasted_tokens.fetch_add(perf.tokens as u64, Ordering::Relaxed);
+        info!("Aborted losing stream of server {} after {} tokens", server, perf.tokens);
+        if let Some(keep_alive) = &keep_alive {
+            let body = json!({ "model": model, "keep_alive": keep_alive }).to_string();
+            let req = ("/api/generate".to_string(), reqwest::Method::POST, "/api/generate".to_string(), None, Some(bytes::Bytes::from(body).into()));
+            let keep_alive = keep_alive.clone();
+            tokio::spawn(async move {
+                match send_request(req, &server, TimeoutProfile::read(10)).await.and_then(|r| Ok(r.error_for_status()?)) {
+                    Ok(_) => info!("Set keep_alive {} on losing server {}", keep_alive, server),
+                    Err(e) => warn!("Failed to set keep_alive on losing server {}: {}", server, e),
+                }
+            });
+        }
+    }
+}
+
 pub struct ServerGuard {
     pub servers: SharedServerList,
     pub key: String,
 }
 
+impl ServerGuard {
+    /// Counts a new connection to the server, released when the guard is dropped.
+    pub fn new(servers: SharedServerList, key: String) -> Self {
+        if let Some(server) = servers.lock().unwrap().get_mut(&key) {
+            server.state.connections += 1;
+            server.state.busy = true;
+        }
+        ServerGuard { servers, key }
+    }
+}
+
+/// Counts a request sent to a server until its response is relayed or given up,
+/// so that concurrency caps also see the requests still racing.
+pub struct PendingGuard {
+    servers: SharedServerList,
+    key: String,
+}
+
+impl PendingGuard {
+    pub fn new(servers: SharedServerList, key: String) -> Self {
+        if let Some(server) = servers.lock().unwrap().get_mut(&key) {
+            server.state.pending += 1;
+        }
+        PendingGuard { servers, key }
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        if let Some(server) = self.servers.lock().unwrap().get_mut(&self.key) {
+            server.state.pending = server.state.pending.saturating_sub(1);
+        }
+    }
+}
+
 impl Drop for ServerGuard {
     fn drop(&mut self) {
-        let mut servers_lock = self.servers.lock().unwrap();
-        if let Some(server) = servers_lock.get_mut(&self.key) {
-            server.state.busy = false;
-            if matches!(server.state.failure_record, FailureRecord::Reliable) {
-                info!("Server {} ({}) is now available", self.key, server.name);
-            }
-            else {
-                info!("Connection closed with unreliable server {} ({})", self.key, server.name);
+        let (name, reliable) = {
+            let mut servers_lock = self.servers.lock().unwrap();
+            match servers_lock.get_mut(&self.key) {
+                Some(server) => {
+                    server.state.connections = server.state.connections.saturating_sub(1);
+                    server.state.busy = server.state.connections > 0;
+                    notify_server_released();
+                    (server.name.clone(), matches!(server.state.failure_record, FailureRecord::Reliable))
+                },
+                None => return,
             }
-            print_server_statuses(&servers_lock);
+        };
+        if reliable {
+            info!("Server {} ({}) is now available", self.key, name);
+        }
+        else {
+            info!("Connection closed with unreliable server {} ({})", self.key, name);
         }
+        request_status_report();
     }
 }
 
@@ -289,9 +1722,42 @@ impl Drop for ServerGuard {
 pub struct ResponseBodyWithGuard<S> {
     pub stream: S,
     pub _guard: ServerGuard,
+    pub _generation: GenerationGuard, // keeps the request counted in its profiles generation
+    pub _permit: Option<OwnedSemaphorePermit>, // counts the generation against the cap of its model
     pub servers: SharedServerList,
     pub key: String,
     pub had_error: bool,
+    /// Longest wait for the next chunk before the stream is given up as stalled.
+    pub idle_timeout: Option<std::time::Duration>,
+    pub stall_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+    pub stalled: bool,
+    /// Told about a stall, the relay is truncated however many bytes reach the client.
+    pub tally: Arc<RelayTally>,
+}
+
+impl<S> ResponseBodyWithGuard<S> {
+    /// Gives the server a worse failure record, unless it is going down as announced.
+    fn demote(&self, error: &dyn std::fmt::Display) {
+        let demoted = {
+            let mut servers_lock = self.servers.lock().unwrap();
+            // a server going down as announced is not unreliable
+            servers_lock.get_mut(&self.key).filter(|server| server.state.leaving.is_none()).map(|server| {
+                let was_reliable = matches!(server.state.failure_record, FailureRecord::Reliable);
+                server.state.failure_record = if was_reliable {
+                    FailureRecord::Unreliable
+                } else {
+                    FailureRecord::SecondChanceGiven
+                };
+                (server.name.clone(), was_reliable)
+            })
+        };
+        match demoted {
+            Some((name, true)) => error!("Server {} ({}) failed during streaming, now marked Unreliable. Error: {}", self.key, name, error),
+            Some((name, false)) => error!("Unreliable server {} ({}) failed during streaming. Error: {}", self.key, name, error),
+            None => {},
+        }
+        request_status_report();
+    }
 }
 
 impl<S> Stream for ResponseBodyWithGuard<S>
@@ -304,68 +1770,251 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
+        if self.stalled {
+            return Poll::Ready(None);
+        }
         let stream = Pin::new(&mut self.stream);
         match stream.poll_next(cx) {
-            Poll::Ready(Some(Ok(bytes))) => Poll::Ready(Some(Ok(bytes))),
+            Poll::Ready(Some(Ok(bytes))) => {
+                if let Some(idle) = self.idle_timeout {
+                    if let Some(timer) = self.stall_timer.as_mut() {
+                        timer.as_mut().reset(tokio::time::Instant::now() + idle);
+                    }
+                }
+                Poll::Ready(Some(Ok(bytes)))
+            },
             Poll::Ready(Some(Err(e))) => {
                 // An error occurred during streaming
                 self.had_error = true; // Mark that an error has occurred
-                {
-                    let mut servers_lock = self.servers.lock().unwrap();
-                    if let Some(server) = servers_lock.get_mut(&self.key) {
-                        if matches!(server.state.failure_record, FailureRecord::Reliable) {
-                            server.state.failure_record = FailureRecord::Unreliable;
-                            error!("Server {} ({}) failed during streaming, now marked Unreliable. Error: {}", self.key, server.name, e);
-                        }
-                        else {
-                            server.state.failure_record = FailureRecord::SecondChanceGiven;
-                            error!("Unreliable server {} ({}) failed during streaming. Error: {}", self.key, server.name, e);
-                        }
-                        print_server_statuses(&servers_lock);
-                    }
-                }
+                self.demote(&e);
                 // Return the error to the client
-                Poll::Ready(Some(Err(std::io::Error::new(std::io::ErrorKind::Other, e))))
+                Poll::Ready(Some(Err(std::io::Error::other(e))))
             },
             Poll::Ready(None) => {
                 if !self.had_error {
                     // Streaming ended successfully
                     // Mark the server as Reliable
-                    let mut servers_lock = self.servers.lock().unwrap();
-                    if let Some(server) = servers_lock.get_mut(&self.key) {
-                        if !matches!(server.state.failure_record, FailureRecord::Reliable) {
+                    let promoted = {
+                        let mut servers_lock = self.servers.lock().unwrap();
+                        servers_lock.get_mut(&self.key).and_then(|server| {
+                            if matches!(server.state.failure_record, FailureRecord::Reliable) {
+                                return None;
+                            }
                             server.state.failure_record = FailureRecord::Reliable;
-                            info!("Server {} ({}) completed streaming successfully and is now marked Reliable", self.key, server.name);
-                            print_server_statuses(&servers_lock);
-                        }
+                            Some(server.name.clone())
+                        })
+                    };
+                    if let Some(name) = promoted {
+                        info!("Server {} ({}) completed streaming successfully and is now marked Reliable", self.key, name);
+                        request_status_report();
                     }
                 }
                 Poll::Ready(None)
             },
-            Poll::Pending => Poll::Pending,
+            Poll::Pending => {
+                // a backend that stops sending without closing the connection would hang the client
+                let stalled = self.stall_timer.as_mut().is_some_and(|timer| std::future::Future::poll(timer.as_mut(), cx).is_ready());
+                if !stalled {
+                    return Poll::Pending;
+                }
+                let secs = self.idle_timeout.unwrap_or_default().as_secs();
+                self.had_error = true;
+                self.stalled = true;
+                self.tally.fail_backend();
+                self.demote(&format!("no chunk for {}s", secs));
+                // ends the stream as a client of Ollama expects an error to be reported
+                let chunk = json!({ "error": format!("Server stopped responding for {}s mid-stream", secs) });
+                Poll::Ready(Some(Ok(bytes::Bytes::from(format!("{}\n", chunk)))))
+            },
         }
     }
 }
 
+/// Merges the model listings picked by `pick` from every server.
+/// When `annotate` is set, each model gets an `available_now` field telling whether
+/// some alive, idle server already has it loaded, i.e. whether it would respond immediately.
+/// When `with_servers` is set, each model gets a `servers` field listing the alive servers
+/// picking it, and whether they have it loaded.
+fn merge_models(
+    snaps: &HashMap<String, ServerSnapshot>,
+    pick: fn(&ServerSnapshot) -> &HashMap<String, Option<ModelConfig>>,
+    annotate: bool,
+    with_servers: bool,
+) -> Vec<Value> {
+    let mut merged_models = HashMap::new();
+    for snap in snaps.values() {
+        info!("Server {} has {} models", snap.name, pick(snap).len());
+        merged_models.extend(pick(snap).clone());
+    }
+    info!("Total models: {}", merged_models.len());
+    // collect all model details
+    merged_models.into_iter().map(|(name, model)| {
+        let mut detail = model.unwrap().detail;
+        if annotate {
+            let available_now = snaps.values().any(|snap|
+                snap.state.health != Health::Dead && !snap.state.busy && snap.actives.contains_key(&name)
+            );
+            if let Some(obj) = detail.as_object_mut() {
+                obj.insert("available_now".to_string(), json!(available_now));
+            }
+        }
+        if with_servers {
+            let mut hosts = snaps.iter()
+                .filter(|(_, snap)| snap.state.health != Health::Dead && pick(snap).contains_key(&name))
+                .collect::<Vec<_>>();
+            hosts.sort_by_key(|(addr, _)| addr.as_str());
+            let hosts = hosts.into_iter().map(|(addr, snap)| json!({
+                "server": addr,
+                "name": snap.name,
+                "loaded": snap.actives.contains_key(&name),
+            })).collect::<Vec<_>>();
+            if let Some(obj) = detail.as_object_mut() {
+                obj.insert("servers".to_string(), json!(hosts));
+            }
+        }
+        detail
+    }).collect()
+}
+
 pub async fn handle_tags(
     _req: Request<Body>,
     servers: SharedServerList,
     _remote_addr: std::net::SocketAddr,
+    annotate: bool,
+    with_servers: bool,
 ) -> Result<Response<Body>, Infallible> {
     let snaps = snapshot_servers(servers, true);
-    let mut merged_models = HashMap::new();
-    for snap in snaps.values() {
-        info!("Server {} has {} models", snap.name, snap.models.len());
-        merged_models.extend(snap.models.clone());
-    }
-    info!("Total models: {}", merged_models.len());
-    // collect all model details
-    let models: Vec<Value> = merged_models.into_iter().map(|(_name, model)|
-        model.unwrap().detail
-    ).collect();
+    let models = merge_models(&snaps, |snap| &snap.models, annotate, with_servers);
     return Ok(make_json_resp(StatusCode::OK, json!({ "models": models })));
 }
 
+pub async fn handle_ps(
+    _req: Request<Body>,
+    servers: SharedServerList,
+    _remote_addr: std::net::SocketAddr,
+    annotate: bool,
+    with_servers: bool,
+) -> Result<Response<Body>, Infallible> {
+    let snaps = snapshot_servers(servers, true);
+    let models = merge_models(&snaps, |snap| &snap.actives, annotate, with_servers);
+    Ok(make_json_resp(StatusCode::OK, json!({ "models": models })))
+}
+
+/// The server each blob digest was checked or uploaded on, for the `/api/create` using the
+/// blob to go there too. Pins older than `BLOB_PIN_TTL` are forgotten.
+static BLOB_SERVERS: LazyLock<Mutex<HashMap<String, (String, std::time::Instant)>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+const BLOB_PIN_TTL: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+
+/// Forgets the blobs pinned to the servers of a previous load balancer.
+pub fn reset_blob_pins() {
+    BLOB_SERVERS.lock().unwrap().clear();
+}
+
+/// The server a blob was pinned to, if it is still configured and alive.
+fn blob_server(servers: SharedServerList, digest: &str) -> Option<String> {
+    let pinned = BLOB_SERVERS.lock().unwrap().get(digest).map(|(server, _)| server.clone())?;
+    let alive = servers.lock().unwrap().get(&pinned).is_some_and(|server| server.state.health != Health::Dead);
+    alive.then_some(pinned)
+}
+
+fn pin_blob(digest: &str, server: &str) {
+    let mut pins = BLOB_SERVERS.lock().unwrap();
+    pins.retain(|_, (_, pinned_at)| pinned_at.elapsed() < BLOB_PIN_TTL);
+    pins.insert(digest.to_string(), (server.to_string(), std::time::Instant::now()));
+}
+
+/// The blob digests a create request refers to: the values of `files` and `adapters`,
+/// and the `@sha256:...` references of a Modelfile.
+fn blob_digests(body: &Value) -> Vec<String> {
+    let mut digests = ["files", "adapters"].iter()
+        .filter_map(|key| body[key].as_object())
+        .flat_map(|files| files.values().filter_map(Value::as_str))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    if let Some(modelfile) = body["modelfile"].as_str() {
+        digests.extend(modelfile.split_whitespace().filter_map(|word| word.strip_prefix('@')).map(str::to_string));
+    }
+    digests
+}
+
+/// The server named by the `X-Ollama-Server` header, by address or name.
+fn wanted_server(req: &Request<Body>, servers: SharedServerList) -> Result<Option<String>, String> {
+    match req.headers().get("x-ollama-server").and_then(|v| v.to_str().ok()) {
+        Some(wanted) => match find_server(servers, Some(wanted), None) {
+            Some(server) => Ok(Some(server)),
+            None => Err(format!("Unknown server {}", wanted)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Sends a request to one server and relays its response as it comes.
+async fn forward_to_server(unpacked_req: UnpackedRequest, server: &str, remote_addr: std::net::SocketAddr, timeouts: TimeoutProfile) -> Response<Body> {
+    info!("Sending {} {} of client {} to server {}", unpacked_req.1, unpacked_req.2, remote_addr, server);
+    match send_request(unpacked_req, server, timeouts).await {
+        Ok(response) => {
+            let mut resp_builder = Response::builder().status(response.status());
+            for (key_h, value) in response.headers() {
+                resp_builder = resp_builder.header(key_h, value);
+            }
+            resp_builder.body(Body::wrap_stream(idle_limited(response.bytes_stream(), timeouts.idle))).unwrap()
+        },
+        Err(e) => {
+            warn!("Request to server {} failed: {:?}", server, e);
+            make_json_resp(StatusCode::BAD_GATEWAY, json!({ "error": format!("Server {} failed: {}", server, e) }))
+        },
+    }
+}
+
+/// Passes blob uploads and checks through to one server: the one named by `X-Ollama-Server`,
+/// by address or name, else the one the blob was already checked or uploaded on, else the
+/// healthiest one. Uploads are streamed, not buffered.
+async fn handle_blobs(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    timeouts: TimeoutProfile,
+) -> Result<Response<Body>, Infallible> {
+    let digest = req.uri().path().trim_start_matches("/api/blobs/").to_string();
+    let server = match wanted_server(&req, servers.clone()) {
+        Ok(Some(server)) => Some(server),
+        Ok(None) => blob_server(servers.clone(), &digest).or_else(|| healthiest_server(servers.clone())),
+        Err(e) => return Ok(make_json_resp(StatusCode::NOT_FOUND, json!({ "error": e }))),
+    };
+    let Some(server) = server else {
+        return Ok(make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": "No server to send the blob to" })));
+    };
+    // pinned before the check answers, so that the upload following a 404 goes to the same server
+    pin_blob(&digest, &server);
+    Ok(forward_to_server(unpack_req_streamed(req), &server, remote_addr, timeouts).await)
+}
+
+/// Creates a model on the server that has its blobs: the one named by `X-Ollama-Server`, else
+/// the one its blobs were uploaded to, else the healthiest one.
+async fn handle_create(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    timeouts: TimeoutProfile,
+) -> Result<Response<Body>, Infallible> {
+    let wanted = match wanted_server(&req, servers.clone()) {
+        Ok(wanted) => wanted,
+        Err(e) => return Ok(make_json_resp(StatusCode::NOT_FOUND, json!({ "error": e }))),
+    };
+    let unpacked_req = match unpack_req(req).await {
+        Ok(req) => req,
+        Err(e) => return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": format!("Error handling request: {}", e) }))),
+    };
+    let body = unpacked_req.4.as_ref().and_then(RequestBody::as_bytes).and_then(|bytes| parse_body(bytes).ok()).unwrap_or_default();
+    let server = wanted
+        .or_else(|| blob_digests(&body).iter().find_map(|digest| blob_server(servers.clone(), digest)))
+        .or_else(|| healthiest_server(servers.clone()));
+    let Some(server) = server else {
+        return Ok(make_json_resp(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": "No server to create the model on" })));
+    };
+    Ok(forward_to_server(unpacked_req, &server, remote_addr, timeouts).await)
+}
+
 pub async fn handle_generate(
     req: Request<Body>,
     _servers: SharedServerList,
@@ -377,7 +2026,7 @@ pub async fn handle_generate(
             return Ok(make_json_resp(StatusCode::BAD_REQUEST, json!({ "error": format!("Error handling request: {}", e) })));
         }
     };
-    let body_bytes = unpacked_req.4.as_ref().unwrap();
+    let body_bytes = unpacked_req.4.as_ref().and_then(RequestBody::as_bytes).unwrap();
     let body = match parse_body(body_bytes) {
         Ok(body) => body,
         Err(e) => {
@@ -386,7 +2035,7 @@ pub async fn handle_generate(
     };
 
     let resp_501: fn(&Value, &str) -> Result<Response<Body>, Infallible> = |body, msg| {
-        error!("Invalid request body: {}", body);
+        error!("Invalid request body: {}", redact_json(body));
         Ok(make_json_resp(StatusCode::NOT_IMPLEMENTED, json!({ "error": msg })))
     };
 
@@ -0,0 +1,50 @@
+/// How the health score of a server moves with the outcome of its requests.
+/// Servers are selected in proportion to their score, a dead server has none.
+pub trait HealthPolicy: Send + Sync + std::fmt::Debug {
+    /// Score of a freshly synced or resurrected server.
+    fn initial(&self) -> f32;
+
+    /// Score after a successful request, `best` if the server won the race.
+    fn succeeded(&self, health: f32, best: bool) -> f32;
+
+    /// Score after a failed request, `None` if the server is now dead.
+    fn failed(&self, health: f32) -> Option<f32>;
+}
+
+/// Additive increase on success, multiplicative decrease on failure, the historical behavior.
+#[derive(Clone, Copy, Debug)]
+pub struct IncrementDecay {
+    pub initial: f32,
+    /// Added to the server that won a race.
+    pub best_increment: f32,
+    /// Added to the other servers that answered successfully.
+    pub increment: f32,
+    /// The score of a failed server is divided by this, it dies below `initial`.
+    pub decay: f32,
+}
+
+impl Default for IncrementDecay {
+    fn default() -> Self {
+        IncrementDecay {
+            initial: 1.0,
+            best_increment: 4.0,
+            increment: 2.0,
+            decay: 2.0,
+        }
+    }
+}
+
+impl HealthPolicy for IncrementDecay {
+    fn initial(&self) -> f32 {
+        self.initial
+    }
+
+    fn succeeded(&self, health: f32, best: bool) -> f32 {
+        health + if best { self.best_increment } else { self.increment }
+    }
+
+    fn failed(&self, health: f32) -> Option<f32> {
+        let health = health / self.decay;
+        (health >= self.initial).then_some(health)
+    }
+}
@@ -0,0 +1,88 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::time::Duration;
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use hyper::{Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::body::{self, Body, BoxError};
+
+enum Phase {
+    /// Backends being selected and raced, the client gets heartbeats meanwhile.
+    Waiting(JoinHandle<Result<Response<Body>, Infallible>>, tokio::time::Interval),
+    Relaying(Body),
+    Done,
+}
+
+/// An empty assistant chunk: Ollama clients append its empty content and keep reading.
+fn heartbeat_line(model: &str) -> Bytes {
+    Bytes::from(format!("{}\n", json!({
+        "model": model,
+        "created_at": chrono::Utc::now().to_rfc3339(),
+        "message": { "role": "assistant", "content": "" },
+        "done": false,
+    })))
+}
+
+/// The error of a failed response as a stream line, the status being already sent.
+fn error_line(status: StatusCode, body: &[u8]) -> Bytes {
+    let error = serde_json::from_slice::<Value>(body).ok()
+        .and_then(|body| body["error"].as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("{}: {}", status, String::from_utf8_lossy(body)));
+    Bytes::from(format!("{}\n", json!({ "error": error })))
+}
+
+/// Answers a streamed chat request right away and sends heartbeats every `interval`
+/// while `handle` selects and races the backends, so that clients with short timeouts
+/// know the request was accepted. The headers of the backend response are lost, and
+/// a failure is reported in the stream like Ollama does, the status being already 200.
+/// Requests with `"stream": false` are handled as usual.
+pub async fn with_heartbeat<F, Fut>(req: Request<Body>, interval: Duration, handle: F) -> Result<Response<Body>, Infallible>
+where
+    F: FnOnce(Request<Body>) -> Fut,
+    Fut: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    let (parts, body) = req.into_parts();
+    let whole_body = body::to_bytes(body).await.unwrap_or_default();
+    let parsed = serde_json::from_slice::<Value>(&whole_body).unwrap_or_default();
+    let req = Request::from_parts(parts, Body::from(whole_body));
+    if parsed["stream"] == json!(false) {
+        return handle(req).await;
+    }
+    let model = parsed["model"].as_str().unwrap_or_default().to_string();
+    let handling = tokio::spawn(handle(req));
+    // the first tick is immediate
+    let ticker = tokio::time::interval(interval);
+    let chunks = stream::unfold(Phase::Waiting(handling, ticker), move |phase| {
+        let model = model.clone();
+        async move {
+            match phase {
+                Phase::Waiting(mut handling, mut ticker) => tokio::select! {
+                    biased;
+                    res = &mut handling => match res {
+                        Ok(Ok(resp)) if resp.status().is_success() => {
+                            let mut body = resp.into_body();
+                            body.next().await.map(|chunk| (chunk, Phase::Relaying(body)))
+                        },
+                        Ok(Ok(resp)) => {
+                            let status = resp.status();
+                            let body = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+                            Some((Ok(error_line(status, &body)), Phase::Done))
+                        },
+                        Err(e) => Some((Ok(error_line(StatusCode::INTERNAL_SERVER_ERROR, e.to_string().as_bytes())), Phase::Done)),
+                    },
+                    _ = ticker.tick() => Some((Ok::<_, BoxError>(heartbeat_line(&model)), Phase::Waiting(handling, ticker))),
+                },
+                Phase::Relaying(mut body) => body.next().await.map(|chunk| (chunk, Phase::Relaying(body))),
+                Phase::Done => None,
+            }
+        }
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::wrap_stream(chunks))
+        .unwrap())
+}
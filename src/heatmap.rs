@@ -0,0 +1,64 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+use chrono::{DateTime, Utc};
+
+use crate::schema::{HeatmapReport, HeatmapSeries, SCHEMA_VERSION};
+
+/// Hours kept, a week.
+const RETENTION_HOURS: i64 = 24 * 7;
+
+const HOUR: i64 = 3600;
+
+/// Requests per hour, by the timestamp of its start.
+type Buckets = BTreeMap<i64, u64>;
+
+/// Requests served per model and server, bucketed by hour, to see when and where each model
+/// is used: what to keep resident, and what to delete.
+static HEATMAP: LazyLock<Mutex<HashMap<(String, String), Buckets>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn hour_of(at: DateTime<Utc>) -> i64 {
+    at.timestamp() - at.timestamp().rem_euclid(HOUR)
+}
+
+/// Forgets the requests counted for the servers of a previous load balancer.
+pub fn reset() {
+    HEATMAP.lock().unwrap().clear();
+}
+
+/// Counts a request for `model` served by `server`.
+pub fn record(server: &str, model: &str) {
+    let hour = hour_of(Utc::now());
+    let mut heatmap = HEATMAP.lock().unwrap();
+    let buckets = heatmap.entry((model.to_string(), server.to_string())).or_default();
+    *buckets.entry(hour).or_default() += 1;
+    // the expired hours go as new ones come
+    if buckets.len() as i64 > RETENTION_HOURS {
+        let oldest = hour - (RETENTION_HOURS - 1) * HOUR;
+        buckets.retain(|h, _| *h >= oldest);
+    }
+}
+
+/// The last `hours` hours, the current one included, optionally of a single model.
+/// Every series has one count per hour, oldest first.
+pub fn report(hours: i64, model: Option<&str>) -> HeatmapReport {
+    let hours = hours.clamp(1, RETENTION_HOURS);
+    let current = hour_of(Utc::now());
+    let start = current - (hours - 1) * HOUR;
+    let heatmap = HEATMAP.lock().unwrap();
+    let mut series = heatmap.iter()
+        .filter(|((m, _), _)| model.is_none_or(|model| model == m))
+        .map(|((model, server), buckets)| HeatmapSeries {
+            model: model.clone(),
+            server: server.clone(),
+            counts: (0..hours).map(|i| buckets.get(&(start + i * HOUR)).copied().unwrap_or(0)).collect(),
+        })
+        .filter(|series| series.counts.iter().any(|count| *count > 0))
+        .collect::<Vec<_>>();
+    series.sort_by(|a, b| a.model.cmp(&b.model).then_with(|| a.server.cmp(&b.server)));
+    HeatmapReport {
+        schema_version: SCHEMA_VERSION,
+        start: DateTime::from_timestamp(start, 0).unwrap_or_default().to_rfc3339(),
+        bucket_secs: HOUR as u64,
+        series,
+    }
+}
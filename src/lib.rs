@@ -0,0 +1,72 @@
+//! A load balancer for Ollama servers, also usable in-process by other Rust services.
+//!
+//! The `ollama_load_balancer` binary is a thin wrapper around [`LoadBalancer`]:
+//!
+//! ```no_run
+//! use clap::Parser;
+//! use ollama_load_balancer::{config::Args, LoadBalancer};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! LoadBalancer::builder().args(Args::parse()).build().await?.serve().await
+//! # }
+//! ```
+//!
+//! Some settings are global to the process, so a process runs a single load balancer.
+
+pub mod config;
+mod state;
+mod handler;
+mod backend;
+mod api;
+mod utils;
+mod auth;
+mod authz;
+mod accounting;
+mod features;
+mod redact;
+mod storage;
+mod strategy;
+mod profiles;
+mod prober;
+pub mod schema;
+mod audit;
+mod traces;
+mod runtime;
+mod coalesce;
+mod events;
+mod cache;
+mod pull;
+mod mirror;
+mod benchmark;
+mod shaping;
+mod body;
+mod compression;
+mod heartbeat;
+mod listener;
+mod concurrency;
+mod dns;
+#[cfg(feature = "discovery")]
+mod dns_sd;
+#[cfg(feature = "discovery")]
+mod discovery;
+mod registry;
+mod membership;
+mod stats;
+pub mod health;
+mod heatmap;
+mod autoscale;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod upstream;
+mod relay;
+mod tls;
+mod balancer;
+mod check;
+mod status;
+#[cfg(unix)]
+mod systemd;
+
+pub use balancer::{LoadBalancer, LoadBalancerBuilder};
+pub use body::Body;
+pub use check::{check, ping};
+pub use status::{print_servers, status};
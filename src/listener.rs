@@ -0,0 +1,144 @@
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+#[cfg(unix)]
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tracing::{info, warn};
+
+/// A client connection, over TCP or a unix socket.
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// Address given to the clients of the unix socket, which have none.
+/// Unspecified, so that it never matches a backend.
+pub const UNIX_PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// Where the load balancer listens: `ADDR:PORT`, or `unix:PATH` for local deployments
+/// behind a reverse proxy, unless systemd passes a socket. The socket file is removed
+/// when the listener is dropped, but for sockets passed by systemd, which owns them.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// With `reuse_port`, TCP addresses are bound with SO_REUSEPORT, shared with the other
+    /// processes binding them the same way.
+    pub async fn bind(listen: &str, reuse_port: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        #[cfg(unix)]
+        if let Some(fd) = crate::systemd::listen_fds().first() {
+            info!("Listening on the socket passed by systemd instead of {}", listen);
+            return from_fd(*fd);
+        }
+        if let Some(path) = listen.strip_prefix("unix:") {
+            if reuse_port {
+                return Err(format!("SO_REUSEPORT only applies to TCP, not to unix:{}", path).into());
+            }
+            #[cfg(unix)]
+            return Ok(Listener::Unix(bind_unix(path)?, Some(PathBuf::from(path))));
+            #[cfg(not(unix))]
+            return Err(format!("Unix sockets are not supported on this platform: {}", path).into());
+        }
+        let addr: SocketAddr = listen.parse()?;
+        if reuse_port {
+            return Ok(Listener::Tcp(bind_reuse_port(addr)?));
+        }
+        Ok(Listener::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    pub async fn accept(&self) -> io::Result<(Box<dyn Io>, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), UNIX_PEER))
+            },
+        }
+    }
+}
+
+/// Takes over a socket passed by socket activation, TCP or unix.
+#[cfg(unix)]
+fn from_fd(fd: std::os::fd::RawFd) -> Result<Listener, Box<dyn std::error::Error>> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    // SAFETY: systemd hands the descriptors from LISTEN_FDS_START on over to us, nothing else owns them
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // the address family tells the socket types apart
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+    }
+    // SAFETY: as above, the descriptor was just released by the TCP listener
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    unix.local_addr().map_err(|e| format!("Socket passed by systemd is neither TCP nor unix: {}", e))?;
+    unix.set_nonblocking(true)?;
+    Ok(Listener::Unix(UnixListener::from_std(unix)?, None))
+}
+
+/// Binds a TCP address that the new process of an upgrade can bind too while this one drains.
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn bind_reuse_port(addr: SocketAddr) -> Result<TcpListener, Box<dyn std::error::Error>> {
+    let socket = if addr.is_ipv4() { tokio::net::TcpSocket::new_v4()? } else { tokio::net::TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(1024)?)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn bind_reuse_port(addr: SocketAddr) -> Result<TcpListener, Box<dyn std::error::Error>> {
+    Err(format!("SO_REUSEPORT is not supported on this platform, cannot share {}", addr).into())
+}
+
+/// Binds a unix socket, replacing the file left behind by an instance that did not exit cleanly.
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<UnixListener, Box<dyn std::error::Error>> {
+    use std::os::unix::fs::FileTypeExt;
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if is_socket {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!("Another process is listening on {}", path).into());
+        }
+        info!("Removing stale socket {}", path);
+        std::fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "http://{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, Some(path)) => write!(f, "unix:{}", path.display()),
+            Listener::Unix(listener, None) => match listener.local_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_path_buf())) {
+                Some(path) => write!(f, "unix:{} (systemd)", path.display()),
+                None => write!(f, "unix socket (systemd)"),
+            },
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, Some(path)) = self {
+            if let Err(e) = std::fs::remove_file(path.as_path()) {
+                warn!("Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex};
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::stream::{self, Stream, StreamExt};
+use ordermap::OrderMap;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use crate::schema::{MemberChange, MemberReport, MembershipEvent, MembershipReport, SCHEMA_VERSION};
+use crate::state::{OllamaServer, SharedServerList};
+
+/// Changes kept for watchers resuming from a revision, older ones get a snapshot instead.
+const HISTORY: usize = 1024;
+
+/// Changes buffered for each watcher, a slower one is disconnected and resumes from its revision.
+const WATCH_BUFFER: usize = 256;
+
+pub enum Change {
+    Added,
+    Removed,
+    Draining,
+    Rejoined,
+}
+
+/// Revisioned changefeed of the backend membership, for external controllers to reconcile
+/// against, e.g. autoscalers or DNS updaters. Changes are recorded under the server list lock,
+/// so that a snapshot and its revision always agree.
+struct Membership {
+    revision: u64,
+    history: VecDeque<MembershipEvent>,
+    sender: broadcast::Sender<MembershipEvent>,
+}
+
+static MEMBERSHIP: LazyLock<Mutex<Membership>> = LazyLock::new(|| Mutex::new(Membership {
+    revision: 0,
+    history: VecDeque::new(),
+    sender: broadcast::channel(WATCH_BUFFER).0,
+}));
+
+/// Starts the changefeed over at revision 0, disconnecting the watchers of a previous load balancer.
+pub fn reset() {
+    *MEMBERSHIP.lock().unwrap() = Membership {
+        revision: 0,
+        history: VecDeque::new(),
+        sender: broadcast::channel(WATCH_BUFFER).0,
+    };
+}
+
+/// Records a change of `server`, to be called with the server list locked.
+pub fn record(change: Change, server: &str, name: &str) {
+    let mut membership = MEMBERSHIP.lock().unwrap();
+    membership.revision += 1;
+    let change_report = MemberChange {
+        revision: membership.revision,
+        at: Utc::now().to_rfc3339(),
+        server: server.to_string(),
+        name: name.to_string(),
+    };
+    let event = match change {
+        Change::Added => MembershipEvent::Added(change_report),
+        Change::Removed => MembershipEvent::Removed(change_report),
+        Change::Draining => MembershipEvent::Draining(change_report),
+        Change::Rejoined => MembershipEvent::Rejoined(change_report),
+    };
+    if membership.history.len() == HISTORY {
+        membership.history.pop_front();
+    }
+    membership.history.push_back(event.clone());
+    // nobody watching is not an error
+    let _ = membership.sender.send(event);
+}
+
+fn report(servers: &OrderMap<String, OllamaServer>, revision: u64) -> MembershipReport {
+    MembershipReport {
+        schema_version: SCHEMA_VERSION,
+        revision,
+        servers: servers.iter().map(|(addr, srv)| MemberReport {
+            address: addr.clone(),
+            name: srv.name.clone(),
+            draining: srv.state.leaving.is_some(),
+        }).collect(),
+    }
+}
+
+/// The backends at the current revision.
+pub fn snapshot(servers: &SharedServerList) -> MembershipReport {
+    let servers = servers.lock().unwrap();
+    let revision = MEMBERSHIP.lock().unwrap().revision;
+    report(&servers, revision)
+}
+
+/// Every change after `since` if it is still in the history, or else a snapshot, then every
+/// change from now on, one JSON document per line. The stream ends if the watcher falls behind.
+pub fn watch(servers: &SharedServerList, since: Option<u64>) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    let (receiver, first) = {
+        let servers = servers.lock().unwrap();
+        let membership = MEMBERSHIP.lock().unwrap();
+        let oldest = membership.revision - membership.history.len() as u64;
+        let first = match since {
+            // the history holds every revision after the oldest one
+            Some(since) if since >= oldest && since <= membership.revision => membership.history.iter()
+                .filter(|event| event.revision() > since)
+                .cloned()
+                .collect(),
+            _ => vec![MembershipEvent::Snapshot(report(&servers, membership.revision))],
+        };
+        (membership.sender.subscribe(), first)
+    };
+    let changes = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event, receiver)),
+            Err(RecvError::Lagged(n)) => {
+                warn!("Membership watcher lagging by {} changes, disconnecting it", n);
+                None
+            },
+            Err(RecvError::Closed) => None,
+        }
+    });
+    stream::iter(first).chain(changes).map(|event| {
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        Ok(Bytes::from(line))
+    })
+}
@@ -0,0 +1,58 @@
+use std::sync::atomic::AtomicU64;
+
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+
+use crate::concurrency::InflightLimit;
+use crate::relay::RELAY_STATS;
+use crate::schema::{AutoscaleReport, RelayReport};
+
+/// Content type of the metrics, OpenMetrics which Prometheus reads as well.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+fn gauge(registry: &mut Registry, name: &str, help: &str, value: f64) {
+    let gauge = Gauge::<f64, AtomicU64>::default();
+    gauge.set(value);
+    registry.register(name, help, gauge);
+}
+
+/// A counter by outcome, named with a `_total` suffix once encoded.
+fn counter(registry: &mut Registry, name: &str, help: &str, values: &[(&str, u64)]) {
+    let family = Family::<Vec<(String, String)>, Counter>::default();
+    for (outcome, value) in values {
+        family.get_or_create(&vec![("outcome".to_string(), outcome.to_string())]).inc_by(*value);
+    }
+    registry.register(name, help, family);
+}
+
+/// The metrics served on `/metrics`, in the OpenMetrics text format. The help texts get
+/// their final period from the encoder.
+pub fn render(autoscale: &AutoscaleReport, streams: usize, inflight: &InflightLimit) -> String {
+    let mut registry = Registry::with_prefix("ollama_lb");
+    gauge(&mut registry, "servers", "Servers known to the balancer", autoscale.current_servers as f64);
+    gauge(&mut registry, "healthy_servers", "Servers taking requests", autoscale.healthy_servers as f64);
+    gauge(&mut registry, "streams_in_flight", "Streams being relayed from the servers", streams as f64);
+    gauge(&mut registry, "queue_depth", "Average requests waiting for a server over the autoscaling window", autoscale.queue_depth);
+    gauge(&mut registry, "utilization", "Average share of the healthy servers relaying a stream over the autoscaling window", autoscale.utilization);
+    gauge(&mut registry, "slo_compliance", "Share of the healthy servers meeting the time to first token SLO", autoscale.slo_compliance);
+    gauge(&mut registry, "autoscale_desired_servers", "Servers the demand calls for", autoscale.desired_servers as f64);
+    gauge(&mut registry, "inflight_requests", "Inference requests in flight, until their response is relayed", inflight.current() as f64);
+    if let Some(max) = inflight.max() {
+        gauge(&mut registry, "inflight_limit", "Inference requests allowed in flight by --max-inflight", max as f64);
+    }
+    counter(&mut registry, "inflight_rejected", "Inference requests turned away over --max-inflight", &[("rejected", inflight.rejected())]);
+    let relay = RelayReport::from_stats(&RELAY_STATS);
+    counter(&mut registry, "relays", "Relayed responses by how they ended", &[
+        ("completed", relay.completed),
+        ("client_disconnect", relay.client_disconnects),
+        ("truncated", relay.truncated),
+        ("short", relay.short),
+    ]);
+    let mut out = String::new();
+    // writing to a String cannot fail
+    let _ = encode(&mut out, &registry);
+    out
+}
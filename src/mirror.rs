@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use hyper::{header, Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::body::Body;
+use crate::tls;
+
+/// Bytes read from the disk at once when serving a cached blob.
+const READ_CHUNK: usize = 256 * 1024;
+
+/// Pull-through cache of the Ollama registry, served under `/v2/`.
+/// Backends pull from it with `ollama pull <host>/library/<model> --insecure`,
+/// so a model is only downloaded from the internet once for the whole fleet.
+#[derive(Debug)]
+pub struct RegistryMirror {
+    dir: PathBuf,
+    upstream: String,
+    /// Address the backends reach the balancer at, models are rewritten to pull through it.
+    pub host: Option<String>,
+    filling: Mutex<HashMap<String, watch::Receiver<Fill>>>,
+    client: reqwest::Client,
+}
+
+impl RegistryMirror {
+    pub fn new(dir: &str, upstream: &str, host: Option<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(dir.join("blobs"))?;
+        std::fs::create_dir_all(dir.join("manifests"))?;
+        Ok(RegistryMirror {
+            dir,
+            upstream: upstream.trim_end_matches('/').to_string(),
+            host,
+            filling: Mutex::new(HashMap::new()),
+            client: tls::client(),
+        })
+    }
+
+    /// The name a backend pulls `model` with to go through the mirror,
+    /// `None` for models of another registry.
+    pub fn mirrored_name(&self, model: &str) -> Option<String> {
+        let host = self.host.as_ref()?;
+        let first = model.split('/').next().unwrap_or_default();
+        if model.contains('/') && (first.contains('.') || first.contains(':')) {
+            return None;
+        }
+        let model = if model.contains('/') { model.to_string() } else { format!("library/{}", model) };
+        Some(format!("{}/{}", host, model))
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        self.dir.join("blobs").join(digest.replace(':', "-"))
+    }
+
+    fn manifest_path(&self, name: &str, reference: &str) -> PathBuf {
+        self.dir.join("manifests").join(name.replace('/', "_")).join(reference.replace(':', "-"))
+    }
+
+    /// Starts downloading a blob into the cache, unless it is already being downloaded,
+    /// and follows the download.
+    fn fill(self: &Arc<Self>, path: &str, digest: &str) -> watch::Receiver<Fill> {
+        let mut filling = self.filling.lock().unwrap();
+        if let Some(fill) = filling.get(digest) {
+            return fill.clone();
+        }
+        let (tx, rx) = watch::channel(Fill::Starting);
+        filling.insert(digest.to_string(), rx.clone());
+        let (mirror, url, digest) = (self.clone(), format!("{}{}", self.upstream, path), digest.to_string());
+        tokio::spawn(async move {
+            let partial = mirror.blob_path(&digest).with_extension("partial");
+            match download_blob(&mirror, &url, &digest, &partial, &tx).await {
+                Ok(written) => {
+                    info!("Registry mirror cached blob {} ({} MiB)", digest, written / 1024 / 1024);
+                    let _ = tx.send(Fill::Done);
+                },
+                Err(e) => {
+                    warn!("Registry mirror failed to cache blob {}: {}", digest, e);
+                    let _ = tokio::fs::remove_file(&partial).await;
+                    let _ = tx.send(Fill::Failed);
+                },
+            }
+            mirror.filling.lock().unwrap().remove(&digest);
+        });
+        rx
+    }
+}
+
+/// Progress of a blob being downloaded into the cache, followed by the requests serving it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Fill {
+    /// Waiting for the upstream to answer.
+    Starting,
+    /// `written` bytes of `len` are on the disk, in the partial file.
+    Writing { len: u64, written: u64 },
+    /// Verified and moved to its final place.
+    Done,
+    Failed,
+}
+
+/// Whether `digest` is a sha256 digest, the only kind the registry uses for blobs.
+fn valid_digest(digest: &str) -> bool {
+    digest.strip_prefix("sha256:")
+        .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')))
+}
+
+/// Whether `part` of a model name or tag is safe to use in a path.
+fn valid_part(part: &str) -> bool {
+    !part.is_empty() && !part.contains("..") && part.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-'))
+}
+
+/// Serves `/v2/` registry requests: manifests are always refreshed from upstream when possible,
+/// blobs come from the disk, downloaded once and served while they are being downloaded.
+pub async fn handle_registry(req: Request<Body>, mirror: Arc<RegistryMirror>) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_string();
+    if path == "/v2/" || path == "/v2" {
+        return Ok(Response::new(Body::from("{}")));
+    }
+    let rest = path.trim_start_matches("/v2/");
+    let resp = if let Some((name, reference)) = rest.rsplit_once("/manifests/") {
+        if !name.split('/').all(valid_part) || !(valid_part(reference) || valid_digest(reference)) {
+            return Ok(bad_request(&path));
+        }
+        serve_manifest(&mirror, name, reference, req.method()).await
+    } else if let Some((name, digest)) = rest.rsplit_once("/blobs/") {
+        if !name.split('/').all(valid_part) || !valid_digest(digest) {
+            return Ok(bad_request(&path));
+        }
+        serve_blob(&mirror, &path, digest, &req).await
+    } else {
+        Err(format!("Unsupported registry request {}", path).into())
+    };
+    Ok(resp.unwrap_or_else(|e| {
+        warn!("Registry mirror failed to serve {}: {}", path, e);
+        Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::from(e.to_string())).unwrap()
+    }))
+}
+
+fn bad_request(path: &str) -> Response<Body> {
+    warn!("Registry mirror refused malformed request {}", path);
+    Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("Malformed name, tag or digest")).unwrap()
+}
+
+async fn serve_manifest(
+    mirror: &RegistryMirror, name: &str, reference: &str, method: &Method,
+) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+    let cached = mirror.manifest_path(name, reference);
+    let url = format!("{}/v2/{}/manifests/{}", mirror.upstream, name, reference);
+    let fetched = async {
+        let resp = mirror.client.get(&url)
+            .header(header::ACCEPT, "application/vnd.docker.distribution.manifest.v2+json")
+            .send().await?
+            .error_for_status()?;
+        let content_type = resp.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok()).unwrap_or("application/json").to_string();
+        Ok::<_, reqwest::Error>((content_type, resp.bytes().await?))
+    }.await;
+    let (content_type, manifest) = match fetched {
+        Ok((content_type, manifest)) => {
+            tokio::fs::create_dir_all(cached.parent().unwrap()).await?;
+            tokio::fs::write(&cached, &manifest).await?;
+            (content_type, manifest)
+        },
+        Err(e) => {
+            // offline, or the upstream is down: the last known manifest still does
+            let manifest = tokio::fs::read(&cached).await.map_err(|_| e)?;
+            info!("Serving cached manifest of {}:{}", name, reference);
+            ("application/vnd.docker.distribution.manifest.v2+json".to_string(), Bytes::from(manifest))
+        },
+    };
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, manifest.len());
+    let body = if method == Method::HEAD { Body::empty() } else { Body::from(manifest) };
+    Ok(builder.body(body)?)
+}
+
+/// Parses a `bytes=START-END` range, the only form Ollama sends.
+fn parse_range(value: &str, len: u64) -> Option<(u64, u64)> {
+    if len == 0 {
+        return None;
+    }
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse::<u64>().ok()?;
+    let end = if end.is_empty() { len - 1 } else { end.parse::<u64>().ok()?.min(len - 1) };
+    (start <= end).then_some((start, end))
+}
+
+async fn serve_blob(
+    mirror: &Arc<RegistryMirror>, path: &str, digest: &str, req: &Request<Body>,
+) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+    let cached = mirror.blob_path(digest);
+    if let Ok(meta) = tokio::fs::metadata(&cached).await {
+        return serve_file(&cached, meta.len(), None, req).await;
+    }
+    let mut fill = mirror.fill(path, digest);
+    // the length is known once the upstream answers
+    loop {
+        let state = *fill.borrow_and_update();
+        match state {
+            Fill::Starting => {},
+            Fill::Writing { len, .. } => {
+                let partial = cached.with_extension("partial");
+                return serve_file(&partial, len, Some(fill), req).await;
+            },
+            Fill::Done => {
+                let len = tokio::fs::metadata(&cached).await?.len();
+                return serve_file(&cached, len, None, req).await;
+            },
+            Fill::Failed => return Err(format!("Failed to download blob {}", digest).into()),
+        }
+        fill.changed().await.map_err(|_| "Blob download ended")?;
+    }
+}
+
+/// Bytes of a blob that can be served, waiting for the download to get that far.
+async fn wait_available(fill: &mut Option<watch::Receiver<Fill>>, pos: u64, len: u64) -> std::io::Result<u64> {
+    let Some(fill) = fill else {
+        return Ok(len);
+    };
+    loop {
+        let state = *fill.borrow_and_update();
+        match state {
+            Fill::Writing { written, .. } if written > pos => return Ok(written),
+            Fill::Done => return Ok(len),
+            Fill::Failed => return Err(std::io::Error::other("blob download failed")),
+            _ => {},
+        }
+        if fill.changed().await.is_err() {
+            return Err(std::io::Error::other("blob download ended"));
+        }
+    }
+}
+
+/// Serves a blob from the disk, following `fill` while it is being downloaded.
+async fn serve_file(
+    file_path: &Path, len: u64, fill: Option<watch::Receiver<Fill>>, req: &Request<Body>,
+) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+    let range = req.headers().get(header::RANGE).and_then(|v| v.to_str().ok()).and_then(|v| parse_range(v, len));
+    let (status, start, end) = match range {
+        Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end),
+        None => (StatusCode::OK, 0, len.saturating_sub(1)),
+    };
+    let mut builder = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_LENGTH, if len == 0 { 0 } else { end - start + 1 });
+    if status == StatusCode::PARTIAL_CONTENT {
+        builder = builder.header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+    }
+    if req.method() == Method::HEAD || len == 0 {
+        return Ok(builder.body(Body::empty())?);
+    }
+    // the partial file is renamed once complete, an open handle keeps reading it
+    let mut file = match tokio::fs::File::open(file_path).await {
+        Ok(file) => file,
+        Err(_) => tokio::fs::File::open(file_path.with_extension("")).await?,
+    };
+    file.seek(SeekFrom::Start(start)).await?;
+    let chunks = stream::unfold((file, start, end + 1, fill), move |(mut file, pos, stop, mut fill)| async move {
+        if pos >= stop {
+            return None;
+        }
+        let available = match wait_available(&mut fill, pos, len).await {
+            Ok(available) => available,
+            Err(e) => return Some((Err(e), (file, stop, stop, None))),
+        };
+        let mut buf = vec![0; READ_CHUNK.min((stop.min(available) - pos) as usize)];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                // the end of a blob being downloaded only goes out once its digest is verified
+                if pos + n as u64 == len {
+                    if let Err(e) = wait_available(&mut fill, len, len).await {
+                        return Some((Err(e), (file, stop, stop, None)));
+                    }
+                }
+                Some((Ok(Bytes::from(buf)), (file, pos + n as u64, stop, fill)))
+            },
+            Err(e) => Some((Err(e), (file, stop, stop, None))),
+        }
+    });
+    Ok(builder.body(Body::wrap_stream(chunks))?)
+}
+
+/// Downloads a whole blob into the partial file, reporting the progress on `tx`,
+/// and moves it to the cache once its digest is verified.
+async fn download_blob(
+    mirror: &RegistryMirror, url: &str, digest: &str, partial: &Path, tx: &watch::Sender<Fill>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let resp = mirror.client.get(url).send().await?.error_for_status()?;
+    let len = resp.content_length().ok_or("no Content-Length")?;
+    let mut file = tokio::fs::File::create(partial).await?;
+    let _ = tx.send(Fill::Writing { len, written: 0 });
+    let mut stream = resp.bytes_stream();
+    let mut hasher = Sha256::new();
+    let mut written = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        // readers follow the file, what is reported written must be on the disk
+        file.flush().await?;
+        hasher.update(&chunk);
+        written += chunk.len() as u64;
+        let _ = tx.send(Fill::Writing { len, written });
+    }
+    if written != len {
+        return Err(format!("got {} of {} bytes", written, len).into());
+    }
+    let actual = format!("sha256:{:x}", hasher.finalize());
+    if actual != digest {
+        return Err(format!("digest mismatch, got {}", actual).into());
+    }
+    tokio::fs::rename(partial, mirror.blob_path(digest)).await?;
+    Ok(written)
+}
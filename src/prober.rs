@@ -0,0 +1,104 @@
+use std::sync::Arc;
+use std::time::Duration;
+use reqwest::header::{self, HeaderMap};
+use reqwest::Method;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::backend::{send_request, send_request_monitored, ReqOpt, TimeoutProfile};
+use crate::config::Preload;
+use crate::health::HealthPolicy;
+use crate::runtime::RuntimeConfig;
+use crate::shaping::keep_alive_value;
+use crate::state::{find_server, mark_server_less_healthy, mark_server_reliable, record_shadow_probe, sync_server, SharedServerList};
+
+/// Options of the synthetic prober.
+#[derive(Clone, Debug)]
+pub struct ProbeOpt {
+    pub model: String,
+    pub interval: Duration,
+    pub req: ReqOpt,
+    pub latency_alpha: f32,
+    pub restore_trust: bool, // successful probes make unreliable servers reliable again
+}
+
+pub async fn probe_server(server: &str, opts: &ProbeOpt) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+    let uri = "/api/generate";
+    let body = json!({
+        "model": opts.model,
+        "prompt": "Hi",
+        "stream": true,
+        "options": { "num_predict": 2 },
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    let req = (uri.to_string(), Method::POST, uri.to_string(), Some(headers), Some(bytes::Bytes::from(body.to_string()).into()));
+    // the first token is all we want to measure
+    let req_opts = ReqOpt { time_measure: 0, ..opts.req };
+    let (perf, resp) = send_request_monitored(req, server, req_opts).await?;
+    if !resp.status.is_success() {
+        return Err(format!("Probe answered with {}", resp.status).into());
+    }
+    Ok(perf.ttft)
+}
+
+/// Periodically runs a tiny generation on every server hosting the probe model, all at once,
+/// so that servers get a fresh time to first token even without production traffic.
+/// A failed probe costs the server health like a failed request, a server that keeps
+/// failing them dies without any client paying for it.
+pub async fn shadow_prober(servers: SharedServerList, opts: ProbeOpt, health: Arc<dyn HealthPolicy>) {
+    let mut ticker = tokio::time::interval(opts.interval);
+    loop {
+        ticker.tick().await;
+        let targets = servers.lock().unwrap().iter()
+            .filter(|(_, srv)| srv.models.contains_key(&opts.model))
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<String>>();
+        let probes = targets.into_iter().map(|target| {
+            let (servers, opts, health) = (servers.clone(), &opts, health.clone());
+            async move {
+                let res = probe_server(&target, opts).await;
+                match &res {
+                    Ok(ttft) => info!("Probe of {} took {}ms to the first token", target, ttft.as_millis()),
+                    Err(e) => warn!("Probe of {} failed: {}", target, e),
+                }
+                if res.is_ok() && opts.restore_trust {
+                    mark_server_reliable(servers.clone(), &target);
+                }
+                if res.is_err() {
+                    mark_server_less_healthy(servers.clone(), &target, health.as_ref());
+                }
+                record_shadow_probe(servers, &target, res.ok(), opts.latency_alpha);
+            }
+        });
+        futures_util::future::join_all(probes).await;
+    }
+}
+
+/// Loads the models to preload on their servers, all at once. An empty generation loads
+/// a model without generating anything, `keep_alive` keeps it loaded afterwards.
+pub async fn preload_models(servers: SharedServerList, preloads: Vec<Preload>, keep_alive: String, runtime: RuntimeConfig) {
+    let keep_alive = keep_alive_value(&keep_alive);
+    let loads = preloads.into_iter().map(|preload| {
+        let servers = servers.clone();
+        let keep_alive = keep_alive.clone();
+        let (sync_timeout, health) = (runtime.sync_timeout, runtime.health.clone());
+        async move {
+            let Some(server) = find_server(servers.clone(), Some(&preload.server), None) else {
+                warn!("Cannot preload {} on unknown server {}", preload.model, preload.server);
+                return;
+            };
+            let uri = "/api/generate";
+            let body = json!({ "model": preload.model, "keep_alive": keep_alive });
+            let req = (uri.to_string(), Method::POST, uri.to_string(), None, Some(bytes::Bytes::from(body.to_string()).into()));
+            // loading a large model takes long, do not time out on it
+            match send_request(req, &server, TimeoutProfile::read(0)).await.and_then(|resp| Ok(resp.error_for_status()?)) {
+                Ok(_) => info!("Preloaded {} on {}", preload.model, server),
+                Err(e) => warn!("Failed to preload {} on {}: {}", preload.model, server, e),
+            }
+            // the selection prefers servers with the model loaded, let it know
+            sync_server(servers, server, sync_timeout, health).await;
+        }
+    });
+    futures_util::future::join_all(loads).await;
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use serde_json::Value;
+use tracing::info;
+
+use crate::backend::TimeoutProfile;
+
+/// Settings that depend on the requested model.
+#[derive(Clone, Debug, Default)]
+pub struct ModelProfile {
+    /// Overrides --timeout-ft for this model.
+    pub timeout_ft: Option<u32>,
+    /// Overrides --time-measure, e.g. longer to tell big slow models apart.
+    pub time_measure: Option<u32>,
+    /// Overrides --measure-tokens.
+    pub measure_tokens: Option<u32>,
+    /// Maximum time in seconds a request may wait for an idle server hosting the model.
+    /// Without it, requests never wait.
+    pub queue_timeout: Option<u32>,
+    /// Servers the model may run on, by address or name. Any server without it.
+    pub servers: Option<Vec<String>>,
+    /// Replaces the `keep_alive` of chat requests, overriding --keep-alive.
+    pub keep_alive: Option<Value>,
+}
+
+/// Ordered list of model patterns with their profile, the first match wins.
+/// A pattern matches the model name exactly, or by prefix when it ends with `*`.
+#[derive(Debug, Default)]
+pub struct ModelProfiles {
+    rules: Vec<(String, ModelProfile)>,
+}
+
+fn read_secs(rule: &Value, field: &str) -> Result<Option<u32>, String> {
+    match &rule[field] {
+        Value::Null => Ok(None),
+        v => v.as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .map(Some)
+            .ok_or_else(|| format!("'{}' must be a number of seconds", field)),
+    }
+}
+
+fn read_count(rule: &Value, field: &str) -> Result<Option<u32>, String> {
+    match &rule[field] {
+        Value::Null => Ok(None),
+        v => v.as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .map(Some)
+            .ok_or_else(|| format!("'{}' must be a count", field)),
+    }
+}
+
+fn read_servers(rule: &Value) -> Result<Option<Vec<String>>, String> {
+    match &rule["servers"] {
+        Value::Null => Ok(None),
+        v => v.as_array()
+            .and_then(|servers| servers.iter().map(|s| s.as_str().map(str::to_string)).collect::<Option<Vec<_>>>())
+            .map(Some)
+            .ok_or_else(|| "'servers' must be an array of server addresses or names".to_string()),
+    }
+}
+
+fn read_keep_alive(rule: &Value) -> Result<Option<Value>, String> {
+    match &rule["keep_alive"] {
+        Value::Null => Ok(None),
+        v @ (Value::Number(_) | Value::String(_)) => Ok(Some(v.clone())),
+        _ => Err("'keep_alive' must be a number of seconds or a duration like \"10m\"".to_string()),
+    }
+}
+
+impl ModelProfiles {
+    /// Loads profiles from a JSON file like:
+    /// `[{ "model": "llama3.3:70b", "timeout_ft": 120, "time_measure": 5, "queue_timeout": 600, "servers": ["gpu-1", "gpu-2"], "keep_alive": "1h" }, { "model": "*", "queue_timeout": 5 }]`
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let rules = serde_json::from_str::<Value>(&contents)?;
+        let rules = rules.as_array().ok_or("Model profiles must be a JSON array")?;
+        let rules = rules.iter().map(|rule| {
+            let pattern = rule["model"].as_str().ok_or("Every model profile needs a 'model' pattern")?;
+            let profile = ModelProfile {
+                timeout_ft: read_secs(rule, "timeout_ft")?,
+                time_measure: read_secs(rule, "time_measure")?,
+                measure_tokens: read_count(rule, "measure_tokens")?,
+                queue_timeout: read_secs(rule, "queue_timeout")?,
+                servers: read_servers(rule)?,
+                keep_alive: read_keep_alive(rule)?,
+            };
+            Ok((pattern.to_string(), profile))
+        }).collect::<Result<Vec<_>, String>>()?;
+        Ok(ModelProfiles { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn get(&self, model: &str) -> ModelProfile {
+        self.rules.iter().find(|(pattern, _)| match pattern.strip_suffix('*') {
+            Some(prefix) => model.starts_with(prefix),
+            None => model == pattern,
+        }).map(|(_, profile)| profile.clone()).unwrap_or_default()
+    }
+}
+
+/// Settings of a backend, overriding the ones of the endpoint class.
+#[derive(Clone, Debug, Default)]
+pub struct BackendProfile {
+    pub timeout_connect: Option<u32>,
+    pub timeout_ft: Option<u32>,
+    pub timeout_idle: Option<u32>,
+    pub timeout_total: Option<u32>,
+    /// Multiplies the health of the server in the selection.
+    pub weight: Option<f32>,
+    /// Streams the server relays at once, it is left out of the selection beyond.
+    pub max_concurrent: Option<usize>,
+}
+
+impl BackendProfile {
+    /// The timeouts of a request to this backend. `keep_first_token` when the model profile
+    /// or the client chose the first token timeout already, it wins over the backend.
+    pub fn timeouts(&self, base: TimeoutProfile, keep_first_token: bool) -> TimeoutProfile {
+        TimeoutProfile {
+            connect: self.timeout_connect.unwrap_or(base.connect),
+            first_token: self.timeout_ft.filter(|_| !keep_first_token).unwrap_or(base.first_token),
+            idle: self.timeout_idle.unwrap_or(base.idle),
+            total: self.timeout_total.unwrap_or(base.total),
+        }
+    }
+}
+
+/// Backend settings by server address or name, the first match wins.
+#[derive(Debug, Default)]
+pub struct BackendProfiles {
+    rules: Vec<(String, BackendProfile)>,
+}
+
+impl BackendProfiles {
+    /// Loads backend settings from a JSON file like:
+    /// `[{ "server": "gpu-1", "timeout_ft": 60, "weight": 2.0, "max_concurrent": 4 }]`
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let rules = serde_json::from_str::<Value>(&contents)?;
+        let rules = rules.as_array().ok_or("Backend options must be a JSON array")?;
+        let rules = rules.iter().map(|rule| {
+            let server = rule["server"].as_str().ok_or("Every backend option needs a 'server' address or name")?;
+            let weight = match &rule["weight"] {
+                Value::Null => None,
+                v => Some(v.as_f64().filter(|w| *w > 0.0).ok_or("'weight' must be a positive number")? as f32),
+            };
+            let profile = BackendProfile {
+                timeout_connect: read_secs(rule, "timeout_connect")?,
+                timeout_ft: read_secs(rule, "timeout_ft")?,
+                timeout_idle: read_secs(rule, "timeout_idle")?,
+                timeout_total: read_secs(rule, "timeout_total")?,
+                weight,
+                max_concurrent: match read_count(rule, "max_concurrent")? {
+                    Some(0) => return Err("'max_concurrent' must be at least 1".to_string()),
+                    max => max.map(|max| max as usize),
+                },
+            };
+            Ok((server.to_string(), profile))
+        }).collect::<Result<Vec<_>, String>>()?;
+        Ok(BackendProfiles { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn get(&self, addr: &str, name: &str) -> BackendProfile {
+        self.rules.iter().find(|(server, _)| server == addr || server == name)
+            .map(|(_, profile)| profile.clone()).unwrap_or_default()
+    }
+}
+
+/// Model and backend profiles that can be reloaded while requests are in flight.
+/// Every reload starts a new generation: new requests get the new profiles right away,
+/// requests in flight keep the profiles they started with until they finish.
+#[derive(Debug)]
+pub struct ProfileStore {
+    path: Option<String>,
+    backends_path: Option<String>,
+    inner: Mutex<Generations>,
+}
+
+#[derive(Debug)]
+struct Generations {
+    current: u64,
+    profiles: Arc<ModelProfiles>,
+    backends: Arc<BackendProfiles>,
+    in_flight: BTreeMap<u64, usize>,
+}
+
+/// Counts a request in the generation it started with, until dropped.
+#[derive(Debug)]
+pub struct GenerationGuard {
+    store: Arc<ProfileStore>,
+    pub generation: u64,
+    /// The backend settings of the generation, what the request keeps using until it is done.
+    pub backends: Arc<BackendProfiles>,
+}
+
+impl ProfileStore {
+    pub fn new(path: Option<String>, profiles: ModelProfiles, backends_path: Option<String>, backends: BackendProfiles) -> Self {
+        let backends = Arc::new(backends);
+        crate::state::set_backend_profiles(backends.clone());
+        ProfileStore {
+            path,
+            backends_path,
+            inner: Mutex::new(Generations { current: 1, profiles: Arc::new(profiles), backends, in_flight: BTreeMap::new() }),
+        }
+    }
+
+    /// Whether there is any file to reload.
+    pub fn is_reloadable(&self) -> bool {
+        self.path.is_some() || self.backends_path.is_some()
+    }
+
+    /// The current profiles, with a guard holding the request in their generation.
+    pub fn checkout(self: &Arc<Self>) -> (Arc<ModelProfiles>, GenerationGuard) {
+        let mut inner = self.inner.lock().unwrap();
+        let generation = inner.current;
+        *inner.in_flight.entry(generation).or_default() += 1;
+        (inner.profiles.clone(), GenerationGuard { store: self.clone(), generation, backends: inner.backends.clone() })
+    }
+
+    /// Reads the profiles files again and starts a new generation with them,
+    /// returns the generation and how many model and backend profiles it has.
+    /// Weights and concurrency caps apply to the selection right away, in-flight requests included.
+    pub fn reload(&self) -> Result<(u64, usize, usize), Box<dyn std::error::Error>> {
+        if !self.is_reloadable() {
+            return Err("No --model-profiles nor --backend-options file to reload".into());
+        }
+        // both files are read before anything changes, a broken one keeps the current generation
+        let profiles = self.path.as_deref().map(ModelProfiles::load).transpose()?;
+        let backends = self.backends_path.as_deref().map(BackendProfiles::load).transpose()?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.current += 1;
+        if let Some(profiles) = profiles {
+            inner.profiles = Arc::new(profiles);
+        }
+        if let Some(backends) = backends {
+            inner.backends = Arc::new(backends);
+            crate::state::set_backend_profiles(inner.backends.clone());
+        }
+        let draining = inner.in_flight.values().sum::<usize>();
+        if draining > 0 {
+            info!("{} requests keep running with older profiles", draining);
+        }
+        Ok((inner.current, inner.profiles.len(), inner.backends.len()))
+    }
+
+    /// The current generation, and the requests in flight per generation.
+    pub fn generations(&self) -> (u64, Vec<(u64, usize)>) {
+        let inner = self.inner.lock().unwrap();
+        (inner.current, inner.in_flight.iter().map(|(g, n)| (*g, *n)).collect())
+    }
+}
+
+impl Drop for GenerationGuard {
+    fn drop(&mut self) {
+        let mut inner = self.store.inner.lock().unwrap();
+        let left = match inner.in_flight.get_mut(&self.generation) {
+            Some(n) => {
+                *n -= 1;
+                *n
+            },
+            None => return,
+        };
+        if left == 0 {
+            inner.in_flight.remove(&self.generation);
+            if self.generation != inner.current {
+                info!("Profiles generation {} fully drained", self.generation);
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use hyper::{Request, Response, StatusCode};
+use reqwest::Method;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::backend::{idle_limited, send_request, TimeoutProfile};
+use crate::body::{self, Body};
+use crate::mirror::RegistryMirror;
+use crate::runtime::RuntimeConfig;
+use crate::state::{provisioning_targets, snapshot_servers, sync_server, Health, SharedServerList};
+
+/// Times a pull is restarted on the same server after its connection broke.
+const PULL_ATTEMPTS: usize = 5;
+
+/// How a pull on one server ended.
+enum PullEnd {
+    Success,
+    Failed(String),
+    /// The client went away, the pull was dropped.
+    Abandoned,
+}
+
+fn json_line(value: Value) -> Bytes {
+    Bytes::from(format!("{}\n", value))
+}
+
+/// Servers a pull goes to: the one named by `X-Ollama-Server`, by address or name,
+/// or every alive server otherwise.
+fn pull_targets(servers: SharedServerList, wanted: Option<&str>) -> Vec<String> {
+    let snaps = snapshot_servers(servers, false);
+    let mut targets = snaps.iter().filter(|(addr, snap)| match wanted {
+        Some(wanted) => addr.as_str() == wanted || snap.name == wanted,
+        None => snap.state.health != Health::Dead && snap.state.misconfigured.is_none(),
+    }).map(|(addr, _)| addr.clone()).collect::<Vec<_>>();
+    targets.sort();
+    targets
+}
+
+/// Pulls on one server, relaying its progress. A broken connection restarts the pull,
+/// Ollama keeps the partially downloaded layers and resumes them from the registry.
+/// The pull is dropped as soon as nobody receives its progress anymore.
+async fn pull_on(server: &str, body: &Bytes, timeouts: TimeoutProfile, tx: &mpsc::Sender<Bytes>) -> PullEnd {
+    let mut last_error = String::new();
+    for attempt in 1..=PULL_ATTEMPTS {
+        if tx.is_closed() {
+            return PullEnd::Abandoned;
+        }
+        if attempt > 1 {
+            info!("Resuming pull on {} (attempt {}/{})", server, attempt, PULL_ATTEMPTS);
+            tokio::time::sleep(Duration::from_secs(2 * attempt as u64)).await;
+        }
+        let req = ("/api/pull".to_string(), Method::POST, "/api/pull".to_string(), None, Some(body.clone().into()));
+        let resp = match send_request(req, server, timeouts).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                last_error = e.to_string();
+                continue;
+            },
+        };
+        let mut stream = idle_limited(resp.bytes_stream(), timeouts.idle);
+        let mut line = Vec::new();
+        loop {
+            let chunk = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = tx.closed() => return PullEnd::Abandoned,
+            };
+            let chunk = match chunk {
+                None => break,
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => {
+                    last_error = e.to_string();
+                    break;
+                },
+            };
+            line.extend_from_slice(&chunk);
+            while let Some(pos) = line.iter().position(|b| *b == b'\n') {
+                let progress = line.drain(..=pos).collect::<Vec<u8>>();
+                let progress = serde_json::from_slice::<Value>(&progress).unwrap_or_default();
+                if let Some(e) = progress["error"].as_str() {
+                    // the backend gave up by itself, e.g. an unknown model: retrying is pointless
+                    return PullEnd::Failed(e.to_string());
+                }
+                if progress["status"] == "success" {
+                    return PullEnd::Success;
+                }
+                let mut progress = progress;
+                progress["server"] = json!(server);
+                if tx.send(json_line(progress)).await.is_err() {
+                    return PullEnd::Abandoned;
+                }
+            }
+        }
+        warn!("Pull on {} broke off: {}", server, last_error);
+    }
+    PullEnd::Failed(format!("gave up after {} attempts: {}", PULL_ATTEMPTS, last_error))
+}
+
+/// Gives a model pulled through the registry mirror its requested name back.
+async fn rename_mirrored(server: &str, mirrored: &str, model: &str, timeouts: TimeoutProfile) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let copy = json!({ "source": mirrored, "destination": model }).to_string();
+    let req = ("/api/copy".to_string(), Method::POST, "/api/copy".to_string(), None, Some(Bytes::from(copy).into()));
+    send_request(req, server, timeouts).await?.error_for_status()?;
+    let delete = json!({ "model": mirrored }).to_string();
+    let req = ("/api/delete".to_string(), Method::DELETE, "/api/delete".to_string(), None, Some(Bytes::from(delete).into()));
+    send_request(req, server, timeouts).await?.error_for_status()?;
+    Ok(())
+}
+
+/// The body of the pulls sent to the backends, and the name they pull the model with
+/// when going through the registry mirror.
+fn backend_pull_body(mut body: Value, model: &str, mirror: Option<&RegistryMirror>) -> (Bytes, Option<String>) {
+    // progress is needed to tell a broken pull from a finished one
+    body["stream"] = json!(true);
+    let mirrored = mirror.and_then(|mirror| mirror.mirrored_name(model));
+    if let Some(mirrored) = &mirrored {
+        info!("Pulling {} through the registry mirror as {}", model, mirrored);
+        body["model"] = json!(mirrored);
+        body["insecure"] = json!(true);
+        if let Some(body) = body.as_object_mut() {
+            body.remove("name");
+        }
+    }
+    (Bytes::from(body.to_string()), mirrored)
+}
+
+/// Pulls `model` on one server, under its own name even when pulled through the mirror.
+async fn pull_model_on(
+    server: &str, model: &str, body: &Bytes, mirrored: Option<&str>, timeouts: TimeoutProfile, tx: &mpsc::Sender<Bytes>
+) -> PullEnd {
+    let end = pull_on(server, body, timeouts, tx).await;
+    match (&end, mirrored) {
+        (PullEnd::Success, Some(mirrored)) => match rename_mirrored(server, mirrored, model, timeouts).await {
+            Ok(()) => end,
+            Err(e) => PullEnd::Failed(format!("failed to rename {}: {}", mirrored, e)),
+        },
+        _ => end,
+    }
+}
+
+/// Pulls a model on the servers one after another, streaming their progress like Ollama does.
+/// Every progress line carries the server it comes from, and `success` is only sent once
+/// the model is on every server.
+pub async fn handle_pull(
+    req: Request<Body>,
+    servers: SharedServerList,
+    remote_addr: std::net::SocketAddr,
+    runtime: &RuntimeConfig,
+    mirror: Option<&RegistryMirror>,
+) -> Result<Response<Body>, Infallible> {
+    let wanted = req.headers().get("x-ollama-server").and_then(|v| v.to_str().ok()).map(str::to_string);
+    let whole_body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let body = serde_json::from_slice::<Value>(&whole_body).unwrap_or_default();
+    let model = body["model"].as_str().or(body["name"].as_str()).unwrap_or_default().to_string();
+    if model.is_empty() {
+        return Ok(error_resp(StatusCode::BAD_REQUEST, "Request body must contain a 'model' field"));
+    }
+    let targets = pull_targets(servers.clone(), wanted.as_deref());
+    if targets.is_empty() {
+        return Ok(error_resp(StatusCode::SERVICE_UNAVAILABLE, "No server to pull on"));
+    }
+    info!("Pulling {} on [{}] for client {}", model, targets.join(", "), remote_addr);
+    let client_stream = body["stream"].as_bool().unwrap_or(true);
+    let (body, mirrored) = backend_pull_body(body, &model, mirror);
+
+    let (tx, mut rx) = mpsc::channel::<Bytes>(64);
+    let (sync_timeout, timeouts) = (runtime.sync_timeout, runtime.timeouts.pull);
+    let health = runtime.health.clone();
+    let pulling = tokio::spawn(async move {
+        let mut failures = Vec::new();
+        for server in targets {
+            match pull_model_on(&server, &model, &body, mirrored.as_deref(), timeouts, &tx).await {
+                PullEnd::Success => info!("Pulled {} on {}", model, server),
+                PullEnd::Failed(e) => {
+                    warn!("Failed to pull {} on {}: {}", model, server, e);
+                    failures.push(format!("{}: {}", server, e));
+                },
+                PullEnd::Abandoned => {
+                    // the next pull of the model resumes from the layers already downloaded
+                    info!("Client {} went away, stopped pulling {} on {}", remote_addr, model, server);
+                    return;
+                },
+            }
+            // let the selection know about the new model right away
+            sync_server(servers.clone(), server, sync_timeout, health.clone()).await;
+        }
+        let end = if failures.is_empty() {
+            json!({ "status": "success" })
+        } else {
+            json!({ "error": format!("pull failed on {}", failures.join(", ")) })
+        };
+        let _ = tx.send(json_line(end)).await;
+    });
+
+    if !client_stream {
+        // like Ollama, only the final status without streaming
+        let mut last = Bytes::new();
+        while let Some(line) = rx.recv().await {
+            last = line;
+        }
+        let _ = pulling.await;
+        let status = if last.starts_with(b"{\"error\"") { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK };
+        return Ok(Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(last))
+            .unwrap());
+    }
+    let progress = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|line| (Ok::<_, std::io::Error>(line), rx))
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::wrap_stream(progress))
+        .unwrap())
+}
+
+fn error_resp(status: StatusCode, msg: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json!({ "error": msg }).to_string()))
+        .unwrap()
+}
+
+/// Time a server that failed to pull a model is left alone before trying again.
+const AUTO_PULL_COOLDOWN: Duration = Duration::from_secs(600);
+
+/// Pulls models in the background on idle servers missing them, when too few servers host them.
+#[derive(Debug, Default)]
+pub struct AutoPull {
+    pulling: Mutex<HashSet<(String, String)>>,
+    failed: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl AutoPull {
+    /// Starts pulling `model` on enough idle servers to have `wanted` servers hosting it.
+    /// Servers already pulling it, or that recently failed to, are skipped.
+    pub fn provision(
+        self: &Arc<Self>,
+        servers: SharedServerList,
+        model: &str,
+        wanted: usize,
+        pinned: Option<&[String]>,
+        runtime: &RuntimeConfig,
+        mirror: Option<Arc<RegistryMirror>>,
+    ) {
+        let targets = {
+            let mut pulling = self.pulling.lock().unwrap();
+            let mut failed = self.failed.lock().unwrap();
+            failed.retain(|_, at| at.elapsed() < AUTO_PULL_COOLDOWN);
+            let in_progress = pulling.iter().filter(|(_, m)| m == model).count();
+            provisioning_targets(servers.clone(), model, wanted.saturating_sub(in_progress), pinned)
+                .into_iter()
+                .filter(|server| {
+                    let key = (server.clone(), model.to_string());
+                    !failed.contains_key(&key) && pulling.insert(key)
+                })
+                .collect::<Vec<_>>()
+        };
+        if targets.is_empty() {
+            return;
+        }
+        info!("Only few servers host {}, pulling it on [{}]", model, targets.join(", "));
+        let (body, mirrored) = backend_pull_body(json!({ "model": model }), model, mirror.as_deref());
+        let (sync_timeout, timeouts, health) = (runtime.sync_timeout, runtime.timeouts.pull, runtime.health.clone());
+        for server in targets {
+            let auto_pull = self.clone();
+            let (servers, model, body, mirrored) = (servers.clone(), model.to_string(), body.clone(), mirrored.clone());
+            let health = health.clone();
+            tokio::spawn(async move {
+                let (tx, mut rx) = mpsc::channel::<Bytes>(64);
+                // nobody follows the progress
+                tokio::spawn(async move { while rx.recv().await.is_some() {} });
+                let key = (server.clone(), model.clone());
+                match pull_model_on(&server, &model, &body, mirrored.as_deref(), timeouts, &tx).await {
+                    PullEnd::Success => info!("Pulled {} on {} in the background", model, server),
+                    PullEnd::Failed(e) => {
+                        warn!("Failed to pull {} on {} in the background: {}", model, server, e);
+                        auto_pull.failed.lock().unwrap().insert(key.clone(), Instant::now());
+                    },
+                    // the progress is drained until the end
+                    PullEnd::Abandoned => {},
+                }
+                sync_server(servers, server, sync_timeout, health).await;
+                auto_pull.pulling.lock().unwrap().remove(&key);
+            });
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::{Arc, RwLock};
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+
+const REDACTED: &str = "<redacted>";
+
+pub const DEFAULT_KEYS: [&str; 8] = [
+    "authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key",
+    "content", "prompt", "images",
+];
+
+/// Header names and JSON keys whose values must never reach the logs.
+/// A key matches when equal ignoring case, or by prefix when it ends with `*`.
+#[derive(Debug)]
+pub struct Redactor {
+    keys: Vec<String>,
+}
+
+static REDACTOR: RwLock<Option<Arc<Redactor>>> = RwLock::new(None);
+
+/// Installs the redaction keys, must be called before serving.
+pub fn init(keys: &[String]) {
+    let keys = keys.iter()
+        .map(|k| k.trim().to_lowercase())
+        .filter(|k| !k.is_empty())
+        .collect();
+    *REDACTOR.write().unwrap() = Some(Arc::new(Redactor { keys }));
+}
+
+fn redactor() -> Arc<Redactor> {
+    if let Some(redactor) = REDACTOR.read().unwrap().as_ref() {
+        return redactor.clone();
+    }
+    REDACTOR.write().unwrap().get_or_insert_with(|| Arc::new(Redactor {
+        keys: DEFAULT_KEYS.iter().map(|k| k.to_string()).collect(),
+    })).clone()
+}
+
+impl Redactor {
+    fn matches(&self, key: &str) -> bool {
+        let key = key.to_lowercase();
+        self.keys.iter().any(|k| match k.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == *k,
+        })
+    }
+
+    fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (k, v) in map.iter_mut() {
+                    if self.matches(k) {
+                        *v = Value::from(REDACTED);
+                    } else {
+                        self.redact_json(v);
+                    }
+                }
+            },
+            Value::Array(items) => items.iter_mut().for_each(|v| self.redact_json(v)),
+            _ => {},
+        }
+    }
+
+    /// Best effort for lines that are not valid JSON, e.g. truncated previews:
+    /// everything from the first sensitive key on is cut.
+    fn redact_raw_line(&self, line: &str) -> String {
+        // ASCII lowercasing keeps byte offsets valid for slicing `line`
+        let lower = line.to_ascii_lowercase();
+        // a whole key is closed by a quote, unless the line was truncated right after it
+        let cut = self.keys.iter()
+            .filter_map(|k| match k.strip_suffix('*') {
+                Some(prefix) => lower.find(&format!("\"{}", prefix)),
+                None => {
+                    let quoted = format!("\"{}\"", k);
+                    lower.find(&quoted)
+                        .or_else(|| lower.ends_with(&quoted[..quoted.len() - 1]).then(|| lower.len() - k.len() - 1))
+                },
+            })
+            .min();
+        match cut {
+            Some(pos) => format!("{}{}", &line[..pos], REDACTED),
+            None => line.to_string(),
+        }
+    }
+}
+
+/// Redacts a JSON value before it is logged.
+pub fn redact_json(value: &Value) -> Value {
+    let mut value = value.clone();
+    redactor().redact_json(&mut value);
+    value
+}
+
+/// Redacts a (possibly truncated) JSON or NDJSON text before it is logged.
+pub fn redact_text(text: &str) -> String {
+    let redactor = redactor();
+    text.split('\n').map(|line| match serde_json::from_str::<Value>(line) {
+        Ok(mut value) => {
+            redactor.redact_json(&mut value);
+            value.to_string()
+        },
+        Err(_) => redactor.redact_raw_line(line),
+    }).collect::<Vec<String>>().join("\n")
+}
+
+/// Formats a header map like `{:?}` does, with sensitive values redacted.
+pub fn redact_headers(headers: &HeaderMap) -> String {
+    let redactor = redactor();
+    let pairs = headers.iter().map(|(k, v)| {
+        let v = if redactor.matches(k.as_str()) { REDACTED } else { v.to_str().unwrap_or("<binary>") };
+        format!("{:?}: {:?}", k.as_str(), v)
+    }).collect::<Vec<String>>();
+    format!("{{{}}}", pairs.join(", "))
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::config::ServerConfig;
+use crate::state::{add_server, remove_server, sync_server, SharedServerList};
+use crate::runtime::RuntimeConfig;
+
+/// A server registered by the agent running next to it.
+#[derive(Debug, Clone)]
+struct Registration {
+    name: String,
+    tags: Vec<String>,
+    registered_at: DateTime<Utc>,
+    last_seen: Instant,
+}
+
+/// Servers that registered themselves on `/admin/register` with the shared token, e.g. from
+/// behind a NAT where nothing can discover them. A registration expires unless renewed within
+/// the TTL, the agent re-registering as a heartbeat. Servers given on the command line or
+/// found by a discovery are never taken over.
+#[derive(Debug)]
+pub struct Registry {
+    token: String,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Registration>>,
+}
+
+/// The outcome of a registration.
+pub enum Registered {
+    New,
+    Renewed,
+    /// The address belongs to a server that did not register itself.
+    Conflict,
+}
+
+impl Registry {
+    pub fn new(token: String, ttl: Duration) -> Self {
+        Registry { token, ttl, entries: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Whether the request carries the shared token as `Authorization: Bearer <token>`.
+    pub fn authenticate(&self, headers: &HeaderMap) -> bool {
+        let presented = headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .unwrap_or_default();
+        // compare in constant time, the endpoint may be exposed to whole networks
+        presented.len() == self.token.len()
+            && presented.bytes().zip(self.token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
+    /// Adds the server, syncing it in the background, or renews its registration.
+    pub fn register(
+        &self,
+        servers: SharedServerList,
+        server: &ServerConfig,
+        tags: Vec<String>,
+        runtime: &RuntimeConfig,
+    ) -> Registered {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&server.address) {
+            entry.last_seen = Instant::now();
+            entry.tags = tags;
+            // also brings back a server removed on /admin/servers meanwhile
+            let known = servers.lock().unwrap().contains_key(&server.address);
+            if entry.name != server.name || !known {
+                entry.name = server.name.clone();
+                add_server(servers.clone(), server);
+            }
+            if !known {
+                tokio::spawn(sync_server(servers, server.address.clone(), runtime.sync_timeout, runtime.health.clone()));
+            }
+            return Registered::Renewed;
+        }
+        if servers.lock().unwrap().contains_key(&server.address) {
+            return Registered::Conflict;
+        }
+        info!("Server {} ({}) registered itself, tags: [{}]", server.address, server.name, tags.join(", "));
+        add_server(servers.clone(), server);
+        entries.insert(server.address.clone(), Registration {
+            name: server.name.clone(),
+            tags,
+            registered_at: Utc::now(),
+            last_seen: Instant::now(),
+        });
+        tokio::spawn(sync_server(servers, server.address.clone(), runtime.sync_timeout, runtime.health.clone()));
+        Registered::New
+    }
+
+    /// Removes a registered server, e.g. on a clean shutdown of its agent.
+    pub fn deregister(&self, servers: SharedServerList, address: &str) -> bool {
+        if self.entries.lock().unwrap().remove(address).is_none() {
+            return false;
+        }
+        info!("Server {} deregistered itself", address);
+        remove_server(servers, address);
+        true
+    }
+
+    /// Removes the servers whose agent stopped renewing their registration.
+    pub fn expire(&self, servers: SharedServerList) {
+        let expired = {
+            let mut entries = self.entries.lock().unwrap();
+            let expired = entries.iter()
+                .filter(|(_, entry)| entry.last_seen.elapsed() > self.ttl)
+                .map(|(addr, _)| addr.clone())
+                .collect::<Vec<_>>();
+            expired.iter().for_each(|addr| { entries.remove(addr); });
+            expired
+        };
+        for addr in expired {
+            info!("Registration of server {} expired after {}s without a heartbeat", addr, self.ttl.as_secs());
+            remove_server(servers.clone(), &addr);
+        }
+    }
+
+    /// The registered servers, for `GET /admin/register`.
+    pub fn report(&self) -> Value {
+        let entries = self.entries.lock().unwrap();
+        let mut servers = entries.iter().map(|(addr, entry)| json!({
+            "address": addr,
+            "name": entry.name,
+            "tags": entry.tags,
+            "registered_at": entry.registered_at.to_rfc3339(),
+            "expires_in_secs": self.ttl.saturating_sub(entry.last_seen.elapsed()).as_secs(),
+        })).collect::<Vec<_>>();
+        servers.sort_by(|a, b| a["address"].as_str().cmp(&b["address"].as_str()));
+        json!({ "ttl_secs": self.ttl.as_secs(), "servers": servers })
+    }
+}
+
+/// Expires the stale registrations, checking a few times per TTL.
+pub async fn expire_loop(registry: Arc<Registry>, servers: SharedServerList) {
+    let mut ticker = tokio::time::interval((registry.ttl() / 4).max(Duration::from_secs(1)));
+    loop {
+        ticker.tick().await;
+        registry.expire(servers.clone());
+    }
+}
@@ -0,0 +1,152 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use futures_util::Stream;
+use tracing::{info, warn};
+
+/// How the relayed responses ended, since startup.
+pub struct RelayStats {
+    pub completed: AtomicU64,
+    pub client_disconnects: AtomicU64, // the client went away before the end of the response
+    pub truncated: AtomicU64, // the backend stream broke off
+    pub short: AtomicU64, // complete on both ends, but fewer bytes delivered than received
+}
+
+pub static RELAY_STATS: RelayStats = RelayStats {
+    completed: AtomicU64::new(0),
+    client_disconnects: AtomicU64::new(0),
+    truncated: AtomicU64::new(0),
+    short: AtomicU64::new(0),
+};
+
+impl RelayStats {
+    /// Counts from zero again, for a new load balancer.
+    pub fn reset(&self) {
+        for counter in [&self.completed, &self.client_disconnects, &self.truncated, &self.short] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Bytes of one relayed response, received from the backend and delivered to the client,
+/// so that a response reported cut off can be told apart: the backend broke off, the client
+/// went away, or the load balancer lost bytes in between.
+#[derive(Debug, Default)]
+pub struct RelayTally {
+    received: AtomicU64,
+    delivered: AtomicU64,
+    backend_ended: AtomicBool,
+    backend_failed: AtomicBool,
+    client_ended: AtomicBool,
+}
+
+impl RelayTally {
+    /// Records that the backend broke off without its connection failing, e.g. it stalled.
+    pub fn fail_backend(&self) {
+        self.backend_failed.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the backend broke off, however the response ended for the client.
+    pub fn backend_failed(&self) -> bool {
+        self.backend_failed.load(Ordering::Relaxed)
+    }
+
+    fn report(&self, server: &str, client: &str) {
+        let received = self.received.load(Ordering::Relaxed);
+        let delivered = self.delivered.load(Ordering::Relaxed);
+        let backend_ended = self.backend_ended.load(Ordering::Relaxed);
+        // hyper stops polling a body once its content length is written
+        let client_ended = self.client_ended.load(Ordering::Relaxed) || (backend_ended && delivered >= received);
+        if self.backend_failed.load(Ordering::Relaxed) {
+            RELAY_STATS.truncated.fetch_add(1, Ordering::Relaxed);
+            warn!("Relay from server {} to client {} truncated by the backend: {} bytes received, {} delivered", server, client, received, delivered);
+        } else if !client_ended {
+            RELAY_STATS.client_disconnects.fetch_add(1, Ordering::Relaxed);
+            let backend = if backend_ended { "complete" } else { "in progress" };
+            warn!("Client {} disconnected from server {}: {} bytes received ({}), {} delivered", client, server, received, backend, delivered);
+        } else if delivered < received {
+            // annotations only add bytes
+            RELAY_STATS.short.fetch_add(1, Ordering::Relaxed);
+            warn!("Relay from server {} to client {} lost bytes: {} received, {} delivered", server, client, received, delivered);
+        } else {
+            RELAY_STATS.completed.fetch_add(1, Ordering::Relaxed);
+            info!("Relayed {} bytes from server {} to client {}, {} delivered", received, server, client, delivered);
+        }
+    }
+}
+
+/// Counts the bytes coming from a backend.
+pub struct Received<S> {
+    stream: S,
+    tally: Arc<RelayTally>,
+}
+
+impl<S> Received<S> {
+    pub fn new(stream: S, tally: Arc<RelayTally>) -> Self {
+        Received { stream, tally }
+    }
+}
+
+impl<S, E> Stream for Received<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let res = Pin::new(&mut self.stream).poll_next(cx);
+        match &res {
+            Poll::Ready(Some(Ok(bytes))) => { self.tally.received.fetch_add(bytes.len() as u64, Ordering::Relaxed); },
+            Poll::Ready(Some(Err(_))) => self.tally.backend_failed.store(true, Ordering::Relaxed),
+            Poll::Ready(None) => self.tally.backend_ended.store(true, Ordering::Relaxed),
+            Poll::Pending => {},
+        }
+        res
+    }
+}
+
+/// Counts the bytes handed to the client, and reports the relay once it is dropped,
+/// i.e. when the response is over or the client went away.
+pub struct Delivered<S> {
+    stream: S,
+    tally: Arc<RelayTally>,
+    server: String,
+    client: String,
+}
+
+impl<S> Delivered<S> {
+    pub fn new(stream: S, tally: Arc<RelayTally>, server: String, client: String) -> Self {
+        Delivered { stream, tally, server, client }
+    }
+}
+
+impl<S, E> Stream for Delivered<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let res = Pin::new(&mut self.stream).poll_next(cx);
+        match &res {
+            Poll::Ready(Some(Ok(bytes))) => { self.tally.delivered.fetch_add(bytes.len() as u64, Ordering::Relaxed); },
+            Poll::Ready(None) => self.tally.client_ended.store(true, Ordering::Relaxed),
+            _ => {},
+        }
+        res
+    }
+}
+
+impl<S> Drop for Delivered<S> {
+    fn drop(&mut self) {
+        self.tally.report(&self.server, &self.client);
+    }
+}
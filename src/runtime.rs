@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::Value;
+
+use crate::backend::TimeoutProfile;
+use crate::config::{Args, SelectCount};
+use crate::health::{HealthPolicy, IncrementDecay};
+use crate::shaping::{keep_alive_value, HistoryLimit};
+use crate::state::{ColdLoad, SelOpt};
+
+/// Every tunable of the balancer that is not a plain request option, built once at startup.
+/// The defaults are the values the balancer has always used.
+#[derive(Clone, Debug)]
+pub struct RuntimeConfig {
+    pub selection: SelectionConfig,
+    pub health: Arc<dyn HealthPolicy>,
+    /// Timeout in seconds of the /api/tags, /api/ps and /api/version requests of a sync.
+    pub sync_timeout: u32,
+    /// Timeouts of the requests relayed to the backends.
+    pub timeouts: TimeoutConfig,
+    /// Bytes of a failed response kept in the logs.
+    pub preview_len: usize,
+    /// Weight of the newest sample in the latency EWMAs.
+    pub latency_alpha: f32,
+    /// Queue waits shorter than this, in milliseconds, are not worth a log line.
+    pub queue_log_threshold_ms: u128,
+    /// Model of the synthetic probes, also the default one of benchmarks.
+    pub probe_model: Option<String>,
+    /// Chat histories longer than this lose their oldest messages.
+    pub history_limit: HistoryLimit,
+    /// Replaces the `keep_alive` of chat requests, so residency is decided centrally.
+    pub keep_alive: Option<Value>,
+    /// The `keep_alive` of the model on the servers that lost a parallel race.
+    pub loser_keep_alive: Option<Value>,
+    /// Whether chat requests sync their candidates before sending to them.
+    pub presync: Presync,
+    /// Candidates synced more recently than this are not synced again before a chat request.
+    pub presync_ttl: Duration,
+    /// Interval of the heartbeats of streamed chat requests while their backends are selected.
+    pub heartbeat: Option<Duration>,
+    /// What `/readyz` requires before reporting ready.
+    pub readiness: Readiness,
+    /// How a backend answering 429 Too Many Requests is treated.
+    pub overload: Overload,
+    /// Longest first token timeout clients may ask for with `X-LB-Timeout-FT`, 0 ignoring it.
+    pub max_timeout_ft: u32,
+    /// How long after its announced shutdown a server that did not rejoin stays excused,
+    /// before it is treated as any dead server.
+    pub leave_timeout: Duration,
+}
+
+/// How a backend answering 429 Too Many Requests is treated: an overloaded backend is healthy,
+/// it only needs fewer requests for a while.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overload {
+    /// Selected less often for the `Retry-After` of the response, or else this long,
+    /// its health untouched.
+    Backoff(Duration),
+    /// Like any other failure, the backend getting closer to dead.
+    Failure,
+}
+
+/// How chat requests sync their candidates, which doubles the round-trips to the backends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Presync {
+    /// Sync, then send if the candidate is alive.
+    Always,
+    /// Send right away and sync meanwhile, only for the next requests to benefit.
+    Concurrent,
+    /// Never, the periodic syncs and request outcomes are trusted.
+    Off,
+}
+
+/// Readiness criteria of the fleet, for orchestrators to hold traffic back until it can serve.
+#[derive(Clone, Debug)]
+pub struct Readiness {
+    /// Healthy servers required.
+    pub min_servers: usize,
+    /// Models each hosted by at least one healthy server.
+    pub models: Vec<String>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness { min_servers: 1, models: Vec::new() }
+    }
+}
+
+/// Selection options of every endpoint, the default one unless overridden.
+#[derive(Default, Clone, Debug)]
+pub struct SelectionConfig {
+    pub default: SelOpt,
+    pub per_endpoint: HashMap<String, SelOpt>,
+}
+
+impl SelectionConfig {
+    pub fn for_endpoint(&self, path: &str) -> SelOpt {
+        self.per_endpoint.get(path).copied().unwrap_or(self.default)
+    }
+}
+
+/// Endpoints sharing their timeouts: running a model, reading what the backends host,
+/// and moving models around.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointClass {
+    Chat,
+    Tags,
+    Pull,
+}
+
+impl EndpointClass {
+    pub fn of(path: &str) -> Self {
+        match path {
+            "/api/tags" | "/api/ps" | "/api/show" | "/api/version" => EndpointClass::Tags,
+            "/api/pull" | "/api/push" | "/api/create" | "/api/copy" | "/api/delete" => EndpointClass::Pull,
+            _ if path.starts_with("/api/blobs/") => EndpointClass::Pull,
+            _ => EndpointClass::Chat,
+        }
+    }
+}
+
+impl std::str::FromStr for EndpointClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chat" => Ok(EndpointClass::Chat),
+            "tags" => Ok(EndpointClass::Tags),
+            "pull" => Ok(EndpointClass::Pull),
+            _ => Err(format!("Unknown endpoint class {}, expected chat, tags or pull", s)),
+        }
+    }
+}
+
+/// Timeouts of every class of endpoints.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeoutConfig {
+    pub chat: TimeoutProfile,
+    pub tags: TimeoutProfile,
+    pub pull: TimeoutProfile,
+}
+
+impl TimeoutConfig {
+    /// The timeouts of --timeout and --timeout-ft, overridden by --timeout-for.
+    pub fn from_args(args: &Args) -> Self {
+        let mut timeouts = TimeoutConfig {
+            chat: TimeoutProfile { connect: args.timeout, first_token: args.timeout_ft, idle: args.timeout_ft, total: 0 },
+            tags: TimeoutProfile::read(args.timeout),
+            // layers can take long between two progress lines
+            pull: TimeoutProfile { connect: 1, first_token: 0, idle: 0, total: 0 },
+        };
+        for class in &args.timeout_for {
+            match class.class {
+                EndpointClass::Chat => timeouts.chat = class.profile,
+                EndpointClass::Tags => timeouts.tags = class.profile,
+                EndpointClass::Pull => timeouts.pull = class.profile,
+            }
+        }
+        timeouts
+    }
+
+    pub fn for_endpoint(&self, path: &str) -> TimeoutProfile {
+        match EndpointClass::of(path) {
+            EndpointClass::Chat => self.chat,
+            EndpointClass::Tags => self.tags,
+            EndpointClass::Pull => self.pull,
+        }
+    }
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            selection: SelectionConfig {
+                default: SelOpt {
+                    count: (3, 6),
+                    resurrect_p: 0.1,
+                    resurrect_n: 1,
+                    strict: false,
+                    cold_load: ColdLoad::TopUp,
+                    slow_factor: 3.0,
+                },
+                per_endpoint: HashMap::new(),
+            },
+            health: Arc::new(IncrementDecay::default()),
+            sync_timeout: 5,
+            timeouts: TimeoutConfig {
+                chat: TimeoutProfile { connect: 1, first_token: 10, idle: 10, total: 0 },
+                tags: TimeoutProfile::read(1),
+                pull: TimeoutProfile { connect: 1, first_token: 0, idle: 0, total: 0 },
+            },
+            preview_len: 100,
+            latency_alpha: 0.2,
+            queue_log_threshold_ms: 100,
+            probe_model: None,
+            history_limit: HistoryLimit::default(),
+            keep_alive: None,
+            loser_keep_alive: None,
+            presync: Presync::Always,
+            presync_ttl: Duration::ZERO,
+            heartbeat: None,
+            readiness: Readiness::default(),
+            overload: Overload::Backoff(Duration::from_secs(5)),
+            max_timeout_ft: 300,
+            leave_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// The defaults, overridden by the command line.
+    pub fn from_args(args: &Args) -> Self {
+        let sel_opt = |count: SelectCount| SelOpt {
+            count: (count.min, count.max),
+            resurrect_p: args.resurrect_p,
+            resurrect_n: args.resurrect_n,
+            strict: args.strict,
+            cold_load: match args.cold_load.as_str() {
+                "wait" => ColdLoad::Wait,
+                "eager" => ColdLoad::Eager,
+                _ => ColdLoad::TopUp,
+            },
+            slow_factor: args.model_slow_factor,
+        };
+        RuntimeConfig {
+            selection: SelectionConfig {
+                default: sel_opt(args.select_count),
+                per_endpoint: args.select_count_for.iter().map(|e| (e.endpoint.clone(), sel_opt(e.count))).collect(),
+            },
+            health: Arc::new(IncrementDecay {
+                initial: args.health_initial,
+                best_increment: args.health_best_increment,
+                increment: args.health_increment,
+                decay: args.health_decay,
+            }),
+            sync_timeout: args.sync_timeout,
+            timeouts: TimeoutConfig::from_args(args),
+            preview_len: args.preview_len,
+            latency_alpha: args.latency_alpha,
+            queue_log_threshold_ms: args.queue_log_threshold_ms,
+            probe_model: args.probe_model.clone(),
+            history_limit: HistoryLimit { messages: args.max_history_messages, tokens: args.max_history_tokens },
+            keep_alive: args.keep_alive.as_deref().map(keep_alive_value),
+            loser_keep_alive: args.loser_keep_alive.as_deref().map(keep_alive_value),
+            presync: match args.presync.as_str() {
+                "concurrent" => Presync::Concurrent,
+                "off" => Presync::Off,
+                _ => Presync::Always,
+            },
+            presync_ttl: Duration::from_secs(args.presync_ttl),
+            heartbeat: (args.heartbeat_secs > 0).then(|| Duration::from_secs(args.heartbeat_secs)),
+            readiness: Readiness { min_servers: args.ready_min_servers, models: args.ready_models.clone() },
+            max_timeout_ft: args.max_timeout_ft,
+            leave_timeout: Duration::from_secs(args.leave_timeout),
+            overload: match args.backend_429.as_str() {
+                "failure" => Overload::Failure,
+                _ => Overload::Backoff(Duration::from_secs(args.backend_429_backoff_secs)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use super::*;
+
+    fn from_cli(flags: &[&str]) -> RuntimeConfig {
+        let args = Args::parse_from(std::iter::once("ollama_load_balancer").chain(flags.iter().copied()));
+        RuntimeConfig::from_args(&args)
+    }
+
+    #[test]
+    fn cli_defaults_match_the_defaults() {
+        let (cli, default) = (from_cli(&[]), RuntimeConfig::default());
+        assert_eq!(cli.selection.default.count, default.selection.default.count);
+        assert_eq!(cli.selection.default.resurrect_p, default.selection.default.resurrect_p);
+        assert_eq!(cli.selection.default.resurrect_n, default.selection.default.resurrect_n);
+        assert_eq!(cli.sync_timeout, 5);
+        assert_eq!(cli.sync_timeout, default.sync_timeout);
+        assert_eq!(cli.preview_len, default.preview_len);
+        assert_eq!(cli.latency_alpha, default.latency_alpha);
+        assert_eq!(cli.queue_log_threshold_ms, default.queue_log_threshold_ms);
+        assert_eq!(cli.max_timeout_ft, default.max_timeout_ft);
+        assert_eq!(cli.leave_timeout, default.leave_timeout);
+        assert_eq!(cli.overload, default.overload);
+        assert_eq!(cli.presync, default.presync);
+    }
+
+    #[test]
+    fn cli_overrides_tunables() {
+        let runtime = from_cli(&[
+            "--sync-timeout", "2", "--preview-len", "500", "--latency-alpha", "0.5",
+            "--queue-log-threshold-ms", "0", "--presync", "off", "--backend-429", "failure",
+        ]);
+        assert_eq!(runtime.sync_timeout, 2);
+        assert_eq!(runtime.preview_len, 500);
+        assert_eq!(runtime.latency_alpha, 0.5);
+        assert_eq!(runtime.queue_log_threshold_ms, 0);
+        assert_eq!(runtime.presync, Presync::Off);
+        assert_eq!(runtime.overload, Overload::Failure);
+    }
+
+    #[test]
+    fn sync_timeout_is_independent_of_request_timeouts() {
+        let runtime = from_cli(&["--timeout", "3"]);
+        assert_eq!(runtime.sync_timeout, 5);
+        assert_eq!(runtime.timeouts.tags.first_token, 3);
+    }
+
+    #[test]
+    fn timeout_classes_override_the_common_timeouts() {
+        let runtime = from_cli(&["--timeout-ft", "20", "--timeout-for", "pull=2:0:60:0"]);
+        assert_eq!(runtime.timeouts.chat.first_token, 20);
+        assert_eq!(runtime.timeouts.pull.connect, 2);
+        assert_eq!(runtime.timeouts.pull.idle, 60);
+        assert_eq!(runtime.timeouts.for_endpoint("/api/blobs/sha256:abc").idle, 60);
+        assert_eq!(runtime.timeouts.for_endpoint("/api/chat").first_token, 20);
+    }
+}
@@ -0,0 +1,798 @@
+use chrono::Utc;
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::Ordering;
+
+use crate::audit::{AuditTrail, RoutingRecord};
+use crate::backend::PerformanceInfo;
+use crate::profiles::ProfileStore;
+use crate::relay::{RelayStats, RELAY_STATS};
+use crate::state::{ConversationMap, Exclusion, FailureRecord, Health, ModelConfig, OllamaServer, SharedServerList, StrictStats, RACE_STATS, STRICT_STATS};
+
+/// Version of the admin contract, bumped on any incompatible change.
+/// Adding an optional field is compatible, renaming or removing one is not.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Selection and health state of every backend, served on `/admin/state`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct StateReport {
+    pub schema_version: u32,
+    /// RFC 3339 time the report was taken.
+    pub generated_at: String,
+    pub servers: Vec<ServerReport>,
+    /// Work thrown away by racing.
+    #[serde(default)]
+    pub racing: Option<RacingReport>,
+    /// Model and backend profiles generations, more than one while a reload drains.
+    #[serde(default)]
+    pub profiles: Option<ProfilesReport>,
+    /// Capacity lost to strict mode, absent when it is off.
+    #[serde(default)]
+    pub strict: Option<StrictReport>,
+    /// How the relayed responses ended.
+    #[serde(default)]
+    pub relay: Option<RelayReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct StrictReport {
+    /// Selections made in strict mode.
+    pub selections: u64,
+    /// Selections that excluded at least one unreliable server.
+    pub reduced: u64,
+    /// Unreliable servers excluded over all selections.
+    pub excluded: u64,
+    /// Selections left with no server at all.
+    pub emptied: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ProfilesReport {
+    /// Generation given to new requests, bumped by every reload.
+    pub generation: u64,
+    /// Requests in flight per generation, older ones drain away.
+    pub in_flight: Vec<GenerationReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct GenerationReport {
+    pub generation: u64,
+    pub requests: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RacingReport {
+    /// Streams aborted because another server won the race.
+    pub aborted: u64,
+    /// Tokens received from the aborted streams before they were closed.
+    pub wasted_tokens: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RelayReport {
+    /// Responses delivered in full.
+    pub completed: u64,
+    /// Responses whose client went away before their end.
+    pub client_disconnects: u64,
+    /// Responses whose backend stream broke off.
+    pub truncated: u64,
+    /// Responses complete on both ends with fewer bytes delivered than received.
+    pub short: u64,
+}
+
+impl RelayReport {
+    pub fn from_stats(stats: &RelayStats) -> Self {
+        RelayReport {
+            completed: stats.completed.load(Ordering::Relaxed),
+            client_disconnects: stats.client_disconnects.load(Ordering::Relaxed),
+            truncated: stats.truncated.load(Ordering::Relaxed),
+            short: stats.short.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl StrictReport {
+    fn from_stats(stats: &StrictStats) -> Self {
+        StrictReport {
+            selections: stats.selections.load(Ordering::Relaxed),
+            reduced: stats.reduced.load(Ordering::Relaxed),
+            excluded: stats.excluded.load(Ordering::Relaxed),
+            emptied: stats.emptied.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ServerReport {
+    pub address: String,
+    pub name: String,
+    /// GPU domain shared with other servers, if declared.
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// Ollama version, unknown until the first successful sync.
+    pub version: Option<String>,
+    pub busy: bool,
+    pub connections: usize,
+    pub health: HealthReport,
+    pub reliability: Reliability,
+    /// EWMA of the time to first token of client requests.
+    pub latency_ms: Option<f32>,
+    /// EWMA of the time to first token of synthetic probes.
+    pub shadow_ttft_ms: Option<f32>,
+    pub shadow_failures: usize,
+    /// Restored from a previous run and not synced yet.
+    pub stale: bool,
+    /// Most VRAM ever seen in use, in bytes.
+    pub vram_peak: u64,
+    /// Why the server is excluded despite being reachable, if it is.
+    #[serde(default)]
+    pub misconfigured: Option<String>,
+    /// Routed around while being benchmarked.
+    #[serde(default)]
+    pub isolated: bool,
+    /// RFC 3339 time the server announced going down at, until it rejoins.
+    #[serde(default)]
+    pub leaving: Option<String>,
+    /// Server registered earlier that is the same Ollama instance, this one is routed around.
+    #[serde(default)]
+    pub duplicate_of: Option<String>,
+    /// Seconds the server is still backed off for after answering 429.
+    #[serde(default)]
+    pub backoff_secs: Option<f32>,
+    /// Models available on the server, from `/api/tags`.
+    pub models: Vec<String>,
+    /// Models loaded on the server, from `/api/ps`.
+    pub loaded: Vec<LoadedModel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HealthReport {
+    Dead,
+    Healthy { score: f32 },
+    /// Not synced yet since startup.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Reliability {
+    Reliable,
+    Unreliable,
+    SecondChanceGiven,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LoadedModel {
+    pub name: String,
+    /// Bytes in total.
+    pub size: Option<u64>,
+    /// Bytes held in VRAM.
+    pub size_vram: Option<u64>,
+    /// RFC 3339 time the model is unloaded at.
+    pub expires_at: Option<String>,
+}
+
+/// Routing history of the recent sessions, served on `/admin/sessions`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SessionsReport {
+    pub schema_version: u32,
+    /// RFC 3339 time the report was taken.
+    pub generated_at: String,
+    /// Most recently active first.
+    pub sessions: Vec<SessionTrail>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SessionTrail {
+    /// `session:<X-Session-Id>`, or `client:<ip>` without the header.
+    pub session: String,
+    /// Oldest first.
+    pub turns: Vec<TurnReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TurnReport {
+    /// RFC 3339 time the turn was routed.
+    pub at: String,
+    pub model: String,
+    pub server: String,
+    pub server_name: String,
+    /// Servers the request was raced on, 0 if it shared the response of an identical request.
+    pub candidates: usize,
+    pub ttft_ms: f32,
+}
+
+impl From<&RoutingRecord> for TurnReport {
+    fn from(record: &RoutingRecord) -> Self {
+        TurnReport {
+            at: record.at.to_rfc3339(),
+            model: record.model.clone(),
+            server: record.server.clone(),
+            server_name: record.server_name.clone(),
+            candidates: record.candidates,
+            ttft_ms: record.ttft.as_secs_f32() * 1000.0,
+        }
+    }
+}
+
+impl From<&Health> for HealthReport {
+    fn from(health: &Health) -> Self {
+        match health {
+            Health::Dead => HealthReport::Dead,
+            Health::Healthy(score) => HealthReport::Healthy { score: *score },
+        }
+    }
+}
+
+impl From<&FailureRecord> for Reliability {
+    fn from(record: &FailureRecord) -> Self {
+        match record {
+            FailureRecord::Reliable => Reliability::Reliable,
+            FailureRecord::Unreliable => Reliability::Unreliable,
+            FailureRecord::SecondChanceGiven => Reliability::SecondChanceGiven,
+        }
+    }
+}
+
+impl From<&ModelConfig> for LoadedModel {
+    fn from(model: &ModelConfig) -> Self {
+        LoadedModel {
+            name: model.name.clone(),
+            size: model.size,
+            size_vram: model.size_vram,
+            expires_at: model.expires_at.map(|t| t.to_rfc3339()),
+        }
+    }
+}
+
+fn server_report(address: &str, srv: &OllamaServer) -> ServerReport {
+    let mut models = srv.models.keys().cloned().collect::<Vec<_>>();
+    models.sort();
+    let mut loaded = srv.actives.values().map(LoadedModel::from).collect::<Vec<_>>();
+    loaded.sort_by(|a, b| a.name.cmp(&b.name));
+    ServerReport {
+        address: address.to_string(),
+        name: srv.name.clone(),
+        domain: srv.domain.clone(),
+        version: srv.version.clone(),
+        busy: srv.state.busy,
+        connections: srv.state.connections,
+        health: if srv.state.unsynced { HealthReport::Unknown } else { (&srv.state.health).into() },
+        reliability: (&srv.state.failure_record).into(),
+        latency_ms: srv.state.latency_ms,
+        shadow_ttft_ms: srv.state.shadow_ttft_ms,
+        shadow_failures: srv.state.shadow_failures,
+        stale: srv.state.stale,
+        vram_peak: srv.state.vram_peak,
+        misconfigured: srv.state.misconfigured.clone(),
+        isolated: srv.state.isolated,
+        leaving: srv.state.leaving.map(|t| t.to_rfc3339()),
+        duplicate_of: srv.state.duplicate_of.clone(),
+        backoff_secs: srv.state.backoff_until
+            .map(|until| until.saturating_duration_since(std::time::Instant::now()).as_secs_f32())
+            .filter(|secs| *secs > 0.0),
+        models,
+        loaded,
+    }
+}
+
+pub fn state_report(servers: &SharedServerList, strict: bool, profiles: &ProfileStore) -> StateReport {
+    let (generation, in_flight) = profiles.generations();
+    let servers = servers.lock().unwrap();
+    StateReport {
+        schema_version: SCHEMA_VERSION,
+        generated_at: Utc::now().to_rfc3339(),
+        servers: servers.iter().map(|(addr, srv)| server_report(addr, srv)).collect(),
+        racing: Some(RacingReport {
+            aborted: RACE_STATS.aborted.load(Ordering::Relaxed),
+            wasted_tokens: RACE_STATS.wasted_tokens.load(Ordering::Relaxed),
+        }),
+        profiles: Some(ProfilesReport {
+            generation,
+            in_flight: in_flight.into_iter().map(|(generation, requests)| GenerationReport { generation, requests }).collect(),
+        }),
+        strict: strict.then(|| StrictReport::from_stats(&STRICT_STATS)),
+        relay: Some(RelayReport::from_stats(&RELAY_STATS)),
+    }
+}
+
+pub fn sessions_report(audit: &AuditTrail) -> SessionsReport {
+    SessionsReport {
+        schema_version: SCHEMA_VERSION,
+        generated_at: Utc::now().to_rfc3339(),
+        sessions: audit.export().into_iter().map(|(session, trail)| SessionTrail {
+            session,
+            turns: trail.iter().map(TurnReport::from).collect(),
+        }).collect(),
+    }
+}
+
+/// Conversations pinned by `--conversation-routing`, served on `/admin/conversations`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ConversationsReport {
+    pub schema_version: u32,
+    /// RFC 3339 time the report was taken.
+    pub generated_at: String,
+    pub capacity: usize,
+    /// Seconds a pin lasts without a new turn, none if pins only get evicted by newer ones.
+    pub ttl_secs: Option<u64>,
+    /// Lookups that found a pinned server.
+    pub hits: u64,
+    pub misses: u64,
+    /// Pins dropped to make room for newer ones.
+    pub evictions: u64,
+    /// Pins dropped for being unused longer than the TTL.
+    pub expirations: u64,
+    /// Most recently used first.
+    pub pins: Vec<PinReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PinReport {
+    /// Hash of the conversation so far, to unpin it with `DELETE /admin/conversations`.
+    pub key: String,
+    pub server: String,
+    /// Seconds since the last turn.
+    pub idle_secs: u64,
+}
+
+pub fn conversations_report(conversations: &ConversationMap) -> ConversationsReport {
+    let stats = &conversations.stats;
+    ConversationsReport {
+        schema_version: SCHEMA_VERSION,
+        generated_at: Utc::now().to_rfc3339(),
+        capacity: conversations.capacity(),
+        ttl_secs: conversations.ttl().map(|ttl| ttl.as_secs()),
+        hits: stats.hits,
+        misses: stats.misses,
+        evictions: stats.evictions,
+        expirations: stats.expirations,
+        pins: conversations.pins().into_iter().map(|(hash, pin)| PinReport {
+            key: format!("{:016x}", hash),
+            server: pin.server,
+            idle_secs: pin.used_at.elapsed().as_secs(),
+        }).collect(),
+    }
+}
+
+/// Result of an isolated benchmark, answered by `/admin/benchmark`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BenchmarkReport {
+    pub schema_version: u32,
+    /// RFC 3339 time the benchmark ended.
+    pub generated_at: String,
+    pub server: String,
+    pub model: String,
+    /// Whether the requests in flight finished before the first run.
+    pub drained: bool,
+    /// Time to first token of every successful run.
+    pub ttft_ms: Vec<f32>,
+    pub failures: usize,
+    /// Latency prior before the benchmark.
+    pub previous_latency_ms: Option<f32>,
+    /// Latency prior after the benchmark, the median of the runs.
+    pub latency_ms: Option<f32>,
+}
+
+/// One line of the `/admin/events` stream.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Race(RaceEvent),
+    Selection(SelectionEvent),
+}
+
+/// Servers selected for a request, and why the others were not.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SelectionEvent {
+    pub schema_version: u32,
+    /// RFC 3339 time of the selection.
+    pub at: String,
+    pub model: String,
+    /// In the order they are tried or raced.
+    pub selected: Vec<String>,
+    pub excluded: Vec<ExclusionReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ExclusionReport {
+    pub server: String,
+    /// First filter that eliminated the server: isolated, draining, duplicate, not_pinned, unsynced, dead,
+    /// misconfigured, missing_model, untrusted, shared_gpu_domain, not_loaded, outranked or at_concurrency_cap.
+    pub reason: String,
+}
+
+/// Which servers a request for a model could go to, served on `/admin/explain`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ExplainReport {
+    pub schema_version: u32,
+    pub model: String,
+    /// Servers the selection would choose from.
+    pub eligible: Vec<String>,
+    pub excluded: Vec<ExclusionReport>,
+}
+
+/// The backend count the demand calls for, served on `/admin/autoscale` and posted to the
+/// autoscaling webhook when it changes.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AutoscaleReport {
+    pub schema_version: u32,
+    pub desired_servers: usize,
+    pub current_servers: usize,
+    /// Servers taking requests: alive, not misconfigured, isolated, draining or a duplicate.
+    pub healthy_servers: usize,
+    /// Average requests waiting in a queue for a generation slot or an idle server over the window.
+    pub queue_depth: f64,
+    /// Average share of the healthy servers relaying a stream over the window.
+    pub utilization: f64,
+    /// Share of the healthy servers whose time to first token meets the SLO, 1 without an SLO.
+    pub slo_compliance: f64,
+    /// What drives the desired count: `queue`, `utilization`, `slo` or `idle`.
+    pub reason: String,
+    pub window_secs: u64,
+}
+
+/// Rolling performance of every model on every server, served on `/admin/stats`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ModelStatsReport {
+    pub schema_version: u32,
+    pub entries: Vec<ModelStatsEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ModelStatsEntry {
+    pub server: String,
+    pub model: String,
+    /// Requests served for the model.
+    pub requests: u64,
+    /// EWMA of the time to first token.
+    pub ttft_ms: f32,
+    /// EWMA of the generation speed, absent until measured in a race.
+    pub tokens_per_sec: Option<f32>,
+    /// Seconds since the last request.
+    pub last_seen_secs: u64,
+}
+
+/// Requests and tokens of every client, served on `/admin/usage`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UsageReport {
+    pub schema_version: u32,
+    /// RFC 3339 time the report was taken.
+    pub generated_at: String,
+    /// The most tokens first.
+    pub clients: Vec<ClientUsageReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClientUsageReport {
+    /// Identity of the client, API keys masked, or `ip:<address>` without authentication.
+    pub client: String,
+    pub requests: u64,
+    /// Prompt + generated tokens.
+    pub tokens: u64,
+    /// Tokens counted against the daily quota.
+    pub day_tokens: u64,
+    /// The most tokens first.
+    pub models: Vec<ModelUsageReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ModelUsageReport {
+    pub model: String,
+    /// Requests answered with a token count.
+    pub requests: u64,
+    pub tokens: u64,
+}
+
+/// Requests per model and server, bucketed by hour, served on `/admin/heatmap`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HeatmapReport {
+    pub schema_version: u32,
+    /// RFC 3339 start of the first bucket.
+    pub start: String,
+    pub bucket_secs: u64,
+    /// Only the pairs with requests in the period.
+    pub series: Vec<HeatmapSeries>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HeatmapSeries {
+    pub model: String,
+    pub server: String,
+    /// Requests per bucket, oldest first.
+    pub counts: Vec<u64>,
+}
+
+/// Traces of the requests kept by the sampling, served on `/admin/traces`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TracesReport {
+    pub schema_version: u32,
+    /// Traces kept since startup, some since pushed out of the buffer.
+    pub kept: u64,
+    /// Traces dropped by the sampling since startup.
+    pub dropped: u64,
+    /// Oldest first.
+    pub traces: Vec<TraceReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TraceReport {
+    /// From the `traceparent` header of the client when it has one.
+    pub trace_id: String,
+    /// RFC 3339 time the request arrived.
+    pub at: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// Until the response headers, streamed bodies take longer.
+    pub duration_ms: u64,
+    /// Until the end of the response body.
+    #[serde(default)]
+    pub total_ms: u64,
+    /// How the response body ended: `completed`, `truncated` by the backend or `client_disconnected`.
+    #[serde(default)]
+    pub outcome: String,
+    /// The backend that answered, if any.
+    pub upstream: Option<String>,
+    /// Why the trace was kept: `error`, including truncated responses, `slow` or `sampled`.
+    pub reason: String,
+}
+
+/// How every candidate of a chat request did.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RaceEvent {
+    pub schema_version: u32,
+    /// RFC 3339 time the race was decided.
+    pub at: String,
+    pub model: String,
+    pub candidates: Vec<CandidateReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CandidateReport {
+    pub server: String,
+    pub outcome: Outcome,
+    /// Time to the first byte, absent when nothing was received.
+    pub ttft_ms: Option<f32>,
+    /// Bytes received within the measurement window.
+    pub bytes: Option<usize>,
+    /// Tokens generated within the measurement window.
+    #[serde(default)]
+    pub tokens: Option<usize>,
+    /// Tokens per second the candidate was ranked by.
+    #[serde(default)]
+    pub tokens_per_sec: Option<f32>,
+    /// Why the candidate failed.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Winner,
+    LoserCancelled,
+    Failed,
+}
+
+impl RaceEvent {
+    pub fn new(model: &str, candidates: Vec<CandidateReport>) -> Self {
+        RaceEvent {
+            schema_version: SCHEMA_VERSION,
+            at: Utc::now().to_rfc3339(),
+            model: model.to_string(),
+            candidates,
+        }
+    }
+}
+
+impl SelectionEvent {
+    pub fn new(model: &str, selected: &[String], excluded: &[(String, Exclusion)]) -> Self {
+        SelectionEvent {
+            schema_version: SCHEMA_VERSION,
+            at: Utc::now().to_rfc3339(),
+            model: model.to_string(),
+            selected: selected.to_vec(),
+            excluded: exclusion_reports(excluded),
+        }
+    }
+}
+
+pub fn exclusion_reports(excluded: &[(String, Exclusion)]) -> Vec<ExclusionReport> {
+    excluded.iter().map(|(server, reason)| ExclusionReport {
+        server: server.clone(),
+        reason: reason.as_str().to_string(),
+    }).collect()
+}
+
+impl CandidateReport {
+    pub fn answered(server: &str, perf: &PerformanceInfo) -> Self {
+        CandidateReport {
+            server: server.to_string(),
+            outcome: Outcome::LoserCancelled,
+            ttft_ms: Some(perf.ttft.as_secs_f32() * 1000.0),
+            bytes: Some(perf.bytes),
+            tokens: Some(perf.tokens),
+            tokens_per_sec: Some(perf.rate()),
+            error: None,
+        }
+    }
+
+    pub fn failed(server: &str, perf: Option<&PerformanceInfo>, error: String) -> Self {
+        CandidateReport {
+            server: server.to_string(),
+            outcome: Outcome::Failed,
+            ttft_ms: perf.map(|p| p.ttft.as_secs_f32() * 1000.0),
+            bytes: perf.map(|p| p.bytes),
+            tokens: perf.map(|p| p.tokens),
+            tokens_per_sec: perf.map(|p| p.rate()),
+            error: Some(error),
+        }
+    }
+}
+
+/// A line of `/admin/servers?watch=true`: the members at a revision first, then every change
+/// of the membership, each with the next revision.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MembershipEvent {
+    Snapshot(MembershipReport),
+    Added(MemberChange),
+    Removed(MemberChange),
+    /// Announced its shutdown, routed around until it rejoins.
+    Draining(MemberChange),
+    Rejoined(MemberChange),
+}
+
+/// The backends at a revision, served on `GET /admin/servers`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MembershipReport {
+    pub schema_version: u32,
+    /// Revision of the last change, revisions restart from 0 with the balancer.
+    pub revision: u64,
+    pub servers: Vec<MemberReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MemberReport {
+    pub address: String,
+    pub name: String,
+    pub draining: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MemberChange {
+    pub revision: u64,
+    /// RFC 3339 time of the change.
+    pub at: String,
+    pub server: String,
+    pub name: String,
+}
+
+impl MembershipEvent {
+    pub fn revision(&self) -> u64 {
+        match self {
+            MembershipEvent::Snapshot(report) => report.revision,
+            MembershipEvent::Added(change) | MembershipEvent::Removed(change)
+                | MembershipEvent::Draining(change) | MembershipEvent::Rejoined(change) => change.revision,
+        }
+    }
+}
+
+/// JSON Schema of every document of the admin API, served on `/admin/schema`.
+pub fn admin_schema() -> Value {
+    serde_json::json!({
+        "schema_version": SCHEMA_VERSION,
+        "state": schema_for!(StateReport),
+        "sessions": schema_for!(SessionsReport),
+        "conversations": schema_for!(ConversationsReport),
+        "events": schema_for!(Event),
+        "explain": schema_for!(ExplainReport),
+        "membership": schema_for!(MembershipEvent),
+        "autoscale": schema_for!(AutoscaleReport),
+        "stats": schema_for!(ModelStatsReport),
+        "heatmap": schema_for!(HeatmapReport),
+        "usage": schema_for!(UsageReport),
+        "traces": schema_for!(TracesReport),
+        "benchmark": schema_for!(BenchmarkReport),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::state::add_server;
+    use serde_json::json;
+
+    fn servers_with(address: &str, name: &str) -> SharedServerList {
+        let servers = SharedServerList::default();
+        add_server(servers.clone(), &ServerConfig { address: address.to_string(), name: name.to_string() });
+        servers
+    }
+
+    #[test]
+    fn server_report_fields_are_stable() {
+        let servers = servers_with("http://10.0.0.1:11434", "gpu-1");
+        let report = {
+            let servers = servers.lock().unwrap();
+            serde_json::to_value(server_report("http://10.0.0.1:11434", &servers["http://10.0.0.1:11434"])).unwrap()
+        };
+        for field in ["address", "name", "domain", "version", "busy", "connections", "health", "reliability", "latency_ms",
+            "shadow_ttft_ms", "shadow_failures", "stale", "vram_peak", "misconfigured", "isolated", "leaving",
+            "duplicate_of", "backoff_secs", "models", "loaded"] {
+            assert!(report.get(field).is_some(), "missing field {}", field);
+        }
+        assert_eq!(report["reliability"], json!("reliable"));
+    }
+
+    #[test]
+    fn health_is_tagged_by_status() {
+        let servers = servers_with("http://10.0.0.1:11434", "gpu-1");
+        let mut servers = servers.lock().unwrap();
+        let server = servers.get_mut("http://10.0.0.1:11434").unwrap();
+        assert_eq!(serde_json::to_value(server_report("a", server).health).unwrap(), json!({ "status": "unknown" }));
+        server.state.unsynced = false;
+        assert_eq!(serde_json::to_value(server_report("a", server).health).unwrap(), json!({ "status": "dead" }));
+        server.state.health = Health::Healthy(2.5);
+        assert_eq!(serde_json::to_value(server_report("a", server).health).unwrap(), json!({ "status": "healthy", "score": 2.5 }));
+    }
+
+    #[test]
+    fn state_report_without_optional_sections_parses() {
+        // what a client of this schema version must keep understanding
+        let state: StateReport = serde_json::from_value(json!({
+            "schema_version": SCHEMA_VERSION,
+            "generated_at": "2026-01-01T00:00:00+00:00",
+            "servers": [{
+                "address": "http://10.0.0.1:11434",
+                "name": "gpu-1",
+                "version": "0.5.7",
+                "busy": false,
+                "connections": 1,
+                "health": { "status": "healthy", "score": 1.0 },
+                "reliability": "second_chance_given",
+                "latency_ms": 120.0,
+                "shadow_ttft_ms": null,
+                "shadow_failures": 0,
+                "stale": false,
+                "vram_peak": 0,
+                "models": ["llama3.2:latest"],
+                "loaded": [{ "name": "llama3.2:latest", "size": 1, "size_vram": 1, "expires_at": null }]
+            }]
+        })).unwrap();
+        assert!(state.racing.is_none() && state.strict.is_none() && state.relay.is_none());
+        let server = &state.servers[0];
+        assert_eq!(server.health, HealthReport::Healthy { score: 1.0 });
+        assert_eq!(server.reliability, Reliability::SecondChanceGiven);
+        assert!(!server.isolated && server.leaving.is_none() && server.misconfigured.is_none());
+    }
+
+    #[test]
+    fn events_are_tagged_by_type() {
+        let event = Event::Selection(SelectionEvent {
+            schema_version: SCHEMA_VERSION,
+            at: "2026-01-01T00:00:00+00:00".to_string(),
+            model: "llama3.2".to_string(),
+            selected: vec!["http://10.0.0.1:11434".to_string()],
+            excluded: vec![ExclusionReport { server: "http://10.0.0.2:11434".to_string(), reason: "dead".to_string() }],
+        });
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["type"], json!("selection"));
+        assert_eq!(value["excluded"][0]["reason"], json!("dead"));
+        assert!(matches!(serde_json::from_value::<Event>(value).unwrap(), Event::Selection(_)));
+    }
+
+    #[test]
+    fn admin_schema_covers_every_document() {
+        let schema = admin_schema();
+        assert_eq!(schema["schema_version"], json!(SCHEMA_VERSION));
+        for document in ["state", "sessions", "conversations", "events", "explain", "membership", "autoscale", "stats",
+            "heatmap", "usage", "traces", "benchmark"] {
+            assert!(schema[document].is_object(), "no schema for {}", document);
+        }
+        assert!(schema["state"].to_string().contains("schema_version"));
+    }
+}
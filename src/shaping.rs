@@ -0,0 +1,55 @@
+use serde_json::{json, Value};
+
+/// An Ollama `keep_alive` from the command line: seconds as a number, or a duration like "10m".
+pub fn keep_alive_value(keep_alive: &str) -> Value {
+    keep_alive.parse::<i64>().map(|secs| json!(secs)).unwrap_or_else(|_| json!(keep_alive))
+}
+
+/// Longest chat history forwarded to the backends, 0 meaning no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HistoryLimit {
+    pub messages: usize,
+    /// Estimated from the length of the contents, about 4 characters per token.
+    pub tokens: usize,
+}
+
+fn estimate_tokens(message: &Value) -> usize {
+    message["content"].as_str().map_or(0, |c| c.chars().count().div_ceil(4))
+}
+
+impl HistoryLimit {
+    pub fn is_set(&self) -> bool {
+        self.messages > 0 || self.tokens > 0
+    }
+
+    /// Drops the oldest messages of `body` until the history fits, returning how many.
+    /// System messages and the last message are always kept, so the history may still exceed
+    /// the limit, but the backend at least sees the instructions and the question.
+    pub fn truncate(&self, body: &mut Value) -> usize {
+        let Some(messages) = body["messages"].as_array_mut() else {
+            return 0;
+        };
+        let is_system = |m: &Value| m["role"] == "system";
+        let total = messages.len();
+        let mut count = total;
+        let mut tokens = messages.iter().map(estimate_tokens).sum::<usize>();
+        let fits = |count: usize, tokens: usize| {
+            (self.messages == 0 || count <= self.messages) && (self.tokens == 0 || tokens <= self.tokens)
+        };
+        let last = total.saturating_sub(1);
+        let mut dropped = vec![false; messages.len()];
+        for (i, message) in messages.iter().enumerate().take(last) {
+            if fits(count, tokens) {
+                break;
+            }
+            if !is_system(message) {
+                dropped[i] = true;
+                count -= 1;
+                tokens -= estimate_tokens(message);
+            }
+        }
+        let mut dropped = dropped.into_iter();
+        messages.retain(|_| !dropped.next().unwrap_or(false));
+        total - count
+    }
+}
@@ -1,13 +1,27 @@
 use ordermap::OrderMap;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use siphasher::sip::SipHasher13;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 use serde_json::Value;
 use rand::{self, Rng};
-use tracing::{info, warn, error};
+use chrono::{DateTime, FixedOffset, Utc};
+use tracing::{debug, info, warn, error};
 
-use crate::config::ServerConfig;
-use crate::api::{api_tags, api_ps};
+use crate::config::{GpuDomain, ServerConfig};
+use crate::api::{api_tags, api_ps, api_version};
 use crate::utils::efraimidis_spirakis_sample;
+use crate::strategy::SelectionStrategy;
+use crate::health::HealthPolicy;
+use crate::runtime::Readiness;
+use crate::backend::{forget_clients, NotJsonError, RedirectError};
+use crate::membership;
+use crate::stats;
+use crate::profiles::BackendProfiles;
 
 #[derive(Clone, Debug)]
 pub enum FailureRecord {
@@ -25,8 +39,22 @@ pub enum Health {
 #[derive(Debug, Clone)]
 pub struct ServerState {
     pub busy: bool,
+    pub connections: usize, // streams being relayed, busy is connections > 0
+    pub pending: usize, // requests sent, whose response is not relayed yet
     pub health: Health, // default to 1.0, max 100.0
     pub failure_record: FailureRecord,
+    pub latency_ms: Option<f32>, // EWMA of the time to first token
+    pub stale: bool, // restored from a previous run, not synced yet
+    pub unsynced: bool, // never synced nor restored, its health is unknown
+    pub shadow_ttft_ms: Option<f32>, // EWMA of the time to first token of synthetic probes
+    pub shadow_failures: usize, // consecutive failed synthetic probes
+    pub vram_peak: u64, // most VRAM ever seen in use, a lower bound of the capacity
+    pub misconfigured: Option<String>, // reachable, but not answering like Ollama
+    pub isolated: bool, // routed around while being benchmarked
+    pub leaving: Option<DateTime<Utc>>, // announced shutdown: routed around, failures are not its fault
+    pub synced_at: Option<Instant>, // last successful sync
+    pub backoff_until: Option<Instant>, // answered 429, selected less often until then
+    pub duplicate_of: Option<String>, // same Ollama instance as this other server, routed around
 }
 
 #[derive(Debug)]
@@ -35,11 +63,16 @@ pub struct OllamaServer {
     pub name: String,
     pub models: HashMap<String, ModelConfig>,
     pub actives: HashMap<String, ModelConfig>,
+    pub version: Option<String>,
+    pub domain: Option<String>, // servers of the same domain share physical hardware
+    pub endpoints: Vec<SocketAddr>, // resolved at sync time
+    pub fingerprint: Option<u64>, // of what only this instance reports, see `fingerprint`
 }
 
 pub struct ServerSnapshot {
     pub state: ServerState,
     pub name: String,
+    pub domain: Option<String>,
     pub models: HashMap<String, Option<ModelConfig>>,
     pub actives: HashMap<String, Option<ModelConfig>>,
 }
@@ -48,21 +81,94 @@ pub struct ServerSnapshot {
 pub struct ModelConfig {
     pub name: String,
     pub detail: Value,
+    pub size: Option<u64>, // bytes, from /api/tags and /api/ps
+    pub size_vram: Option<u64>, // bytes held in VRAM, from /api/ps
+    pub expires_at: Option<DateTime<FixedOffset>>, // unload time, from /api/ps
+}
+
+impl ModelConfig {
+    pub fn new(name: String, detail: Value) -> Self {
+        let size = detail["size"].as_u64();
+        let size_vram = detail["size_vram"].as_u64();
+        let expires_at = detail["expires_at"].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+        ModelConfig { name, detail, size, size_vram, expires_at }
+    }
+
+    /// Same model without the raw detail, cheap to clone for selection.
+    pub fn summary(&self) -> Self {
+        ModelConfig {
+            name: self.name.clone(),
+            detail: Value::Null,
+            size: self.size,
+            size_vram: self.size_vram,
+            expires_at: self.expires_at,
+        }
+    }
+
+    /// A loaded model past its expiry has most likely been unloaded since the last sync.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| t < Utc::now())
+    }
+
+    /// Partially offloaded models run on the CPU for the rest, which is much slower.
+    pub fn fully_in_vram(&self) -> bool {
+        match (self.size, self.size_vram) {
+            (Some(size), Some(vram)) => vram >= size,
+            _ => true,
+        }
+    }
 }
 
 pub type SharedServerList = Arc<Mutex<OrderMap<String, OllamaServer>>>;
 
+/// Set whenever a server status changes, cleared by the status reporter.
+static STATUS_CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the background reporter to print the server statuses on its next tick.
+/// Cheap enough for the request path: it never touches the lock nor the log.
+pub fn request_status_report() {
+    STATUS_CHANGED.store(true, Ordering::Relaxed);
+}
+
 /// Prints a nicely formatted list of the servers, their name, busy status, and reliability.
-pub fn print_server_statuses(servers: &OrderMap<String, OllamaServer>) {
+/// The lines are formatted under the lock, but logged after it is released.
+pub fn print_server_statuses(servers: &SharedServerList) {
+    let lines = {
+        let servers = servers.lock().unwrap();
+        servers.iter().enumerate().map(|(i, (address, srv))| {
+            let busy_status = if srv.state.busy { "Busy" } else { "Available" };
+            let reliability = match srv.state.failure_record {
+                FailureRecord::Reliable => "Reliable",
+                FailureRecord::Unreliable => "Unreliable",
+                FailureRecord::SecondChanceGiven => "SecondChanceGiven",
+            };
+            let reliability = match &srv.state.misconfigured {
+                Some(reason) => format!("Misconfigured, {}", reason),
+                None => reliability.to_string(),
+            };
+            let stale = if srv.state.stale { " (stale)" } else { "" };
+            let probe = match (srv.state.shadow_ttft_ms, srv.state.shadow_failures) {
+                (_, n) if n > 0 => format!(", Probe: {} failures", n),
+                (Some(ttft), _) => format!(", Probe: {:.0}ms", ttft),
+                (None, _) => String::new(),
+            };
+            format!("{}. Address: {} ({}), Busy: {}, Reliability: {}{}{}", i + 1, address, srv.name, busy_status, reliability, probe, stale)
+        }).collect::<Vec<String>>()
+    };
     info!("Current server statuses:");
-    for (i, (address, srv)) in servers.iter().enumerate() {
-        let busy_status = if srv.state.busy { "Busy" } else { "Available" };
-        let reliability = match srv.state.failure_record {
-            FailureRecord::Reliable => "Reliable",
-            FailureRecord::Unreliable => "Unreliable",
-            FailureRecord::SecondChanceGiven => "SecondChanceGiven",
-        };
-        info!("{}. Address: {} ({}), Busy: {}, Reliability: {}", i + 1, address, srv.name, busy_status, reliability);
+    for line in lines {
+        info!("{}", line);
+    }
+}
+
+/// Prints the server statuses at most once per `interval`, and only if something changed.
+pub async fn status_reporter(servers: SharedServerList, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if STATUS_CHANGED.swap(false, Ordering::Relaxed) {
+            print_server_statuses(&servers);
+        }
     }
 }
 
@@ -76,40 +182,147 @@ pub fn add_server(servers_shared: SharedServerList, server: &ServerConfig) {
     servers.insert(server.address.clone(), OllamaServer {
         state: ServerState {
             busy: false,
+            connections: 0,
+            pending: 0,
             health: Health::Dead, // default to dead
             failure_record: FailureRecord::Reliable,
+            latency_ms: None,
+            stale: false,
+            unsynced: true,
+            shadow_ttft_ms: None,
+            shadow_failures: 0,
+            vram_peak: 0,
+            misconfigured: None,
+            isolated: false,
+            leaving: None,
+            synced_at: None,
+            backoff_until: None,
+            duplicate_of: None,
         },
         name: server.name.clone(),
         models: HashMap::new(),
         actives: HashMap::new(),
+        version: None,
+        domain: None,
+        endpoints: Vec::new(),
+        fingerprint: None,
     });
+    membership::record(membership::Change::Added, &server.address, &server.name);
     info!("Added server ({}) {} with name {}", servers.len(), server.address, server.name);
 }
 
+/// Forgets a server, its requests in flight finish undisturbed.
+pub fn remove_server(servers: SharedServerList, target: &str) -> bool {
+    let mut servers = servers.lock().unwrap();
+    let removed = servers.remove(target);
+    if let Some(server) = &removed {
+        invalidate_last_snapshot();
+        membership::record(membership::Change::Removed, target, &server.name);
+        stats::forget(target);
+        forget_clients(target);
+        info!("Removed server {} ({})", target, server.name);
+        // its duplicates take over
+        detect_duplicates(&mut servers);
+    }
+    removed.is_some()
+}
+
+/// Puts the servers of every domain together, unknown addresses are reported and skipped.
+pub fn assign_domains(servers: SharedServerList, domains: &[GpuDomain]) {
+    let mut servers = servers.lock().unwrap();
+    for domain in domains {
+        for addr in &domain.addresses {
+            match servers.get_mut(addr) {
+                Some(server) => server.domain = Some(domain.name.clone()),
+                None => warn!("Server {} of GPU domain {} is not a known server", addr, domain.name),
+            }
+        }
+        info!("GPU domain {}: [{}]", domain.name, domain.addresses.join(", "));
+    }
+}
+
 pub fn mark_server(servers: SharedServerList, target: &str, health: Health) {
     let mut servers = servers.lock().unwrap();
     if let Some(server) = servers.get_mut(target) {
+        if health == Health::Dead && server.state.leaving.is_some() {
+            info!("Server {} is down as announced", target);
+            return;
+        }
         server.state.health = health;
+        server.state.unsynced = false;
         info!("Marked server {} as dead", target);
     } else {
         warn!("Server {} not found", target);
     }
 }
+/// Marks dead the servers whose hostname stopped resolving, they stay dead while the
+/// failure is remembered since every request and sync to them fails right away.
+pub fn mark_unresolvable(servers: &SharedServerList, host: &str) {
+    let mut servers = servers.lock().unwrap();
+    for (addr, server) in servers.iter_mut() {
+        let matches = reqwest::Url::parse(addr).ok().is_some_and(|url| url.host_str() == Some(host));
+        let known_dead = server.state.health == Health::Dead && !server.state.unsynced;
+        if !matches || known_dead || server.state.leaving.is_some() {
+            continue;
+        }
+        warn!("Marked server {} as dead: its host {} does not resolve", addr, host);
+        server.state.health = Health::Dead;
+        server.state.unsynced = false;
+        request_status_report();
+    }
+}
+/// Excludes a server that is reachable but does not answer like Ollama,
+/// it is only retried as a resurrection until it syncs again.
+pub fn mark_server_misconfigured(servers: SharedServerList, target: &str, reason: String) {
+    let mut servers = servers.lock().unwrap();
+    if let Some(server) = servers.get_mut(target) {
+        warn!("Marked server {} as misconfigured: {}", target, reason);
+        server.state.misconfigured = Some(reason);
+        server.state.unsynced = false;
+        request_status_report();
+    } else {
+        warn!("Server {} not found", target);
+    }
+}
+
+/// Marks the server misconfigured if the error says so, returns whether it did.
+pub fn mark_if_misconfigured(
+    servers: SharedServerList, target: &str, e: &(dyn std::error::Error + Send + Sync + 'static)
+) -> bool {
+    if e.is::<NotJsonError>() || e.is::<RedirectError>() {
+        mark_server_misconfigured(servers, target, e.to_string());
+        true
+    } else {
+        false
+    }
+}
+
+/// Trusts a server again after it failed during streaming.
+pub fn mark_server_reliable(servers: SharedServerList, target: &str) {
+    let mut servers = servers.lock().unwrap();
+    if let Some(server) = servers.get_mut(target) {
+        if !matches!(server.state.failure_record, FailureRecord::Reliable) {
+            server.state.failure_record = FailureRecord::Reliable;
+            info!("Server {} ({}) passed a probe and is now marked Reliable", target, server.name);
+            request_status_report();
+        }
+    }
+}
+
 pub fn mark_server_dead(servers: SharedServerList, target: &str) {
     mark_server(servers, target, Health::Dead);
 }
 pub fn mark_server_healthy(servers: SharedServerList, target: &str, health: f32) {
     mark_server(servers, target, Health::Healthy(health));
 }
-pub fn mark_server_more_healthy(servers: SharedServerList, target: &str, is_best: bool) {
-    let h_inc = if is_best { 4.0 } else { 2.0 };
+pub fn mark_server_more_healthy(servers: SharedServerList, target: &str, is_best: bool, policy: &dyn HealthPolicy) {
     let mut servers = servers.lock().unwrap();
     if let Some(server) = servers.get_mut(target) {
         if let Health::Healthy(h) = server.state.health {
-            server.state.health = Health::Healthy(h + h_inc);
+            server.state.health = Health::Healthy(policy.succeeded(h, is_best));
         } else {
             info!("Server {} is resurrected", target);
-            server.state.health = Health::Healthy(1.0);
+            server.state.health = Health::Healthy(policy.initial());
         }
         info!(
             "Marked server {} as more healthy{}, now: {:?}", 
@@ -121,17 +334,19 @@ pub fn mark_server_more_healthy(servers: SharedServerList, target: &str, is_best
         warn!("Server {} not found", target);
     }
 }
-pub fn mark_server_less_healthy(servers: SharedServerList, target: &str) {
-    let h_div = 2.0;
+pub fn mark_server_less_healthy(servers: SharedServerList, target: &str, policy: &dyn HealthPolicy) {
     let mut servers = servers.lock().unwrap();
     if let Some(server) = servers.get_mut(target) {
+        if server.state.leaving.is_some() {
+            info!("Server {} failed during its announced shutdown, not penalized", target);
+            return;
+        }
         if let Health::Healthy(h) = server.state.health {
-            let new_h = h / h_div;
-            if new_h < 1.0 {
+            if let Some(new_h) = policy.failed(h) {
+                server.state.health = Health::Healthy(new_h);
+            } else {
                 info!("Server {} passed away", target);
                 server.state.health = Health::Dead;
-            } else {
-                server.state.health = Health::Healthy(new_h);
             }
         }
         info!("Marked server {} as less healthy, now: {:?}", target, server.state.health);
@@ -140,79 +355,1126 @@ pub fn mark_server_less_healthy(servers: SharedServerList, target: &str) {
     }
 }
 
+/// Backs off a server that answered 429 Too Many Requests: it is overloaded, not failing,
+/// so its health is untouched and it is only selected less often for `backoff`.
+pub fn mark_server_busy(servers: SharedServerList, target: &str, backoff: Duration) {
+    let mut servers = servers.lock().unwrap();
+    if let Some(server) = servers.get_mut(target) {
+        let until = Instant::now() + backoff;
+        // a longer backoff already running stands
+        if server.state.backoff_until.is_none_or(|current| current < until) {
+            server.state.backoff_until = Some(until);
+        }
+        info!("Server {} is overloaded, backing off for {:.1}s", target, backoff.as_secs_f32());
+    } else {
+        warn!("Server {} not found", target);
+    }
+}
+
+fn vram_in_use<'a>(actives: impl Iterator<Item = &'a ModelConfig>) -> u64 {
+    actives.filter(|m| !m.is_expired()).filter_map(|m| m.size_vram).sum()
+}
+
+/// Whether `model` can be loaded on the server without evicting another model.
+/// The VRAM capacity is unknown, the peak usage seen so far stands for it.
+fn fits_in_spare_vram(snap: &ServerSnapshot, model: &str) -> bool {
+    let in_use = vram_in_use(snap.actives.values().flatten());
+    if in_use == 0 {
+        return true;
+    }
+    let size = snap.models.get(model).cloned().flatten().and_then(|m| m.size);
+    match size {
+        Some(size) => in_use + size <= snap.state.vram_peak,
+        None => true,
+    }
+}
+
+/// Folds a new sample into a latency EWMA weighting it by `alpha`.
+pub fn ewma(current: Option<f32>, sample: f32, alpha: f32) -> f32 {
+    match current {
+        Some(ewma) => ewma * (1.0 - alpha) + sample * alpha,
+        None => sample,
+    }
+}
+
+pub fn record_latency(servers: SharedServerList, target: &str, ttft: Duration, alpha: f32) {
+    let sample = ttft.as_secs_f32() * 1000.0;
+    let mut servers = servers.lock().unwrap();
+    if let Some(server) = servers.get_mut(target) {
+        server.state.latency_ms = Some(ewma(server.state.latency_ms, sample, alpha));
+    }
+}
+
+/// Replaces the latency priors of a server with a fresh measurement.
+pub fn reset_latency(servers: SharedServerList, target: &str, ttft_ms: f32) {
+    let mut servers = servers.lock().unwrap();
+    if let Some(server) = servers.get_mut(target) {
+        server.state.latency_ms = Some(ttft_ms);
+        server.state.shadow_ttft_ms = Some(ttft_ms);
+    }
+}
+
+/// Records the outcome of a synthetic probe, `None` meaning it failed.
+pub fn record_shadow_probe(servers: SharedServerList, target: &str, ttft: Option<Duration>, alpha: f32) {
+    let mut servers = servers.lock().unwrap();
+    if let Some(server) = servers.get_mut(target) {
+        match ttft {
+            Some(ttft) => {
+                let sample = ttft.as_secs_f32() * 1000.0;
+                server.state.shadow_ttft_ms = Some(ewma(server.state.shadow_ttft_ms, sample, alpha));
+                server.state.shadow_failures = 0;
+            },
+            None => server.state.shadow_failures += 1,
+        }
+    }
+}
+
 pub async fn sync_server(
     servers: SharedServerList,
     target: String,
     timeout_secs: u32,
+    policy: Arc<dyn HealthPolicy>,
 ) -> Health {
     let target = target.as_str();
-    let models = api_tags(target, timeout_secs);
-    let active_models = api_ps(target, timeout_secs); // send this request ahead
+    let (models, active_models, version, endpoints) = tokio::join!(
+        api_tags(target, timeout_secs),
+        api_ps(target, timeout_secs),
+        api_version(target, timeout_secs),
+        resolve_endpoints(target, Duration::from_secs(timeout_secs as u64)),
+    );
 
-    let models = match models.await {
+    let models = match models {
         Ok(models) => models,
         Err(e) => {
             warn!("Failed to fetch models from {}: {}", target, e);
-            mark_server_dead(servers, target);
+            if !mark_if_misconfigured(servers.clone(), target, e.as_ref()) {
+                mark_server_dead(servers, target);
+            }
             return Health::Dead;
         }
     };
 
-    let active_models = match active_models.await {
+    let active_models = match active_models {
         Ok(active_models) => active_models,
         Err(e) => {
             warn!("Failed to fetch active models from {}: {}", target, e);
-            mark_server_dead(servers, target);
+            if !mark_if_misconfigured(servers.clone(), target, e.as_ref()) {
+                mark_server_dead(servers, target);
+            }
             return Health::Dead;
         }
     };
 
+    // very old servers lack /api/version, this is not a reason to consider them dead
+    let version = match version {
+        Ok(version) => Some(version),
+        Err(e) => {
+            warn!("Failed to fetch version from {}: {}", target, e);
+            None
+        }
+    };
+
     let mut servers = servers.lock().unwrap();
     if let Some(server) = servers.get_mut(target) {
+        // a failed fetch keeps the version known from the previous syncs
+        if let Some(version) = version {
+            if server.version.as_ref() != Some(&version) {
+                info!("Server {} runs Ollama {}", target, version);
+            }
+            server.version = Some(version);
+        }
+        // a sync only tells the server is up: an alive server keeps the health its requests earned,
+        // or the one it had in the previous run, and only a new or dead one starts over
+        let health = match server.state.health {
+            Health::Healthy(h) if !server.state.unsynced => Health::Healthy(h),
+            _ => Health::Healthy(policy.initial()),
+        };
+        server.state.stale = false;
+        server.state.unsynced = false;
+        server.state.synced_at = Some(Instant::now());
+        if server.state.misconfigured.take().is_some() {
+            info!("Server {} answers like Ollama again", target);
+        }
         server.models = models.into_iter().map(|m| (m.name.clone(), m)).collect();
         server.actives = active_models.into_iter().map(|m| (m.name.clone(), m)).collect();
-        server.state.health = Health::Healthy(1.0); // default to 1.0
+        server.state.vram_peak = server.state.vram_peak.max(vram_in_use(server.actives.values()));
+        server.state.health = health.clone();
+        server.endpoints = endpoints;
+        server.fingerprint = fingerprint(server);
         let active_summary = server.actives.keys().map(String::as_str).collect::<Vec<&str>>().join(", ");
         let model_summary = server.models.keys().map(String::as_str).collect::<Vec<&str>>().join(", ");
         info!("Synced server {}, found models: {}\n> All models: [{}]\n> Active models: [{}]",
             target, server.models.len(), model_summary, active_summary);
-        Health::Healthy(1.0)
+        detect_duplicates(&mut servers);
+        health
     } else {
         warn!("Server {} not found", target);
         Health::Dead
     }
 }
 
+/// Syncs every server at once, returning their health.
+pub async fn sync_all(servers: SharedServerList, timeout_secs: u32, policy: Arc<dyn HealthPolicy>) -> Vec<(String, Health)> {
+    let targets = servers.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+    let syncs = targets.into_iter().map(|target| {
+        let (servers, policy) = (servers.clone(), policy.clone());
+        async move {
+            let health = sync_server(servers, target.clone(), timeout_secs, policy).await;
+            (target, health)
+        }
+    });
+    futures_util::future::join_all(syncs).await
+}
+
+/// Syncs every server periodically whatever the traffic, so that models pulled or deleted
+/// directly on a backend show up without waiting for a request to it.
+pub async fn refresh_loop(servers: SharedServerList, interval: Duration, timeout_secs: u32, policy: Arc<dyn HealthPolicy>) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick is immediate, the initial sync already covers it
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let synced = sync_all(servers.clone(), timeout_secs, policy.clone()).await;
+        let dead = synced.iter().filter(|(_, health)| *health == Health::Dead).count();
+        info!("Refreshed {} servers, {} dead", synced.len(), dead);
+    }
+}
+
+/// The socket addresses of a server, to tell that a hostname and an IP are the same server.
+async fn resolve_endpoints(target: &str, timeout: Duration) -> Vec<SocketAddr> {
+    let Some((host, port)) = reqwest::Url::parse(target).ok()
+        .and_then(|url| url.host_str().map(str::to_string).zip(url.port_or_known_default())) else {
+        return Vec::new();
+    };
+    // IPv6 hosts come bracketed
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    match tokio::time::timeout(timeout, tokio::net::lookup_host((host, port))).await {
+        Ok(Ok(addrs)) => addrs.collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// What tells an instance apart from another one provisioned the same way: the expiry times
+/// of its loaded models, down to the nanosecond, along with its version and models. None
+/// without a model loaded for a while, identical fleets would look alike.
+fn fingerprint(server: &OllamaServer) -> Option<u64> {
+    let horizon = Utc::now() + chrono::Duration::days(365);
+    // models kept loaded forever expire at the same far future time everywhere
+    let mut loaded = server.actives.values()
+        .filter_map(|m| m.expires_at.filter(|t| *t < horizon).map(|t| (m.name.as_str(), t)))
+        .collect::<Vec<_>>();
+    if loaded.is_empty() {
+        return None;
+    }
+    loaded.sort();
+    let mut digests = server.models.values().map(|m| m.detail["digest"].as_str().unwrap_or_default()).collect::<Vec<_>>();
+    digests.sort();
+    let mut hasher = DefaultHasher::new();
+    (&server.version, loaded, digests).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// The path of a base URL, which tells apart the backends a reverse proxy routes by path
+/// behind a single socket address.
+fn base_path(url: &str) -> String {
+    reqwest::Url::parse(url).map(|url| url.path().trim_end_matches('/').to_string()).unwrap_or_default()
+}
+
+/// Marks the alive servers that are the same Ollama instance as a server registered before
+/// them, as told by a shared socket address and path or by their fingerprint, so that the
+/// instance is neither raced against itself nor counted twice.
+fn detect_duplicates(servers: &mut OrderMap<String, OllamaServer>) {
+    let mut originals: Vec<(String, Vec<SocketAddr>, String, Option<u64>)> = Vec::new();
+    for (addr, srv) in servers.iter_mut() {
+        if srv.state.health == Health::Dead {
+            srv.state.duplicate_of = None;
+            continue;
+        }
+        let path = base_path(addr);
+        let original = originals.iter().find(|(_, endpoints, original_path, fingerprint)| {
+            (*original_path == path && srv.endpoints.iter().any(|e| endpoints.contains(e)))
+                || (srv.fingerprint.is_some() && srv.fingerprint == *fingerprint)
+        }).map(|(original, _, _, _)| original.clone());
+        if original != srv.state.duplicate_of {
+            match &original {
+                Some(original) => warn!("Server {} is the same instance as server {}, routing around it", addr, original),
+                None => info!("Server {} is not a duplicate of server {} anymore", addr, srv.state.duplicate_of.as_deref().unwrap_or_default()),
+            }
+            if original.is_some() {
+                invalidate_last_snapshot();
+            }
+            srv.state.duplicate_of = original.clone();
+            request_status_report();
+        }
+        if original.is_none() {
+            originals.push((addr.clone(), srv.endpoints.clone(), path, srv.fingerprint));
+        }
+    }
+}
+
+pub fn server_versions(servers: SharedServerList, targets: &[String]) -> HashMap<String, Option<String>> {
+    let servers = servers.lock().unwrap();
+    targets.iter().map(|t| {
+        (t.clone(), servers.get(t.as_str()).and_then(|srv| srv.version.clone()))
+    }).collect()
+}
+
 pub fn snapshot_servers(servers: SharedServerList, need_detail: bool) -> HashMap<String, ServerSnapshot> {
     let servers = servers.lock().unwrap();
+    build_snapshot(&servers, need_detail)
+}
+
+/// Load and health of a GPU domain as a whole: its connections add up,
+/// and its alive servers share the health of the weakest one.
+struct DomainLoad {
+    connections: usize,
+    health: Option<f32>,
+}
+
+fn domain_loads(servers: &OrderMap<String, OllamaServer>) -> HashMap<&str, DomainLoad> {
+    let mut loads: HashMap<&str, DomainLoad> = HashMap::new();
+    for srv in servers.values() {
+        if let Some(domain) = srv.domain.as_deref() {
+            let load = loads.entry(domain).or_insert(DomainLoad { connections: 0, health: None });
+            load.connections += srv.state.connections;
+            if let Health::Healthy(h) = srv.state.health {
+                load.health = Some(load.health.map_or(h, |min| min.min(h)));
+            }
+        }
+    }
+    loads
+}
+
+fn build_snapshot(servers: &OrderMap<String, OllamaServer>, need_detail: bool) -> HashMap<String, ServerSnapshot> {
+    let loads = domain_loads(servers);
     servers.iter().map(|(addr, srv)| {
+        // without details, models still carry their sizes for VRAM-aware selection
         let models: HashMap<String, Option<ModelConfig>> = if need_detail {
             srv.models.iter().map(|(k, v)| (k.clone(), Some(v.clone()))).collect()
         } else {
-            srv.models.keys().map(|k| (k.clone(), None)).collect()
+            srv.models.iter().map(|(k, v)| (k.clone(), Some(v.summary()))).collect()
         };
-        // let actives = srv.actives.keys().map(|k| (k.clone(), ())).collect();
         let actives: HashMap<String, Option<ModelConfig>> = if need_detail {
             srv.actives.iter().map(|(k, v)| (k.clone(), Some(v.clone()))).collect()
         } else {
-            srv.actives.keys().map(|k| (k.clone(), None)).collect()
+            srv.actives.iter().map(|(k, v)| (k.clone(), Some(v.summary()))).collect()
         };
+        let mut state = srv.state.clone();
+        if let Some(load) = srv.domain.as_deref().and_then(|d| loads.get(d)) {
+            state.connections = load.connections;
+            state.busy = load.connections > 0;
+            if let (Health::Healthy(_), Some(h)) = (&state.health, load.health) {
+                state.health = Health::Healthy(h);
+            }
+        }
         (addr.clone(), ServerSnapshot {
-            state: srv.state.clone(),
+            state,
             name: srv.name.clone(),
+            domain: srv.domain.clone(),
             models,
             actives,
         })
     }).collect()
 }
 
-#[derive(Default)]
+/// Longest time the selection path waits for the server list lock
+/// before falling back to the last known snapshot.
+const LOCK_WAIT: Duration = Duration::from_millis(50);
+
+/// Factor the health weight of a backed off server is divided by in the selection.
+const BACKOFF_PENALTY: f32 = 8.0;
+
+/// Backend settings of the current profiles generation, weights and concurrency caps
+/// apply to every selection as soon as they are reloaded.
+static BACKEND_PROFILES: Mutex<Option<Arc<BackendProfiles>>> = Mutex::new(None);
+
+pub fn set_backend_profiles(backends: Arc<BackendProfiles>) {
+    *BACKEND_PROFILES.lock().unwrap_or_else(PoisonError::into_inner) = Some(backends);
+    // the last snapshot was weighted with the previous settings
+    *LAST_SNAPSHOT.lock().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// Last snapshot taken for selection, served when the server list lock is contended.
+/// Invalidated whenever a server gets routed around, it would route to it otherwise.
+static LAST_SNAPSHOT: Mutex<Option<SelectionSnapshot>> = Mutex::new(None);
+
+/// Counters describing how often the hot path had to wait for the server list lock.
+pub struct LockStats {
+    pub acquired: AtomicU64,
+    pub contended: AtomicU64,
+    pub fallbacks: AtomicU64,
+    pub waited_us: AtomicU64,
+}
+
+pub static LOCK_STATS: LockStats = LockStats {
+    acquired: AtomicU64::new(0),
+    contended: AtomicU64::new(0),
+    fallbacks: AtomicU64::new(0),
+    waited_us: AtomicU64::new(0),
+};
+
+impl LockStats {
+    pub fn summary(&self) -> String {
+        format!(
+            "acquired: {}, contended: {}, fallbacks: {}, waited: {}ms",
+            self.acquired.load(Ordering::Relaxed),
+            self.contended.load(Ordering::Relaxed),
+            self.fallbacks.load(Ordering::Relaxed),
+            self.waited_us.load(Ordering::Relaxed) / 1000,
+        )
+    }
+}
+
+/// Tries to lock the server list for at most `wait`, returns `None` on timeout.
+/// A poisoned lock is taken over, the server list stays usable after a panicking holder.
+pub fn try_lock_for(
+    servers: &SharedServerList,
+    wait: Duration,
+) -> Option<MutexGuard<'_, OrderMap<String, OllamaServer>>> {
+    let try_lock = || match servers.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    };
+    if let Some(guard) = try_lock() {
+        LOCK_STATS.acquired.fetch_add(1, Ordering::Relaxed);
+        return Some(guard);
+    }
+    LOCK_STATS.contended.fetch_add(1, Ordering::Relaxed);
+    let start = Instant::now();
+    // waiting blocks the thread, let the runtime move its other tasks elsewhere meanwhile
+    let guard = blocking(|| loop {
+        if let Some(guard) = try_lock() {
+            return Some(guard);
+        }
+        if start.elapsed() >= wait {
+            return None;
+        }
+        std::thread::sleep(Duration::from_micros(200));
+    });
+    LOCK_STATS.waited_us.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+    if guard.is_some() {
+        LOCK_STATS.acquired.fetch_add(1, Ordering::Relaxed);
+    }
+    guard
+}
+
+/// Runs `f`, which blocks the thread, without starving the other tasks of a multi-threaded runtime.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// Drops the snapshot served when the lock is contended, once a server it shows as
+/// selectable must not be selected anymore.
+fn invalidate_last_snapshot() {
+    *LAST_SNAPSHOT.lock().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// What a selection chooses from: the selectable servers, and the servers hidden from it with why.
+#[derive(Clone)]
+struct SelectionSnapshot {
+    snaps: Arc<HashMap<String, ServerSnapshot>>,
+    hidden: Arc<Vec<(String, Exclusion)>>,
+}
+
+/// Takes a snapshot for server selection without blocking behind a slow lock holder.
+/// If the lock can't be obtained within `LOCK_WAIT`, the last known snapshot is reused.
+pub fn snapshot_for_selection(servers: SharedServerList) -> Arc<HashMap<String, ServerSnapshot>> {
+    selection_snapshot(servers).snaps
+}
+
+fn selection_snapshot(servers: SharedServerList) -> SelectionSnapshot {
+    // isolated, leaving, duplicate and saturated servers are invisible to the selection,
+    // nothing gets routed to them
+    let selectable = |servers: &OrderMap<String, OllamaServer>| {
+        let mut snaps = build_snapshot(servers, false);
+        let backends = BACKEND_PROFILES.lock().unwrap_or_else(PoisonError::into_inner).clone().unwrap_or_default();
+        let mut hidden = Vec::new();
+        snaps.retain(|addr, snap| {
+            let max_concurrent = backends.get(addr, &snap.name).max_concurrent;
+            let reason = if snap.state.isolated {
+                Some(Exclusion::Isolated)
+            } else if snap.state.leaving.is_some() {
+                Some(Exclusion::Draining)
+            } else if snap.state.duplicate_of.is_some() {
+                Some(Exclusion::Duplicate)
+            } else if max_concurrent.is_some_and(|max| snap.state.connections + snap.state.pending >= max) {
+                // handling as many requests as it is allowed to, racing ones included
+                Some(Exclusion::AtConcurrencyCap)
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                hidden.push((addr.clone(), reason));
+            }
+            reason.is_none()
+        });
+        for (addr, snap) in snaps.iter_mut() {
+            if let (Health::Healthy(h), Some(weight)) = (&snap.state.health, backends.get(addr, &snap.name).weight) {
+                snap.state.health = Health::Healthy(h * weight);
+            }
+        }
+        // overloaded servers weigh less in the selection, without being any less healthy
+        let now = Instant::now();
+        for snap in snaps.values_mut().filter(|snap| snap.state.backoff_until.is_some_and(|until| until > now)) {
+            if let Health::Healthy(h) = snap.state.health {
+                snap.state.health = Health::Healthy(h / BACKOFF_PENALTY);
+            }
+        }
+        SelectionSnapshot { snaps: Arc::new(snaps), hidden: Arc::new(hidden) }
+    };
+    let snapshot = match try_lock_for(&servers, LOCK_WAIT) {
+        Some(guard) => selectable(&guard),
+        None => {
+            let cached = LAST_SNAPSHOT.lock().unwrap_or_else(PoisonError::into_inner).clone();
+            if let Some(cached) = cached {
+                LOCK_STATS.fallbacks.fetch_add(1, Ordering::Relaxed);
+                warn!("Server list lock contended, selecting from the last known snapshot ({})", LOCK_STATS.summary());
+                return cached;
+            }
+            // nothing cached, or it went stale, we have no choice but to wait
+            selectable(&blocking(|| servers.lock().unwrap_or_else(PoisonError::into_inner)))
+        },
+    };
+    *LAST_SNAPSHOT.lock().unwrap_or_else(PoisonError::into_inner) = Some(snapshot.clone());
+    snapshot
+}
+
+/// Ranks the alive servers hosting `model` by rendezvous hashing on `affinity_key`,
+/// so the same key keeps landing on the same server while the fleet is unchanged,
+/// and only keys of a server that leaves get remapped.
+pub fn rank_by_affinity(
+    servers: SharedServerList, model: &str, affinity_key: &str, strict: bool, pinned: Option<&[String]>,
+) -> Vec<String> {
+    let snaps = snapshot_for_selection(servers);
+    let mut ranked = snaps.iter().filter(|(addr, snap)| {
+        snap.state.health != Health::Dead && snap.state.misconfigured.is_none() && snap.models.contains_key(model)
+            && is_trusted(snap, strict) && is_pinned_to(pinned, addr, snap)
+    }).map(|(addr, _)| {
+        let mut hasher = DefaultHasher::new();
+        (affinity_key, addr).hash(&mut hasher);
+        (hasher.finish(), addr.clone())
+    }).collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.cmp(a));
+    ranked.into_iter().map(|(_, addr)| addr).collect()
+}
+
+/// Keys of the conversation hashes, fixed since the hashes are persisted and shared between
+/// instances: they must not change from one run or one Rust release to the next.
+const CONVERSATION_KEYS: (u64, u64) = (0x6f6c_6c61_6d61_2d6c, 0x622d_636f_6e76_6f73);
+
+/// Hashes of every prefix of a chat history: `hashes[i]` covers `messages[..=i]`.
+/// Only the role and content of the messages count, clients tend to echo other fields inconsistently.
+pub fn conversation_hashes(model: &str, messages: &[Value]) -> Vec<u64> {
+    let mut hasher = SipHasher13::new_with_keys(CONVERSATION_KEYS.0, CONVERSATION_KEYS.1);
+    // every string is terminated, so that moving bytes from one field to the next changes the hash
+    let write = |hasher: &mut SipHasher13, s: &str| {
+        hasher.write(s.as_bytes());
+        hasher.write_u8(0xff);
+    };
+    write(&mut hasher, model);
+    messages.iter().map(|m| {
+        write(&mut hasher, m["role"].as_str().unwrap_or_default());
+        write(&mut hasher, m["content"].as_str().unwrap_or_default());
+        hasher.finish()
+    }).collect()
+}
+
+/// Small LRU map from conversation prefix hashes to the server that served them,
+/// so that follow-up turns find their KV cache still warm.
+/// Pins unused for longer than the TTL are dropped, the server has likely unloaded the model by then.
+#[derive(Debug)]
+pub struct ConversationMap {
+    capacity: usize,
+    ttl: Option<Duration>,
+    tick: u64,
+    entries: HashMap<u64, ConversationPin>,
+    evicted: Vec<u64>,
+    recorded: Vec<u64>,
+    pub stats: ConversationStats,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConversationPin {
+    pub server: String,
+    last_used: u64,
+    pub used_at: Instant,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ConversationStats {
+    /// Lookups that found a pinned server.
+    pub hits: u64,
+    pub misses: u64,
+    /// Pins dropped to make room for newer ones.
+    pub evictions: u64,
+    /// Pins dropped for being unused longer than the TTL.
+    pub expirations: u64,
+}
+
+pub type SharedConversationMap = Arc<Mutex<ConversationMap>>;
+
+impl ConversationMap {
+    pub fn new(capacity: usize, ttl: Option<Duration>) -> Self {
+        ConversationMap {
+            capacity, ttl, tick: 0, entries: HashMap::new(), evicted: Vec::new(), recorded: Vec::new(), stats: ConversationStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    fn is_expired(&self, pin: &ConversationPin) -> bool {
+        self.ttl.is_some_and(|ttl| pin.used_at.elapsed() > ttl)
+    }
+
+    /// Finds the server that served the longest known prefix of the conversation.
+    pub fn lookup(&mut self, hashes: &[u64]) -> Option<String> {
+        if hashes.is_empty() {
+            return None;
+        }
+        self.tick += 1;
+        for h in hashes.iter().rev() {
+            let Some(pin) = self.entries.get(h) else { continue };
+            if self.is_expired(pin) {
+                self.entries.remove(h);
+                self.evicted.push(*h);
+                self.stats.expirations += 1;
+                continue;
+            }
+            let pin = self.entries.get_mut(h).unwrap();
+            pin.last_used = self.tick;
+            pin.used_at = Instant::now();
+            self.stats.hits += 1;
+            return Some(pin.server.clone());
+        }
+        self.stats.misses += 1;
+        None
+    }
+
+    pub fn record(&mut self, hash: u64, server: &str) {
+        self.tick += 1;
+        self.entries.insert(hash, ConversationPin { server: server.to_string(), last_used: self.tick, used_at: Instant::now() });
+        self.recorded.push(hash);
+        if self.entries.len() > self.capacity {
+            self.purge_expired();
+        }
+        if self.entries.len() > self.capacity {
+            let oldest = self.entries.iter().min_by_key(|(_, pin)| pin.last_used).map(|(h, _)| *h);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+                self.evicted.push(oldest);
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    /// Drops the pins unused for longer than the TTL.
+    pub fn purge_expired(&mut self) {
+        let expired = self.entries.iter().filter(|(_, pin)| self.is_expired(pin)).map(|(h, _)| *h).collect::<Vec<_>>();
+        for h in expired {
+            self.entries.remove(&h);
+            self.evicted.push(h);
+            self.stats.expirations += 1;
+        }
+    }
+
+    /// Unpins a conversation, returning the server it was pinned to.
+    pub fn remove(&mut self, hash: u64) -> Option<String> {
+        let pin = self.entries.remove(&hash)?;
+        self.evicted.push(hash);
+        Some(pin.server)
+    }
+
+    /// Unpins every conversation of `server`, returning how many there were.
+    pub fn remove_server(&mut self, server: &str) -> usize {
+        let hashes = self.entries.iter().filter(|(_, pin)| pin.server == server).map(|(h, _)| *h).collect::<Vec<_>>();
+        for h in &hashes {
+            self.entries.remove(h);
+            self.evicted.push(*h);
+        }
+        hashes.len()
+    }
+
+    /// The live pins, most recently used first.
+    pub fn pins(&self) -> Vec<(u64, ConversationPin)> {
+        let mut pins = self.entries.iter()
+            .filter(|(_, pin)| !self.is_expired(pin))
+            .map(|(h, pin)| (*h, pin.clone()))
+            .collect::<Vec<_>>();
+        pins.sort_by_key(|(_, pin)| std::cmp::Reverse(pin.last_used));
+        pins
+    }
+
+    /// Pins recorded since the last call and still there, so that persisted copies only get
+    /// what changed.
+    pub fn take_recorded(&mut self) -> Vec<(u64, String)> {
+        let mut recorded = std::mem::take(&mut self.recorded);
+        recorded.sort_unstable();
+        recorded.dedup();
+        recorded.into_iter().filter_map(|h| Some((h, self.entries.get(&h)?.server.clone()))).collect()
+    }
+
+    /// Hashes evicted or unpinned since the last call, so that persisted copies can be dropped too.
+    pub fn take_evicted(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.evicted)
+    }
+}
+
+/// Whether `target` is alive, hosts `model`, is trusted under strict mode, and may run the model.
+pub fn can_serve(servers: SharedServerList, target: &str, model: &str, strict: bool, pinned: Option<&[String]>) -> bool {
+    let snaps = snapshot_for_selection(servers);
+    snaps.get(target).is_some_and(|snap| {
+        snap.state.health != Health::Dead && snap.state.misconfigured.is_none() && snap.models.contains_key(model)
+            && is_trusted(snap, strict) && is_pinned_to(pinned, target, snap)
+    })
+}
+
+/// Whether `target` has `model` loaded and not about to expire.
+pub fn has_model_loaded(servers: SharedServerList, target: &str, model: &str) -> bool {
+    let servers = servers.lock().unwrap();
+    servers.get(target).and_then(|server| server.actives.get(model)).is_some_and(|m| !m.is_expired())
+}
+
+/// Whether `target` is alive and was synced less than `ttl` ago.
+pub fn is_freshly_synced(servers: SharedServerList, target: &str, ttl: Duration) -> bool {
+    let servers = servers.lock().unwrap();
+    servers.get(target).is_some_and(|server| {
+        server.state.health != Health::Dead && server.state.synced_at.is_some_and(|at| at.elapsed() < ttl)
+    })
+}
+
+/// Whether any alive and well configured server hosts `model`.
+pub fn has_healthy_server(servers: SharedServerList, model: &str) -> bool {
+    let snaps = snapshot_for_selection(servers);
+    snaps.values().any(|snap| {
+        snap.state.health != Health::Dead && snap.state.misconfigured.is_none() && snap.models.contains_key(model)
+    })
+}
+
+/// The alive, well configured server with the best health score.
+pub fn healthiest_server(servers: SharedServerList) -> Option<String> {
+    let snaps = snapshot_for_selection(servers);
+    snaps.iter()
+        .filter(|(_, snap)| snap.state.misconfigured.is_none())
+        .filter_map(|(addr, snap)| match snap.state.health {
+            Health::Healthy(h) => Some((addr, h)),
+            Health::Dead => None,
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(addr, _)| addr.clone())
+}
+
+/// The healthy servers, and why the fleet is not ready to take traffic, nothing when it is.
+pub fn readiness_gaps(servers: SharedServerList, readiness: &Readiness) -> (usize, usize, Vec<String>) {
+    let snaps = snapshot_for_selection(servers);
+    let healthy = snaps.values()
+        .filter(|snap| snap.state.health != Health::Dead && snap.state.misconfigured.is_none())
+        .collect::<Vec<_>>();
+    // servers still waiting for their first sync at startup
+    let unknown = snaps.values().filter(|snap| snap.state.unsynced).count();
+    let mut gaps = Vec::new();
+    if healthy.len() < readiness.min_servers {
+        gaps.push(format!("{} of {} required servers are healthy", healthy.len(), readiness.min_servers));
+        if unknown > 0 {
+            gaps.push(format!("{} servers are not synced yet", unknown));
+        }
+    }
+    for model in &readiness.models {
+        if !healthy.iter().any(|snap| snap.models.contains_key(model)) {
+            gaps.push(format!("no healthy server hosts {}", model));
+        }
+    }
+    (healthy.len(), unknown, gaps)
+}
+
+/// Idle healthy servers to pull `model` on so that `wanted` servers host it, none if enough already do
+/// or if no server hosts it at all.
+pub fn provisioning_targets(servers: SharedServerList, model: &str, wanted: usize, pinned: Option<&[String]>) -> Vec<String> {
+    let snaps = snapshot_for_selection(servers);
+    // only top up models the fleet already hosts, a client must not make every server download anything
+    if !snaps.values().any(|snap| snap.models.contains_key(model)) {
+        return Vec::new();
+    }
+    let usable = snaps.iter().filter(|(addr, snap)| {
+        snap.state.health != Health::Dead && snap.state.misconfigured.is_none() && is_pinned_to(pinned, addr, snap)
+    }).collect::<Vec<_>>();
+    let hosting = usable.iter().filter(|(_, snap)| snap.models.contains_key(model)).count();
+    let mut idle = usable.into_iter()
+        .filter(|(_, snap)| !snap.models.contains_key(model) && !snap.state.busy)
+        .map(|(addr, snap)| (addr.clone(), match snap.state.health { Health::Healthy(h) => h, Health::Dead => 0.0 }))
+        .collect::<Vec<_>>();
+    // the healthiest servers first
+    idle.sort_by(|a, b| b.1.total_cmp(&a.1));
+    idle.into_iter().take(wanted.saturating_sub(hosting)).map(|(addr, _)| addr).collect()
+}
+
+/// Set once the balancer is shutting down, new requests get no backend anymore.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn begin_shutdown() {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Clears what a previous load balancer of the process left behind: its shutdown, its
+/// requests in flight, its profiles and selection snapshot, and its counters.
+pub fn reset() {
+    SHUTTING_DOWN.store(false, Ordering::Relaxed);
+    STATUS_CHANGED.store(false, Ordering::Relaxed);
+    DISPATCHING.store(0, Ordering::Relaxed);
+    QUEUED.store(0, Ordering::Relaxed);
+    *BACKEND_PROFILES.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    *LAST_SNAPSHOT.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    LAST_SAMPLED.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    let counters = [
+        &LOCK_STATS.acquired, &LOCK_STATS.contended, &LOCK_STATS.fallbacks, &LOCK_STATS.waited_us,
+        &STRICT_STATS.selections, &STRICT_STATS.reduced, &STRICT_STATS.excluded, &STRICT_STATS.emptied,
+        &RACE_STATS.aborted, &RACE_STATS.wasted_tokens,
+    ];
+    for counter in counters {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Streams being relayed from all servers.
+pub fn streams_in_flight(servers: &SharedServerList) -> usize {
+    servers.lock().unwrap().values().map(|server| server.state.connections).sum()
+}
+
+/// Requests waiting for a backend, which `streams_in_flight` does not count yet.
+static DISPATCHING: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a request as dispatching until dropped, i.e. until its response starts.
+pub struct DispatchGuard(());
+
+impl Drop for DispatchGuard {
+    fn drop(&mut self) {
+        // a guard of a previous load balancer may outlive the reset
+        let _ = DISPATCHING.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n.saturating_sub(1)));
+        notify_server_released();
+    }
+}
+
+pub fn begin_dispatch() -> DispatchGuard {
+    DISPATCHING.fetch_add(1, Ordering::Relaxed);
+    DispatchGuard(())
+}
+
+/// Requests waiting in a queue, for a generation slot of their model or for an idle server.
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a request as queued until dropped, i.e. until it may pick its servers.
+pub struct QueueGuard(());
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        let _ = QUEUED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n.saturating_sub(1)));
+    }
+}
+
+pub fn requests_queued() -> usize {
+    QUEUED.load(Ordering::Relaxed)
+}
+
+pub fn begin_queue() -> QueueGuard {
+    QUEUED.fetch_add(1, Ordering::Relaxed);
+    QueueGuard(())
+}
+
+/// Woken up whenever a server finishes relaying a stream, for the few tasks waiting for a drain.
+static SERVER_RELEASED: Notify = Notify::const_new();
+
+/// Hands every released server to one queued request at a time, waking all of them
+/// would have them rush the same server.
+static QUEUE_TURN: Notify = Notify::const_new();
+static RELEASES: AtomicU64 = AtomicU64::new(0);
+
+pub fn notify_server_released() {
+    RELEASES.fetch_add(1, Ordering::Relaxed);
+    SERVER_RELEASED.notify_waiters();
+    QUEUE_TURN.notify_one();
+}
+
+fn has_idle_server(servers: &SharedServerList, model: &str) -> bool {
+    let snaps = snapshot_for_selection(servers.clone());
+    let mut eligible = snaps.values().filter(|snap| {
+        snap.state.health != Health::Dead && snap.state.misconfigured.is_none() && snap.models.contains_key(model)
+    }).peekable();
+    // nothing to wait for if no server can serve the model at all
+    eligible.peek().is_none() || eligible.any(|snap| !snap.state.busy)
+}
+
+/// Routes requests around `target`, or back to it. Returns false if the server is unknown
+/// or already in that state.
+pub fn set_isolated(servers: SharedServerList, target: &str, isolated: bool) -> bool {
+    let mut servers = servers.lock().unwrap();
+    match servers.get_mut(target) {
+        Some(server) if server.state.isolated != isolated => {
+            server.state.isolated = isolated;
+            if isolated {
+                invalidate_last_snapshot();
+            }
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Records that `target` announced going down at `at`, or that it is back with `None`.
+/// Returns false if the server is unknown.
+pub fn set_leaving(servers: SharedServerList, target: &str, at: Option<DateTime<Utc>>) -> bool {
+    let mut servers = servers.lock().unwrap();
+    match servers.get_mut(target) {
+        Some(server) => {
+            match (server.state.leaving.is_some(), at.is_some()) {
+                (false, true) => membership::record(membership::Change::Draining, target, &server.name),
+                (true, false) => membership::record(membership::Change::Rejoined, target, &server.name),
+                _ => {},
+            }
+            server.state.leaving = at;
+            if at.is_some() {
+                invalidate_last_snapshot();
+            }
+            true
+        },
+        None => false,
+    }
+}
+
+/// Ends the announced shutdown at `at` of a server that did not rejoin in time: it is dead
+/// until a sync finds it again. Returns false if it rejoined or announced another shutdown since.
+pub fn expire_leaving(servers: SharedServerList, target: &str, at: DateTime<Utc>) -> bool {
+    let mut servers = servers.lock().unwrap();
+    match servers.get_mut(target) {
+        Some(server) if server.state.leaving == Some(at) => {
+            server.state.leaving = None;
+            server.state.health = Health::Dead;
+            invalidate_last_snapshot();
+            warn!("Server {} did not rejoin after its announced shutdown, now dead", target);
+            request_status_report();
+            true
+        },
+        _ => false,
+    }
+}
+
+/// The server a request is about: named by address or name, or else the one at the client IP.
+pub fn find_server(servers: SharedServerList, wanted: Option<&str>, remote_ip: Option<std::net::IpAddr>) -> Option<String> {
+    let servers = servers.lock().unwrap();
+    servers.iter().find(|(addr, srv)| match wanted {
+        Some(wanted) => addr.as_str() == wanted || srv.name == wanted,
+        None => reqwest::Url::parse(addr).ok().and_then(|url| url.host_str()?.trim_matches(['[', ']']).parse().ok())
+            .is_some_and(|ip: std::net::IpAddr| Some(ip) == remote_ip),
+    }).map(|(addr, _)| addr.clone())
+}
+
+/// Whether `ip` is the address of a server, as written in its address or as resolved by its last sync.
+pub fn is_server_ip(servers: SharedServerList, ip: std::net::IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let servers = servers.lock().unwrap();
+    servers.iter().any(|(addr, srv)| {
+        srv.endpoints.iter().any(|endpoint| endpoint.ip().to_canonical() == ip)
+            || reqwest::Url::parse(addr).ok().and_then(|url| url.host_str()?.trim_matches(['[', ']']).parse().ok())
+                .is_some_and(|host: std::net::IpAddr| host.to_canonical() == ip)
+    })
+}
+
+/// The server at `wanted`: its address, its name, or the `host:port` of its address.
+pub fn find_server_at(servers: SharedServerList, wanted: &str) -> Option<String> {
+    let servers = servers.lock().unwrap();
+    servers.iter().find(|(addr, srv)| {
+        addr.as_str() == wanted || srv.name == wanted || reqwest::Url::parse(addr).ok().is_some_and(|url| {
+            url.host_str().zip(url.port_or_known_default()).is_some_and(|(host, port)| format!("{}:{}", host, port) == wanted)
+        })
+    }).map(|(addr, _)| addr.clone())
+}
+
+/// Waits until `target` relays no stream anymore.
+/// Returns false if it still did after `timeout`.
+pub async fn wait_for_drain(servers: SharedServerList, target: &str, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let released = SERVER_RELEASED.notified();
+        let connections = servers.lock().unwrap().get(target).map(|s| s.state.connections).unwrap_or_default();
+        if connections == 0 {
+            return true;
+        }
+        if tokio::time::timeout_at(deadline, released).await.is_err() {
+            return false;
+        }
+    }
+}
+
+/// Waits until no request waits for a backend and no server relays a stream anymore.
+/// Returns false if some still did after `timeout`.
+pub async fn wait_for_idle_fleet(servers: SharedServerList, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let released = SERVER_RELEASED.notified();
+        if DISPATCHING.load(Ordering::Relaxed) == 0 && streams_in_flight(&servers) == 0 {
+            return true;
+        }
+        if tokio::time::timeout_at(deadline, released).await.is_err() {
+            return false;
+        }
+    }
+}
+
+/// Waits until some alive server hosting `model` is idle.
+/// Returns false if none got idle within `timeout`.
+pub async fn wait_for_idle_server(servers: SharedServerList, model: &str, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    if has_idle_server(&servers, model) {
+        return true;
+    }
+    // a release while checking is not missed, the turn is kept until a request takes it
+    let mut passed_on = None;
+    loop {
+        if tokio::time::timeout_at(deadline, QUEUE_TURN.notified()).await.is_err() {
+            return false;
+        }
+        if has_idle_server(&servers, model) {
+            return true;
+        }
+        // the released server is of no use to this request, the next one gets its turn,
+        // once per release so that a server nobody can use is not passed around forever
+        let release = RELEASES.load(Ordering::Relaxed);
+        if passed_on != Some(release) {
+            passed_on = Some(release);
+            QUEUE_TURN.notify_one();
+        }
+    }
+}
+
+/// When alive servers that do not have the model loaded get selected, making them load it.
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub enum ColdLoad {
+    /// Only to top up the selection to its minimum count.
+    #[default]
+    TopUp,
+    /// Only if no server has the model loaded, requests rather queue on busy ones.
+    Wait,
+    /// Also whenever all servers having the model loaded are busy, idle ones go first.
+    Eager,
+}
+
+#[derive(Default, Clone, Copy, Debug)]
 pub struct SelOpt {
     pub count: (usize, usize),
     pub resurrect_p: f32,
     pub resurrect_n: usize,
+    pub strict: bool, // never select servers that failed during streaming
+    pub cold_load: ColdLoad,
+    pub slow_factor: f32, // servers this much slower than the fastest for the model come last, 0 disables
+}
+
+/// Why a server was left out of a selection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exclusion {
+    Isolated, // being benchmarked
+    Draining, // announced its shutdown
+    Duplicate, // same instance as another server
+    NotPinned, // the model profile pins it elsewhere
+    Unsynced, // not synced yet since startup
+    Dead,
+    Misconfigured,
+    MissingModel,
+    Untrusted, // failed while streaming, in strict mode
+    SharedGpuDomain, // another selected server runs on the same hardware
+    NotLoaded, // alive, but the cold load policy did not need it
+    Outranked, // eligible, but the selection was full
+    AtConcurrencyCap, // handling as many requests as its backend options allow
+}
+
+impl Exclusion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Exclusion::Isolated => "isolated",
+            Exclusion::Draining => "draining",
+            Exclusion::Duplicate => "duplicate",
+            Exclusion::NotPinned => "not_pinned",
+            Exclusion::Unsynced => "unsynced",
+            Exclusion::Dead => "dead",
+            Exclusion::Misconfigured => "misconfigured",
+            Exclusion::MissingModel => "missing_model",
+            Exclusion::Untrusted => "untrusted",
+            Exclusion::SharedGpuDomain => "shared_gpu_domain",
+            Exclusion::NotLoaded => "not_loaded",
+            Exclusion::Outranked => "outranked",
+            Exclusion::AtConcurrencyCap => "at_concurrency_cap",
+        }
+    }
+}
+
+/// The servers to try in order, and why every other server was left out.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub servers: Vec<String>,
+    pub excluded: Vec<(String, Exclusion)>,
 }
 
+/// How much strict mode cuts into the capacity.
+pub struct StrictStats {
+    pub selections: AtomicU64, // selections made in strict mode
+    pub reduced: AtomicU64, // selections that excluded at least one server
+    pub excluded: AtomicU64, // servers excluded over all selections
+    pub emptied: AtomicU64, // selections left with no server at all
+}
+
+pub static STRICT_STATS: StrictStats = StrictStats {
+    selections: AtomicU64::new(0),
+    reduced: AtomicU64::new(0),
+    excluded: AtomicU64::new(0),
+    emptied: AtomicU64::new(0),
+};
+
+impl StrictStats {
+    pub fn summary(&self) -> String {
+        format!(
+            "selections: {}, reduced: {}, excluded: {}, emptied: {}",
+            self.selections.load(Ordering::Relaxed),
+            self.reduced.load(Ordering::Relaxed),
+            self.excluded.load(Ordering::Relaxed),
+            self.emptied.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// What racing costs the backends.
+pub struct RaceStats {
+    pub aborted: AtomicU64, // streams aborted because another server won
+    pub wasted_tokens: AtomicU64, // tokens received from aborted streams
+}
+
+pub static RACE_STATS: RaceStats = RaceStats {
+    aborted: AtomicU64::new(0),
+    wasted_tokens: AtomicU64::new(0),
+};
+
+/// Whether a server can be trusted with a request, strict mode refuses servers
+/// that broke a stream until they prove themselves again.
+fn is_trusted(snap: &ServerSnapshot, strict: bool) -> bool {
+    !strict || matches!(snap.state.failure_record, FailureRecord::Reliable)
+}
+
+/// Selection tick at which every server was last sampled, ties between equal health
+/// go to the least recently sampled server instead of clustering on one.
+static LAST_SAMPLED: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+static SAMPLE_TICK: AtomicU64 = AtomicU64::new(0);
+
 pub fn sample_by_health<'a>(
     snaps: &HashMap<String, ServerSnapshot>,
     source: &[&'a String],
@@ -226,26 +1488,93 @@ pub fn sample_by_health<'a>(
         };
         health
     }).collect::<Vec<_>>();
-    let indices = efraimidis_spirakis_sample(&healths, count, rng);
+    let mut last_sampled = LAST_SAMPLED.lock().unwrap();
+    let ranks = source.iter().map(|name| last_sampled.get(name.as_str()).copied().unwrap_or_default()).collect::<Vec<_>>();
+    let indices = efraimidis_spirakis_sample(&healths, &ranks, count, rng);
+    for i in &indices {
+        let tick = SAMPLE_TICK.fetch_add(1, Ordering::Relaxed) + 1;
+        last_sampled.insert(source[*i].clone(), tick);
+    }
     indices.into_iter().map(|i| source[i]).collect()
 }
 
+/// Whether `pinned`, the servers a model is restricted to by address or name, allows this one.
+pub fn is_pinned_to(pinned: Option<&[String]>, addr: &str, snap: &ServerSnapshot) -> bool {
+    pinned.is_none_or(|pinned| pinned.iter().any(|p| p == addr || *p == snap.name))
+}
+
+/// Why a server can never be selected for `model` at the moment, `None` if it can.
+fn exclusion_of(snap: &ServerSnapshot, addr: &str, model: &str, strict: bool, pinned: Option<&[String]>) -> Option<Exclusion> {
+    if !is_pinned_to(pinned, addr, snap) {
+        Some(Exclusion::NotPinned)
+    } else if snap.state.unsynced {
+        Some(Exclusion::Unsynced)
+    } else if snap.state.health == Health::Dead {
+        Some(Exclusion::Dead)
+    } else if snap.state.misconfigured.is_some() {
+        Some(Exclusion::Misconfigured)
+    } else if !snap.models.contains_key(model) {
+        Some(Exclusion::MissingModel)
+    } else if !is_trusted(snap, strict) {
+        Some(Exclusion::Untrusted)
+    } else {
+        None
+    }
+}
+
+/// What a selection for `model` would have to choose from, without selecting anything:
+/// the eligible servers, and why the others are not.
+pub fn explain_selection(
+    servers: SharedServerList,
+    model: &str,
+    strict: bool,
+    pinned: Option<&[String]>,
+) -> (Vec<String>, Vec<(String, Exclusion)>) {
+    let SelectionSnapshot { snaps, hidden } = selection_snapshot(servers);
+    let mut excluded = hidden.to_vec();
+    let mut eligible = Vec::new();
+    for (addr, snap) in snaps.iter() {
+        match exclusion_of(snap, addr, model, strict, pinned) {
+            Some(reason) => excluded.push((addr.clone(), reason)),
+            None => eligible.push(addr.clone()),
+        }
+    }
+    eligible.sort();
+    excluded.sort_by(|a, b| a.0.cmp(&b.0));
+    (eligible, excluded)
+}
+
 pub fn select_servers(
     servers: SharedServerList,
     model: String,
     opts: SelOpt,
-) -> Vec<String> {
+    pinned: Option<&[String]>,
+    strategy: &dyn SelectionStrategy,
+) -> Selection {
+    select_servers_excluding(servers, model, opts, pinned, strategy, &[])
+}
+
+/// Same as `select_servers`, leaving out the `tried` servers, e.g. those a request already failed on,
+/// so that the counts are filled with other servers.
+pub fn select_servers_excluding(
+    servers: SharedServerList,
+    model: String,
+    opts: SelOpt,
+    pinned: Option<&[String]>,
+    strategy: &dyn SelectionStrategy,
+    tried: &[String],
+) -> Selection {
+    let SelectionSnapshot { snaps, hidden } = selection_snapshot(servers);
     let mut rng = rand::rng();
     let (mut min_sel, mut max_sel) = opts.count;
     let mut resurrect_n = if rng.random::<f32>() < opts.resurrect_p {
-        min_sel -= opts.resurrect_n;
-        max_sel -= opts.resurrect_n;
+        min_sel = min_sel.saturating_sub(opts.resurrect_n);
+        max_sel = max_sel.saturating_sub(opts.resurrect_n);
         opts.resurrect_n
     } else {
         0
     };
 
-    let snaps = snapshot_servers(servers, false);
     let mut selected: Vec<(&str, Vec<&String>)> = Vec::new();
     let mut num_selected = 0; // NOTE: num_selected means not selected.len()
 
@@ -254,38 +1583,85 @@ pub fn select_servers(
         let actives = snap.actives.keys().map(|k| k.as_str()).collect::<Vec<&str>>().join(", ");
         info!("> {}: health: {:?}, actives: [{}]", addr, snap.state.health, actives);
     }
-    info!("Selecting servers with min: {} max: {} resurrect: {}", min_sel, max_sel, resurrect_n);
+    info!("Selecting servers with min: {} max: {} resurrect: {} strategy: {}", min_sel, max_sel, resurrect_n, strategy.name());
+
+    // strict mode drops untrusted servers before anything else
+    let untrusted = snaps.iter()
+        .filter(|(_, snap)| snap.models.contains_key(&model) && !is_trusted(snap, opts.strict))
+        .map(|(addr, _)| addr)
+        .collect::<Vec<_>>();
+    if opts.strict {
+        STRICT_STATS.selections.fetch_add(1, Ordering::Relaxed);
+    }
+    if !untrusted.is_empty() {
+        STRICT_STATS.reduced.fetch_add(1, Ordering::Relaxed);
+        STRICT_STATS.excluded.fetch_add(untrusted.len() as u64, Ordering::Relaxed);
+        info!("Strict mode excluded {} unreliable servers: [{}]", untrusted.len(),
+            untrusted.iter().map(|s| s.as_str()).collect::<Vec<&str>>().join(", "));
+    }
 
     // 1. choose from alive servers with the model activated
     // NOTE: servers that are alive but do not have the target model are NEVER selected
-    let alives = snaps.iter().filter_map(|(addr, snap)| {
-        if snap.state.health != Health::Dead && snap.models.contains_key(&model) {
+    // pinned models never go anywhere else, even dead servers are only resurrected among their pins
+    let alives = snaps.iter().filter(|(addr, snap)| {
+        !untrusted.contains(addr) && !tried.contains(addr) && is_pinned_to(pinned, addr, snap)
+    }).filter_map(|(addr, snap)| {
+        if snap.state.health != Health::Dead && snap.state.misconfigured.is_none() && snap.models.contains_key(&model) {
             Some(addr)
         } else {
             None
         }
     }).collect::<Vec<_>>();
-    let actives = alives.iter().filter(|name| {
-        snaps.get(name.as_str()).unwrap().actives.contains_key(&model)
-    }).cloned().collect::<Vec<_>>();
-    if actives.len() <= max_sel { 
-        selected.push(("active", actives));
-    } else {
-        selected.push(("active", sample_by_health(&snaps, &actives, max_sel, &mut rng)));
+    // models past their expiry are considered unloaded
+    let resident = |name: &String| snaps.get(name.as_str()).unwrap()
+        .actives.get(&model).cloned().flatten().filter(|m| !m.is_expired());
+    let actives = alives.iter().filter(|name| resident(name).is_some()).cloned().collect::<Vec<_>>();
+    // servers running the model fully in VRAM first, partially offloaded ones are slower
+    let (in_vram, offloaded): (Vec<_>, Vec<_>) = actives.iter()
+        .partition(|name| resident(name).is_some_and(|m| m.fully_in_vram()));
+    // servers known to be slow with this very model come last, whatever their overall latency
+    let slow = |name: &&String| stats::is_slow(name, &model, opts.slow_factor);
+    let (slow_in_vram, in_vram): (Vec<_>, Vec<_>) = in_vram.into_iter().partition(slow);
+    let (slow_offloaded, offloaded): (Vec<_>, Vec<_>) = offloaded.into_iter().partition(slow);
+    // the strategy also orders the servers, which matters for sequential requests
+    let mut picked = Vec::new();
+    for tier in [in_vram, offloaded, slow_in_vram, slow_offloaded] {
+        let left = max_sel - picked.len();
+        picked.extend(strategy.pick(&snaps, &tier, tier.len().min(left), &mut rng));
     }
+    selected.push(("active", picked));
     num_selected += selected.last().unwrap().1.len();
 
     // 2. choose from alive but inactive servers
-    if num_selected < min_sel {
-        let inactives = alives.iter().filter(|name| {
-            !snaps.get(name.as_str()).unwrap().actives.contains_key(&model)
-        }).cloned().collect::<Vec<_>>();
-        if num_selected + inactives.len() <= min_sel {
-            selected.push(("inactive", inactives));
+    let all_busy = !actives.is_empty() && actives.iter().all(|name| snaps.get(name.as_str()).unwrap().state.busy);
+    let count = match opts.cold_load {
+        ColdLoad::Wait if !actives.is_empty() => 0,
+        ColdLoad::Eager if all_busy => min_sel.max(1).min(max_sel.saturating_sub(num_selected)),
+        _ => min_sel.saturating_sub(num_selected),
+    };
+    if count > 0 {
+        let mut inactives = alives.iter().filter(|name| resident(name).is_none()).cloned().collect::<Vec<_>>();
+        if all_busy && opts.cold_load == ColdLoad::Eager {
+            // loading the model only beats queueing where nothing else is running
+            inactives.retain(|name| !snaps.get(name.as_str()).unwrap().state.busy);
+        }
+        // prefer loading the model where it does not evict another one
+        let (spare, evicting): (Vec<_>, Vec<_>) = inactives.iter()
+            .partition(|name| fits_in_spare_vram(snaps.get(name.as_str()).unwrap(), &model));
+        let (slow_spare, spare): (Vec<_>, Vec<_>) = spare.into_iter().partition(slow);
+        let (slow_evicting, evicting): (Vec<_>, Vec<_>) = evicting.into_iter().partition(slow);
+        let mut picked = Vec::new();
+        for tier in [spare, evicting, slow_spare, slow_evicting] {
+            let left = count - picked.len();
+            picked.extend(strategy.pick(&snaps, &tier, tier.len().min(left), &mut rng));
+        }
+        num_selected += picked.len();
+        if all_busy && opts.cold_load == ColdLoad::Eager {
+            // idle servers answer first in sequential dispatch
+            selected.insert(0, ("cold", picked));
         } else {
-            selected.push(("inactive", sample_by_health(&snaps, &inactives, min_sel - num_selected, &mut rng)));
+            selected.push(("inactive", picked));
         }
-        num_selected += selected.last().unwrap().1.len();
     }
 
     // 3. choose from dead servers
@@ -293,17 +1669,37 @@ pub fn select_servers(
         resurrect_n += min_sel - num_selected;
     }
     if resurrect_n > 0 {
-        let deads = snaps.iter().filter_map(|(addr, snap)| {
-            if snap.state.health == Health::Dead {
+        let deads = snaps.iter().filter(|(addr, snap)| {
+            is_trusted(snap, opts.strict) && !tried.contains(addr) && is_pinned_to(pinned, addr, snap)
+        }).filter_map(|(addr, snap)| {
+            if snap.state.health == Health::Dead || snap.state.misconfigured.is_some() {
                 Some(addr)
             } else {
                 None
             }
         }).collect::<Vec<_>>();
         selected.push(("resurrect", sample_by_health(&snaps, &deads, resurrect_n, &mut rng)));
-        num_selected += selected.last().unwrap().1.len();
     }
 
+    // never race two servers sharing the same hardware, the first pick of a domain wins
+    let mut domains_taken = Vec::new();
+    let mut domain_skipped = Vec::new();
+    for (_, addrs) in selected.iter_mut() {
+        addrs.retain(|addr| match snaps.get(addr.as_str()).unwrap().domain.as_deref() {
+            Some(domain) if domains_taken.contains(&domain) => {
+                info!("Skipped server {} sharing GPU domain {} with another selected server", addr, domain);
+                domain_skipped.push(*addr);
+                false
+            },
+            Some(domain) => {
+                domains_taken.push(domain);
+                true
+            },
+            None => true,
+        });
+    }
+    num_selected = selected.iter().map(|(_, addrs)| addrs.len()).sum::<usize>();
+
     // make a summary
     let summary = selected.iter().map(|(tag, addrs)| {
         let names = addrs.iter().map(|a| snaps.get(a.as_str()).unwrap().name.as_str()).collect::<Vec<&str>>();
@@ -314,6 +1710,36 @@ pub fn select_servers(
         }
     }).collect::<Vec<String>>().join("\n");
     info!("Selected {} servers for model {}:\n{}", num_selected, model, summary);
+    if opts.strict && num_selected == 0 && !untrusted.is_empty() {
+        STRICT_STATS.emptied.fetch_add(1, Ordering::Relaxed);
+        warn!("Strict mode left no server for model {} ({})", model, STRICT_STATS.summary());
+    }
 
-    selected.into_iter().flat_map(|(_, addrs)| addrs).map(|s| s.clone()).collect()
-}
\ No newline at end of file
+    let servers = selected.into_iter().flat_map(|(_, addrs)| addrs).cloned().collect::<Vec<_>>();
+    // the first filter that eliminated each server which was not selected
+    let mut excluded = hidden.to_vec();
+    for (addr, snap) in snaps.iter().filter(|(addr, _)| !servers.contains(addr)) {
+        let reason = exclusion_of(snap, addr, &model, opts.strict, pinned).unwrap_or_else(|| {
+            if domain_skipped.contains(&addr) {
+                Exclusion::SharedGpuDomain
+            } else if resident(addr).is_none() {
+                Exclusion::NotLoaded
+            } else {
+                Exclusion::Outranked
+            }
+        });
+        excluded.push((addr.clone(), reason));
+    }
+    excluded.sort_by(|a, b| a.0.cmp(&b.0));
+    if !excluded.is_empty() {
+        let reasons = excluded.iter()
+            .map(|(addr, reason)| format!("{} ({})", addr, reason.as_str()))
+            .collect::<Vec<_>>().join(", ");
+        if num_selected == 0 {
+            warn!("No server selected for model {}, excluded: {}", model, reasons);
+        } else {
+            debug!("Excluded for model {}: {}", model, reasons);
+        }
+    }
+    Selection { servers, excluded }
+}
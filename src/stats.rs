@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::schema::{ModelStatsEntry, ModelStatsReport, SCHEMA_VERSION};
+use crate::state::ewma;
+
+/// Requests a pair must have served before its figures are trusted by the selection.
+const MIN_REQUESTS: u64 = 3;
+
+/// Figures older than this are not trusted either, so that a demoted server gets measured again.
+const STALE_AFTER: Duration = Duration::from_secs(600);
+
+/// Rolling figures of one model on one server.
+#[derive(Debug, Clone)]
+struct ModelStats {
+    requests: u64,
+    ttft_ms: f32,
+    tokens_per_sec: Option<f32>,
+    last_seen: Instant,
+}
+
+/// Performance per (server, model), since a server good at small models can be a poor
+/// choice for large ones, which its overall latency does not tell.
+static STATS: LazyLock<Mutex<HashMap<(String, String), ModelStats>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Forgets the performance measured by a previous load balancer.
+pub fn reset() {
+    STATS.lock().unwrap().clear();
+}
+
+/// Records a request served by `server` for `model`. `tokens_per_sec` is absent when the
+/// response was relayed before it could be measured.
+pub fn record(server: &str, model: &str, ttft: Duration, tokens_per_sec: Option<f32>, alpha: f32) {
+    let ttft_ms = ttft.as_secs_f32() * 1000.0;
+    let mut stats = STATS.lock().unwrap();
+    let entry = stats.entry((server.to_string(), model.to_string())).or_insert(ModelStats {
+        requests: 0,
+        ttft_ms,
+        tokens_per_sec: None,
+        last_seen: Instant::now(),
+    });
+    entry.requests += 1;
+    entry.ttft_ms = ewma(Some(entry.ttft_ms), ttft_ms, alpha);
+    if let Some(rate) = tokens_per_sec.filter(|rate| *rate > 0.0) {
+        entry.tokens_per_sec = Some(ewma(entry.tokens_per_sec, rate, alpha));
+    }
+    entry.last_seen = Instant::now();
+}
+
+/// Drops the figures of a server leaving the pool.
+pub fn forget(server: &str) {
+    STATS.lock().unwrap().retain(|(s, _), _| s != server);
+}
+
+/// Whether `server` is more than `factor` times slower to the first token than the fastest
+/// server measured for `model`. Pairs with too few or stale figures are never deemed slow.
+pub fn is_slow(server: &str, model: &str, factor: f32) -> bool {
+    if factor <= 0.0 {
+        return false;
+    }
+    let stats = STATS.lock().unwrap();
+    let trusted = |s: &&ModelStats| s.requests >= MIN_REQUESTS && s.last_seen.elapsed() < STALE_AFTER;
+    let Some(own) = stats.get(&(server.to_string(), model.to_string())).filter(trusted) else {
+        return false;
+    };
+    let best = stats.iter()
+        .filter(|((_, m), s)| m == model && trusted(s))
+        .map(|(_, s)| s.ttft_ms)
+        .fold(f32::INFINITY, f32::min);
+    own.ttft_ms > best * factor
+}
+
+/// Served on `/admin/stats`, ordered by model then server.
+pub fn report() -> ModelStatsReport {
+    let stats = STATS.lock().unwrap();
+    let mut entries = stats.iter().map(|((server, model), s)| ModelStatsEntry {
+        server: server.clone(),
+        model: model.clone(),
+        requests: s.requests,
+        ttft_ms: s.ttft_ms,
+        tokens_per_sec: s.tokens_per_sec,
+        last_seen_secs: s.last_seen.elapsed().as_secs(),
+    }).collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.model.cmp(&b.model).then_with(|| a.server.cmp(&b.server)));
+    ModelStatsReport { schema_version: SCHEMA_VERSION, entries }
+}
@@ -0,0 +1,58 @@
+//! `ollama_load_balancer status`: the readiness and servers of a running load balancer,
+//! from its admin API.
+
+use serde_json::Value;
+
+use crate::schema::{HealthReport, StateReport, SCHEMA_VERSION};
+use crate::tls;
+
+/// Prints the readiness of the load balancer at `url` and a table of its servers.
+pub async fn status(url: &str, api_key: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let url = url.trim_end_matches('/');
+    let client = tls::client();
+    // /readyz answers 503 with the same document while not ready
+    let ready: Value = client.get(format!("{}/readyz", url)).send().await?.json().await?;
+    let mut request = client.get(format!("{}/admin/state", url));
+    if let Some(key) = api_key {
+        request = request.bearer_auth(key);
+    }
+    let resp = request.send().await?;
+    if !resp.status().is_success() {
+        return Err(format!("{}/admin/state answered {}", url, resp.status()).into());
+    }
+    let state: StateReport = resp.json().await?;
+    if state.schema_version != SCHEMA_VERSION {
+        return Err(format!("Unsupported admin schema version {}, expected {}", state.schema_version, SCHEMA_VERSION).into());
+    }
+
+    println!(
+        "{}: {} ({}), {} healthy of {} servers",
+        url, ready["status"].as_str().unwrap_or("unknown"), ready["stage"].as_str().unwrap_or("-"),
+        ready["healthy"], state.servers.len()
+    );
+    for reason in ready["reasons"].as_array().into_iter().flatten() {
+        println!("  not ready: {}", reason.as_str().unwrap_or_default());
+    }
+    println!();
+    print_servers(&state);
+    Ok(())
+}
+
+/// Prints a table of the servers of a state report, one line each.
+pub fn print_servers(state: &StateReport) {
+    println!("{:<28} {:<16} {:<10} {:>5} {:>9}  MODELS (LOADED)", "ADDRESS", "NAME", "STATUS", "CONNS", "TTFT");
+    for server in &state.servers {
+        let status = match (&server.health, &server.misconfigured, server.isolated, &server.leaving) {
+            (_, _, _, Some(_)) => "leaving".to_string(),
+            (_, _, true, _) => "isolated".to_string(),
+            (_, Some(_), _, _) => "misconf".to_string(),
+            (HealthReport::Dead, _, _, _) => "dead".to_string(),
+            (HealthReport::Unknown, _, _, _) => "unknown".to_string(),
+            (HealthReport::Healthy { score }, _, _, _) => format!("ok {:.1}", score),
+        };
+        let ttft = server.latency_ms.map_or("-".to_string(), |ms| format!("{:.0}ms", ms));
+        let loaded = server.loaded.iter().map(|m| m.name.as_str()).collect::<Vec<_>>();
+        println!("{:<28} {:<16} {:<10} {:>5} {:>9}  {} ({})",
+            server.address, server.name, status, server.connections, ttft, server.models.len(), loaded.join(", "));
+    }
+}
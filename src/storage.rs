@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::accounting::Accounting;
+use crate::state::{Health, ModelConfig, SharedConversationMap, SharedServerList};
+
+pub type StorageResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const NS_USAGE: &str = "usage";
+pub const NS_HEALTH: &str = "health";
+pub const NS_SESSIONS: &str = "sessions";
+pub const NS_KEYS: &str = "keys";
+pub const NS_SERVERS: &str = "servers";
+
+/// Namespaced key-value store holding the balancer state worth keeping across restarts,
+/// or sharing between several balancer instances.
+///
+/// Calls may block on I/O, keep them off the request path.
+pub trait Storage: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn get(&self, namespace: &str, key: &str) -> StorageResult<Option<String>>;
+    fn put(&self, namespace: &str, key: &str, value: &str) -> StorageResult<()>;
+    fn delete(&self, namespace: &str, key: &str) -> StorageResult<()>;
+    fn list(&self, namespace: &str) -> StorageResult<Vec<(String, String)>>;
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// Default storage, nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: Mutex<HashMap<String, HashMap<String, String>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get(&self, namespace: &str, key: &str) -> StorageResult<Option<String>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.get(namespace).and_then(|ns| ns.get(key)).cloned())
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &str) -> StorageResult<()> {
+        let mut data = self.data.lock().unwrap();
+        data.entry(namespace.to_string()).or_default().insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> StorageResult<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(ns) = data.get_mut(namespace) {
+            ns.remove(key);
+        }
+        Ok(())
+    }
+
+    fn list(&self, namespace: &str) -> StorageResult<Vec<(String, String)>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.get(namespace).map(|ns| {
+            ns.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        }).unwrap_or_default())
+    }
+}
+
+/// Single-file storage for one balancer box.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    pub fn open(path: &str) -> StorageResult<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS kv (
+                namespace TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (namespace, key)
+            )",
+            (),
+        )?;
+        Ok(SqliteStorage { conn: Mutex::new(conn) })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn get(&self, namespace: &str, key: &str) -> StorageResult<Option<String>> {
+        use rusqlite::OptionalExtension;
+        let conn = self.conn.lock().unwrap();
+        let value = conn.query_row(
+            "SELECT value FROM kv WHERE namespace = ?1 AND key = ?2",
+            (namespace, key),
+            |row| row.get(0),
+        ).optional()?;
+        Ok(value)
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &str) -> StorageResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)
+             ON CONFLICT (namespace, key) DO UPDATE SET value = excluded.value",
+            (namespace, key, value),
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> StorageResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM kv WHERE namespace = ?1 AND key = ?2", (namespace, key))?;
+        Ok(())
+    }
+
+    fn list(&self, namespace: &str) -> StorageResult<Vec<(String, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT key, value FROM kv WHERE namespace = ?1")?;
+        let rows = stmt.query_map((namespace,), |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+/// Storage shared by several balancer instances, each namespace is a Redis hash.
+#[cfg(feature = "redis")]
+pub struct RedisStorage {
+    conn: Mutex<redis::Connection>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStorage {
+    pub fn open(url: &str) -> StorageResult<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = client.get_connection()?;
+        Ok(RedisStorage { conn: Mutex::new(conn) })
+    }
+
+    fn hash_key(namespace: &str) -> String {
+        format!("ollama_lb:{}", namespace)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Storage for RedisStorage {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn get(&self, namespace: &str, key: &str) -> StorageResult<Option<String>> {
+        use redis::Commands;
+        let mut conn = self.conn.lock().unwrap();
+        Ok(conn.hget(Self::hash_key(namespace), key)?)
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &str) -> StorageResult<()> {
+        use redis::Commands;
+        let mut conn = self.conn.lock().unwrap();
+        let _: () = conn.hset(Self::hash_key(namespace), key, value)?;
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> StorageResult<()> {
+        use redis::Commands;
+        let mut conn = self.conn.lock().unwrap();
+        let _: () = conn.hdel(Self::hash_key(namespace), key)?;
+        Ok(())
+    }
+
+    fn list(&self, namespace: &str) -> StorageResult<Vec<(String, String)>> {
+        use redis::Commands;
+        let mut conn = self.conn.lock().unwrap();
+        let all: HashMap<String, String> = conn.hgetall(Self::hash_key(namespace))?;
+        Ok(all.into_iter().collect())
+    }
+}
+
+/// Opens the storage described by `--storage`: `memory`, `sqlite:<path>` or `redis://...`.
+pub fn open_storage(spec: &str) -> StorageResult<SharedStorage> {
+    if spec == "memory" {
+        return Ok(Arc::new(MemoryStorage::default()));
+    }
+    if let Some(_path) = spec.strip_prefix("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        return Ok(Arc::new(SqliteStorage::open(_path)?));
+        #[cfg(not(feature = "sqlite"))]
+        return Err("SQLite storage requires building with the `sqlite` feature".into());
+    }
+    if spec.starts_with("redis://") || spec.starts_with("rediss://") {
+        #[cfg(feature = "redis")]
+        return Ok(Arc::new(RedisStorage::open(spec)?));
+        #[cfg(not(feature = "redis"))]
+        return Err("Redis storage requires building with the `redis` feature".into());
+    }
+    Err(format!("Unknown storage `{}`, expected memory, sqlite:<path> or redis://...", spec).into())
+}
+
+/// Restores the persisted state at startup. Must run after the initial health sync,
+/// which would otherwise reset the restored health values.
+pub fn restore_state(
+    storage: &dyn Storage,
+    servers: &SharedServerList,
+    accounting: Option<&Accounting>,
+    conversations: Option<&SharedConversationMap>,
+) -> StorageResult<()> {
+    let healths = storage.list(NS_HEALTH)?;
+    {
+        let mut servers = servers.lock().unwrap();
+        for (addr, value) in healths.iter() {
+            let health = value.parse::<f32>().ok();
+            if let (Some(server), Some(h)) = (servers.get_mut(addr.as_str()), health) {
+                // only alive servers get their history back, dead ones stay dead
+                if server.state.health != Health::Dead {
+                    server.state.health = Health::Healthy(h.max(1.0));
+                }
+            }
+        }
+    }
+    if let Some(accounting) = accounting {
+        for (key, value) in storage.list(NS_USAGE)? {
+            if let Ok(value) = serde_json::from_str::<Value>(&value) {
+                accounting.restore(&key, &value);
+            }
+        }
+    }
+    if let Some(conversations) = conversations {
+        let mut conversations = conversations.lock().unwrap();
+        for (hash, server) in storage.list(NS_SESSIONS)? {
+            if let Ok(hash) = hash.parse::<u64>() {
+                conversations.record(hash, &server);
+            }
+        }
+        // restored pins are persisted already, unless the capacity left them out
+        conversations.take_recorded();
+    }
+    info!("Restored state from {} storage ({} health records)", storage.name(), healths.len());
+    Ok(())
+}
+
+fn persist_state(
+    storage: &dyn Storage,
+    servers: &SharedServerList,
+    accounting: Option<&Accounting>,
+    conversations: Option<&SharedConversationMap>,
+) -> StorageResult<()> {
+    let healths = servers.lock().unwrap().iter().filter_map(|(addr, srv)| match srv.state.health {
+        Health::Healthy(h) => Some((addr.clone(), h)),
+        Health::Dead => None,
+    }).collect::<Vec<_>>();
+    for (addr, h) in healths {
+        storage.put(NS_HEALTH, &addr, &h.to_string())?;
+    }
+    if let Some(accounting) = accounting {
+        for (key, value) in accounting.export() {
+            storage.put(NS_USAGE, &key, &value.to_string())?;
+        }
+    }
+    if let Some(conversations) = conversations {
+        let (entries, evicted) = {
+            let mut conversations = conversations.lock().unwrap();
+            conversations.purge_expired();
+            (conversations.take_recorded(), conversations.take_evicted())
+        };
+        for hash in evicted {
+            storage.delete(NS_SESSIONS, &hash.to_string())?;
+        }
+        for (hash, server) in entries {
+            storage.put(NS_SESSIONS, &hash.to_string(), &server)?;
+        }
+    }
+    Ok(())
+}
+
+/// Periodically writes the in-memory state to the storage.
+pub async fn persist_loop(
+    storage: SharedStorage,
+    servers: SharedServerList,
+    accounting: Option<Arc<Accounting>>,
+    conversations: Option<SharedConversationMap>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await; // the first tick completes immediately
+    loop {
+        ticker.tick().await;
+        let storage = storage.clone();
+        let servers = servers.clone();
+        let accounting = accounting.clone();
+        let conversations = conversations.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            persist_state(storage.as_ref(), &servers, accounting.as_deref(), conversations.as_ref())
+        }).await;
+        match res {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => warn!("Failed to persist state: {}", e),
+            Err(e) => warn!("State persistence task failed: {}", e),
+        }
+    }
+}
+
+/// Saves what was learned about every server (models, loaded models, version, latency)
+/// so that the next run can route before its first sync completes.
+pub fn save_warm_cache(storage: &dyn Storage, servers: &SharedServerList) -> StorageResult<usize> {
+    let entries = servers.lock().unwrap().iter().filter(|(_, srv)| !srv.models.is_empty()).map(|(addr, srv)| {
+        let health = match srv.state.health {
+            Health::Healthy(h) => Some(h),
+            Health::Dead => None,
+        };
+        (addr.clone(), json!({
+            "models": srv.models.values().map(|m| m.detail.clone()).collect::<Vec<Value>>(),
+            "actives": srv.actives.values().map(|m| m.detail.clone()).collect::<Vec<Value>>(),
+            "version": srv.version,
+            "latency_ms": srv.state.latency_ms,
+            "health": health,
+        }))
+    }).collect::<Vec<_>>();
+    for (addr, entry) in entries.iter() {
+        storage.put(NS_SERVERS, addr, &entry.to_string())?;
+    }
+    Ok(entries.len())
+}
+
+fn parse_models(value: &Value) -> HashMap<String, ModelConfig> {
+    value.as_array().map(|models| models.iter().filter_map(|m| {
+        let name = m["name"].as_str()?.to_string();
+        Some((name.clone(), ModelConfig::new(name, m.clone())))
+    }).collect()).unwrap_or_default()
+}
+
+/// Loads the servers saved by `save_warm_cache`, marked stale until their first sync.
+/// Returns the number of configured servers that got warmed up.
+pub fn load_warm_cache(storage: &dyn Storage, servers: &SharedServerList) -> StorageResult<usize> {
+    let addrs = servers.lock().unwrap().keys().cloned().collect::<Vec<String>>();
+    let mut warmed = 0;
+    for addr in addrs {
+        let entry = match storage.get(NS_SERVERS, &addr)? {
+            Some(entry) => serde_json::from_str::<Value>(&entry)?,
+            None => continue,
+        };
+        let mut servers = servers.lock().unwrap();
+        if let Some(server) = servers.get_mut(addr.as_str()) {
+            server.models = parse_models(&entry["models"]);
+            server.actives = parse_models(&entry["actives"]);
+            server.version = entry["version"].as_str().map(String::from);
+            server.state.latency_ms = entry["latency_ms"].as_f64().map(|l| l as f32);
+            server.state.health = match entry["health"].as_f64() {
+                Some(h) => Health::Healthy(h as f32),
+                None => Health::Dead,
+            };
+            server.state.stale = true;
+            server.state.unsynced = false;
+            warmed += 1;
+        }
+    }
+    Ok(warmed)
+}
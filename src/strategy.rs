@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rand::seq::SliceRandom;
+
+use crate::state::{sample_by_health, ServerSnapshot};
+
+/// Decides which of the eligible servers get a request, and in which order.
+pub trait SelectionStrategy: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    /// Picks at most `count` servers out of `candidates`, preferred first.
+    fn pick<'a>(
+        &self,
+        snaps: &HashMap<String, ServerSnapshot>,
+        candidates: &[&'a String],
+        count: usize,
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Vec<&'a String>;
+}
+
+/// Weighted random sampling by health value, the historical behavior.
+#[derive(Debug)]
+pub struct HealthWeighted;
+
+impl SelectionStrategy for HealthWeighted {
+    fn name(&self) -> &'static str {
+        "health"
+    }
+
+    fn pick<'a>(
+        &self,
+        snaps: &HashMap<String, ServerSnapshot>,
+        candidates: &[&'a String],
+        count: usize,
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Vec<&'a String> {
+        sample_by_health(snaps, candidates, count, rng)
+    }
+}
+
+/// Rotates through the candidates, sorted by address so the order is stable.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl SelectionStrategy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn pick<'a>(
+        &self,
+        _snaps: &HashMap<String, ServerSnapshot>,
+        candidates: &[&'a String],
+        count: usize,
+        _rng: &mut rand::rngs::ThreadRng,
+    ) -> Vec<&'a String> {
+        if candidates.is_empty() {
+            return Vec::new();
+        }
+        let mut sorted = candidates.to_vec();
+        sorted.sort();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % sorted.len();
+        sorted.iter().cycle().skip(start).take(count.min(sorted.len())).cloned().collect()
+    }
+}
+
+/// Prefers the servers relaying the fewest streams, ties are broken randomly.
+#[derive(Debug)]
+pub struct LeastConnections;
+
+impl SelectionStrategy for LeastConnections {
+    fn name(&self) -> &'static str {
+        "least-connections"
+    }
+
+    fn pick<'a>(
+        &self,
+        snaps: &HashMap<String, ServerSnapshot>,
+        candidates: &[&'a String],
+        count: usize,
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Vec<&'a String> {
+        let mut shuffled = candidates.to_vec();
+        shuffled.shuffle(rng);
+        // stable sort, so the shuffle decides among equals
+        shuffled.sort_by_key(|addr| snaps.get(addr.as_str()).map_or(usize::MAX, |s| s.state.connections));
+        shuffled.into_iter().take(count).collect()
+    }
+}
+
+/// Prefers the servers with the lowest time to first token, measured on production
+/// traffic or by the synthetic prober. Servers never measured come first, so that they get measured.
+#[derive(Debug)]
+pub struct LowestLatency;
+
+impl SelectionStrategy for LowestLatency {
+    fn name(&self) -> &'static str {
+        "lowest-latency"
+    }
+
+    fn pick<'a>(
+        &self,
+        snaps: &HashMap<String, ServerSnapshot>,
+        candidates: &[&'a String],
+        count: usize,
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Vec<&'a String> {
+        let mut shuffled = candidates.to_vec();
+        shuffled.shuffle(rng);
+        // probes stand in for servers that served no production traffic yet
+        let latency = |addr: &String| snaps.get(addr.as_str())
+            .and_then(|s| s.state.latency_ms.or(s.state.shadow_ttft_ms))
+            .unwrap_or(0.0);
+        shuffled.sort_by(|a, b| latency(a).total_cmp(&latency(b)));
+        shuffled.into_iter().take(count).collect()
+    }
+}
+
+pub const STRATEGIES: [&str; 4] = ["health", "round-robin", "least-connections", "lowest-latency"];
+
+pub fn make_strategy(name: &str) -> Result<Box<dyn SelectionStrategy>, String> {
+    match name {
+        "health" => Ok(Box::new(HealthWeighted)),
+        "round-robin" => Ok(Box::new(RoundRobin::default())),
+        "least-connections" => Ok(Box::new(LeastConnections)),
+        "lowest-latency" => Ok(Box::new(LowestLatency)),
+        _ => Err(format!("Unknown strategy `{}`, expected one of: {}", name, STRATEGIES.join(", "))),
+    }
+}
@@ -0,0 +1,75 @@
+//! Integration with systemd, without linking libsystemd: readiness and watchdog
+//! notifications for `Type=notify` units, and sockets passed by socket activation.
+//! Everything is a no-op when the process was not started by systemd.
+
+use std::env;
+use std::os::fd::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// First file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether a `LISTEN_PID` or `WATCHDOG_PID` variable is meant for this process.
+fn is_for_us(var: &str) -> bool {
+    env::var(var).ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id())
+}
+
+/// Sends a state, e.g. `READY=1`, to the service manager if it listens on `NOTIFY_SOCKET`.
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send_notification(&path, state) {
+        warn!("Failed to notify systemd of {}: {}", state, e);
+    }
+}
+
+fn send_notification(path: &str, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("abstract socket {}", name)));
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// The sockets passed by socket activation, none if the process was not socket activated.
+pub fn listen_fds() -> Vec<RawFd> {
+    if !is_for_us("LISTEN_PID") {
+        return Vec::new();
+    }
+    // the variables stay set, changing the environment of a multi-threaded process is unsound,
+    // and LISTEN_PID already tells the children of this process that the sockets are not theirs
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok()).unwrap_or_default();
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+/// How often the watchdog must be pinged, if `WatchdogSec=` is set in the unit.
+pub fn watchdog_interval() -> Option<Duration> {
+    if env::var("WATCHDOG_PID").is_ok() && !is_for_us("WATCHDOG_PID") {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    // twice as often as required, so that a late tick does not get us killed
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Pings the watchdog forever. A runtime so stuck that this task does not run gets restarted.
+pub async fn watchdog(interval: Duration) {
+    info!("Pinging the systemd watchdog every {:.1}s", interval.as_secs_f32());
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
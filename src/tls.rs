@@ -0,0 +1,83 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// TLS implementation of the outgoing connections: to the backends, the Kubernetes API,
+/// the registry and the authentication, authorization and autoscaling services.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsBackend {
+    /// The platform library: OpenSSL on Linux, e.g. a FIPS validated build of it,
+    /// Secure Transport on macOS and SChannel on Windows.
+    Native,
+    /// rustls, no system library involved.
+    Rustls,
+}
+
+impl TlsBackend {
+    /// The implementations this build was compiled with, the preferred one first.
+    pub fn compiled() -> Vec<TlsBackend> {
+        let mut compiled = Vec::new();
+        if cfg!(feature = "native-tls") {
+            compiled.push(TlsBackend::Native);
+        }
+        if cfg!(feature = "rustls") {
+            compiled.push(TlsBackend::Rustls);
+        }
+        compiled
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TlsBackend::Native => "native",
+            TlsBackend::Rustls => "rustls",
+        }
+    }
+}
+
+impl FromStr for TlsBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(TlsBackend::Native),
+            "rustls" => Ok(TlsBackend::Rustls),
+            _ => Err(format!("Unknown TLS backend {}, expected native or rustls", s)),
+        }
+    }
+}
+
+static TLS_BACKEND: Mutex<Option<TlsBackend>> = Mutex::new(None);
+
+/// Picks the TLS implementation of every client, when building the load balancer:
+/// the wanted one, or the preferred one compiled in. Fails if it was not compiled in.
+pub fn set_tls_backend(wanted: Option<TlsBackend>) -> Result<Option<TlsBackend>, String> {
+    let compiled = TlsBackend::compiled();
+    let backend = match wanted {
+        Some(wanted) if !compiled.contains(&wanted) => {
+            return Err(format!("TLS backend {} requires building with the `{}` feature", wanted.name(), match wanted {
+                TlsBackend::Native => "native-tls",
+                TlsBackend::Rustls => "rustls",
+            }));
+        },
+        Some(wanted) => Some(wanted),
+        None => compiled.first().copied(),
+    };
+    *TLS_BACKEND.lock().unwrap() = backend;
+    Ok(backend)
+}
+
+/// A client builder using the chosen TLS implementation, every client starts from it.
+pub fn client_builder() -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder();
+    match *TLS_BACKEND.lock().unwrap() {
+        #[cfg(feature = "native-tls")]
+        Some(TlsBackend::Native) => builder.use_native_tls(),
+        #[cfg(feature = "rustls")]
+        Some(TlsBackend::Rustls) => builder.use_rustls_tls(),
+        _ => builder,
+    }
+}
+
+/// A client with the chosen TLS implementation and nothing else configured.
+pub fn client() -> reqwest::Client {
+    client_builder().build().unwrap()
+}
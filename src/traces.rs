@@ -0,0 +1,198 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use hyper::body::{Body as _, Frame, SizeHint};
+use hyper::header::HeaderMap;
+use hyper::StatusCode;
+use tracing::warn;
+
+use crate::body::{Body, BoxError};
+use crate::relay::RelayTally;
+use crate::schema::{TraceReport, TracesReport, SCHEMA_VERSION};
+use crate::tls;
+
+/// How often the kept traces are posted to the collector.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Which requests are worth a trace, decided once their response body is over: every failed,
+/// truncated or slow request, and a share of the fast successes to know what normal looks like.
+#[derive(Debug, Clone)]
+pub struct TraceSampling {
+    /// Requests answered slower than this are always kept.
+    pub slow: Duration,
+    /// Share of the fast successes kept, from 0 to 1.
+    pub success_rate: f64,
+    /// `success_rate` of single endpoints.
+    pub per_endpoint: HashMap<String, f64>,
+}
+
+impl TraceSampling {
+    /// Why a trace is kept, `None` if it is dropped.
+    pub fn decide(&self, trace: &TraceReport) -> Option<&'static str> {
+        let status = StatusCode::from_u16(trace.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || trace.outcome == "truncated" {
+            return Some("error");
+        }
+        if Duration::from_millis(trace.duration_ms) >= self.slow {
+            return Some("slow");
+        }
+        let rate = self.per_endpoint.get(&trace.path).copied().unwrap_or(self.success_rate);
+        (rand::random::<f64>() < rate).then_some("sampled")
+    }
+}
+
+/// The traces kept by the sampling, newest last, served on `/admin/traces` and posted to the
+/// collector, if any.
+#[derive(Debug)]
+pub struct Tracer {
+    sampling: TraceSampling,
+    capacity: usize,
+    export: Option<String>,
+    traces: Mutex<VecDeque<TraceReport>>,
+    unexported: Mutex<VecDeque<TraceReport>>,
+    kept: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Tracer {
+    pub fn new(sampling: TraceSampling, capacity: usize, export: Option<String>) -> Self {
+        Tracer {
+            sampling,
+            capacity,
+            export,
+            traces: Mutex::new(VecDeque::new()),
+            unexported: Mutex::new(VecDeque::new()),
+            kept: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Decides on the trace of a request whose response is over, `trace` getting its reason if kept.
+    pub fn finish(&self, mut trace: TraceReport) {
+        let Some(reason) = self.sampling.decide(&trace) else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        self.kept.fetch_add(1, Ordering::Relaxed);
+        trace.reason = reason.to_string();
+        if self.export.is_some() {
+            push_capped(&mut self.unexported.lock().unwrap(), trace.clone(), self.capacity);
+        }
+        push_capped(&mut self.traces.lock().unwrap(), trace, self.capacity);
+    }
+
+    pub fn report(&self) -> TracesReport {
+        TracesReport {
+            schema_version: SCHEMA_VERSION,
+            kept: self.kept.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            traces: self.traces.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+}
+
+fn push_capped(traces: &mut VecDeque<TraceReport>, trace: TraceReport, capacity: usize) {
+    traces.push_back(trace);
+    while traces.len() > capacity {
+        traces.pop_front();
+    }
+}
+
+/// Posts the traces kept since the previous export to the collector every few seconds, as a
+/// `TracesReport`. Traces that fail to post are not retried, not to pile up.
+pub async fn export_loop(tracer: Arc<Tracer>) {
+    let Some(url) = tracer.export.clone() else {
+        return;
+    };
+    let http = tls::client_builder().timeout(Duration::from_secs(10)).build().unwrap();
+    let mut ticker = tokio::time::interval(EXPORT_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let traces = std::mem::take(&mut *tracer.unexported.lock().unwrap());
+        if traces.is_empty() {
+            continue;
+        }
+        let count = traces.len();
+        let batch = TracesReport {
+            schema_version: SCHEMA_VERSION,
+            kept: tracer.kept.load(Ordering::Relaxed),
+            dropped: tracer.dropped.load(Ordering::Relaxed),
+            traces: traces.into(),
+        };
+        if let Err(e) = http.post(&url).json(&batch).send().await.and_then(|resp| resp.error_for_status()) {
+            warn!("Failed to export {} traces to {}: {}", count, url, e);
+        }
+    }
+}
+
+/// A response body that hands its trace to the tracer once it is over, with how it ended.
+pub struct TracedBody {
+    body: Body,
+    tally: Option<Arc<RelayTally>>,
+    tracer: Arc<Tracer>,
+    trace: Option<TraceReport>,
+    started: Instant,
+    outcome: Option<&'static str>,
+}
+
+impl TracedBody {
+    /// `tally` tells relayed responses cut off by the backend, which may end in an error line.
+    pub fn new(body: Body, tally: Option<Arc<RelayTally>>, tracer: Arc<Tracer>, trace: TraceReport, started: Instant) -> Self {
+        TracedBody { body, tally, tracer, trace: Some(trace), started, outcome: None }
+    }
+}
+
+impl hyper::body::Body for TracedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let res = Pin::new(&mut self.body).poll_frame(cx);
+        match &res {
+            Poll::Ready(None) => { self.outcome.get_or_insert("completed"); },
+            Poll::Ready(Some(Err(_))) => self.outcome = Some("truncated"),
+            _ => {},
+        }
+        res
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for TracedBody {
+    fn drop(&mut self) {
+        let Some(mut trace) = self.trace.take() else {
+            return;
+        };
+        // hyper stops polling a body once its content length is written
+        let ended = if self.body.is_end_stream() { "completed" } else { "client_disconnected" };
+        let outcome = match &self.tally {
+            Some(tally) if tally.backend_failed() => "truncated",
+            _ => self.outcome.unwrap_or(ended),
+        };
+        trace.outcome = outcome.to_string();
+        trace.total_ms = self.started.elapsed().as_millis() as u64;
+        self.tracer.finish(trace);
+    }
+}
+
+/// The trace id of the W3C `traceparent` header of the client, so that its traces and ours
+/// can be joined, or else a new one.
+pub fn trace_id(headers: &HeaderMap) -> String {
+    headers.get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split('-').nth(1))
+        .filter(|id| id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()))
+}
@@ -0,0 +1,111 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use bytes::Bytes;
+use futures_util::Stream;
+use serde_json::{json, Value};
+
+/// Which backend served a response, echoed to the client so that routing can be verified.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub server: String,
+    pub name: String,
+    /// Time to first token, or to the response headers for the endpoints not raced.
+    pub ttft: Duration,
+    /// Whether it served the request as a fallback, after every selected server failed it.
+    pub failover: bool,
+}
+
+impl Upstream {
+    /// `X-LB-Upstream: server=...; name=...; ttft_ms=...; failover=...`
+    pub fn header_value(&self) -> String {
+        format!("server={}; name={}; ttft_ms={}; failover={}", self.server, self.name, self.ttft.as_millis(), self.failover)
+    }
+
+    /// The upstream of a `header_value`.
+    pub fn parse(value: &str) -> Option<Upstream> {
+        let field = |name: &str| value.split("; ").find_map(|part| part.strip_prefix(name)?.strip_prefix('='));
+        Some(Upstream {
+            server: field("server")?.to_string(),
+            name: field("name")?.to_string(),
+            ttft: Duration::from_millis(field("ttft_ms")?.parse().ok()?),
+            failover: field("failover")? == "true",
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "server": self.server,
+            "name": self.name,
+            "ttft_ms": self.ttft.as_millis() as u64,
+            "failover": self.failover,
+        })
+    }
+}
+
+/// Adds the upstream as `lb_upstream` to the final chunk of a response, the one with
+/// `"done": true`. The other lines are relayed as soon as they are complete.
+pub struct AnnotatedBody<S> {
+    stream: S,
+    upstream: Value,
+    line: Vec<u8>,
+}
+
+impl<S> AnnotatedBody<S> {
+    pub fn new(stream: S, upstream: &Upstream) -> Self {
+        AnnotatedBody { stream, upstream: upstream.to_json(), line: Vec::new() }
+    }
+
+    fn annotate(&self, line: &[u8]) -> Option<Bytes> {
+        let mut chunk = serde_json::from_slice::<Value>(line).ok()?;
+        if chunk["done"] != json!(true) {
+            return None;
+        }
+        chunk["lb_upstream"] = self.upstream.clone();
+        let mut annotated = serde_json::to_vec(&chunk).ok()?;
+        if line.ends_with(b"\n") {
+            annotated.push(b'\n');
+        }
+        Some(Bytes::from(annotated))
+    }
+
+    /// The complete lines of `line`, the final one annotated.
+    fn take_lines(&mut self, upto: usize) -> Bytes {
+        let lines = self.line.drain(..upto).collect::<Vec<u8>>();
+        let last_start = lines[..lines.len() - 1].iter().rposition(|b| *b == b'\n').map_or(0, |pos| pos + 1);
+        match self.annotate(&lines[last_start..]) {
+            Some(annotated) => [&lines[..last_start], &annotated[..]].concat().into(),
+            None => lines.into(),
+        }
+    }
+}
+
+impl<S, E> Stream for AnnotatedBody<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(bytes))) => {
+                    self.line.extend_from_slice(&bytes);
+                    // the final chunk comes last, only the lines before it are worth looking at
+                    if let Some(pos) = self.line.iter().rposition(|b| *b == b'\n') {
+                        return Poll::Ready(Some(Ok(self.take_lines(pos + 1))));
+                    }
+                },
+                Poll::Ready(None) if !self.line.is_empty() => {
+                    // non-streaming responses are a single object without a trailing newline
+                    let len = self.line.len();
+                    return Poll::Ready(Some(Ok(self.take_lines(len))));
+                },
+                other => return other,
+            }
+        }
+    }
+}
@@ -1,16 +1,39 @@
 use rand::{self, Rng};
 
+/// Weighted sampling of `count` indices without replacement, the heaviest likeliest first.
+///
+/// Candidates of equal weight are interchangeable to the sampler, so their random keys are
+/// handed out by `ranks` instead, the lowest rank getting the best key: ties are broken
+/// deterministically while each weight class keeps its odds.
 pub fn efraimidis_spirakis_sample(
     weights: &[f32],
+    ranks: &[u64],
     count: usize,
     rng: &mut rand::rngs::ThreadRng,
 ) -> Vec<usize> {
-    let keys = rng.random_iter().take(count).collect::<Vec<f32>>();
+    let mut keys = rng.random_iter().take(weights.len()).collect::<Vec<f32>>();
+    let mut classes: Vec<(u32, Vec<usize>)> = Vec::new();
+    for (idx, weight) in weights.iter().enumerate() {
+        match classes.iter_mut().find(|(bits, _)| *bits == weight.to_bits()) {
+            Some((_, members)) => members.push(idx),
+            None => classes.push((weight.to_bits(), vec![idx])),
+        }
+    }
+    for (_, mut members) in classes.into_iter().filter(|(_, members)| members.len() > 1) {
+        let mut class_keys = members.iter().map(|idx| keys[*idx]).collect::<Vec<_>>();
+        // the largest key wins
+        class_keys.sort_by(|a, b| b.total_cmp(a));
+        members.sort_by_key(|idx| ranks.get(*idx).copied().unwrap_or_default());
+        for (idx, key) in members.into_iter().zip(class_keys) {
+            keys[idx] = key;
+        }
+    }
     let mut results= keys.iter()
         .zip(weights.iter())
         .zip(0..weights.len())
         .map(|((key, weight), idx)| (-key.ln() / weight, idx))
         .collect::<Vec<_>>();
-    results.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    // a NaN weight must not take the whole selection down
+    results.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
     results.iter().take(count).map(|(_, idx)| *idx).collect()
-}
\ No newline at end of file
+}